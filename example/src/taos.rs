@@ -14,7 +14,8 @@ pub struct Migrations {
 }
 
 async fn run(rbatis: Arc<RBatis>) -> Result<(), MigrationsError> {
-    let migration_driver = Arc::new(RbatisMigrationDriver::new(rbatis.clone(), None));
+    let migration_driver = Arc::new(RbatisMigrationDriver::new(rbatis.clone(), None)
+        .with_tdengine_offset_seconds(-16 * 60 * 60));
     let migration_runner = MigrationRunner::new(Migrations {}, migration_driver.clone(), migration_driver.clone(),true);
     migration_runner.migrate().await?;
     Ok(())
@@ -0,0 +1 @@
+CREATE TABLE lorem(id SERIAL, ipsum VARCHAR(16));
@@ -0,0 +1,2 @@
+CREATE INDEX idx_lorem_ipsum ON lorem(ipsum);
+CREATE TABLE dolor(id BIGSERIAL PRIMARY KEY, sit INTEGER, ahmed BIGINT);
\ No newline at end of file
@@ -0,0 +1 @@
+CREATE TABLE init(id SERIAL);
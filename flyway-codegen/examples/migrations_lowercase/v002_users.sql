@@ -0,0 +1 @@
+CREATE TABLE users(id SERIAL, name VARCHAR(64));
@@ -1,14 +1,71 @@
 use proc_macro::TokenStream;
 use std::env;
-use std::num::ParseIntError;
 use std::path::PathBuf;
 use std::str::FromStr;
 use proc_macro2::Span;
 use quote::quote;
-use syn::{LitStr};
+use syn::{Ident, LitBool, LitStr, Token};
+use syn::parse::{Parse, ParseStream};
+use syn::punctuated::Punctuated;
 use syn::__private::TokenStream2;
 
-use flyway_sql_changelog::ChangelogFile;
+use flyway_sql_changelog::{parse_migration_stem, ChangelogFile};
+
+/// Directory used if the `migrations` macro is given no path, relative to the crate root
+const DEFAULT_MIGRATIONS_DIR: &str = "migrations";
+
+/// Environment variable overriding the path given to the `migrations` macro
+const MIGRATIONS_DIR_ENV: &str = "FLYWAY_MIGRATIONS_DIR";
+
+/// Extensions of migration files if the `migrations` macro is given none
+const DEFAULT_EXTENSIONS: [&str; 1] = ["sql"];
+
+/// The arguments of the `migrations` macro
+#[derive(Debug, Clone)]
+struct MigrationsArgs {
+    /// The migrations directory, relative to the crate root
+    path: Option<String>,
+    /// The extensions of migration files, without the leading dot
+    extensions: Vec<String>,
+    /// Whether the `V` prefix of migration files may also be lowercase
+    case_insensitive: bool,
+}
+
+impl Parse for MigrationsArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut args = MigrationsArgs {
+            path: None,
+            extensions: DEFAULT_EXTENSIONS.iter().map(|extension| extension.to_string()).collect(),
+            case_insensitive: false,
+        };
+        if input.peek(LitStr) {
+            args.path = Some(input.parse::<LitStr>()?.value());
+            if !input.is_empty() {
+                input.parse::<Token![,]>()?;
+            }
+        }
+        while !input.is_empty() {
+            let key: Ident = input.parse()?;
+            input.parse::<Token![=]>()?;
+            if key == "extensions" {
+                let content;
+                syn::bracketed!(content in input);
+                args.extensions = Punctuated::<LitStr, Token![,]>::parse_terminated(&content)?
+                    .iter()
+                    .map(|extension| extension.value().trim_start_matches('.').to_string())
+                    .collect();
+            } else if key == "case_insensitive" {
+                args.case_insensitive = input.parse::<LitBool>()?.value;
+            } else {
+                return Err(syn::Error::new(key.span(), format!("Unknown argument: {}", key)));
+            }
+            if !input.is_empty() {
+                input.parse::<Token![,]>()?;
+            }
+        }
+        return Ok(args);
+    }
+}
 
 /// Represents migration files loaded from a directory
 #[derive(Debug, Clone)]
@@ -20,9 +77,25 @@ struct MigrationInfo {
 
 /// Attribute macro for automatically generating a `flyway::MigrationStore`
 ///
-/// The macro takes one required literal string parameter representing the directory containing
-/// the migration files. Each file must be named like `V<version>_<name>.sql`, where `<version>`
-/// is a valid integer and `<name>` is some name describing what the migration does.
+/// The macro takes one optional literal string parameter representing the directory containing
+/// the migration files, relative to the crate root. Without it, the `migrations` directory of
+/// the crate root is used. Each file must be named like `V<version>_<name>.sql`, where
+/// `<version>` is a valid integer and `<name>` is some name describing what the migration does.
+///
+/// Files with other extensions than `.sql` are loaded with the `extensions` argument, e.g.
+/// `#[migrations("migrations/", extensions = ["sql", "ddl"])]`. The extension is not part of the
+/// name of the migration.
+///
+/// With `case_insensitive = true`, files starting with a lowercase `v` (e.g. `v001_init.sql`)
+/// are loaded as well.
+///
+/// Files starting with `V` and having one of the extensions, but not matching the pattern
+/// otherwise (e.g. `V1-typo.sql`), are skipped with a compiler warning.
+///
+/// If the environment variable `FLYWAY_MIGRATIONS_DIR` is set, it overrides the path. As the
+/// macro is expanded at compile time, this is the environment of the build, not of the running
+/// program. The generated code reads the variable with `option_env!`, so Cargo rebuilds the
+/// crate when it changes.
 ///
 /// Example:
 /// ```ignore
@@ -45,12 +118,12 @@ pub fn migrations(args: TokenStream, input: TokenStream) -> TokenStream {
     let input_struct = syn::parse_macro_input!(input_clone as syn::ItemStruct);
     // println!("input struct: {:?}", &input_struct);
 
-    let path = if args.is_empty() {
-        map_to_crate_root(None)
-    } else {
-        let migrations_path = syn::parse_macro_input!(args as LitStr).value();
-        map_to_crate_root(Some(migrations_path.as_str()))
-    };
+    let args = syn::parse_macro_input!(args as MigrationsArgs);
+    let path = resolve_migrations_dir(args.path.as_deref());
+    if !path.is_dir() {
+        let message = format!("Migrations directory does not exist: {}", path.display());
+        return syn::Error::new(Span::call_site(), message).to_compile_error().into();
+    }
 
     #[cfg(feature = "debug_mode")]
     if cfg!(debug_assertions){
@@ -58,8 +131,13 @@ pub fn migrations(args: TokenStream, input: TokenStream) -> TokenStream {
     }
 
 
-    let migrations = get_migrations(&path)
+    let migrations = get_migrations(&path, &args.extensions, args.case_insensitive)
         .expect("Error while gathering migration file information.");
+    let warnings: Vec<TokenStream2> = get_malformed_migrations(&path, &args.extensions, args.case_insensitive)
+        .expect("Error while gathering migration file information.")
+        .iter()
+        .map(|(filename, reason)| skipped_file_warning(filename.as_str(), reason))
+        .collect();
     #[cfg(feature = "debug_mode")]
     if cfg!(debug_assertions){
         println!("migrations: {:?}", &migrations);
@@ -69,17 +147,12 @@ pub fn migrations(args: TokenStream, input: TokenStream) -> TokenStream {
         .map(|migration| {
             let name = migration.name.as_str();
             let version = migration.version;
-            let filename = migration.filename.as_str();
-            let file_path = path.clone().join(filename).display().to_string();
-            let content = std::fs::read_to_string(file_path.as_str())
-                .expect(format!("Could not read migration file: {}", file_path).as_str());
-
-            // just check if the changelog can be loaded correctly:
-            let _changelog = ChangelogFile::from_string(version, name,content.as_str())
-                .expect(format!("Migration file is not a valid SQL changelog file: {}", file_path).as_str());
+            let changelog = load_migration(&path, migration);
+            let content = changelog.content();
+            let checksum = changelog.checksum().expect("Error while computing the checksum of a migration file.");
 
             quote! {
-                (#version, #name.to_string(), #content)
+                (#version, #name.to_string(), #content, #checksum)
             }
         })
         .collect();
@@ -93,7 +166,7 @@ pub fn migrations(args: TokenStream, input: TokenStream) -> TokenStream {
 
                 let mut result: Vec<ChangelogFile> = [#(#migration_tokens),*].iter()
                 .map(|migration| {
-                    ChangelogFile::from_string(migration.0,migration.1.to_string().as_str(), migration.2).unwrap()
+                    ChangelogFile::from_string_with_checksum(migration.0,migration.1.to_string().as_str(), migration.2, migration.3).unwrap()
                 })
                 .collect();
                 return result;
@@ -102,13 +175,45 @@ pub fn migrations(args: TokenStream, input: TokenStream) -> TokenStream {
     };
     // println!("result: {}", result.to_string());
 
+    // The macro reads the variable too, but only variables read by the compiled code are tracked
+    let env_name = MIGRATIONS_DIR_ENV;
+    let env_tracking = quote! {
+        const _: ::core::option::Option<&str> = ::core::option_env!(#env_name);
+    };
+
     let input: TokenStream2 = input.into();
     return quote! {
         #input
         #result
+        #env_tracking
+        #(#warnings)*
     }.into();
 }
 
+/// Read a migration file, checking that it is a valid changelog
+///
+/// The checksum of the returned changelog is embedded into the generated code, so that it is
+/// not computed again at runtime.
+fn load_migration(path: &PathBuf, migration: &MigrationInfo) -> ChangelogFile {
+    let file_path = path.join(migration.filename.as_str()).display().to_string();
+    let content = std::fs::read_to_string(file_path.as_str())
+        .expect(format!("Could not read migration file: {}", file_path).as_str());
+
+    return ChangelogFile::from_string(migration.version, migration.name.as_str(), content.as_str())
+        .expect(format!("Migration file is not a valid SQL changelog file: {}", file_path).as_str());
+}
+
+/// Get the migrations directory for the path given to the macro
+///
+/// The directory is taken from `FLYWAY_MIGRATIONS_DIR` if set, else from the given path, else
+/// `DEFAULT_MIGRATIONS_DIR` is used. Relative paths are relative to the crate root.
+fn resolve_migrations_dir(path: Option<&str>) -> PathBuf {
+    return match env::var(MIGRATIONS_DIR_ENV) {
+        Ok(dir) if !dir.is_empty() => map_to_crate_root(Some(dir.as_str())),
+        _ => map_to_crate_root(Some(path.unwrap_or(DEFAULT_MIGRATIONS_DIR))),
+    };
+}
+
 /// Map a path to the root of the crate
 fn map_to_crate_root(path: Option<&str>) -> PathBuf {
     let root = env::var("CARGO_MANIFEST_DIR")
@@ -121,60 +226,259 @@ fn map_to_crate_root(path: Option<&str>) -> PathBuf {
     return result;
 }
 
-/// List migrations contained inside a directory
-fn get_migrations(path: &PathBuf) -> Result<Vec<MigrationInfo>, std::io::Error> {
-    let result: Vec<MigrationInfo> = std::fs::read_dir(path)?
+/// The result of matching a filename against the migration naming pattern
+#[derive(Debug, Clone, PartialEq)]
+enum ParsedFilename {
+    /// The file is a migration
+    Migration(u64, String),
+    /// The file looks like a migration, but does not match the pattern for the given reason
+    Malformed(&'static str),
+    /// The file is not a migration
+    Ignored,
+}
+
+/// Match a filename against `V<version>_<name>.<extension>`
+///
+/// Version and name are split by `parse_migration_stem`, like the runtime stores of `flyway`
+/// do, so e.g. `V20240115_1200__add_users.sql` is version `202401151200` named `add_users`. If
+/// `case_insensitive` is set, the prefix may also be a lowercase `v`.
+fn parse_filename(filename: &str, extensions: &[String], case_insensitive: bool) -> ParsedFilename {
+    let prefixed = filename.starts_with("V") || (case_insensitive && filename.starts_with("v"));
+    if !prefixed {
+        return ParsedFilename::Ignored;
+    }
+    let stem = extensions.iter()
+        .find_map(|extension| filename.strip_suffix(format!(".{}", extension).as_str()));
+    let stem = match stem {
+        Some(stem) => stem,
+        None => return ParsedFilename::Ignored,
+    };
+
+    return match parse_migration_stem(&stem[1..]) {
+        Ok((version, name)) => ParsedFilename::Migration(version, name.to_string()),
+        Err(reason) => ParsedFilename::Malformed(reason),
+    };
+}
+
+/// List the names of the files inside a directory
+fn get_filenames(path: &PathBuf) -> Result<Vec<String>, std::io::Error> {
+    let result: Vec<String> = std::fs::read_dir(path)?
         .filter(|entry| entry.is_ok())
         .map(|entry| entry.unwrap().file_name().to_str().map(|v| v.to_string()))
         .filter(|filename| filename.is_some())
         .map(|filename| filename.unwrap())
-        .filter(|filename| filename.starts_with("V") && filename.ends_with(".sql"))
-        .map(|filename| {
-            let index = filename.find("_");
-            let mut version = "";
-            let mut name = "";
-            if let Some(index) = index {
-                if index > 1 && index < filename.len() - "V.sql".len() {
-                    if filename[1..index].chars().all(|ch| ch >= '0' && ch <= '9') {
-                        version = &filename[1..index];
-                        name = &filename[(index + 1)..(filename.len() - ".sql".len())];
-                    }
-                }
-            }
+        .collect();
+    return Ok(result);
+}
 
-            return if version.is_empty() {
-                None
-            } else {
-                let result: Result<Option<u64>, ParseIntError> = version.parse::<u64>()
-                    .map(|version| Some(version))
-                    .or(Ok(None));
-
-                let result = result.unwrap()
-                    .map(|version| {
-                        MigrationInfo {
-                            version,
-                            filename: filename.to_string(),
-                            name: name.to_string()
-                        }
-                    });
-                return result
-            };
+/// List migrations contained inside a directory
+///
+/// Only files ending with one of `extensions` are considered. The migrations are sorted by
+/// version (and filename), so that the generated code does not depend on the order of the
+/// directory entries.
+fn get_migrations(path: &PathBuf, extensions: &[String], case_insensitive: bool) -> Result<Vec<MigrationInfo>, std::io::Error> {
+    let mut result: Vec<MigrationInfo> = get_filenames(path)?
+        .into_iter()
+        .filter_map(|filename| match parse_filename(filename.as_str(), extensions, case_insensitive) {
+            ParsedFilename::Migration(version, name) => Some(MigrationInfo { version, filename, name }),
+            _ => None,
         })
-        .filter(|info| info.is_some())
-        .map(|info| info.unwrap())
         .collect();
+    result.sort_by(|a, b| a.version.cmp(&b.version).then_with(|| a.filename.cmp(&b.filename)));
     return Ok(result);
 }
 
+/// List files inside a directory that look like migrations but are skipped, with the reason
+fn get_malformed_migrations(path: &PathBuf, extensions: &[String], case_insensitive: bool) -> Result<Vec<(String, &'static str)>, std::io::Error> {
+    let mut result: Vec<(String, &'static str)> = get_filenames(path)?
+        .into_iter()
+        .filter_map(|filename| match parse_filename(filename.as_str(), extensions, case_insensitive) {
+            ParsedFilename::Malformed(reason) => Some((filename, reason)),
+            _ => None,
+        })
+        .collect();
+    result.sort();
+    return Ok(result);
+}
+
+/// Generate code producing a compiler warning for a skipped migration file
+///
+/// `proc_macro::Diagnostic` is not available on stable, so the warning is the deprecation
+/// warning of a generated item that is used right away.
+fn skipped_file_warning(filename: &str, reason: &str) -> TokenStream2 {
+    let note = format!("Skipping migration file `{}`: {}. Migration files must be named `V<version>_<name>.<extension>`.", filename, reason);
+    return quote! {
+        const _: () = {
+            #[deprecated(note = #note)]
+            struct SkippedMigrationFile;
+            let _ = SkippedMigrationFile;
+        };
+    };
+}
+
 #[cfg(test)]
 mod test {
+    use flyway_sql_changelog::ChangelogFile;
+
     #[test]
     pub fn test_get_migrations() {
         let path = crate::map_to_crate_root(Some("examples/migrations"));
-        let result = crate::get_migrations(&path);
+        let result = crate::get_migrations(&path, &["sql".to_string()], false);
         match result {
             Ok(migrations) => {
                 assert_eq!(migrations.len(), 2, "Two migrations have been successfully loaded.");
+                let versions: Vec<u64> = migrations.iter().map(|migration| migration.version).collect();
+                assert_eq!(versions, vec![1, 2], "Migrations are sorted by version.");
+            }
+            Err(err) => {
+                assert!(false, "Migration loading failed: {}", err);
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_get_migrations_lowercase() {
+        let path = crate::map_to_crate_root(Some("examples/migrations_lowercase"));
+        let migrations = crate::get_migrations(&path, &["sql".to_string()], false).unwrap();
+        assert!(migrations.is_empty(), "Lowercase migrations are ignored by default.");
+
+        let migrations = crate::get_migrations(&path, &["sql".to_string()], true).unwrap();
+        let names: Vec<(u64, String)> = migrations.into_iter().map(|migration| (migration.version, migration.name)).collect();
+        assert_eq!(names, vec![(1, "init".to_string()), (2, "users".to_string())]);
+
+        let path = crate::map_to_crate_root(Some("examples/migrations"));
+        let migrations = crate::get_migrations(&path, &["sql".to_string()], true).unwrap();
+        assert_eq!(migrations.len(), 2, "Uppercase migrations are loaded if case insensitive.");
+    }
+
+    #[test]
+    pub fn test_get_migrations_with_extensions() {
+        let dir = std::env::temp_dir().join(format!("flyway-codegen-extensions-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("V1_users.sql"), "SELECT 1;").unwrap();
+        std::fs::write(dir.join("V2_index.ddl"), "SELECT 2;").unwrap();
+        std::fs::write(dir.join("V3_notes.txt"), "SELECT 3;").unwrap();
+        std::fs::write(dir.join("V4_.ddl"), "SELECT 4;").unwrap();
+
+        let result = crate::get_migrations(&dir, &["sql".to_string(), "ddl".to_string()], false);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let migrations = result.unwrap();
+        let names: Vec<(u64, String)> = migrations.into_iter().map(|migration| (migration.version, migration.name)).collect();
+        assert_eq!(names, vec![(1, "users".to_string()), (2, "index".to_string())]);
+    }
+
+    #[test]
+    pub fn test_get_migrations_sorted() {
+        let dir = std::env::temp_dir().join(format!("flyway-codegen-sorted-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for filename in ["V10_c.sql", "V2_b.sql", "V100_d.sql", "V1_a.sql"] {
+            std::fs::write(dir.join(filename), "SELECT 1;").unwrap();
+        }
+
+        let result = crate::get_migrations(&dir, &["sql".to_string()], false);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let versions: Vec<u64> = result.unwrap().iter().map(|migration| migration.version).collect();
+        assert_eq!(versions, vec![1, 2, 10, 100]);
+    }
+
+    #[test]
+    pub fn test_parse_filename() {
+        use crate::ParsedFilename;
+
+        let extensions = vec!["sql".to_string()];
+        assert_eq!(crate::parse_filename("V1_init.sql", &extensions, false), ParsedFilename::Migration(1, "init".to_string()));
+        assert_eq!(crate::parse_filename("V1-typo.sql", &extensions, false), ParsedFilename::Malformed("missing `_` between version and name"));
+        assert_eq!(crate::parse_filename("V_missing_version.sql", &extensions, false), ParsedFilename::Malformed("missing version"));
+        assert_eq!(crate::parse_filename("V1a_name.sql", &extensions, false), ParsedFilename::Malformed("version is not a number"));
+        assert_eq!(crate::parse_filename("V1_.sql", &extensions, false), ParsedFilename::Malformed("missing name"));
+        assert_eq!(crate::parse_filename("V99999999999999999999_big.sql", &extensions, false), ParsedFilename::Malformed("version is too large"));
+        assert_eq!(crate::parse_filename("v2_lower.sql", &extensions, false), ParsedFilename::Ignored);
+        assert_eq!(crate::parse_filename("v2_lower.sql", &extensions, true), ParsedFilename::Migration(2, "lower".to_string()));
+        assert_eq!(crate::parse_filename("V2_upper.sql", &extensions, true), ParsedFilename::Migration(2, "upper".to_string()));
+        assert_eq!(crate::parse_filename("V3_notes.txt", &extensions, false), ParsedFilename::Ignored);
+        assert_eq!(crate::parse_filename("README.md", &extensions, false), ParsedFilename::Ignored);
+        assert_eq!(crate::parse_filename("V20240115_1200__add_users.sql", &extensions, false),
+                   ParsedFilename::Migration(202401151200, "add_users".to_string()));
+        assert_eq!(crate::parse_filename("V20240115120000__add_users.sql", &extensions, false),
+                   ParsedFilename::Migration(20240115120000, "add_users".to_string()));
+        assert_eq!(crate::parse_filename("V1_add__users.sql", &extensions, false), ParsedFilename::Migration(1, "add__users".to_string()));
+    }
+
+    #[test]
+    pub fn test_get_migrations_with_timestamps() {
+        let dir = std::env::temp_dir().join(format!("flyway-codegen-timestamps-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for filename in ["V20240115_1200__add_users.sql", "V20231231_2359__init.sql", "V20240115_0930__add_roles.sql", "V20240201_0000__add_index.sql"] {
+            std::fs::write(dir.join(filename), "SELECT 1;").unwrap();
+        }
+
+        let result = crate::get_migrations(&dir, &["sql".to_string()], false);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let names: Vec<(u64, String)> = result.unwrap().into_iter().map(|migration| (migration.version, migration.name)).collect();
+        assert_eq!(names, vec![(202312312359, "init".to_string()), (202401150930, "add_roles".to_string()),
+                               (202401151200, "add_users".to_string()), (202402010000, "add_index".to_string())]);
+    }
+
+    #[test]
+    pub fn test_get_malformed_migrations() {
+        let dir = std::env::temp_dir().join(format!("flyway-codegen-malformed-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for filename in ["V1_init.sql", "V1-typo.sql", "V_missing_version.sql", "README.md"] {
+            std::fs::write(dir.join(filename), "SELECT 1;").unwrap();
+        }
+
+        let result = crate::get_malformed_migrations(&dir, &["sql".to_string()], false);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let filenames: Vec<String> = result.unwrap().into_iter().map(|(filename, _)| filename).collect();
+        assert_eq!(filenames, vec!["V1-typo.sql".to_string(), "V_missing_version.sql".to_string()]);
+    }
+
+    #[test]
+    pub fn test_parse_extensions_arg() {
+        let args: crate::MigrationsArgs = syn::parse_str(r#""migrations/", extensions = [".sql", "ddl"]"#).unwrap();
+        assert_eq!(args.path.as_deref(), Some("migrations/"));
+        assert_eq!(args.extensions, vec!["sql".to_string(), "ddl".to_string()]);
+
+        let args: crate::MigrationsArgs = syn::parse_str("").unwrap();
+        assert_eq!(args.path, None);
+        assert_eq!(args.extensions, vec!["sql".to_string()]);
+        assert!(!args.case_insensitive);
+
+        let args: crate::MigrationsArgs = syn::parse_str("case_insensitive = true").unwrap();
+        assert!(args.case_insensitive);
+    }
+
+    #[test]
+    pub fn test_embedded_checksum() {
+        let path = crate::map_to_crate_root(Some("examples/migrations"));
+        let migrations = crate::get_migrations(&path, &["sql".to_string()], false).unwrap();
+        assert!(!migrations.is_empty());
+        for migration in migrations {
+            let embedded = crate::load_migration(&path, &migration);
+            let content = std::fs::read_to_string(path.join(migration.filename.as_str())).unwrap();
+            let fresh = ChangelogFile::from_string(migration.version, migration.name.as_str(), content.as_str()).unwrap();
+            let restored = ChangelogFile::from_string_with_checksum(migration.version, migration.name.as_str(),
+                                                                    content.as_str(), embedded.checksum().unwrap()).unwrap();
+            assert_eq!(restored.checksum().unwrap(), fresh.checksum().unwrap());
+        }
+    }
+
+    #[test]
+    pub fn test_migrations_dir_env() {
+        let dir = std::env::temp_dir().join(format!("flyway-codegen-env-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("V1_init.sql"), "SELECT 1;").unwrap();
+
+        std::env::set_var(crate::MIGRATIONS_DIR_ENV, dir.as_os_str());
+        let path = crate::resolve_migrations_dir(Some("examples/migrations"));
+        std::env::remove_var(crate::MIGRATIONS_DIR_ENV);
+        let result = crate::get_migrations(&path, &["sql".to_string()], false);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(path, dir);
+        match result {
+            Ok(migrations) => {
+                assert_eq!(migrations.len(), 1, "The migrations of the env directory have been loaded.");
             }
             Err(err) => {
                 assert!(false, "Migration loading failed: {}", err);
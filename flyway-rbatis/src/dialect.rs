@@ -0,0 +1,704 @@
+use flyway::ChangelogFile;
+use rbs::{to_value, Value};
+
+/// Status of a version whose changelog is being executed
+pub const STATUS_IN_PROGRESS: &str = "in_progress";
+/// Status of a deployed version
+pub const STATUS_DEPLOYED: &str = "deployed";
+/// Status of a version skipped after its changelog failed
+pub const STATUS_FAILED: &str = "fail";
+
+/// Type of a migration identified by its version
+pub const MIGRATION_TYPE_VERSIONED: &str = "versioned";
+/// Type of a repeatable migration, which is identified by its name and has no version
+pub const MIGRATION_TYPE_REPEATABLE: &str = "repeatable";
+
+/// Length of the longest status written by the driver
+pub const MAX_STATUS_LEN: usize = max_len(&[STATUS_IN_PROGRESS, STATUS_DEPLOYED, STATUS_FAILED]);
+/// Length of the longest checksum of the built-in algorithms, i.e. `sha256:` and 64 hex digits
+pub const MAX_CHECKSUM_LEN: usize = 71;
+
+/// Width of the `status` column
+pub const STATUS_COL_LEN: usize = 16;
+/// Width of the `checksum` column
+pub const CHECKSUM_COL_LEN: usize = 255;
+/// Width of the `name` column
+pub const NAME_COL_LEN: usize = 255;
+/// Width of the `migration_type` column
+pub const TYPE_COL_LEN: usize = 16;
+/// Width of the `applied_at` column, which holds an ISO-8601 timestamp like `2024-01-15T12:00:00.000000Z`
+pub const APPLIED_AT_COL_LEN: usize = 32;
+
+const _: () = assert!(MAX_STATUS_LEN <= STATUS_COL_LEN, "STATUS_COL_LEN is too small");
+const _: () = assert!(MAX_CHECKSUM_LEN <= CHECKSUM_COL_LEN, "CHECKSUM_COL_LEN is too small");
+const _: () = assert!(max_len(&[MIGRATION_TYPE_VERSIONED, MIGRATION_TYPE_REPEATABLE]) <= TYPE_COL_LEN, "TYPE_COL_LEN is too small");
+
+/// Values of the row of a migration written to the migrations table
+///
+/// The methods of `DialectSql` writing rows get the values as a whole, so adding a column
+/// does not change their signatures.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct RowValues {
+    /// The type, one of the `MIGRATION_TYPE_*` constants
+    pub migration_type: String,
+    /// The version, `None` for repeatable migrations
+    pub version: Option<u64>,
+    /// The name of the changelog
+    pub name: String,
+    /// The checksum, see `ChangelogFile::recorded_checksum()`
+    pub checksum: String,
+    /// The status, one of the `STATUS_*` constants
+    pub status: String,
+    /// The value of the `ts` column
+    pub ts: i64,
+    /// The value of the `applied_at` column
+    pub applied_at: String,
+}
+
+impl RowValues {
+    /// Get the values of the row of `changelog_file`, failing if its checksum cannot be computed
+    pub fn new(changelog_file: &ChangelogFile, status: &str, ts: i64, applied_at: String) -> flyway::Result<RowValues> {
+        Ok(RowValues {
+            migration_type: MIGRATION_TYPE_VERSIONED.to_string(),
+            version: Some(changelog_file.version),
+            name: changelog_file.name.clone(),
+            checksum: changelog_file.recorded_checksum()?,
+            status: status.to_string(),
+            ts,
+            applied_at,
+        })
+    }
+
+    /// Get the values of the row of `changelog_file` as a repeatable migration, keyed by its name only
+    pub fn repeatable(changelog_file: &ChangelogFile, status: &str, ts: i64, applied_at: String) -> flyway::Result<RowValues> {
+        let mut row = RowValues::new(changelog_file, status, ts, applied_at)?;
+        row.migration_type = MIGRATION_TYPE_REPEATABLE.to_string();
+        row.version = None;
+        Ok(row)
+    }
+}
+
+/// The condition matching the row of a migration by `(migration_type, version, name)`, and its parameters
+pub(crate) fn key_condition(row: &RowValues) -> (&'static str, Vec<Value>) {
+    match row.version {
+        Some(version) => ("migration_type=? and version=? and name=?",
+                          vec![to_value!(row.migration_type.as_str()), to_value!(version), to_value!(row.name.as_str())]),
+        None => ("migration_type=? and version IS NULL and name=?",
+                 vec![to_value!(row.migration_type.as_str()), to_value!(row.name.as_str())]),
+    }
+}
+
+/// The `CREATE TABLE` of the default dialect, with `id_definition` as type of the surrogate key
+fn create_table_with_id(migrations_table_name: &str, id_definition: &str) -> String {
+    format!(r#"CREATE TABLE IF NOT EXISTS {} (
+                id {},
+                version BIGINT NULL,
+                migration_type varchar({}) null,
+                ts       varchar(255) null,
+                applied_at varchar({}) null,
+                name     varchar({}) null,
+                checksum   varchar({}) null,
+                status VARCHAR({})
+            );"#, migrations_table_name, id_definition, TYPE_COL_LEN, APPLIED_AT_COL_LEN, NAME_COL_LEN, CHECKSUM_COL_LEN, STATUS_COL_LEN)
+}
+
+const fn max_len(values: &[&str]) -> usize {
+    let mut max = 0;
+    let mut i = 0;
+    while i < values.len() {
+        if values[i].len() > max {
+            max = values[i].len();
+        }
+        i += 1;
+    }
+    max
+}
+
+/// SQL statements for the migrations table of one database type
+///
+/// The default methods produce standard SQL, so an implementation only overrides the
+/// statements its database does not understand. Use `RbatisDbDriverType::dialect()` to get
+/// the implementation for a driver type.
+///
+/// A row is identified by `(migration_type, version, name)`. Versioned migrations are written
+/// as `versioned`, repeatable ones as `repeatable` with a NULL version. New tables have a
+/// surrogate `id` as primary key; tables created with `version` as primary key keep it, so
+/// they cannot store repeatable migrations. `prepare()` sets the `migration_type` of rows
+/// written before the column was added to `versioned` and creates the unique index on
+/// `(migration_type, version, name)` as `<table>_key`. TDengine and ClickHouse have neither
+/// a surrogate key nor the index.
+///
+/// `applied_at` holds the time the status of a row was last set, as ISO-8601 UTC string.
+/// `ts` is only used to order and replace rows. The statements writing a row get its values
+/// as `RowValues`.
+pub trait DialectSql: Send + Sync {
+    /// 建表语句
+    fn create_table(&self, migrations_table_name: &str) -> String {
+        create_table_with_id(migrations_table_name, "BIGINT GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY")
+    }
+
+    /// Statement counting the tables named like the migrations table, i.e. `1` if it exists
+    fn table_exists(&self, migrations_table_name: &str) -> String {
+        format!(r#"SELECT COUNT(*) FROM INFORMATION_SCHEMA.TABLES WHERE TABLE_NAME = '{}';"#, migrations_table_name)
+    }
+
+    /// 删表语句
+    fn drop_table(&self, migrations_table_name: &str) -> String {
+        format!(r#"DROP TABLE IF EXISTS {};"#, migrations_table_name)
+    }
+
+    /// 旧版本建的迁移表可能缺少的列及其定义
+    fn upgradable_columns(&self) -> Vec<(&'static str, String)> {
+        vec![("ts", "varchar(255) null".to_string()),
+             ("name", format!("varchar({}) null", NAME_COL_LEN)),
+             ("checksum", format!("varchar({}) null", CHECKSUM_COL_LEN)),
+             ("migration_type", format!("varchar({}) null", TYPE_COL_LEN)),
+             ("applied_at", format!("varchar({}) null", APPLIED_AT_COL_LEN))]
+    }
+
+    /// 查询迁移表现有列的语句, `None` 表示支持 `ADD COLUMN IF NOT EXISTS`, 无需查询
+    fn list_columns(&self, migrations_table_name: &str) -> Option<String> {
+        Some(format!(r#"SELECT COLUMN_NAME FROM INFORMATION_SCHEMA.COLUMNS WHERE TABLE_NAME = '{}';"#,
+                     migrations_table_name))
+    }
+
+    /// 加列语句
+    fn add_column(&self, migrations_table_name: &str, column: &str, definition: &str) -> String {
+        format!(r#"ALTER TABLE {} ADD COLUMN {} {};"#, migrations_table_name, column, definition)
+    }
+
+    /// Statement creating the index on `(status, version)` used by the version queries
+    ///
+    /// `None` means the database has no secondary indexes, or doesn't benefit from them.
+    fn create_status_index(&self, migrations_table_name: &str) -> Option<String> {
+        Some(format!(r#"CREATE INDEX IF NOT EXISTS {}_status ON {} (status, version);"#,
+                     migrations_table_name, migrations_table_name))
+    }
+
+    /// Statement creating the unique index on `(migration_type, version, name)`
+    ///
+    /// `None` means the database has no unique indexes.
+    fn create_key_index(&self, migrations_table_name: &str) -> Option<String> {
+        Some(format!(r#"CREATE UNIQUE INDEX IF NOT EXISTS {}_key ON {} (migration_type, version, name);"#,
+                     migrations_table_name, migrations_table_name))
+    }
+
+    /// Statement setting the `migration_type` of rows written before the column was added
+    ///
+    /// `None` means rows cannot be updated; rows without a type are read as versioned then.
+    fn fill_migration_type(&self, migrations_table_name: &str) -> Option<String> {
+        if !self.supports_update() {
+            return None;
+        }
+        Some(format!(r#"UPDATE {} SET migration_type='{}' WHERE migration_type IS NULL;"#,
+                     migrations_table_name, MIGRATION_TYPE_VERSIONED))
+    }
+
+    /// 查询迁移表现有索引名的语句, `None` 表示建索引语句本身是幂等的, 无需查询
+    fn list_indexes(&self, _migrations_table_name: &str) -> Option<String> {
+        None
+    }
+
+    /// Statement widening the `version` column of an existing table to 64 bit
+    ///
+    /// `None` means the column is 64 bit already or cannot be altered.
+    fn widen_version_column(&self, _migrations_table_name: &str) -> Option<String> {
+        None
+    }
+
+    /// Whether the status of a version can be changed with `UPDATE`
+    ///
+    /// If not, the row of the version is overwritten by inserting a row with the same `ts`.
+    fn supports_update(&self) -> bool {
+        true
+    }
+
+    /// Whether changelogs can be executed inside a transaction
+    ///
+    /// If not, the begin/commit/rollback methods of the driver do nothing and the statements
+    /// are executed directly.
+    fn supports_transactions(&self) -> bool {
+        true
+    }
+
+    /// Error messages of transient lock errors after which a statement can be retried
+    ///
+    /// Only errors that leave the surrounding transaction usable are listed, since the driver
+    /// retries the failing statement, not the whole changelog. A message matches if it contains
+    /// one of the returned fragments, ignoring case. By default nothing is retried.
+    fn retryable_errors(&self) -> Vec<&'static str> {
+        vec![]
+    }
+
+    /// Statement selecting the version of the database server, e.g. `14.5`
+    ///
+    /// `None` means the version cannot be queried, so `requires_db` annotations are not checked.
+    fn server_version(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// 查询语句中的表名, 例如需要 `FINAL` 去重的数据库
+    fn select_from(&self, migrations_table_name: &str) -> String {
+        migrations_table_name.to_string()
+    }
+
+    /// 更新版本状态的语句及参数
+    fn update_status(&self, migrations_table_name: &str, row: &RowValues) -> (String, Vec<Value>) {
+        let (condition, key) = key_condition(row);
+        let mut params = vec![to_value!(row.status.as_str()), to_value!(row.applied_at.as_str())];
+        params.extend(key);
+        (format!(r#"UPDATE {} SET status=?, applied_at=? where {};"#, migrations_table_name, condition), params)
+    }
+
+    /// Statement and parameters setting status, checksum, `ts` and `applied_at` of an existing version
+    fn update_version(&self, migrations_table_name: &str, row: &RowValues) -> (String, Vec<Value>) {
+        let (condition, key) = key_condition(row);
+        let mut params = vec![to_value!(row.status.as_str()), to_value!(row.checksum.as_str()), to_value!(row.ts), to_value!(row.applied_at.as_str())];
+        params.extend(key);
+        (format!(r#"UPDATE {} SET status=?, checksum=?, ts=?, applied_at=? where {};"#, migrations_table_name, condition), params)
+    }
+
+    /// 插入版本的语句及参数
+    fn insert_version(&self, migrations_table_name: &str, row: &RowValues) -> (String, Vec<Value>) {
+        (format!(r#"INSERT INTO {}(ts,applied_at,version,migration_type,name,checksum, status) VALUES (?,?,?,?,?,?,?);"#, migrations_table_name),
+         insert_params(row))
+    }
+
+    /// Statement and parameters inserting a version or setting the status of an existing one
+    ///
+    /// This must insert the row like `insert_version` does or, if the version has a row
+    /// already, only set its status and `applied_at`, in a single atomic statement. Concurrent runs then never
+    /// insert the same version twice. `None` means there is no such statement, so the status is
+    /// updated first and the row is inserted if nothing has been updated. It is only used for
+    /// versioned rows: not every database treats the NULL versions of repeatable rows as equal
+    /// in unique indexes, so these are always updated, then inserted.
+    fn upsert_version(&self, _migrations_table_name: &str, _row: &RowValues) -> Option<(String, Vec<Value>)> {
+        None
+    }
+}
+
+/// The parameters of `insert_version`, in the order of its columns
+fn insert_params(row: &RowValues) -> Vec<Value> {
+    vec![to_value!(row.ts), to_value!(row.applied_at.as_str()), to_value!(row.version), to_value!(row.migration_type.as_str()),
+         to_value!(row.name.as_str()), to_value!(row.checksum.as_str()), to_value!(row.status.as_str())]
+}
+
+/// The `INSERT` of `insert_version` followed by `clause`, which handles an existing version
+fn insert_with_clause(dialect: &dyn DialectSql, migrations_table_name: &str, row: &RowValues, clause: &str) -> (String, Vec<Value>) {
+    let (insert_statement, params) = dialect.insert_version(migrations_table_name, row);
+    (format!("{} {};", insert_statement.trim_end_matches(';'), clause), params)
+}
+
+pub(crate) struct MySqlDialect;
+
+impl DialectSql for MySqlDialect {
+    fn create_table(&self, migrations_table_name: &str) -> String {
+        create_table_with_id(migrations_table_name, "BIGINT AUTO_INCREMENT PRIMARY KEY")
+    }
+
+    fn upsert_version(&self, migrations_table_name: &str, row: &RowValues) -> Option<(String, Vec<Value>)> {
+        Some(insert_with_clause(self, migrations_table_name, row,
+                                "ON DUPLICATE KEY UPDATE status=VALUES(status), applied_at=VALUES(applied_at)"))
+    }
+
+    fn list_columns(&self, migrations_table_name: &str) -> Option<String> {
+        Some(format!(r#"SELECT COLUMN_NAME FROM information_schema.COLUMNS WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = '{}';"#,
+                     migrations_table_name))
+    }
+
+    fn table_exists(&self, migrations_table_name: &str) -> String {
+        format!(r#"SELECT COUNT(*) FROM information_schema.TABLES WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = '{}';"#,
+                migrations_table_name)
+    }
+
+    fn create_status_index(&self, migrations_table_name: &str) -> Option<String> {
+        // MySQL has no `CREATE INDEX IF NOT EXISTS`, existing indexes are listed instead
+        Some(format!(r#"CREATE INDEX {}_status ON {} (status, version);"#, migrations_table_name, migrations_table_name))
+    }
+
+    fn create_key_index(&self, migrations_table_name: &str) -> Option<String> {
+        Some(format!(r#"CREATE UNIQUE INDEX {}_key ON {} (migration_type, version, name);"#, migrations_table_name, migrations_table_name))
+    }
+
+    fn list_indexes(&self, migrations_table_name: &str) -> Option<String> {
+        Some(format!(r#"SELECT INDEX_NAME FROM information_schema.STATISTICS WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = '{}';"#,
+                     migrations_table_name))
+    }
+
+    fn retryable_errors(&self) -> Vec<&'static str> {
+        // ER_LOCK_WAIT_TIMEOUT (1205) only rolls back the statement. A deadlock (1213) rolls back
+        // the whole transaction, so it is not retried.
+        vec!["Lock wait timeout exceeded"]
+    }
+
+    fn server_version(&self) -> Option<&'static str> {
+        Some("SELECT VERSION()")
+    }
+
+    fn widen_version_column(&self, migrations_table_name: &str) -> Option<String> {
+        Some(format!(r#"ALTER TABLE {} MODIFY version BIGINT NULL;"#, migrations_table_name))
+    }
+}
+
+/// Postgres aborts the transaction on any error, so no statement is ever retried
+pub(crate) struct PgDialect;
+
+impl DialectSql for PgDialect {
+    fn list_columns(&self, _migrations_table_name: &str) -> Option<String> {
+        None
+    }
+
+    fn table_exists(&self, migrations_table_name: &str) -> String {
+        // Unquoted names are stored in lower case
+        format!(r#"SELECT COUNT(*) FROM information_schema.tables WHERE table_schema = current_schema() AND table_name = LOWER('{}');"#,
+                migrations_table_name)
+    }
+
+    fn add_column(&self, migrations_table_name: &str, column: &str, definition: &str) -> String {
+        format!(r#"ALTER TABLE {} ADD COLUMN IF NOT EXISTS {} {};"#, migrations_table_name, column, definition)
+    }
+
+    fn widen_version_column(&self, migrations_table_name: &str) -> Option<String> {
+        Some(format!(r#"ALTER TABLE {} ALTER COLUMN version TYPE BIGINT;"#, migrations_table_name))
+    }
+
+    fn upsert_version(&self, migrations_table_name: &str, row: &RowValues) -> Option<(String, Vec<Value>)> {
+        Some(insert_with_clause(self, migrations_table_name, row,
+                                "ON CONFLICT (migration_type, version, name) DO UPDATE SET status=excluded.status, applied_at=excluded.applied_at"))
+    }
+
+    fn server_version(&self) -> Option<&'static str> {
+        Some("SELECT current_setting('server_version')")
+    }
+}
+
+/// Upserts need SQLite 3.24 or newer
+pub(crate) struct SqliteDialect;
+
+impl DialectSql for SqliteDialect {
+    fn create_table(&self, migrations_table_name: &str) -> String {
+        // An INTEGER PRIMARY KEY is the rowid, which is assigned on insert
+        create_table_with_id(migrations_table_name, "INTEGER PRIMARY KEY")
+    }
+
+    fn upsert_version(&self, migrations_table_name: &str, row: &RowValues) -> Option<(String, Vec<Value>)> {
+        Some(insert_with_clause(self, migrations_table_name, row,
+                                "ON CONFLICT (migration_type, version, name) DO UPDATE SET status=excluded.status, applied_at=excluded.applied_at"))
+    }
+
+    fn list_columns(&self, migrations_table_name: &str) -> Option<String> {
+        Some(format!(r#"SELECT name FROM pragma_table_info('{}');"#, migrations_table_name))
+    }
+
+    fn table_exists(&self, migrations_table_name: &str) -> String {
+        format!(r#"SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = '{}';"#, migrations_table_name)
+    }
+
+    fn retryable_errors(&self) -> Vec<&'static str> {
+        // SQLITE_BUSY and SQLITE_LOCKED
+        vec!["database is locked", "database table is locked"]
+    }
+
+    fn server_version(&self) -> Option<&'static str> {
+        Some("SELECT sqlite_version()")
+    }
+}
+
+/// SQL Server treats NULLs as equal in unique indexes, so repeatable rows are unique by name
+pub(crate) struct MsSqlDialect;
+
+impl DialectSql for MsSqlDialect {
+    fn create_table(&self, migrations_table_name: &str) -> String {
+        format!(r#"IF OBJECT_ID(N'{}', N'U') IS NULL
+            CREATE TABLE {} (
+                id BIGINT IDENTITY(1,1) PRIMARY KEY,
+                version BIGINT NULL,
+                migration_type varchar({}) null,
+                ts       varchar(255) null,
+                applied_at varchar({}) null,
+                name     varchar({}) null,
+                checksum   varchar({}) null,
+                status VARCHAR({})
+            );"#, migrations_table_name, migrations_table_name, TYPE_COL_LEN, APPLIED_AT_COL_LEN, NAME_COL_LEN, CHECKSUM_COL_LEN,
+                STATUS_COL_LEN)
+    }
+
+    fn add_column(&self, migrations_table_name: &str, column: &str, definition: &str) -> String {
+        format!(r#"ALTER TABLE {} ADD {} {};"#, migrations_table_name, column, definition)
+    }
+
+    fn create_status_index(&self, migrations_table_name: &str) -> Option<String> {
+        Some(format!(r#"IF NOT EXISTS (SELECT * FROM sys.indexes WHERE name = N'{}_status' AND object_id = OBJECT_ID(N'{}'))
+            CREATE INDEX {}_status ON {} (status, version);"#,
+                     migrations_table_name, migrations_table_name, migrations_table_name, migrations_table_name))
+    }
+
+    fn create_key_index(&self, migrations_table_name: &str) -> Option<String> {
+        Some(format!(r#"IF NOT EXISTS (SELECT * FROM sys.indexes WHERE name = N'{}_key' AND object_id = OBJECT_ID(N'{}'))
+            CREATE UNIQUE INDEX {}_key ON {} (migration_type, version, name);"#,
+                     migrations_table_name, migrations_table_name, migrations_table_name, migrations_table_name))
+    }
+
+    fn retryable_errors(&self) -> Vec<&'static str> {
+        // Error 1222 only fails the statement. A deadlock victim (1205) loses its transaction,
+        // so it is not retried.
+        vec!["Lock request time out period exceeded"]
+    }
+
+    fn server_version(&self) -> Option<&'static str> {
+        Some("SELECT CAST(SERVERPROPERTY('ProductVersion') AS NVARCHAR(128))")
+    }
+
+    fn upsert_version(&self, migrations_table_name: &str, row: &RowValues) -> Option<(String, Vec<Value>)> {
+        // HOLDLOCK keeps the range locked between matching and inserting
+        let (_, params) = self.insert_version(migrations_table_name, row);
+        Some((format!(r#"MERGE {} WITH (HOLDLOCK) AS target
+            USING (SELECT ? AS ts, ? AS applied_at, ? AS version, ? AS migration_type, ? AS name, ? AS checksum, ? AS status) AS source
+            ON target.migration_type = source.migration_type AND target.version = source.version AND target.name = source.name
+            WHEN MATCHED THEN UPDATE SET status = source.status, applied_at = source.applied_at
+            WHEN NOT MATCHED THEN INSERT (ts, applied_at, version, migration_type, name, checksum, status)
+                VALUES (source.ts, source.applied_at, source.version, source.migration_type, source.name, source.checksum, source.status);"#,
+                      migrations_table_name), params))
+    }
+}
+
+/// TDengine cannot change the type of an existing column, so old tables keep an `int` version
+///
+/// `ts` is the primary key, and there are no unique indexes. The driver reuses the `ts` of
+/// the row of a migration to keep a single row per `(migration_type, version, name)`.
+pub(crate) struct TDengineDialect;
+
+impl DialectSql for TDengineDialect {
+    fn create_table(&self, migrations_table_name: &str) -> String {
+        format!(r#"CREATE TABLE IF NOT EXISTS {} (`ts` TIMESTAMP, `version` bigint, `migration_type` nchar({}), `applied_at` nchar({}), `name` nchar({}) , `checksum` nchar({}), `status` nchar({}))
+                  "#, migrations_table_name, TYPE_COL_LEN, APPLIED_AT_COL_LEN, NAME_COL_LEN, CHECKSUM_COL_LEN, STATUS_COL_LEN)
+    }
+
+    fn upgradable_columns(&self) -> Vec<(&'static str, String)> {
+        vec![("name", format!("nchar({})", NAME_COL_LEN)), ("checksum", format!("nchar({})", CHECKSUM_COL_LEN)),
+             ("migration_type", format!("nchar({})", TYPE_COL_LEN)), ("applied_at", format!("nchar({})", APPLIED_AT_COL_LEN))]
+    }
+
+    fn list_columns(&self, migrations_table_name: &str) -> Option<String> {
+        Some(format!(r#"SELECT col_name FROM information_schema.ins_columns WHERE table_name = '{}'"#,
+                     migrations_table_name))
+    }
+
+    fn table_exists(&self, migrations_table_name: &str) -> String {
+        format!(r#"SELECT COUNT(*) FROM information_schema.ins_tables WHERE db_name = DATABASE() AND table_name = '{}'"#,
+                migrations_table_name)
+    }
+
+    fn add_column(&self, migrations_table_name: &str, column: &str, definition: &str) -> String {
+        format!(r#"ALTER TABLE {} ADD COLUMN `{}` {}"#, migrations_table_name, column, definition)
+    }
+
+    fn create_status_index(&self, _migrations_table_name: &str) -> Option<String> {
+        // Only tags can be indexed, and the table is ordered by `ts` anyway
+        None
+    }
+
+    fn create_key_index(&self, _migrations_table_name: &str) -> Option<String> {
+        // There are no unique indexes, see the documentation of the dialect
+        None
+    }
+
+    fn server_version(&self) -> Option<&'static str> {
+        Some("SELECT SERVER_VERSION()")
+    }
+
+    fn supports_update(&self) -> bool {
+        false
+    }
+}
+
+/// Oracle has no `IF NOT EXISTS` and rejects statements ending with `;`
+pub(crate) struct OracleDialect;
+
+impl DialectSql for OracleDialect {
+    fn create_table(&self, migrations_table_name: &str) -> String {
+        // ORA-00955: name is already used by an existing object
+        format!(r#"BEGIN
+                EXECUTE IMMEDIATE 'CREATE TABLE {} (
+                    id       NUMBER(19) GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+                    version  NUMBER(19) NULL,
+                    migration_type VARCHAR2({}) NULL,
+                    ts       VARCHAR2(255) NULL,
+                    applied_at VARCHAR2({}) NULL,
+                    name     VARCHAR2({}) NULL,
+                    checksum VARCHAR2({}) NULL,
+                    status   VARCHAR2({})
+                )';
+            EXCEPTION
+                WHEN OTHERS THEN
+                    IF SQLCODE != -955 THEN
+                        RAISE;
+                    END IF;
+            END;"#, migrations_table_name, TYPE_COL_LEN, APPLIED_AT_COL_LEN, NAME_COL_LEN, CHECKSUM_COL_LEN, STATUS_COL_LEN)
+    }
+
+    fn drop_table(&self, migrations_table_name: &str) -> String {
+        // ORA-00942: table or view does not exist
+        format!(r#"BEGIN
+                EXECUTE IMMEDIATE 'DROP TABLE {}';
+            EXCEPTION
+                WHEN OTHERS THEN
+                    IF SQLCODE != -942 THEN
+                        RAISE;
+                    END IF;
+            END;"#, migrations_table_name)
+    }
+
+    fn upgradable_columns(&self) -> Vec<(&'static str, String)> {
+        vec![("ts", "VARCHAR2(255) NULL".to_string()),
+             ("name", format!("VARCHAR2({}) NULL", NAME_COL_LEN)),
+             ("checksum", format!("VARCHAR2({}) NULL", CHECKSUM_COL_LEN)),
+             ("migration_type", format!("VARCHAR2({}) NULL", TYPE_COL_LEN)),
+             ("applied_at", format!("VARCHAR2({}) NULL", APPLIED_AT_COL_LEN))]
+    }
+
+    fn list_columns(&self, migrations_table_name: &str) -> Option<String> {
+        Some(format!(r#"SELECT COLUMN_NAME FROM USER_TAB_COLUMNS WHERE TABLE_NAME = UPPER('{}')"#,
+                     migrations_table_name))
+    }
+
+    fn table_exists(&self, migrations_table_name: &str) -> String {
+        format!(r#"SELECT COUNT(*) FROM USER_TABLES WHERE TABLE_NAME = UPPER('{}')"#, migrations_table_name)
+    }
+
+    fn add_column(&self, migrations_table_name: &str, column: &str, definition: &str) -> String {
+        format!(r#"ALTER TABLE {} ADD ({} {})"#, migrations_table_name, column, definition)
+    }
+
+    fn create_status_index(&self, migrations_table_name: &str) -> Option<String> {
+        // ORA-00955: name is already used by an existing object
+        // ORA-01408: such column list already indexed
+        Some(format!(r#"BEGIN
+                EXECUTE IMMEDIATE 'CREATE INDEX {}_status ON {} (status, version)';
+            EXCEPTION
+                WHEN OTHERS THEN
+                    IF SQLCODE NOT IN (-955, -1408) THEN
+                        RAISE;
+                    END IF;
+            END;"#, migrations_table_name, migrations_table_name))
+    }
+
+    fn create_key_index(&self, migrations_table_name: &str) -> Option<String> {
+        // ORA-00955: name is already used by an existing object
+        // ORA-01408: such column list already indexed
+        Some(format!(r#"BEGIN
+                EXECUTE IMMEDIATE 'CREATE UNIQUE INDEX {}_key ON {} (migration_type, version, name)';
+            EXCEPTION
+                WHEN OTHERS THEN
+                    IF SQLCODE NOT IN (-955, -1408) THEN
+                        RAISE;
+                    END IF;
+            END;"#, migrations_table_name, migrations_table_name))
+    }
+
+    fn fill_migration_type(&self, migrations_table_name: &str) -> Option<String> {
+        Some(format!(r#"UPDATE {} SET migration_type='{}' WHERE migration_type IS NULL"#,
+                     migrations_table_name, MIGRATION_TYPE_VERSIONED))
+    }
+
+    fn retryable_errors(&self) -> Vec<&'static str> {
+        // Oracle only rolls back the statement that detected the deadlock (ORA-00060) or timed
+        // out waiting for a lock (ORA-30006)
+        vec!["ORA-00060", "ORA-30006"]
+    }
+
+    fn server_version(&self) -> Option<&'static str> {
+        Some("SELECT VERSION FROM PRODUCT_COMPONENT_VERSION WHERE PRODUCT LIKE 'Oracle%' AND ROWNUM = 1")
+    }
+
+    fn update_status(&self, migrations_table_name: &str, row: &RowValues) -> (String, Vec<Value>) {
+        let (statement, params) = GenericDialect.update_status(migrations_table_name, row);
+        (statement.trim_end_matches(';').to_string(), params)
+    }
+
+    fn update_version(&self, migrations_table_name: &str, row: &RowValues) -> (String, Vec<Value>) {
+        let (statement, params) = GenericDialect.update_version(migrations_table_name, row);
+        (statement.trim_end_matches(';').to_string(), params)
+    }
+
+    fn insert_version(&self, migrations_table_name: &str, row: &RowValues) -> (String, Vec<Value>) {
+        (format!(r#"INSERT INTO {}(ts,applied_at,version,migration_type,name,checksum, status) VALUES (?,?,?,?,?,?,?)"#, migrations_table_name),
+         insert_params(row))
+    }
+}
+
+/// ClickHouse has neither transactions nor a standard `UPDATE`
+///
+/// The state table is a `ReplacingMergeTree` ordered by version. Changing the status of a
+/// version inserts a new row with a higher `ts`, and queries read the table with `FINAL` so
+/// that only the latest row of each version is seen. As the table is ordered by the non-nullable
+/// version, it can only store versioned migrations.
+pub(crate) struct ClickHouseDialect;
+
+impl DialectSql for ClickHouseDialect {
+    fn create_table(&self, migrations_table_name: &str) -> String {
+        format!(r#"CREATE TABLE IF NOT EXISTS {} (
+                version  UInt64,
+                migration_type Nullable(String),
+                ts       Int64,
+                applied_at Nullable(String),
+                name     Nullable(String),
+                checksum Nullable(String),
+                status   String
+            ) ENGINE = ReplacingMergeTree(ts) ORDER BY version"#, migrations_table_name)
+    }
+
+    fn upgradable_columns(&self) -> Vec<(&'static str, String)> {
+        vec![("name", "Nullable(String)".to_string()), ("checksum", "Nullable(String)".to_string()),
+             ("migration_type", "Nullable(String)".to_string()), ("applied_at", "Nullable(String)".to_string())]
+    }
+
+    fn list_columns(&self, _migrations_table_name: &str) -> Option<String> {
+        None
+    }
+
+    fn table_exists(&self, migrations_table_name: &str) -> String {
+        format!(r#"SELECT COUNT(*) FROM system.tables WHERE database = currentDatabase() AND name = '{}'"#, migrations_table_name)
+    }
+
+    fn add_column(&self, migrations_table_name: &str, column: &str, definition: &str) -> String {
+        format!(r#"ALTER TABLE {} ADD COLUMN IF NOT EXISTS {} {}"#, migrations_table_name, column, definition)
+    }
+
+    fn create_status_index(&self, _migrations_table_name: &str) -> Option<String> {
+        // The table is ordered by version and small, a data skipping index would not help
+        None
+    }
+
+    fn create_key_index(&self, _migrations_table_name: &str) -> Option<String> {
+        // Rows with the same version are merged instead
+        None
+    }
+
+    fn supports_update(&self) -> bool {
+        false
+    }
+
+    fn supports_transactions(&self) -> bool {
+        false
+    }
+
+    fn select_from(&self, migrations_table_name: &str) -> String {
+        format!("{} FINAL", migrations_table_name)
+    }
+
+    fn server_version(&self) -> Option<&'static str> {
+        Some("SELECT version()")
+    }
+
+    fn insert_version(&self, migrations_table_name: &str, row: &RowValues) -> (String, Vec<Value>) {
+        (format!(r#"INSERT INTO {}(ts,applied_at,version,migration_type,name,checksum, status) VALUES (?,?,?,?,?,?,?)"#, migrations_table_name),
+         insert_params(row))
+    }
+}
+
+/// Dialect for databases not directly known to `flyway-rbatis`
+pub(crate) struct GenericDialect;
+
+impl DialectSql for GenericDialect {}
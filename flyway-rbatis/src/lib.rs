@@ -1,4 +1,4 @@
-use std::cell::Cell;
+use std::collections::HashMap;
 use std::ops::DerefMut;
 use std::sync::{Arc};
 use std::time::Duration;
@@ -6,14 +6,21 @@ use tokio::sync::Mutex;
 use serde::{Deserialize, Serialize};
 
 use rbatis::{Error, RBatis};
-use flyway::{MigrationExecutor, MigrationState, MigrationStateManager, MigrationsError, MigrationStatus, ChangelogFile};
+use flyway::{MigrationExecutor, MigrationState, MigrationStateManager, MigrationsError, MigrationStatus, ChangelogFile, ChangelogOutcome, SqlStatement,
+             redact_string_literals};
 use rbs::{to_value, Value};
 use async_trait::async_trait;
-use rbatis::executor::RBatisTxExecutor;
+use rbatis::executor::{Executor, RBatisConnExecutor, RBatisTxExecutor};
 use rbatis::rbatis_codegen::ops::AsProxy;
 use rbatis::rbdc::datetime::DateTime;
 use rbatis::rbdc::timestamp::Timestamp;
 
+mod dialect;
+pub use dialect::{DialectSql, RowValues, APPLIED_AT_COL_LEN, CHECKSUM_COL_LEN, MAX_CHECKSUM_LEN, MAX_STATUS_LEN, MIGRATION_TYPE_REPEATABLE,
+                  MIGRATION_TYPE_VERSIONED, NAME_COL_LEN, STATUS_COL_LEN, STATUS_DEPLOYED, STATUS_FAILED,
+                  STATUS_IN_PROGRESS, TYPE_COL_LEN};
+use dialect::{key_condition, ClickHouseDialect, GenericDialect, MsSqlDialect, MySqlDialect, OracleDialect, PgDialect, SqliteDialect, TDengineDialect};
+
 /// Default table name for the migration state management table
 pub const DEFAULT_MIGRATIONS_TABLE: &str = "flyway_migrations";
 
@@ -22,135 +29,285 @@ pub const DEFAULT_MIGRATIONS_TABLE: &str = "flyway_migrations";
 #[derive(Clone, Debug, Serialize, Deserialize)]
 struct MigrationInfo {
     ts:DateTime,
-    version: u32,
+    version: Option<u64>,
     name: Option<String>,
     checksum: Option<String>,
     status:Option<String>,
 }
-/// Available driver types supported by Rbatis
-pub enum RbatisDbDriverType {
-    MySql,
-    Pg,
-    Sqlite,
-    MsSql,
-    TDengine,
-    Other(String),
+/// A row of the migrations table, with `ts` in whatever type the column has
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct VersionRow {
+    ts: Value,
+    applied_at: Option<String>,
+    version: u64,
+    name: Option<String>,
+    checksum: Option<String>,
+    status: Option<String>,
 }
-/// 不同数据库的建表语句
-fn create_table_sql(db_type:RbatisDbDriverType, migrations_table_name: String) -> String {
-        match db_type {
-            RbatisDbDriverType::MySql => {
-              format!(r#"CREATE TABLE IF NOT EXISTS {} (
-                version INTEGER PRIMARY KEY,
-                ts       varchar(255) null,
-                name     varchar(255) null,
-                checksum   varchar(255) null,
-                status VARCHAR(16)
-            );"#,migrations_table_name)
-            }
-            RbatisDbDriverType::Pg => {
-                format!(r#"CREATE TABLE IF NOT EXISTS {} (
-                version INTEGER PRIMARY KEY,
-                ts       varchar(255) null,
-                name     varchar(255) null,
-                checksum   varchar(255) null,
-                status VARCHAR(16)
-            );"#,migrations_table_name)
-            }
-            RbatisDbDriverType::Sqlite => {
-                format!(r#"CREATE TABLE IF NOT EXISTS {} (
-                version INTEGER PRIMARY KEY,
-                ts       varchar(255) null,
-                name     varchar(255) null,
-                checksum   varchar(255) null,
-                status VARCHAR(16)
-            );"#,migrations_table_name)
-            }
-            RbatisDbDriverType::MsSql => {
-                format!(r#"CREATE TABLE IF NOT EXISTS {} (
-                version INTEGER PRIMARY KEY,
-                ts       varchar(255) null,
-                name     varchar(255) null,
-                checksum   varchar(255) null,
-                status VARCHAR(16)
-            );"#,migrations_table_name)
+
+impl VersionRow {
+    fn into_state(self) -> MigrationState {
+        let status = match self.status.as_deref() {
+            Some(STATUS_DEPLOYED) => MigrationStatus::Deployed,
+            Some(STATUS_FAILED) => MigrationStatus::Failed,
+            _ => MigrationStatus::InProgress,
+        };
+        return MigrationState {
+            version: self.version,
+            status,
+            name: self.name,
+            checksum: self.checksum.map(|checksum| checksum.trim().to_string()).filter(|checksum| !checksum.is_empty()),
+            // Rows written before `applied_at` was added only have a `ts`
+            applied_at: self.applied_at.or_else(|| value_to_string(self.ts)),
+        };
+    }
+}
+
+/// Render a value read from the database without the quotes `Display` puts around strings
+fn value_to_string(value: Value) -> Option<String> {
+    return match value {
+        Value::Null => None,
+        Value::String(value) => Some(value),
+        Value::Ext(_, value) => value_to_string(*value),
+        value => Some(value.to_string()),
+    };
+}
+
+/// Render milliseconds since the Unix epoch as the ISO-8601 UTC timestamp written to `applied_at`
+fn format_applied_at(millis: i64) -> String {
+    return DateTime::from_timestamp_millis(millis).format("YYYY-MM-DDThh:mm:ss.000000Z");
+}
+
+/// The `ts` of a new row on TDengine, given the current time and the latest `ts` in the table
+///
+/// This is the current time, or one millisecond after the latest `ts` if that is not in the
+/// past (e.g. when several versions are begun within the same millisecond). The time the
+/// version has been applied is kept in `applied_at` either way.
+fn next_tdengine_ts(now: i64, latest: Option<i64>) -> i64 {
+    return match latest {
+        Some(latest) if latest >= now => latest + 1,
+        _ => now,
+    };
+}
+
+/// Version and checksum of a deployed migration
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct AppliedChecksum {
+    version: u64,
+    checksum: Option<String>,
+}
+
+/// How statements are written to the debug log when they are executed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StatementLogMode {
+    /// Log the full statement
+    Full,
+    /// Log at most the given number of characters of each statement
+    Truncate(usize),
+    /// Replace all string literals with `?` before logging
+    ///
+    /// This keeps data inserted by migrations (which may contain secrets or personal data)
+    /// out of the logs.
+    RedactLiterals,
+}
+
+impl StatementLogMode {
+    /// Render a statement for logging
+    fn render(&self, statement: &str) -> String {
+        match self {
+            StatementLogMode::Full => {
+                return statement.to_string();
             }
-            RbatisDbDriverType::TDengine => {
-               format!(r#"CREATE TABLE IF NOT EXISTS {} (`ts` TIMESTAMP, `version` int,`name` nchar(255) , `checksum` nchar(255), `status` nchar(255))
-                  "#,migrations_table_name)
+            StatementLogMode::Truncate(max_len) => {
+                if statement.chars().count() <= *max_len {
+                    return statement.to_string();
+                }
+                return format!("{}...", statement.chars().take(*max_len).collect::<String>());
             }
-            RbatisDbDriverType::Other(_) => {
-                format!(r#"CREATE TABLE IF NOT EXISTS {} (
-                version INTEGER PRIMARY KEY,
-                ts       varchar(255) null,
-                name     varchar(255) null,
-                checksum   varchar(255) null,
-                status VARCHAR(16)
-            );"#,migrations_table_name)
+            StatementLogMode::RedactLiterals => {
+                return redact_string_literals(statement);
             }
         }
+    }
 }
-/// 不同数据库的update
-fn update_sql(db_type:RbatisDbDriverType,migrations_table_name: String,status:String,version:u64)->String{
-    match db_type {
-        RbatisDbDriverType::MySql => {
-            format!(r#"UPDATE {} SET status='{}' where version={};"#,
-                    migrations_table_name.as_str(),status.as_str(), version)
-        }
-        RbatisDbDriverType::Pg => {
-            unimplemented!()
-        }
-        RbatisDbDriverType::Sqlite => {
-            unimplemented!()
 
-        }
-        RbatisDbDriverType::MsSql => {
-            unimplemented!()
+/// How often a statement is retried after a transient lock error
+///
+/// Which errors are retried depends on the database, see `DialectSql::retryable_errors`:
+///
+///  * MySQL: lock wait timeouts (1205)
+///  * SQL Server: lock request timeouts (1222)
+///  * SQLite: `database is locked` and `database table is locked`
+///  * Oracle: deadlocks (ORA-00060) and lock wait timeouts (ORA-30006)
+///
+/// Deadlocks on MySQL and SQL Server, and any error on Postgres, abort the whole transaction and
+/// are never retried. All other errors fail the changelog immediately.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// The maximum number of retries per statement, `0` disables retrying
+    pub max_retries: u32,
+    /// The delay before the first retry, doubled for every further retry
+    pub backoff: Duration,
+}
 
-        }
-        RbatisDbDriverType::TDengine => {
-            unimplemented!()
-        }
-        RbatisDbDriverType::Other(_) => {
-            unimplemented!()
+impl RetryPolicy {
+    /// Create a policy retrying a statement up to `max_retries` times
+    pub fn new(max_retries: u32, backoff: Duration) -> RetryPolicy {
+        return RetryPolicy {
+            max_retries,
+            backoff,
+        };
+    }
+
+    /// A policy that never retries
+    pub fn disabled() -> RetryPolicy {
+        return RetryPolicy::new(0, Duration::ZERO);
+    }
 
+    /// Get the delay before the given retry (starting at `0`), `None` if no retries are left
+    fn delay(&self, retry: u32) -> Option<Duration> {
+        if retry >= self.max_retries {
+            return None;
         }
+        return Some(self.backoff.saturating_mul(2u32.saturating_pow(retry)));
     }
 }
-/// 不同数据库的insert
-fn insert_sql(db_type:RbatisDbDriverType,migrations_table_name: String,status:String)->String{
-match db_type {
-    RbatisDbDriverType::MySql => {
-        format!(r#"INSERT INTO {}(ts,version,name,checksum, status) VALUES (?,?,?,?, '{}');"#,
-                migrations_table_name.as_str(),status.as_str())
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        return RetryPolicy::disabled();
     }
-    RbatisDbDriverType::Pg => {
-        unimplemented!()
+}
 
+/// Source of the timestamps written to the `ts` column of the migrations table
+///
+/// The driver uses the `SystemClock` unless another clock is set via
+/// `RbatisMigrationDriver::with_clock`, e.g. a fixed time in tests. Closures returning
+/// milliseconds are clocks too.
+pub trait Clock: Send + Sync {
+    /// The current time in milliseconds since the Unix epoch
+    fn now_millis(&self) -> i64;
+}
+
+/// The system clock, in UTC
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> i64 {
+        return DateTime::utc().unix_timestamp_millis();
     }
-    RbatisDbDriverType::Sqlite => {
-        unimplemented!()
+}
 
+impl<F: Fn() -> i64 + Send + Sync> Clock for F {
+    fn now_millis(&self) -> i64 {
+        return self();
     }
-    RbatisDbDriverType::MsSql => {
-        unimplemented!()
+}
 
+/// Available driver types supported by Rbatis
+pub enum RbatisDbDriverType {
+    MySql,
+    Pg,
+    Sqlite,
+    MsSql,
+    TDengine,
+    Oracle,
+    ClickHouse,
+    Other(String),
+}
+impl RbatisDbDriverType {
+    /// Get the SQL dialect used for the migrations table of this driver type
+    pub fn dialect(&self) -> &'static dyn DialectSql {
+        match self {
+            RbatisDbDriverType::MySql => &MySqlDialect,
+            RbatisDbDriverType::Pg => &PgDialect,
+            RbatisDbDriverType::Sqlite => &SqliteDialect,
+            RbatisDbDriverType::MsSql => &MsSqlDialect,
+            RbatisDbDriverType::TDengine => &TDengineDialect,
+            RbatisDbDriverType::Oracle => &OracleDialect,
+            RbatisDbDriverType::ClickHouse => &ClickHouseDialect,
+            RbatisDbDriverType::Other(_) => &GenericDialect,
+        }
     }
-    RbatisDbDriverType::TDengine => {
-        unimplemented!()
+}
+
+/// Bind values for the placeholders of a statement, see the `params` annotation
+fn statement_params(statement: &SqlStatement) -> flyway::Result<Vec<Value>> {
+    return statement.params()
+        .map(|params| params.into_iter().map(|param| to_value!(param)).collect())
+        .or_else(|err| Err(MigrationsError::migration_database_step_failed(None, Some(Box::new(err)))));
+}
+
+/// The upsert of `row`, `None` for repeatable rows, see `DialectSql::upsert_version`
+fn upsert_version(dialect: &dyn DialectSql, migrations_table_name: &str, row: &RowValues) -> Option<(String, Vec<Value>)> {
+    if row.version.is_none() {
+        return None;
     }
-    RbatisDbDriverType::Other(_) => {
-        unimplemented!()
+    return dialect.upsert_version(migrations_table_name, row);
+}
 
+/// Check that the row of a migration fits into the columns of the migrations table
+///
+/// Lengths are compared in bytes, which is the stricter measure for databases counting the
+/// width of a column in characters.
+fn check_column_lengths(row: &RowValues) -> flyway::Result<()> {
+    let columns = [
+        ("name", row.name.len(), NAME_COL_LEN),
+        ("checksum", row.checksum.len(), CHECKSUM_COL_LEN),
+        ("status", row.status.len(), STATUS_COL_LEN),
+    ];
+    for (column, len, max_len) in columns {
+        if len > max_len {
+            let migration = match row.version {
+                Some(version) => format!("version {}", version),
+                None => format!("repeatable migration {}", row.name),
+            };
+            return Err(MigrationsError::custom_message(
+                format!("The {} of {} is {} bytes long, but the {} column only holds {}.",
+                        column, migration, len, column, max_len).as_str(), None, None));
+        }
     }
+    return Ok(());
 }
+
+/// Prefix a SQLite pragma with the `PRAGMA` keyword, unless it has it already
+fn pragma_statement(pragma: &str) -> String {
+    let pragma = pragma.trim().trim_end_matches(';');
+    let keyword = pragma.get(..6).filter(|keyword| keyword.eq_ignore_ascii_case("PRAGMA"));
+    let has_keyword = keyword.is_some() && pragma[6..].starts_with(char::is_whitespace);
+    if has_keyword {
+        return pragma.to_string();
+    }
+    return format!("PRAGMA {}", pragma);
 }
 
 /// Rbatis implementation of `MigrationStateManager` and `MigrationExecutor`
+///
+/// A driver owns exactly one transaction slot, which is used by the `MigrationExecutor` methods.
+/// Wrapping the driver in an `Arc` and passing it to a single `MigrationRunner` as both state
+/// manager and executor is the intended use. To run several `MigrationRunner`s concurrently
+/// against the same `RBatis` pool, give each runner its own driver via `fork()`, otherwise
+/// the runners will compete for the same transaction slot.
 pub struct RbatisMigrationDriver {
     db: Arc<RBatis>,
     migrations_table_name: String,
-    tx: Mutex<Cell<Option<RBatisTxExecutor>>>,
+    /// The transaction opened by `begin_transaction`, or the one provided by the caller
+    tx: Mutex<Option<RBatisTxExecutor>>,
+    /// The connection the statements of a changelog run on while no transaction is open
+    conn: Mutex<Option<RBatisConnExecutor>>,
+    /// Whether `tx` is owned by the caller, see `with_transaction`
+    external_tx: bool,
+    /// How executed statements are logged
+    statement_log_mode: StatementLogMode,
+    /// Offset applied to timestamps read back from TDengine
+    tdengine_offset_seconds: i32,
+    /// How statements failing with a transient lock error are retried
+    retry_policy: RetryPolicy,
+    /// Source of the timestamps written to the migrations table
+    clock: Arc<dyn Clock>,
+    /// Pragmas issued by `prepare` on SQLite databases
+    sqlite_pragmas: Vec<String>,
 }
 
 impl RbatisMigrationDriver {
@@ -165,8 +322,151 @@ impl RbatisMigrationDriver {
             migrations_table_name: migrations_table_name.map(|v| v.to_string())
                 .or(Some(DEFAULT_MIGRATIONS_TABLE.to_string()))
                 .unwrap(),
-            tx: Mutex::new(Cell::new(None)),
+            tx: Mutex::new(None),
+            conn: Mutex::new(None),
+            external_tx: false,
+            statement_log_mode: StatementLogMode::Full,
+            tdengine_offset_seconds: 0,
+            retry_policy: RetryPolicy::disabled(),
+            clock: Arc::new(SystemClock),
+            sqlite_pragmas: Vec::new(),
+        }
+    }
+
+    /// Create a new driver executing all changelogs in a transaction owned by the caller
+    ///
+    /// The driver keeps a clone of `tx`, which shares the connection of the caller's executor.
+    /// `begin_transaction`, `commit_transaction` and `rollback_transaction` do nothing, so the
+    /// caller decides when the transaction ends, e.g. together with other startup DDL:
+    ///
+    /// ```ignore
+    /// let tx = db.acquire_begin().await?;
+    /// let driver = Arc::new(RbatisMigrationDriver::with_transaction(db.clone(), tx.clone(), None));
+    /// MigrationRunner::new(Migrations {}, driver.clone(), driver, false)
+    ///     .with_atomic_versioning(true)
+    ///     .migrate().await?;
+    /// tx.commit().await?;
+    /// ```
+    ///
+    /// Only the statements of the changelogs and, with `with_atomic_versioning(true)`, the
+    /// deployed versions are written through the transaction. Everything else, i.e. `prepare`,
+    /// reading the deployed versions and recording versions without atomic versioning, uses
+    /// separate connections of `db`, so those records survive a rollback. A failing changelog is
+    /// not rolled back by the driver, so `fail_continue` should not be used: roll back the whole
+    /// transaction instead. The driver must not be used after the transaction has ended;
+    /// `fork()` returns a driver opening its own transactions.
+    pub fn with_transaction(db: Arc<RBatis>, tx: RBatisTxExecutor, migrations_table_name: Option<&str>) -> RbatisMigrationDriver {
+        return RbatisMigrationDriver {
+            tx: Mutex::new(Some(tx)),
+            external_tx: true,
+            ..RbatisMigrationDriver::new(db, migrations_table_name)
+        };
+    }
+
+    /// Set how executed statements are logged (default: `StatementLogMode::Full`)
+    pub fn with_statement_log_mode(mut self, statement_log_mode: StatementLogMode) -> RbatisMigrationDriver {
+        self.statement_log_mode = statement_log_mode;
+        return self;
+    }
+
+    /// Create a driver for the database at `url`, picking the Rbatis driver from its scheme
+    ///
+    /// Supported schemes are `mysql://`, `postgres://`, `sqlite://`, `mssql://` and
+    /// `taos+ws://`. The Rbatis driver of a database is only available with the corresponding
+    /// feature of this crate: `mysql`, `pg`, `sqlite`, `mssql` or `tdengine`.
+    pub fn from_url(url: &str, migrations_table_name: Option<&str>) -> flyway::Result<RbatisMigrationDriver> {
+        let scheme = url.split_once("://").map(|(scheme, _)| scheme.to_lowercase()).unwrap_or_default();
+        let feature = match scheme.as_str() {
+            "mysql" => "mysql",
+            "postgres" | "postgresql" => "pg",
+            "sqlite" => "sqlite",
+            "mssql" | "sqlserver" => "mssql",
+            "taos" | "taos+ws" | "taosws" => "tdengine",
+            _ => return Err(MigrationsError::migration_setup_failed(Some(
+                format!("Unknown database URL scheme: '{}'", scheme).into()))),
+        };
+
+        let db = RBatis::new();
+        let result: Option<rbatis::Result<()>> = match feature {
+            #[cfg(feature = "mysql")]
+            "mysql" => Some(db.init(rbdc_mysql::driver::MysqlDriver {}, url)),
+            #[cfg(feature = "pg")]
+            "pg" => Some(db.init(rbdc_pg::driver::PgDriver {}, url)),
+            #[cfg(feature = "sqlite")]
+            "sqlite" => Some(db.init(rbdc_sqlite::driver::SqliteDriver {}, url)),
+            #[cfg(feature = "mssql")]
+            "mssql" => Some(db.init(rbdc_mssql::driver::MssqlDriver {}, url)),
+            #[cfg(feature = "tdengine")]
+            "tdengine" => Some(db.init(rbdc_tdengine::driver::TaosDriver {}, url)),
+            _ => None,
+        };
+        match result {
+            Some(result) => result.or_else(|err| Err(MigrationsError::migration_setup_failed(Some(err.into()))))?,
+            None => return Err(MigrationsError::migration_setup_failed(Some(
+                format!("The '{}' feature of flyway-rbatis is required for '{}' URLs", feature, scheme).into()))),
         }
+        return Ok(RbatisMigrationDriver::new(Arc::new(db), migrations_table_name));
+    }
+
+    /// Set the offset applied to timestamps read back from TDengine (default: `0`, i.e. no shift)
+    ///
+    /// TDengine has no `UPDATE`, so the status of a version is changed by inserting a row with
+    /// the same `ts` as the existing one, which overwrites it. Each `ts` read back from the
+    /// migrations table is passed through `DateTime::set_offset` with this offset before it is
+    /// written again, so set it to the negated shift of the read-back `ts`. If the `ts` comes back
+    /// 16 hours late, e.g. because both the server and the client apply UTC+8 as in the taos
+    /// example, this is `-16 * 60 * 60`. Releases before the offset was configurable always used
+    /// `-16 * 60 * 60`.
+    pub fn with_tdengine_offset_seconds(mut self, tdengine_offset_seconds: i32) -> RbatisMigrationDriver {
+        self.tdengine_offset_seconds = tdengine_offset_seconds;
+        return self;
+    }
+
+    /// Set how statements failing with a transient lock error are retried (default: never)
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> RbatisMigrationDriver {
+        self.retry_policy = retry_policy;
+        return self;
+    }
+
+    /// Set the clock the timestamps written to the migrations table are taken from (default: `SystemClock`)
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> RbatisMigrationDriver {
+        self.clock = clock;
+        return self;
+    }
+
+    /// Set the pragmas issued by `prepare` on SQLite databases (default: none)
+    ///
+    /// The pragmas are executed in order before the migrations table is created, e.g.
+    /// `journal_mode=WAL` or `PRAGMA foreign_keys=ON`; the `PRAGMA` keyword is optional. They are
+    /// ignored for other databases. Note that most pragmas, like `foreign_keys`, only apply to
+    /// the pooled connection `prepare` uses, while `journal_mode=WAL` is stored in the database.
+    pub fn with_sqlite_pragmas(mut self, sqlite_pragmas: Vec<String>) -> RbatisMigrationDriver {
+        self.sqlite_pragmas = sqlite_pragmas;
+        return self;
+    }
+
+    /// Create a new driver sharing the database and configuration of this one
+    ///
+    /// The returned driver has its own, empty transaction slot, so it can be used by another
+    /// `MigrationRunner` at the same time as this driver.
+    pub fn fork(&self) -> RbatisMigrationDriver {
+        return RbatisMigrationDriver {
+            db: self.db.clone(),
+            migrations_table_name: self.migrations_table_name.clone(),
+            tx: Mutex::new(None),
+            conn: Mutex::new(None),
+            external_tx: false,
+            statement_log_mode: self.statement_log_mode.clone(),
+            tdengine_offset_seconds: self.tdengine_offset_seconds,
+            retry_policy: self.retry_policy.clone(),
+            clock: self.clock.clone(),
+            sqlite_pragmas: self.sqlite_pragmas.clone(),
+        }
+    }
+
+    /// Get the name of the table the migration state is stored in
+    pub fn table_name(&self) -> &str {
+        return self.migrations_table_name.as_str();
     }
 
     /// The the driver type of the `Rbatis` instance
@@ -183,10 +483,203 @@ impl RbatisMigrationDriver {
             "postgres" => RbatisDbDriverType::Pg,
             "sqlite" => RbatisDbDriverType::Sqlite,
             "Taos"=>RbatisDbDriverType::TDengine,
+            "oracle" => RbatisDbDriverType::Oracle,
+            "clickhouse" => RbatisDbDriverType::ClickHouse,
             _ => RbatisDbDriverType::Other(driver_type_name.to_string())
         };
         return Ok(result);
     }
+
+    /// Widen the `version` column of a migrations table created by an older release to `BIGINT`
+    ///
+    /// New tables are created with a 64 bit `version` column, which is needed for versions like
+    /// `20240115120000`. Tables created before have a 32 bit column on some databases; call
+    /// this once to migrate them. Does nothing for databases whose column is 64 bit already or
+    /// cannot be altered, see `DialectSql::widen_version_column`.
+    pub async fn widen_version_column(&self) -> flyway::Result<()> {
+        let statement = match self.dialect()?.widen_version_column(self.migrations_table_name.as_str()) {
+            Some(statement) => statement,
+            None => return Ok(()),
+        };
+        let db = self.db.clone();
+        let db = db.acquire()
+            .await
+            .or_else(|err| Err(MigrationsError::migration_database_failed(None, Some(err.into()))))?;
+        log::debug!("Upgrade Statement: {}", statement.as_str());
+        let _result = db.exec(statement.as_str(), vec![])
+            .await
+            .or_else(|err| Err(MigrationsError::migration_setup_failed(Some(err.into()))))?;
+        return Ok(());
+    }
+}
+
+impl RbatisMigrationDriver {
+    /// Get the SQL dialect of the database
+    fn dialect(&self) -> flyway::Result<&'static dyn DialectSql> {
+        return self.driver_type()
+            .map(|driver_type| driver_type.dialect())
+            .or_else(|err| Err(MigrationsError::migration_database_failed(None, Some(err.into()))));
+    }
+
+    /// Execute a statement of a changelog, retrying it according to the retry policy
+    async fn exec_statement(&self, executor: &dyn Executor, dialect: &dyn DialectSql, statement: &SqlStatement) -> flyway::Result<u64> {
+        log::debug!("Executing statement: {}", self.statement_log_mode.render(statement.statement.as_str()));
+        let retryable_errors = dialect.retryable_errors();
+        let mut retry: u32 = 0;
+        loop {
+            let err = match executor.exec(statement.statement.as_str(), statement_params(statement)?).await {
+                Ok(result) => return Ok(result.rows_affected),
+                Err(err) => err,
+            };
+            let message = err.to_string().to_lowercase();
+            let retryable = retryable_errors.iter().any(|fragment| message.contains(fragment.to_lowercase().as_str()));
+            let delay = match self.retry_policy.delay(retry) {
+                Some(delay) if retryable => delay,
+                _ => return Err(MigrationsError::migration_versioning_failed(Some(err.into()))),
+            };
+            retry += 1;
+            log::warn!("Statement failed with a transient error, retry {} of {} in {:?}: {}",
+                retry, self.retry_policy.max_retries, delay, err);
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Set the status of a version, inserting its row if it does not exist yet
+    async fn set_version_status(&self, changelog_file: &ChangelogFile, status: &str) -> flyway::Result<()> {
+        let now = self.clock.now_millis();
+        let row = RowValues::new(changelog_file, status, now, format_applied_at(now))?;
+        return self.write_row(row).await;
+    }
+
+    /// Set the status of the row of a migration, inserting it if it does not exist yet
+    async fn write_row(&self, mut row: RowValues) -> flyway::Result<()> {
+        check_column_lengths(&row)?;
+        let db = self.db.clone();
+        let db = db.acquire()
+            .await
+            .or_else(|err| Err(MigrationsError::migration_database_failed(None, Some(err.into()))))?;
+
+        let dialect = self.dialect()?;
+
+        // Without UPDATE, the row of the version is replaced by inserting a new one. TDengine
+        // overwrites rows with the same ts, ClickHouse keeps the row with the highest ts.
+        if !dialect.supports_update() {
+            if let Ok(RbatisDbDriverType::TDengine) = self.driver_type() {
+                row.ts = self.resolve_tdengine_ts(&db, &row).await;
+            }
+            return self.insert_version(&db, &row).await;
+        }
+
+        if let Some((upsert_statement, params)) = upsert_version(dialect, self.migrations_table_name.as_str(), &row) {
+            log::debug!("Upsert statement: {}", upsert_statement.as_str());
+            let _upsert_result = db.exec(upsert_statement.as_str(), params)
+                .await
+                .or_else(|err| Err(MigrationsError::migration_versioning_failed(Some(err.into()))))?;
+            return Ok(());
+        }
+
+        let (update_statement, params) = dialect.update_status(self.migrations_table_name.as_str(), &row);
+
+        log::debug!("Update statement: {}", update_statement.as_str());
+        let update_result = db.exec(update_statement.as_str(), params)
+            .await
+            .or_else(|err| Err(MigrationsError::migration_versioning_failed(Some(err.into()))))?;
+
+        if update_result.rows_affected < 1 {
+            return self.insert_version(&db, &row).await;
+        }
+        return Ok(());
+    }
+
+    /// Get the `ts` of the row of a migration on TDengine
+    ///
+    /// TDengine has no `UPDATE`, but inserting a row with the `ts` of an existing row overwrites
+    /// that row. Reusing the `ts` of the existing row of a migration therefore keeps exactly one
+    /// row per `(migration_type, version, name)`, whatever its status. Rows written before
+    /// `migration_type` was added have no type and are matched by version only. If a version has several rows (e.g. written by
+    /// older releases, which only looked for `in_progress` rows), all but the oldest are deleted,
+    /// which needs TDengine 3.0 or newer.
+    ///
+    /// If the version has no row yet, a new `ts` is needed. As `ts` is the primary key, it must
+    /// not be the `ts` of another version, which it would overwrite. The current time is used,
+    /// unless a row has been written in the same or a later millisecond, see `next_tdengine_ts`.
+    async fn resolve_tdengine_ts(&self, db: &RBatisConnExecutor, row: &RowValues) -> i64 {
+        let (ts_select, params) = match row.version {
+            Some(version) => (format!(r#"select ts,version from {} where version=? and (migration_type=? or migration_type is null) order by ts asc;"#,
+                                      self.migrations_table_name.as_str()),
+                              vec![to_value!(version), to_value!(row.migration_type.as_str())]),
+            None => {
+                let (condition, params) = key_condition(row);
+                (format!(r#"select ts,version from {} where {} order by ts asc;"#, self.migrations_table_name.as_str(), condition), params)
+            }
+        };
+        let migration = row.version.map(|version| version.to_string()).unwrap_or_else(|| row.name.clone());
+        match db.query_decode::<Vec<MigrationInfo>>(ts_select.as_str(), params).await {
+            Ok(result) => {
+                if let Some((info, duplicates)) = result.split_first() {
+                    for duplicate in duplicates {
+                        let ts = self.tdengine_ts_millis(&duplicate.ts);
+                        let delete_statement = format!(r#"delete from {} where ts=?;"#, self.migrations_table_name.as_str());
+                        log::debug!("Delete statement: {}", delete_statement.as_str());
+                        if let Err(e) = db.exec(delete_statement.as_str(), vec![to_value!(ts)]).await {
+                            log::warn!("Could not delete duplicate row of {}: {}", migration, e);
+                        }
+                    }
+                    return self.tdengine_ts_millis(&info.ts);
+                }
+            }
+            Err(e) => {
+                log::error!("数据异常:{}",e.to_string())
+            }
+        };
+        let latest_select = format!(r#"select ts,version from {} order by ts desc limit 1;"#, self.migrations_table_name.as_str());
+        let latest = match db.query_decode::<Vec<MigrationInfo>>(latest_select.as_str(), vec![]).await {
+            Ok(result) => result.first().map(|info| self.tdengine_ts_millis(&info.ts)),
+            Err(e) => {
+                log::warn!("Could not read the latest ts: {}", e);
+                None
+            }
+        };
+        return next_tdengine_ts(self.clock.now_millis(), latest);
+    }
+
+    /// Convert a `ts` read back from TDengine into the milliseconds it has been written with
+    fn tdengine_ts_millis(&self, ts: &DateTime) -> i64 {
+        return ts.clone().set_offset(self.tdengine_offset_seconds).unix_timestamp_millis();
+    }
+
+    /// Create the index `<table>_<suffix>` with `statement`, unless it exists already
+    async fn create_index(&self, db: &RBatisConnExecutor, statement: &str, suffix: &str) -> flyway::Result<()> {
+        let dialect = self.dialect()?;
+        let exists = match dialect.list_indexes(self.migrations_table_name.as_str()) {
+            Some(list_statement) => {
+                let index = format!("{}_{}", self.migrations_table_name, suffix).to_lowercase();
+                let rows: Vec<HashMap<String, String>> = db.query_decode(list_statement.as_str(), vec![])
+                    .await
+                    .or_else(|err| Err(MigrationsError::migration_setup_failed(Some(err.into()))))?;
+                rows.iter().filter_map(|row| row.values().next()).any(|existing| existing.to_lowercase() == index)
+            }
+            None => false,
+        };
+        if !exists {
+            log::debug!("Index Statement: {}", statement);
+            let _result = db.exec(statement, vec![])
+                .await
+                .or_else(|err| Err(MigrationsError::migration_setup_failed(Some(err.into()))))?;
+        }
+        return Ok(());
+    }
+
+    /// Insert the row of a version
+    async fn insert_version(&self, db: &RBatisConnExecutor, row: &RowValues) -> flyway::Result<()> {
+        let (insert_statement, params) = self.dialect()?
+            .insert_version(self.migrations_table_name.as_str(), row);
+        log::debug!("Insert statement: {}", insert_statement.as_str());
+        let _insert_result = db.exec(insert_statement.as_str(), params)
+            .await
+            .or_else(|err| Err(MigrationsError::migration_versioning_failed(Some(err.into()))))?;
+        return Ok(());
+    }
 }
 
 /// Implementation of the `MigrationStateManager`
@@ -195,36 +688,98 @@ impl MigrationStateManager for RbatisMigrationDriver {
     async fn prepare(&self) -> flyway::Result<()> {
         log::debug!("Preparing Migrations Table ...");
         let db = self.db.clone();
-      let statement=create_table_sql(self.driver_type().unwrap(),self.migrations_table_name.clone());
-        let mut db = db.acquire()
+        let dialect = self.dialect()?;
+        let statement = dialect.create_table(self.migrations_table_name.as_str());
+        let db = db.acquire()
             .await
             .or_else(|err| Err(MigrationsError::migration_database_failed(None, Some(err.into()))))?;
 
+        if matches!(self.driver_type(), Ok(RbatisDbDriverType::Sqlite)) {
+            for pragma in self.sqlite_pragmas.iter() {
+                let pragma = pragma_statement(pragma);
+                log::debug!("Pragma Statement: {}", pragma.as_str());
+                let _result = db.exec(pragma.as_str(), vec![])
+                    .await
+                    .or_else(|err| Err(MigrationsError::migration_setup_failed(Some(err.into()))))?;
+            }
+        }
+
         log::debug!("Preparation Statement: {}", statement.as_str());
         let _result = db.exec(statement.as_str(), vec![])
             .await
             .or_else(|err| Err(MigrationsError::migration_setup_failed(Some(err.into()))))?;
+
+        // Tables created by older versions may lack columns, add them so that inserts don't fail
+        let existing_columns: Option<Vec<String>> = match dialect.list_columns(self.migrations_table_name.as_str()) {
+            Some(statement) => {
+                // Each row is decoded as a map, as the name of the column differs between databases
+                let rows: Vec<HashMap<String, String>> = db.query_decode(statement.as_str(), vec![])
+                    .await
+                    .or_else(|err| Err(MigrationsError::migration_setup_failed(Some(err.into()))))?;
+                Some(rows.iter().filter_map(|row| row.values().next()).map(|column| column.to_lowercase()).collect())
+            }
+            None => None,
+        };
+        for (column, definition) in dialect.upgradable_columns() {
+            let exists = existing_columns.as_ref()
+                .map(|columns| columns.iter().any(|existing| existing == column))
+                .unwrap_or(false);
+            if exists {
+                continue;
+            }
+            let statement = dialect.add_column(self.migrations_table_name.as_str(), column, definition.as_str());
+            log::debug!("Upgrade Statement: {}", statement.as_str());
+            let _result = db.exec(statement.as_str(), vec![])
+                .await
+                .or_else(|err| Err(MigrationsError::migration_setup_failed(Some(err.into()))))?;
+        }
+
+        // Rows written before `migration_type` was added are versioned, and must be typed to be matched by the key
+        if let Some(statement) = dialect.fill_migration_type(self.migrations_table_name.as_str()) {
+            log::debug!("Upgrade Statement: {}", statement.as_str());
+            let _result = db.exec(statement.as_str(), vec![])
+                .await
+                .or_else(|err| Err(MigrationsError::migration_setup_failed(Some(err.into()))))?;
+        }
+
+        // The version queries filter on status, index it so they don't scan the whole history
+        if let Some(statement) = dialect.create_status_index(self.migrations_table_name.as_str()) {
+            self.create_index(&db, statement.as_str(), "status").await?;
+        }
+        if let Some(statement) = dialect.create_key_index(self.migrations_table_name.as_str()) {
+            self.create_index(&db, statement.as_str(), "key").await?;
+        }
         log::debug!("Preparing Migrations Table ... done");
         return Ok(());
     }
 
+    async fn is_prepared(&self) -> flyway::Result<bool> {
+        let db = self.db.clone();
+        let db = db.acquire()
+            .await
+            .or_else(|err| Err(MigrationsError::migration_database_failed(None, Some(err.into()))))?;
+        let statement = self.dialect()?.table_exists(self.migrations_table_name.as_str());
+        log::debug!("Table Statement: {}", statement.as_str());
+        let count: u64 = db.query_decode(statement.as_str(), vec![])
+            .await
+            .or_else(|err| Err(MigrationsError::migration_database_failed(None, Some(err.into()))))?;
+        return Ok(count > 0);
+    }
+
     async fn lowest_version(&self) -> flyway::Result<Option<MigrationState>> {
         log::debug!("Retrieving lowest version ... ");
         let db = self.db.clone();
         let mut db = db.acquire()
             .await
             .or_else(|err| Err(MigrationsError::migration_database_failed(None, Some(err.into()))))?;
-        let version: Option<u64> = db.query_decode(format!("SELECT MIN(version) FROM {} WHERE status='deployed';",
-                                                           self.migrations_table_name.as_str()).as_str(), vec![])
+        let version: Option<u64> = db.query_decode(format!("SELECT MIN(version) FROM {} WHERE status='deployed'",
+                                                           self.dialect()?.select_from(self.migrations_table_name.as_str())).as_str(), vec![])
             .await
             .or_else(|err| Err(MigrationsError::migration_versioning_failed(Some(err.into()))))?;
 
         log::debug!("Retrieving lowest version ... {:?}", &version);
         return Ok(version.and_then(|version|
-            Some(MigrationState {
-                version,
-                status: MigrationStatus::Deployed
-            })));
+            Some(MigrationState::new(version, MigrationStatus::Deployed))));
     }
 
     async fn highest_version(&self) -> flyway::Result<Option<MigrationState>> {
@@ -233,17 +788,14 @@ impl MigrationStateManager for RbatisMigrationDriver {
         let mut db = db.acquire()
             .await
             .or_else(|err| Err(MigrationsError::migration_database_failed(None, Some(err.into()))))?;
-        let version: Option<u64> = db.query_decode(format!("SELECT MAX(version) FROM {} WHERE status='deployed';",
-                                                           self.migrations_table_name.as_str()).as_str(), vec![])
+        let version: Option<u64> = db.query_decode(format!("SELECT MAX(version) FROM {} WHERE status='deployed'",
+                                                           self.dialect()?.select_from(self.migrations_table_name.as_str())).as_str(), vec![])
             .await
             .or_else(|err| Err(MigrationsError::migration_versioning_failed(Some(err.into()))))?;
 
         log::debug!("Retrieving highest version ... {:?}", &version);
         return Ok(version.and_then(|version|
-            Some(MigrationState {
-                version,
-                status: MigrationStatus::Deployed
-            })));
+            Some(MigrationState::new(version, MigrationStatus::Deployed))));
     }
 
     async fn list_versions(&self) -> flyway::Result<Vec<MigrationState>> {
@@ -252,213 +804,161 @@ impl MigrationStateManager for RbatisMigrationDriver {
         let mut db = db.acquire()
             .await
             .or_else(|err| Err(MigrationsError::migration_database_failed(None, Some(err.into()))))?;
-        let versions: Vec<u64> = db.query_decode(format!("SELECT version FROM {} WHERE status='deployed' ORDER BY version asc;",
-                                                         self.migrations_table_name.as_str()).as_str(), vec![])
+        let rows: Vec<VersionRow> = db.query_decode(format!("SELECT ts, applied_at, version, name, checksum, status FROM {} WHERE status='deployed' AND version IS NOT NULL ORDER BY version asc",
+                                                            self.dialect()?.select_from(self.migrations_table_name.as_str())).as_str(), vec![])
             .await
             .or_else(|err| Err(MigrationsError::migration_versioning_failed(Some(err.into()))))?;
 
-        let versions: Vec<MigrationState> = versions.iter()
-            .map(|version|
-                MigrationState {
-                    version: *version,
-                    status: MigrationStatus::Deployed
-                })
+        let versions: Vec<MigrationState> = rows.into_iter()
+            .map(|row| row.into_state())
             .collect();
 
         log::debug!("Listing versions ... {:?}", &versions);
         return Ok(versions);
     }
 
-    async fn begin_version(&self, changelog_file: &ChangelogFile) -> flyway::Result<()> {
-        log::debug!("Beginning version ... {}", changelog_file.version);
+    async fn get_version(&self, version: u64) -> flyway::Result<Option<MigrationState>> {
+        log::debug!("Retrieving version {} ... ", version);
         let db = self.db.clone();
-        let mut db = db.acquire()
+        let db = db.acquire()
             .await
             .or_else(|err| Err(MigrationsError::migration_database_failed(None, Some(err.into()))))?;
-
-       match   self.driver_type(){
-           Ok(db_type) => {
-               match db_type {
-                   RbatisDbDriverType::TDengine => {
-                       let mut ts:i64=DateTime::utc().unix_timestamp_millis()+changelog_file.version() as i64;
-                       let ts_select=format!(r#"select ts,version from {} where status='in_progress' and version=? limit 1;"#, self.migrations_table_name.as_str());
-                       match   db.query_decode::<Vec<MigrationInfo>>(ts_select.as_str(),vec![to_value!(changelog_file.version.clone())]).await{
-                           Ok(result) => {
-                               // println!("{:?}",result);
-                              if result.first().is_some(){
-                                  let  time=result.first().unwrap().ts.clone().set_offset(-16*60*60);
-                                   ts=time.unix_timestamp_millis();
-                              }
-                           }
-                           Err(e) => {
-                               log::error!("数据异常:{}",e.to_string())
-                           }
-                       };
-                       let insert_statement = format!(r#"INSERT INTO {}(ts,version,name,checksum, status) VALUES (?,?,?,?, 'in_progress');"#,
-                                                      self.migrations_table_name.as_str());
-                       log::debug!("Insert statement: {}", insert_statement.as_str());
-                       let _insert_result = db.exec(insert_statement.as_str(), vec![to_value!(ts),to_value!(changelog_file.version.clone()),to_value!(changelog_file.name.clone()),to_value!(changelog_file.checksum.clone())])
-                           .await
-                           .or_else(|err| Err(MigrationsError::migration_versioning_failed(Some(err.into()))))?;
-                       return Ok(());
-                   }
-                 _ => {}
-               }
-           }
-           Err(_) => {}
-       }
-
-        // let update_statement = format!(r#"UPDATE {} SET status='in_progress' where version={};"#,
-        //                                self.migrations_table_name.as_str(), changelog_file.version);
-        let update_statement =update_sql(self.driver_type().unwrap(),self.migrations_table_name.clone(),"in_progress".to_string(),changelog_file.version.clone());
-
-        log::debug!("Update statement: {}", update_statement.as_str());
-        let update_result = db.exec(update_statement.as_str(), vec![])
+        // Without UPDATE, older rows of the version may not have been replaced yet
+        let rows: Vec<VersionRow> = db.query_decode(format!("SELECT ts, applied_at, version, name, checksum, status FROM {} WHERE version=? ORDER BY ts desc",
+                                                            self.dialect()?.select_from(self.migrations_table_name.as_str())).as_str(), vec![to_value!(version)])
             .await
             .or_else(|err| Err(MigrationsError::migration_versioning_failed(Some(err.into()))))?;
 
-        if update_result.rows_affected < 1 {
-            let  ts:i64=DateTime::utc().unix_timestamp_millis()+changelog_file.version as i64;
+        let state = rows.into_iter().next().map(|row| row.into_state());
+        log::debug!("Retrieving version {} ... {:?}", version, &state);
+        return Ok(state);
+    }
 
-            // let insert_statement = format!(r#"INSERT INTO {}(ts,version,name,checksum, status) VALUES (?,?,?,?, 'in_progress');"#,
-            //                                self.migrations_table_name.as_str());
-           let insert_statement=insert_sql(self.driver_type().unwrap(),self.migrations_table_name.clone(),"in_progress".to_string());
-            log::debug!("Insert statement: {}", insert_statement.as_str());
-            let _insert_result = db.exec(insert_statement.as_str(), vec![to_value!(ts),to_value!(changelog_file.version.clone()),to_value!(changelog_file.name.clone()),to_value!(changelog_file.checksum.clone())])
-                .await
-                .or_else(|err| Err(MigrationsError::migration_versioning_failed(Some(err.into()))))?;
-        }
+    async fn is_deployed(&self, version: u64) -> flyway::Result<bool> {
+        log::debug!("Checking version {} ... ", version);
+        let db = self.db.clone();
+        let db = db.acquire()
+            .await
+            .or_else(|err| Err(MigrationsError::migration_database_failed(None, Some(err.into()))))?;
+        // COUNT instead of EXISTS, which e.g. Oracle and MSSQL don't support without a FROM clause
+        let count: u64 = db.query_decode(format!("SELECT COUNT(*) FROM {} WHERE version=? AND status='deployed'",
+                                                 self.dialect()?.select_from(self.migrations_table_name.as_str())).as_str(), vec![to_value!(version)])
+            .await
+            .or_else(|err| Err(MigrationsError::migration_versioning_failed(Some(err.into()))))?;
 
-        return Ok(());
+        log::debug!("Checking version {} ... {}", version, count > 0);
+        return Ok(count > 0);
     }
 
-    async fn finish_version(&self, changelog_file: &ChangelogFile) -> flyway::Result<()> {
-        log::debug!("Finishing version ... {}", changelog_file.version);
+    async fn server_version(&self) -> flyway::Result<Option<String>> {
+        let statement = match self.dialect()?.server_version() {
+            Some(statement) => statement,
+            None => return Ok(None),
+        };
+        log::debug!("Retrieving server version ... ");
         let db = self.db.clone();
-        let mut db = db.acquire()
+        let db = db.acquire()
+            .await
+            .or_else(|err| Err(MigrationsError::migration_database_failed(None, Some(err.into()))))?;
+        let version: Option<String> = db.query_decode(statement, vec![])
             .await
             .or_else(|err| Err(MigrationsError::migration_database_failed(None, Some(err.into()))))?;
 
+        log::debug!("Retrieving server version ... {:?}", &version);
+        return Ok(version);
+    }
 
-        match   self.driver_type(){
-            Ok(db_type) => {
-                match db_type {
-                    RbatisDbDriverType::TDengine => {
-                        let mut ts:i64=DateTime::utc().unix_timestamp_millis()+changelog_file.version as i64;
-                        let ts_select=format!(r#"select ts,version from {} where status='in_progress' and version=? limit 1;"#, self.migrations_table_name.as_str());
-                        match   db.query_decode::<Vec<MigrationInfo>>(ts_select.as_str(),vec![to_value!(changelog_file.version.clone())]).await{
-                            Ok(result) => {
-                                if result.first().is_some(){
-                                    let  time=result.first().unwrap().ts.clone().set_offset(-16*60*60);
-                                    ts=time.unix_timestamp_millis();                               }
-                            }
-                            Err(e) => {
-                                log::error!("数据异常:{}",e.to_string())
-                            }
-                        };
-
-                        let insert_statement = format!(r#"INSERT INTO {}(ts,version,name,checksum, status) VALUES (?,?,?,?, 'deployed');"#,
-                                                       self.migrations_table_name.as_str());
-                        log::debug!("Insert statement: {}", insert_statement.as_str());
-                        let _insert_result = db.exec(insert_statement.as_str(), vec![to_value!(ts),to_value!(changelog_file.version.clone()),to_value!(changelog_file.name.clone()),to_value!(changelog_file.checksum.clone())])
-                            .await
-                            .or_else(|err| Err(MigrationsError::migration_versioning_failed(Some(err.into()))))?;
-                        return Ok(());
-                    }
-                    _ => {}
-                }
-            }
-            Err(_) => {}
-        }
+    async fn list_problems(&self) -> flyway::Result<Vec<MigrationState>> {
+        log::debug!("Listing problems ... ");
+        let db = self.db.clone();
+        let db = db.acquire()
+            .await
+            .or_else(|err| Err(MigrationsError::migration_database_failed(None, Some(err.into()))))?;
+        let rows: Vec<VersionRow> = db.query_decode(format!("SELECT ts, applied_at, version, name, checksum, status FROM {} WHERE status IN (?, ?) AND version IS NOT NULL ORDER BY version asc",
+                                                            self.dialect()?.select_from(self.migrations_table_name.as_str())).as_str(),
+                                                    vec![to_value!(STATUS_IN_PROGRESS), to_value!(STATUS_FAILED)])
+            .await
+            .or_else(|err| Err(MigrationsError::migration_versioning_failed(Some(err.into()))))?;
 
+        let problems: Vec<MigrationState> = rows.into_iter()
+            .map(|row| row.into_state())
+            .collect();
 
-        // let update_statement = format!(r#"UPDATE {} SET status='deployed' where version={};"#,
-        //                                self.migrations_table_name.as_str(), changelog_file.version);
-        let update_statement =update_sql(self.driver_type().unwrap(),self.migrations_table_name.clone(),"deployed".to_string(),changelog_file.version.clone());
+        log::debug!("Listing problems ... {:?}", &problems);
+        return Ok(problems);
+    }
 
-        log::debug!("Update statement: {}", update_statement.as_str());
-        let update_result = db.exec(update_statement.as_str(), vec![])
+    async fn applied_checksums(&self) -> flyway::Result<Vec<(u64, Option<String>)>> {
+        log::debug!("Listing checksums ... ");
+        let db = self.db.clone();
+        let db = db.acquire()
+            .await
+            .or_else(|err| Err(MigrationsError::migration_database_failed(None, Some(err.into()))))?;
+        let checksums: Vec<AppliedChecksum> = db.query_decode(format!("SELECT version, checksum FROM {} WHERE status='deployed' AND version IS NOT NULL ORDER BY version asc",
+                                                                      self.dialect()?.select_from(self.migrations_table_name.as_str())).as_str(), vec![])
             .await
             .or_else(|err| Err(MigrationsError::migration_versioning_failed(Some(err.into()))))?;
 
-        if update_result.rows_affected < 1 {
-            let  ts:i64=DateTime::utc().unix_timestamp_millis()+changelog_file.version as i64;
+        let checksums: Vec<(u64, Option<String>)> = checksums.into_iter()
+            .map(|applied| (applied.version, applied.checksum.map(|checksum| checksum.trim().to_string()).filter(|checksum| !checksum.is_empty())))
+            .collect();
 
-            // let insert_statement = format!(r#"INSERT INTO {}(ts,version,name,checksum, status) VALUES (?,?,?,?, 'in_progress');"#,
-            //                                self.migrations_table_name.as_str());
-            let insert_statement=insert_sql(self.driver_type().unwrap(),self.migrations_table_name.clone(),"in_progress".to_string());
+        log::debug!("Listing checksums ... {:?}", &checksums);
+        return Ok(checksums);
+    }
 
-            log::debug!("Insert statement: {}", insert_statement.as_str());
-            let _insert_result = db.exec(insert_statement.as_str(), vec![to_value!(ts),to_value!(changelog_file.version.clone()),to_value!(changelog_file.name.clone()),to_value!(changelog_file.checksum.clone())])
-                .await
-                .or_else(|err| Err(MigrationsError::migration_versioning_failed(Some(err.into()))))?;
-        }
+    async fn begin_version(&self, changelog_file: &ChangelogFile) -> flyway::Result<()> {
+        log::debug!("Beginning version ... {}", changelog_file.version);
+        return self.set_version_status(changelog_file, STATUS_IN_PROGRESS).await;
+    }
 
-        return Ok(());
+    async fn finish_version(&self, changelog_file: &ChangelogFile) -> flyway::Result<()> {
+        log::debug!("Finishing version ... {}", changelog_file.version);
+        return self.set_version_status(changelog_file, STATUS_DEPLOYED).await;
     }
 
     async fn skip_version(&self, changelog_file: &ChangelogFile) -> flyway::Result<()> {
         log::debug!("Skip version ... {}", changelog_file.version);
+        return self.set_version_status(changelog_file, STATUS_FAILED).await;
+    }
+
+    async fn finish_rerun(&self, changelog_file: &ChangelogFile) -> flyway::Result<()> {
+        log::debug!("Finishing re-run of version ... {}", changelog_file.version);
+        let dialect = self.dialect()?;
+        // Without UPDATE, the inserted row carries the new checksum anyway
+        if !dialect.supports_update() {
+            return self.set_version_status(changelog_file, STATUS_DEPLOYED).await;
+        }
+        let ts: i64 = self.clock.now_millis();
+        let row = RowValues::new(changelog_file, STATUS_DEPLOYED, ts, format_applied_at(ts))?;
+        check_column_lengths(&row)?;
         let db = self.db.clone();
-        let mut db = db.acquire()
+        let db = db.acquire()
             .await
             .or_else(|err| Err(MigrationsError::migration_database_failed(None, Some(err.into()))))?;
 
-
-        match   self.driver_type(){
-            Ok(db_type) => {
-                match db_type {
-                    RbatisDbDriverType::TDengine => {
-                        let mut ts:i64=DateTime::utc().unix_timestamp_millis()+changelog_file.version as i64;
-                        let ts_select=format!(r#"select ts,version from {} where status='in_progress' and version=? limit 1;"#, self.migrations_table_name.as_str());
-                        match   db.query_decode::<Vec<MigrationInfo>>(ts_select.as_str(),vec![to_value!(changelog_file.version.clone())]).await{
-                            Ok(result) => {
-                                if result.first().is_some(){
-                                    let  time=result.first().unwrap().ts.clone().set_offset(-16*60*60);
-                                    ts=time.unix_timestamp_millis();                               }
-                            }
-                            Err(e) => {
-                                log::error!("数据异常:{}",e.to_string())
-                            }
-                        };
-
-                        let insert_statement = format!(r#"INSERT INTO {}(ts,version,name,checksum, status) VALUES (?,?,?,?, 'fail');"#,
-                                                       self.migrations_table_name.as_str());
-                        log::debug!("Insert statement: {}", insert_statement.as_str());
-                        let _insert_result = db.exec(insert_statement.as_str(), vec![to_value!(ts),to_value!(changelog_file.version.clone()),to_value!(changelog_file.name.clone()),to_value!(changelog_file.checksum.clone())])
-                            .await
-                            .or_else(|err| Err(MigrationsError::migration_versioning_failed(Some(err.into()))))?;
-                        return Ok(());
-                    }
-                    _ => {}
-                }
-            }
-            Err(_) => {}
-        }
-
-
-        // let update_statement = format!(r#"UPDATE {} SET status='deployed' where version={};"#,
-        //                                self.migrations_table_name.as_str(), changelog_file.version);
-        let update_statement =update_sql(self.driver_type().unwrap(),self.migrations_table_name.clone(),"fail".to_string(),changelog_file.version.clone());
-
+        let (update_statement, params) = dialect.update_version(self.migrations_table_name.as_str(), &row);
         log::debug!("Update statement: {}", update_statement.as_str());
-        let update_result = db.exec(update_statement.as_str(), vec![])
+        let _update_result = db.exec(update_statement.as_str(), params)
             .await
             .or_else(|err| Err(MigrationsError::migration_versioning_failed(Some(err.into()))))?;
+        return Ok(());
+    }
 
-        if update_result.rows_affected < 1 {
-            let  ts:i64=DateTime::utc().unix_timestamp_millis()+changelog_file.version as i64;
-
-            // let insert_statement = format!(r#"INSERT INTO {}(ts,version,name,checksum, status) VALUES (?,?,?,?, 'in_progress');"#,
-            //                                self.migrations_table_name.as_str());
-            let insert_statement=insert_sql(self.driver_type().unwrap(),self.migrations_table_name.clone(),"in_progress".to_string());
-
-            log::debug!("Insert statement: {}", insert_statement.as_str());
-            let _insert_result = db.exec(insert_statement.as_str(), vec![to_value!(ts),to_value!(changelog_file.version.clone()),to_value!(changelog_file.name.clone()),to_value!(changelog_file.checksum.clone())])
-                .await
-                .or_else(|err| Err(MigrationsError::migration_versioning_failed(Some(err.into()))))?;
-        }
+    #[cfg(feature = "clean")]
+    async fn clean(&self) -> flyway::Result<()> {
+        log::debug!("Dropping Migrations Table ...");
+        let db = self.db.clone();
+        let statement = self.dialect()?.drop_table(self.migrations_table_name.as_str());
+        let db = db.acquire()
+            .await
+            .or_else(|err| Err(MigrationsError::migration_database_failed(None, Some(err.into()))))?;
 
+        log::debug!("Drop Statement: {}", statement.as_str());
+        let _result = db.exec(statement.as_str(), vec![])
+            .await
+            .or_else(|err| Err(MigrationsError::migration_database_failed(None, Some(err.into()))))?;
+        log::debug!("Dropping Migrations Table ... done");
         return Ok(());
     }
 }
@@ -466,11 +966,31 @@ impl MigrationStateManager for RbatisMigrationDriver {
 /// Implementation of the `MigrationExecutor`
 #[async_trait]
 impl MigrationExecutor for RbatisMigrationDriver {
+    fn supports_transactions(&self) -> bool {
+        return self.dialect()
+            .map(|dialect| dialect.supports_transactions())
+            .unwrap_or(true);
+    }
+
+    fn supports_atomic_versioning(&self) -> bool {
+        return self.dialect()
+            .map(|dialect| dialect.supports_transactions() && dialect.supports_update())
+            .unwrap_or(false);
+    }
+
     async fn begin_transaction(&self) -> flyway::Result<()> {
         log::debug!("Beginning transaction ...");
+        if self.external_tx {
+            log::debug!("Using the transaction provided by the caller");
+            return Ok(());
+        }
+        if !self.dialect()?.supports_transactions() {
+            log::debug!("Transactions are not supported, statements are executed directly");
+            return Ok(());
+        }
         {
-            let mut tx_guard = self.tx.lock().await;
-            if tx_guard.get_mut().is_some() {
+            let tx_guard = self.tx.lock().await;
+            if tx_guard.is_some() {
                 return Err(MigrationsError::migration_database_failed(None, None));
             }
         }
@@ -482,36 +1002,99 @@ impl MigrationExecutor for RbatisMigrationDriver {
                 .or_else(|err| Err(MigrationsError::migration_versioning_failed(Some(err.into()))))?
         };
 
-        let tx_guard = self.tx.lock().await;
-        tx_guard.set(Some(tx));
+        let mut tx_guard = self.tx.lock().await;
+        *tx_guard = Some(tx);
         return Ok(());
     }
 
-    async fn execute_changelog_file(&self, changelog_file: &flyway::ChangelogFile) -> flyway::Result<()> {
-        log::debug!("Executing changelog file ... {:?}", &changelog_file);
+    async fn execute_statement(&self, statement: &SqlStatement) -> flyway::Result<u64> {
+        let dialect = self.dialect()?;
         let mut tx_guard = self.tx.lock().await;
-        let tx = tx_guard.get_mut().as_mut();
-        match tx {
-            Some(tx) => {
-                for statement in changelog_file.iter() {
-                    log::debug!("Executing statement: {}", statement.statement.as_str());
-                    tx.exec(statement.statement.as_str(), vec![])
-                        .await
-                        .or_else(|err| Err(MigrationsError::migration_versioning_failed(Some(err.into()))))?;
-                }
+        // Without an open transaction (no transaction support, or a changelog annotated with
+        // `transactional: false`) the statement is executed directly, on the connection of its
+        // changelog if it is part of one
+        if let Some(tx) = tx_guard.as_mut() {
+            return self.exec_statement(tx, dialect, statement).await;
+        }
+        let conn_guard = self.conn.lock().await;
+        match conn_guard.as_ref() {
+            Some(conn) => {
+                return self.exec_statement(conn, dialect, statement).await;
             },
             None => {
-                return Err(MigrationsError::migration_database_failed(None, None));
+                let db = self.db.clone();
+                let db = db.acquire()
+                    .await
+                    .or_else(|err| Err(MigrationsError::migration_database_failed(None, Some(err.into()))))?;
+                return self.exec_statement(&db, dialect, statement).await;
             }
         };
+    }
+
+    async fn execute_changelog_file(&self, changelog_file: &ChangelogFile) -> flyway::Result<ChangelogOutcome> {
+        if self.tx.lock().await.is_some() {
+            return flyway::execute_statements(self, changelog_file, None).await;
+        }
+        // Each pooled connection is a separate session, so without a transaction all statements
+        // share one connection. Otherwise e.g. `USE db;` or `SET search_path` would not apply to
+        // the statements after them.
+        let conn = self.db.acquire()
+            .await
+            .or_else(|err| Err(MigrationsError::migration_database_failed(None, Some(err.into()))))?;
+        *self.conn.lock().await = Some(conn);
+        let result = flyway::execute_statements(self, changelog_file, None).await;
+        *self.conn.lock().await = None;
+        return result;
+    }
+
+    async fn finish_version_in_transaction(&self, changelog_file: &ChangelogFile) -> flyway::Result<()> {
+        log::debug!("Finishing version in transaction ... {}", changelog_file.version);
+        let ts: i64 = self.clock.now_millis();
+        let row = RowValues::new(changelog_file, STATUS_DEPLOYED, ts, format_applied_at(ts))?;
+        check_column_lengths(&row)?;
+        let dialect = self.dialect()?;
+        let mut tx_guard = self.tx.lock().await;
+        let tx = match tx_guard.as_mut() {
+            Some(tx) => tx,
+            None => return Err(MigrationsError::migration_database_failed(None, None)),
+        };
+
+        if let Some((upsert_statement, params)) = upsert_version(dialect, self.migrations_table_name.as_str(), &row) {
+            log::debug!("Upsert statement: {}", upsert_statement.as_str());
+            let _upsert_result = tx.exec(upsert_statement.as_str(), params)
+                .await
+                .or_else(|err| Err(MigrationsError::migration_versioning_failed(Some(err.into()))))?;
+            return Ok(());
+        }
+
+        let (update_statement, params) = dialect.update_status(self.migrations_table_name.as_str(), &row);
+        log::debug!("Update statement: {}", update_statement.as_str());
+        let update_result = tx.exec(update_statement.as_str(), params)
+            .await
+            .or_else(|err| Err(MigrationsError::migration_versioning_failed(Some(err.into()))))?;
+
+        if update_result.rows_affected < 1 {
+            let (insert_statement, params) = dialect.insert_version(self.migrations_table_name.as_str(), &row);
+            log::debug!("Insert statement: {}", insert_statement.as_str());
+            let _insert_result = tx.exec(insert_statement.as_str(), params)
+                .await
+                .or_else(|err| Err(MigrationsError::migration_versioning_failed(Some(err.into()))))?;
+        }
         return Ok(());
     }
 
     async fn commit_transaction(&self) -> flyway::Result<()> {
         log::debug!("Committing transaction ...");
+        if self.external_tx {
+            log::debug!("The transaction provided by the caller is committed by the caller");
+            return Ok(());
+        }
+        if !self.dialect()?.supports_transactions() {
+            return Ok(());
+        }
         let mut tx = {
-            let tx_guard = self.tx.lock().await;
-            tx_guard.replace(None)
+            let mut tx_guard = self.tx.lock().await;
+            tx_guard.take()
         };
 
         match tx.as_mut() {
@@ -528,9 +1111,17 @@ impl MigrationExecutor for RbatisMigrationDriver {
 
     async fn rollback_transaction(&self) -> flyway::Result<()> {
         log::debug!("Rolling back transaction ...");
+        if self.external_tx {
+            log::warn!("The transaction provided by the caller must be rolled back by the caller");
+            return Ok(());
+        }
+        if !self.dialect()?.supports_transactions() {
+            log::warn!("Transactions are not supported, nothing is rolled back");
+            return Ok(());
+        }
         let mut tx = {
-            let tx_guard = self.tx.lock().await;
-            tx_guard.replace(None)
+            let mut tx_guard = self.tx.lock().await;
+            tx_guard.take()
         };
 
         match tx.as_mut() {
@@ -540,8 +1131,315 @@ impl MigrationExecutor for RbatisMigrationDriver {
                     .or_else(|err| Err(MigrationsError::migration_database_failed(None, Some(err.into()))));
             }
             None => {
-                return Err(MigrationsError::migration_database_failed(None, None));
+                log::debug!("No transaction is open, nothing is rolled back");
+                return Ok(());
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use rbatis::RBatis;
+    use flyway::ChangelogFile;
+    use rbatis::rbdc::datetime::DateTime;
+    use rbs::Value;
+    use crate::{format_applied_at, next_tdengine_ts, pragma_statement, RbatisDbDriverType, RbatisMigrationDriver, RowValues, VersionRow, APPLIED_AT_COL_LEN, DEFAULT_MIGRATIONS_TABLE,
+                MIGRATION_TYPE_REPEATABLE, STATUS_DEPLOYED, STATUS_IN_PROGRESS};
+
+    #[test]
+    pub fn test_table_name() {
+        let db = Arc::new(RBatis::new());
+        let driver = RbatisMigrationDriver::new(db.clone(), None);
+        assert_eq!(driver.table_name(), DEFAULT_MIGRATIONS_TABLE);
+
+        let driver = RbatisMigrationDriver::new(db, Some("schema_versions"));
+        assert_eq!(driver.table_name(), "schema_versions");
+        assert_eq!(driver.fork().table_name(), "schema_versions");
+    }
+
+    #[test]
+    pub fn test_clock() {
+        let db = Arc::new(RBatis::new());
+        let driver = RbatisMigrationDriver::new(db, None).with_clock(Arc::new(|| 1_700_000_000_000));
+        assert_eq!(driver.clock.now_millis(), 1_700_000_000_000);
+        assert_eq!(driver.fork().clock.now_millis(), 1_700_000_000_000, "Forks share the clock.");
+
+        // A ts read back from TDengine is shifted back to the millis it has been written with
+        let written = DateTime::from_timestamp_millis(driver.clock.now_millis());
+        assert_eq!(driver.tdengine_ts_millis(&written), 1_700_000_000_000);
+        let driver = driver.with_tdengine_offset_seconds(-8 * 60 * 60);
+        let read_back = written.clone().set_offset(8 * 60 * 60);
+        assert_eq!(driver.tdengine_ts_millis(&read_back), 1_700_000_000_000);
+    }
+
+    #[test]
+    pub fn test_sqlite_pragmas() {
+        let db = Arc::new(RBatis::new());
+        let driver = RbatisMigrationDriver::new(db, None);
+        assert!(driver.sqlite_pragmas.is_empty(), "No pragmas must be issued by default.");
+        let driver = driver.with_sqlite_pragmas(vec!["journal_mode=WAL".to_string(), "PRAGMA foreign_keys=ON;".to_string()]);
+        assert_eq!(driver.fork().sqlite_pragmas.len(), 2, "Forks share the pragmas.");
+
+        assert_eq!(pragma_statement("journal_mode=WAL"), "PRAGMA journal_mode=WAL");
+        assert_eq!(pragma_statement(" pragma foreign_keys=ON; "), "pragma foreign_keys=ON");
+        assert_eq!(pragma_statement("pragmatic=1"), "PRAGMA pragmatic=1");
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    pub async fn test_prepare_upgrades_table() {
+        use std::collections::HashMap;
+        use flyway::MigrationStateManager;
+
+        let path = std::env::temp_dir().join(format!("flyway_rbatis_prepare_{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let driver = RbatisMigrationDriver::from_url(format!("sqlite://{}", path.display()).as_str(), None).unwrap();
+        // A table as created by old releases
+        driver.db.exec("CREATE TABLE flyway_migrations (version BIGINT NULL UNIQUE, status VARCHAR(16))", vec![]).await.unwrap();
+        driver.db.exec("INSERT INTO flyway_migrations (version, status) VALUES (1, 'deployed')", vec![]).await.unwrap();
+
+        driver.prepare().await.unwrap();
+        driver.prepare().await.unwrap();
+        let columns: Vec<HashMap<String, String>> = driver.db.query_decode("SELECT name FROM pragma_table_info('flyway_migrations')", vec![])
+            .await
+            .unwrap();
+        let columns: Vec<&str> = columns.iter().filter_map(|column| column.get("name")).map(|name| name.as_str()).collect();
+        for (column, _) in RbatisDbDriverType::Sqlite.dialect().upgradable_columns() {
+            assert!(columns.contains(&column), "Missing column {}: {:?}", column, columns);
+        }
+        assert!(driver.is_deployed(1).await.unwrap(), "Existing rows must be kept.");
+        let untyped: u64 = driver.db.query_decode("SELECT COUNT(*) FROM flyway_migrations WHERE migration_type IS NULL", vec![]).await.unwrap();
+        assert_eq!(untyped, 0, "Existing rows must be typed as versioned.");
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    pub async fn test_is_prepared() {
+        use flyway::MigrationStateManager;
+
+        let path = std::env::temp_dir().join(format!("flyway_rbatis_is_prepared_{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let driver = RbatisMigrationDriver::from_url(format!("sqlite://{}", path.display()).as_str(), None).unwrap();
+        assert!(!driver.is_prepared().await.unwrap());
+        driver.prepare().await.unwrap();
+        assert!(driver.is_prepared().await.unwrap());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    pub async fn test_is_deployed() {
+        use flyway::MigrationStateManager;
+
+        let path = std::env::temp_dir().join(format!("flyway_rbatis_is_deployed_{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let driver = RbatisMigrationDriver::from_url(format!("sqlite://{}", path.display()).as_str(), None).unwrap();
+        driver.prepare().await.unwrap();
+        driver.prepare().await.unwrap();
+        let changelog = ChangelogFile::from_string(1, "users", "CREATE TABLE users(id INT);").unwrap();
+        assert!(!driver.is_deployed(1).await.unwrap());
+
+        driver.begin_version(&changelog).await.unwrap();
+        assert!(!driver.is_deployed(1).await.unwrap(), "A version in progress is not deployed.");
+        driver.finish_version(&changelog).await.unwrap();
+        assert!(driver.is_deployed(1).await.unwrap());
+        assert!(!driver.is_deployed(2).await.unwrap());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    pub async fn test_changelog_runs_on_one_connection() {
+        use flyway::MigrationExecutor;
+
+        let path = std::env::temp_dir().join(format!("flyway_rbatis_one_connection_{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let driver = RbatisMigrationDriver::from_url(format!("sqlite://{}", path.display()).as_str(), None).unwrap();
+        // Temporary tables only exist on the connection that created them
+        let changelog = ChangelogFile::from_string(1, "temp", "CREATE TEMP TABLE staging(id INT);
+            INSERT INTO staging VALUES (1);
+            INSERT INTO staging VALUES (2);
+            DROP TABLE staging;").unwrap();
+        for _ in 0..3 {
+            driver.execute_changelog_file(&changelog).await.unwrap();
+        }
+        assert!(driver.conn.lock().await.is_none(), "The connection must be released after the changelog.");
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    pub async fn test_rollback_without_transaction() {
+        use flyway::MigrationExecutor;
+
+        let path = std::env::temp_dir().join(format!("flyway_rbatis_rollback_{}.db", std::process::id()));
+        let driver = RbatisMigrationDriver::from_url(format!("sqlite://{}", path.display()).as_str(), None).unwrap();
+        assert!(driver.rollback_transaction().await.is_ok(), "Without an open transaction there is nothing to roll back.");
+        driver.begin_transaction().await.unwrap();
+        driver.rollback_transaction().await.unwrap();
+        assert!(driver.tx.lock().await.is_none());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    pub async fn test_server_version() {
+        use flyway::MigrationStateManager;
+
+        let path = std::env::temp_dir().join(format!("flyway_rbatis_server_version_{}.db", std::process::id()));
+        let driver = RbatisMigrationDriver::from_url(format!("sqlite://{}", path.display()).as_str(), None).unwrap();
+        let version = driver.server_version().await.unwrap();
+        assert!(version.as_deref().is_some_and(|version| version.starts_with("3.")), "Unexpected version: {:?}", version);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    pub fn test_upsert_version() {
+        let changelog = ChangelogFile::from_string(3, "users", "CREATE TABLE users(id INT);").unwrap();
+        let row = RowValues::new(&changelog, STATUS_IN_PROGRESS, 1, "2023-11-14T22:13:20.000000Z".to_string()).unwrap();
+        let upsert = |driver_type: RbatisDbDriverType| driver_type.dialect().upsert_version(DEFAULT_MIGRATIONS_TABLE, &row);
+        let (statement, params) = upsert(RbatisDbDriverType::Pg).unwrap();
+        assert!(statement.ends_with(") ON CONFLICT (migration_type, version, name) DO UPDATE SET status=excluded.status, applied_at=excluded.applied_at;"), "{}", statement);
+        assert_eq!(params.len(), 7);
+        assert!(upsert(RbatisDbDriverType::MySql).unwrap().0.contains("ON DUPLICATE KEY UPDATE"));
+        assert!(upsert(RbatisDbDriverType::MsSql).unwrap().0.starts_with("MERGE"));
+        assert!(upsert(RbatisDbDriverType::Oracle).is_none(), "Dialects without upsert must update, then insert.");
+
+        // Repeatable rows are keyed by type and name, with a NULL version
+        let repeatable = RowValues::repeatable(&changelog, STATUS_IN_PROGRESS, 1, "2023-11-14T22:13:20.000000Z".to_string()).unwrap();
+        assert!(crate::upsert_version(RbatisDbDriverType::Pg.dialect(), DEFAULT_MIGRATIONS_TABLE, &repeatable).is_none());
+        let (statement, params) = RbatisDbDriverType::Pg.dialect().update_status(DEFAULT_MIGRATIONS_TABLE, &repeatable);
+        assert_eq!(statement, "UPDATE flyway_migrations SET status=?, applied_at=? where migration_type=? and version IS NULL and name=?;");
+        assert_eq!(params[2], Value::String(MIGRATION_TYPE_REPEATABLE.to_string()));
+        let (_, params) = RbatisDbDriverType::Pg.dialect().insert_version(DEFAULT_MIGRATIONS_TABLE, &repeatable);
+        assert_eq!(params[2], Value::Null);
+        assert_eq!(params[3], Value::String(MIGRATION_TYPE_REPEATABLE.to_string()));
+    }
+
+    #[test]
+    pub fn test_applied_at() {
+        assert_eq!(format_applied_at(1_700_000_000_123), "2023-11-14T22:13:20.123000Z");
+        assert!(format_applied_at(1_700_000_000_123).len() <= APPLIED_AT_COL_LEN);
+
+        let row = |applied_at: Option<&str>| VersionRow {
+            ts: Value::String("1700000000003".to_string()),
+            applied_at: applied_at.map(|applied_at| applied_at.to_string()),
+            version: 3,
+            name: None,
+            checksum: None,
+            status: Some(STATUS_DEPLOYED.to_string()),
+        };
+        assert_eq!(row(Some("2023-11-14T22:13:20.000000Z")).into_state().applied_at.as_deref(), Some("2023-11-14T22:13:20.000000Z"));
+        assert_eq!(row(None).into_state().applied_at.as_deref(), Some("1700000000003"), "Old rows fall back to ts.");
+    }
+
+    #[test]
+    pub fn test_written_ts() {
+        // The ts is the time of the clock, without any offset
+        let changelog = ChangelogFile::from_string(3, "users", "CREATE TABLE users(id INT);").unwrap();
+        let row = RowValues::new(&changelog, STATUS_DEPLOYED, 1_700_000_000_000, "2023-11-14T22:13:20.000000Z".to_string()).unwrap();
+        let (_, params) = RbatisDbDriverType::Pg.dialect().insert_version(DEFAULT_MIGRATIONS_TABLE, &row);
+        assert_eq!(params[0], Value::I64(1_700_000_000_000));
+
+        // TDengine only moves the ts if another row has it already
+        assert_eq!(next_tdengine_ts(1_700_000_000_000, None), 1_700_000_000_000);
+        assert_eq!(next_tdengine_ts(1_700_000_000_000, Some(1_699_999_999_000)), 1_700_000_000_000);
+        assert_eq!(next_tdengine_ts(1_700_000_000_000, Some(1_700_000_000_000)), 1_700_000_000_001);
+        assert_eq!(next_tdengine_ts(1_700_000_000_000, Some(1_700_000_000_005)), 1_700_000_000_006);
+    }
+
+    #[test]
+    pub fn test_version_column_is_64_bit() {
+        for driver_type in [RbatisDbDriverType::MySql, RbatisDbDriverType::Pg, RbatisDbDriverType::MsSql, RbatisDbDriverType::TDengine] {
+            let statement = driver_type.dialect().create_table(DEFAULT_MIGRATIONS_TABLE);
+            assert!(statement.to_lowercase().contains("version bigint") || statement.contains("`version` bigint"), "{}", statement);
+        }
+        let widen = |driver_type: RbatisDbDriverType| driver_type.dialect().widen_version_column(DEFAULT_MIGRATIONS_TABLE);
+        assert_eq!(widen(RbatisDbDriverType::Pg).unwrap(), "ALTER TABLE flyway_migrations ALTER COLUMN version TYPE BIGINT;");
+        assert!(widen(RbatisDbDriverType::MySql).is_some());
+        assert!(widen(RbatisDbDriverType::Sqlite).is_none(), "SQLite integers are 64 bit already.");
+    }
+
+    #[test]
+    pub fn test_status_index() {
+        let index = |driver_type: RbatisDbDriverType| driver_type.dialect().create_status_index(DEFAULT_MIGRATIONS_TABLE);
+        assert_eq!(index(RbatisDbDriverType::Pg).unwrap(),
+                   "CREATE INDEX IF NOT EXISTS flyway_migrations_status ON flyway_migrations (status, version);");
+        for driver_type in [RbatisDbDriverType::MySql, RbatisDbDriverType::MsSql, RbatisDbDriverType::Oracle] {
+            assert!(index(driver_type).unwrap().contains("flyway_migrations_status ON flyway_migrations (status, version)"));
+        }
+        assert!(index(RbatisDbDriverType::TDengine).is_none(), "TDengine has no secondary indexes.");
+        assert!(RbatisDbDriverType::MySql.dialect().list_indexes(DEFAULT_MIGRATIONS_TABLE).is_some(),
+                "MySQL has no CREATE INDEX IF NOT EXISTS.");
+        assert!(RbatisDbDriverType::Sqlite.dialect().list_indexes(DEFAULT_MIGRATIONS_TABLE).is_none());
+    }
+
+    #[test]
+    pub fn test_key_index() {
+        let index = |driver_type: RbatisDbDriverType| driver_type.dialect().create_key_index(DEFAULT_MIGRATIONS_TABLE);
+        assert_eq!(index(RbatisDbDriverType::Pg).unwrap(),
+                   "CREATE UNIQUE INDEX IF NOT EXISTS flyway_migrations_key ON flyway_migrations (migration_type, version, name);");
+        for driver_type in [RbatisDbDriverType::MySql, RbatisDbDriverType::MsSql, RbatisDbDriverType::Oracle] {
+            assert!(index(driver_type).unwrap().contains("UNIQUE INDEX flyway_migrations_key ON flyway_migrations (migration_type, version, name)"));
+            assert!(driver_type.dialect().create_table(DEFAULT_MIGRATIONS_TABLE).contains("PRIMARY KEY"));
+        }
+        assert!(index(RbatisDbDriverType::TDengine).is_none(), "TDengine has no unique indexes.");
+        assert!(RbatisDbDriverType::TDengine.dialect().fill_migration_type(DEFAULT_MIGRATIONS_TABLE).is_none(), "TDengine cannot update rows.");
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    pub async fn test_repeatable_row() {
+        use flyway::MigrationStateManager;
+
+        let path = std::env::temp_dir().join(format!("flyway_rbatis_repeatable_{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let driver = RbatisMigrationDriver::from_url(format!("sqlite://{}", path.display()).as_str(), None).unwrap();
+        driver.prepare().await.unwrap();
+        let changelog = ChangelogFile::from_string(1, "views", "CREATE VIEW active_users AS SELECT 1;").unwrap();
+        driver.begin_version(&changelog).await.unwrap();
+        driver.finish_version(&changelog).await.unwrap();
+
+        // A repeatable migration with the same name is stored next to the versioned one
+        let repeatable = |status: &str| RowValues::repeatable(&changelog, status, 2, format_applied_at(2)).unwrap();
+        driver.write_row(repeatable(STATUS_IN_PROGRESS)).await.unwrap();
+        driver.write_row(repeatable(STATUS_DEPLOYED)).await.unwrap();
+        let count = |condition: &str| {
+            let statement = format!("SELECT COUNT(*) FROM flyway_migrations WHERE {}", condition);
+            let db = driver.db.clone();
+            async move { db.query_decode::<u64>(statement.as_str(), vec![]).await.unwrap() }
+        };
+        assert_eq!(count("1=1").await, 2);
+        assert_eq!(count("migration_type='repeatable' AND version IS NULL AND name='views' AND status='deployed'").await, 1);
+        assert_eq!(count("migration_type='versioned' AND version=1 AND status='deployed'").await, 1);
+        assert_eq!(driver.list_versions().await.unwrap().len(), 1, "Repeatable rows have no version.");
+
+        // The key keeps a versioned row from being inserted twice
+        let row = RowValues::new(&changelog, STATUS_DEPLOYED, 3, format_applied_at(3)).unwrap();
+        let (statement, params) = RbatisDbDriverType::Sqlite.dialect().insert_version(DEFAULT_MIGRATIONS_TABLE, &row);
+        assert!(driver.db.exec(statement.as_str(), params).await.is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    pub async fn test_status_index_is_created() {
+        use std::collections::HashMap;
+        use flyway::MigrationStateManager;
+
+        let path = std::env::temp_dir().join(format!("flyway_rbatis_status_index_{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let driver = RbatisMigrationDriver::from_url(format!("sqlite://{}", path.display()).as_str(), None).unwrap();
+        driver.prepare().await.unwrap();
+        driver.prepare().await.unwrap();
+        let indexes: Vec<HashMap<String, String>> = driver.db.query_decode("SELECT name FROM pragma_index_list('flyway_migrations')", vec![])
+            .await
+            .unwrap();
+        assert!(indexes.iter().any(|index| index.get("name").is_some_and(|name| name == "flyway_migrations_status")),
+                "Missing index: {:?}", indexes);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,33 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use flyway_sql_changelog::split_sql;
+
+/// A migration with many small statements, comments and quoted values
+fn large_migration() -> String {
+    let mut sql = String::from("-- large data migration\nCREATE TABLE users(id INT PRIMARY KEY, name VARCHAR(255), note TEXT);\n");
+    for i in 0..20_000 {
+        sql.push_str(format!("-- user {}\nINSERT INTO users(id, name, note) VALUES ({}, 'user-{}', \"it's; quoted\");\n", i, i, i).as_str());
+    }
+    return sql;
+}
+
+/// A migration with a single, very long comment
+fn long_comment() -> String {
+    let mut sql = String::from("-- ");
+    for _ in 0..10_000 {
+        sql.push_str("lorem ipsum ");
+    }
+    sql.push_str("\nSELECT 1;\n");
+    return sql;
+}
+
+fn bench_split_sql(c: &mut Criterion) {
+    let mut group = c.benchmark_group("split_sql");
+    for (name, sql) in [("large_migration", large_migration()), ("long_comment", long_comment())] {
+        group.throughput(Throughput::Bytes(sql.len() as u64));
+        group.bench_function(name, |b| b.iter(|| split_sql(black_box(sql.as_str())).count()));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_split_sql);
+criterion_main!(benches);
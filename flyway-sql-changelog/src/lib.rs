@@ -1,14 +1,19 @@
-use std::path::{Path};
-use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::io::{BufRead, BufReader, Read};
 use std::string::FromUtf8Error;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
 use std::cmp::Ordering;
+use std::collections::HashMap;
 
-use serde::{ Deserialize, Serialize };
+use serde::{ Deserialize, Deserializer, Serialize, Serializer };
+use serde::de::DeserializeOwned;
 use std::error::Error;
 use std::fmt::{Display, Formatter};
 use std::hash::{Hash, Hasher};
 use siphasher::sip128::SipHasher13;
+use sha2::Digest;
+#[cfg(feature = "encoding")]
+pub use encoding_rs;
 
 const SINGLE_QUOTE1: u8 = '\'' as u8;
 const SINGLE_QUOTE2: u8 = '`' as u8;
@@ -19,6 +24,12 @@ const BACKSLASH: u8 = '\\' as u8;
 const MINUS: u8 = '-' as u8;
 const LINEFEED: u8 = '\n' as u8;
 
+/// Default prefix of statement annotation lines
+pub const DEFAULT_ANNOTATION_PREFIX: &str = "--! ";
+
+/// Number of bytes read at once when the content is streamed
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
 /// Kinds of errors that can occur when processing a `ChangelogFile`
 #[derive(Debug)]
 pub enum ChangelogErrorKind {
@@ -28,6 +39,14 @@ pub enum ChangelogErrorKind {
     /// max_version, requested_max_version
     MaxVersionNotFound(String, String),
     IoError(std::io::Error),
+    /// name of the environment variable referenced by a statement parameter
+    MissingParameter(String),
+    /// name of a placeholder without a value
+    MissingPlaceholder(String),
+    /// description of why the SQL cannot be split into statements
+    InvalidSql(String),
+    /// path of a changelog file that is not valid UTF-8
+    InvalidEncoding(PathBuf),
     Other(Box<dyn std::error::Error + Send + Sync>),
 }
 
@@ -38,12 +57,17 @@ pub struct ChangelogError {
 }
 
 impl ChangelogError {
-    pub fn emtpy_change_log() -> ChangelogError {
+    pub fn empty_changelog() -> ChangelogError {
         return ChangelogError {
             kind: ChangelogErrorKind::EmptyChangelog,
         };
     }
 
+    #[deprecated(note = "use `ChangelogError::empty_changelog` instead")]
+    pub fn emtpy_change_log() -> ChangelogError {
+        return ChangelogError::empty_changelog();
+    }
+
     pub fn min_version_not_found(actual_min_version: &str, requested_min_version: &str) -> ChangelogError {
         return ChangelogError {
             kind: ChangelogErrorKind::MinVersionNotFound(actual_min_version.to_string(), requested_min_version.to_string()),
@@ -62,6 +86,30 @@ impl ChangelogError {
         };
     }
 
+    pub fn missing_parameter(name: &str) -> ChangelogError {
+        return ChangelogError {
+            kind: ChangelogErrorKind::MissingParameter(name.to_string()),
+        };
+    }
+
+    pub fn missing_placeholder(name: &str) -> ChangelogError {
+        return ChangelogError {
+            kind: ChangelogErrorKind::MissingPlaceholder(name.to_string()),
+        };
+    }
+
+    pub fn invalid_sql(message: &str) -> ChangelogError {
+        return ChangelogError {
+            kind: ChangelogErrorKind::InvalidSql(message.to_string()),
+        };
+    }
+
+    pub fn invalid_encoding(path: &Path) -> ChangelogError {
+        return ChangelogError {
+            kind: ChangelogErrorKind::InvalidEncoding(path.to_path_buf()),
+        };
+    }
+
     pub fn other(other_error: Box<dyn std::error::Error + Send + Sync>) -> ChangelogError {
         return ChangelogError {
             kind: ChangelogErrorKind::Other(other_error),
@@ -71,6 +119,43 @@ impl ChangelogError {
     pub fn kind(&self) -> &ChangelogErrorKind {
         &self.kind
     }
+
+    /// Create a cloneable and serializable snapshot of this error
+    pub fn to_info(&self) -> ChangelogErrorInfo {
+        return ChangelogErrorInfo {
+            kind: self.kind.name().to_string(),
+            message: self.to_string(),
+        };
+    }
+}
+
+impl ChangelogErrorKind {
+    /// The name of the kind, e.g. `"EmptyChangelog"`
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChangelogErrorKind::EmptyChangelog => "EmptyChangelog",
+            ChangelogErrorKind::MinVersionNotFound(_, _) => "MinVersionNotFound",
+            ChangelogErrorKind::MaxVersionNotFound(_, _) => "MaxVersionNotFound",
+            ChangelogErrorKind::IoError(_) => "IoError",
+            ChangelogErrorKind::MissingParameter(_) => "MissingParameter",
+            ChangelogErrorKind::MissingPlaceholder(_) => "MissingPlaceholder",
+            ChangelogErrorKind::InvalidSql(_) => "InvalidSql",
+            ChangelogErrorKind::InvalidEncoding(_) => "InvalidEncoding",
+            ChangelogErrorKind::Other(_) => "Other",
+        }
+    }
+}
+
+/// A snapshot of a `ChangelogError`
+///
+/// Unlike `ChangelogError`, this can be cloned and serialized, e.g. for caching errors or
+/// returning them from an API.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangelogErrorInfo {
+    /// The name of the error kind, see `ChangelogErrorKind::name`
+    pub kind: String,
+    /// The error message
+    pub message: String,
 }
 
 impl From<std::io::Error> for ChangelogError {
@@ -94,6 +179,18 @@ impl Display for ChangelogError {
             ChangelogErrorKind::IoError(io_error) => {
                 return io_error.fmt(fmt);
             }
+            ChangelogErrorKind::MissingParameter(name) => {
+                return write!(fmt, "Environment variable {} referenced by a statement parameter is not set.", name);
+            }
+            ChangelogErrorKind::MissingPlaceholder(name) => {
+                return write!(fmt, "No value for placeholder {}.", name);
+            }
+            ChangelogErrorKind::InvalidSql(message) => {
+                return write!(fmt, "Invalid SQL: {}.", message);
+            }
+            ChangelogErrorKind::InvalidEncoding(path) => {
+                return write!(fmt, "Changelog file {} is not valid UTF-8. Re-save it with UTF-8 encoding.", path.display());
+            }
             ChangelogErrorKind::Other(other_error) => {
                 return other_error.fmt(fmt);
             }
@@ -124,11 +221,88 @@ pub struct ChangelogFile {
     pub version: u64,
     /// The name ChangelogFile
     pub name:String,
-    /// The checksum
+    /// The checksum, `0` if the content is loaded lazily
+    #[deprecated(note = "use `checksum()`, which also works for changelogs loaded lazily")]
     pub checksum: u64,
-
-    /// The full code of this `ChangelogFile`
+    /// The full code, empty if the content is loaded lazily or streamed
+    #[deprecated(note = "use `load()` or `content()`, which also work for changelogs loaded lazily")]
     pub content: Arc<String>,
+    /// The checksum, computed when it is accessed for the first time
+    computed_checksum: OnceLock<u64>,
+    /// The algorithm of the checksum recorded for this `ChangelogFile`, see `recorded_checksum()`
+    checksum_algorithm: Option<Arc<dyn ChecksumAlgorithm>>,
+    /// The file annotation, parsed when it is accessed for the first time
+    annotation: OnceLock<Option<ChangelogAnnotation>>,
+
+    /// The source of the full code of this `ChangelogFile`
+    source: ChangelogContent,
+}
+
+/// The source of the content of a `ChangelogFile`
+#[derive(Debug, Clone)]
+pub enum ChangelogContent {
+    /// The content is held in memory
+    Loaded(Arc<String>),
+    /// The content is read from a file when it is accessed for the first time
+    ///
+    /// Clones of a `ChangelogFile` share the loaded content.
+    Lazy(Arc<LazyContent>),
+    /// The statements are read from a file in chunks whenever they are iterated
+    ///
+    /// Only the checksum and the file annotation are kept in memory. Accessing the whole text,
+    /// e.g. by `load()`, still loads it like `Lazy` does.
+    Streamed(Arc<StreamedContent>),
+}
+
+/// Content of a `ChangelogFile` that is read from a file on first access
+#[derive(Debug)]
+pub struct LazyContent {
+    /// The file containing the content
+    path: PathBuf,
+    /// The content, once it has been read
+    content: OnceLock<Arc<String>>,
+}
+
+impl LazyContent {
+    /// Get the path of the file containing the content
+    pub fn path(&self) -> &Path {
+        return self.path.as_path();
+    }
+
+    /// Get the content, reading it from the file if necessary
+    fn load(&self) -> Result<&Arc<String>> {
+        if let Some(content) = self.content.get() {
+            return Ok(content);
+        }
+        let content = read_utf8_file(self.path.as_path())?;
+        let _ = self.content.set(Arc::new(content));
+        return Ok(self.content.get().unwrap());
+    }
+}
+
+/// Content of a `ChangelogFile` whose statements are streamed from a file
+///
+/// The file annotation is read when the `ChangelogFile` is created.
+#[derive(Debug)]
+pub struct StreamedContent {
+    /// The file containing the content, with the content once it has been loaded as a whole
+    file: LazyContent,
+}
+
+impl StreamedContent {
+    /// Get the path of the file containing the content
+    pub fn path(&self) -> &Path {
+        return self.file.path();
+    }
+}
+
+/// Serialized form of a `ChangelogFile`
+#[derive(Serialize, Deserialize)]
+struct SerializedChangelogFile {
+    version: u64,
+    name: String,
+    checksum: u64,
+    content: String,
 }
 
 /// Internal state of the `SqlStatementIterator`
@@ -156,10 +330,184 @@ enum SqlStatementIteratorState {
 /// Changelog files support annotating SQL statements so special error- and transaction-handling
 /// may be applied to the statement. Support for those annotations is not guaranteed by
 /// driver implementations.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+///
+/// Keys this version does not know, e.g. keys written for a newer version, are kept in
+/// `extra()` and otherwise ignored. Annotations are only parsed with the `annotations` feature
+/// (enabled by default), statements have no annotation without it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct SqlStatementAnnotation {
     /// Continue the migration if the annotated statement fails
     may_fail: Option<bool>,
+
+    /// Execute the statement outside of the transaction of its changelog if `false`, see
+    /// `ChangelogFile::split_into_transactions`
+    transactional: Option<bool>,
+
+    /// Values bound to the `?` placeholders of the statement, in order
+    ///
+    /// Every value is bound as a string. A value of the exact form `${NAME}` is replaced by the
+    /// value of the environment variable `NAME` when the statement is executed, and it is an
+    /// error if that variable is not set. All other values are bound as they are. Values are
+    /// never spliced into the SQL text. Inside a YAML flow sequence, `${NAME}` must be quoted:
+    ///
+    /// ```sql
+    /// --! params: ["${ADMIN_EMAIL}", admin]
+    /// INSERT INTO users(email, role) VALUES (?, ?);
+    /// ```
+    params: Option<Vec<String>>,
+
+    /// Unknown keys
+    #[cfg(feature = "annotations")]
+    #[serde(flatten)]
+    extra: HashMap<String, serde_yaml::Value>,
+}
+
+impl SqlStatementAnnotation {
+    /// Whether the migration continues if the annotated statement fails
+    pub fn may_fail(&self) -> bool {
+        return self.may_fail.unwrap_or(false);
+    }
+
+    /// Whether the statement is executed inside the transaction of its changelog (default: `true`)
+    pub fn transactional(&self) -> bool {
+        return self.transactional.unwrap_or(true);
+    }
+
+    /// The raw parameter values, before environment variables are substituted
+    pub fn params(&self) -> &[String] {
+        return self.params.as_deref().unwrap_or(&[]);
+    }
+
+    /// The keys of the annotation not known to this version, with their values
+    #[cfg(feature = "annotations")]
+    pub fn extra(&self) -> &HashMap<String, serde_yaml::Value> {
+        return &self.extra;
+    }
+}
+
+/// The annotation of a whole changelog file
+///
+/// It is written as `--!! ` lines before the first statement of the file:
+///
+/// ```sql
+/// --!! transactional: false
+/// CREATE INDEX CONCURRENTLY idx_users_email ON users(email);
+/// ```
+///
+/// File annotations apply to the file as a whole and statement annotations (`--! `) to their
+/// statement only. The keys of both do not overlap, so neither overrides the other. Statement
+/// keys in a file annotation are kept in `extra()` and otherwise ignored. Like statement
+/// annotations, file annotations are ignored without the `annotations` feature.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChangelogAnnotation {
+    /// Execute the file inside a transaction
+    transactional: Option<bool>,
+
+    /// Keep `--` comments in the statements sent to the database
+    preserve_comments: Option<bool>,
+
+    /// The database versions the file needs, e.g. `>=14`
+    requires_db: Option<String>,
+
+    /// Unknown keys
+    #[cfg(feature = "annotations")]
+    #[serde(flatten)]
+    extra: HashMap<String, serde_yaml::Value>,
+}
+
+impl ChangelogAnnotation {
+    /// Whether the file is executed inside a transaction (default: `true`)
+    pub fn transactional(&self) -> bool {
+        return self.transactional.unwrap_or(true);
+    }
+
+    /// Whether `--` comments are kept in the statements (default: `false`)
+    pub fn preserve_comments(&self) -> bool {
+        return self.preserve_comments.unwrap_or(false);
+    }
+
+    /// The database versions the file needs as semver requirement, e.g. `>=14` (default: any)
+    pub fn requires_db(&self) -> Option<&str> {
+        return self.requires_db.as_deref();
+    }
+
+    /// The keys of the annotation not known to this version, with their values
+    #[cfg(feature = "annotations")]
+    pub fn extra(&self) -> &HashMap<String, serde_yaml::Value> {
+        return &self.extra;
+    }
+}
+
+/// Parse the `--!! ` lines at the top of a changelog file
+fn parse_file_annotation(content: &str) -> Option<ChangelogAnnotation> {
+    let mut annotation = String::new();
+    for line in content.lines() {
+        let line = line.trim_start();
+        if line.is_empty() {
+            continue;
+        }
+        match line.strip_prefix("--!! ") {
+            Some(value) => {
+                annotation.push_str(value);
+                annotation.push('\n');
+            },
+            None => break,
+        }
+    }
+    if annotation.is_empty() {
+        return None;
+    }
+    return parse_annotation::<ChangelogAnnotation>(annotation.as_bytes())
+        .or_else(|err| {
+            log::warn!("Ignoring invalid file annotation: {}", err);
+            return Err(err);
+        })
+        .ok()
+        .flatten();
+}
+
+/// Parse the YAML of an annotation
+///
+/// Returns `None` without the `annotations` feature.
+#[cfg(feature = "annotations")]
+fn parse_annotation<T: DeserializeOwned>(yaml: &[u8]) -> std::result::Result<Option<T>, String> {
+    return serde_yaml::from_slice::<T>(yaml)
+        .map(Some)
+        .or_else(|err| Err(err.to_string()));
+}
+
+/// Parse the YAML of an annotation
+///
+/// Returns `None` without the `annotations` feature.
+#[cfg(not(feature = "annotations"))]
+fn parse_annotation<T: DeserializeOwned>(_yaml: &[u8]) -> std::result::Result<Option<T>, String> {
+    return Ok(None);
+}
+
+/// Read the file annotation from the lines at the top of `reader`, see `parse_file_annotation`
+fn read_file_annotation<R: BufRead>(reader: R) -> Result<Option<ChangelogAnnotation>> {
+    let mut header = String::new();
+    for line in reader.lines() {
+        let line = line?;
+        let trimmed = line.trim_start();
+        if !trimmed.is_empty() && !trimmed.starts_with("--!! ") {
+            break;
+        }
+        header.push_str(line.as_str());
+        header.push('\n');
+    }
+    return Ok(parse_file_annotation(header.as_str()));
+}
+
+/// Statements of a changelog grouped by transaction, see `ChangelogFile::split_into_transactions`
+#[derive(Debug, Clone)]
+pub enum StatementGroup {
+    /// Consecutive statements sharing one transaction
+    Transactional(Vec<SqlStatement>),
+    /// A statement executed on its own, outside of any transaction
+    Standalone(SqlStatement),
 }
 
 /// A single, optionally annotated, SQL statement
@@ -171,72 +519,439 @@ pub struct SqlStatement {
     pub statement: String,
 }
 
+impl SqlStatement {
+    /// Get the values to bind to the placeholders of the statement
+    ///
+    /// Environment variable references are substituted as described for the `params`
+    /// annotation. Returns an empty list if the statement has no parameters.
+    pub fn params(&self) -> Result<Vec<String>> {
+        let params = match &self.annotation {
+            Some(annotation) => annotation.params(),
+            None => return Ok(Vec::new()),
+        };
+        return params.iter()
+            .map(|param| {
+                match param.strip_prefix("${").and_then(|param| param.strip_suffix('}')) {
+                    Some(name) => std::env::var(name)
+                        .or_else(|_| Err(ChangelogError::missing_parameter(name))),
+                    None => Ok(param.clone()),
+                }
+            })
+            .collect();
+    }
+}
+
+/// A statement as it is executed, see `ChangelogFile::resolved_statements`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResolvedStatement {
+    /// The index of the statement among all statements of the changelog
+    pub index: usize,
+    /// The line the SQL of the statement starts at, counting from 1
+    pub line: usize,
+    /// The byte offset the SQL of the statement starts at
+    pub offset: usize,
+    /// The statement with placeholders replaced and its effective annotation
+    ///
+    /// The annotation is always set. Its `transactional()` is `false` if either the statement or
+    /// the whole file is annotated with `transactional: false`.
+    pub statement: SqlStatement,
+}
+
+/// The content split by a `SqlStatementIterator`
+enum StatementContent<'a> {
+    /// Content shared with e.g. a `ChangelogFile`
+    Shared(Arc<String>),
+    /// Content borrowed from the caller
+    Borrowed(&'a str),
+    /// Content read from a reader, with the chunk read last
+    Reader(Box<dyn Read + Send + 'a>, Vec<u8>),
+}
+
+impl<'a> StatementContent<'a> {
+    /// Get the content, or the current chunk if it is read from a reader
+    fn as_bytes(&self) -> &[u8] {
+        return match self {
+            StatementContent::Shared(content) => content.as_bytes(),
+            StatementContent::Borrowed(content) => content.as_bytes(),
+            StatementContent::Reader(_, chunk) => chunk.as_slice(),
+        };
+    }
+}
+
+impl<'a> std::fmt::Debug for StatementContent<'a> {
+    fn fmt(&self, fmt: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StatementContent::Shared(content) => return fmt.debug_tuple("Shared").field(content).finish(),
+            StatementContent::Borrowed(content) => return fmt.debug_tuple("Borrowed").field(content).finish(),
+            StatementContent::Reader(_, chunk) => return fmt.debug_tuple("Reader").field(&chunk.len()).finish(),
+        }
+    }
+}
+
 /// An iterator for a `ChangelogFile`
-#[derive(Debug, Clone)]
-pub struct SqlStatementIterator {
-    /// `Arc` reference to the content of the changelog
-    content: Arc<String>,
-    /// Current position inside the content
+///
+/// The iterator either shares the content of a `ChangelogFile` or borrows a string, so creating
+/// it never copies the content. Created by `from_reader`, it reads the content in chunks while
+/// iterating instead.
+#[derive(Debug)]
+pub struct SqlStatementIterator<'a> {
+    /// The content of the changelog
+    content: StatementContent<'a>,
+    /// Current position inside the content, or inside the current chunk of a reader
     position: usize,
     /// Current state of the iterator
     state: SqlStatementIteratorState,
+    /// Whether `--` comments are kept in the statements
+    preserve_comments: bool,
+    /// The prefix of statement annotation lines
+    annotation_prefix: String,
+    /// The error of the first statement annotation that could not be parsed
+    annotation_error: Option<String>,
+    /// The error that ended reading the content
+    read_error: Option<ChangelogError>,
 }
 
+// The deprecated fields are still filled for users reading them
+#[allow(deprecated)]
 impl ChangelogFile {
     /// Load `ChangelogFile` from a given path
     pub fn from_path(path: &Path) -> Result<ChangelogFile> {
-        let mut version = 0;
-        let mut name="".to_string();
-        let basename_opt = path.components().last();
-        if let Some(basename) = basename_opt {
-            let basename = basename.as_os_str().to_str().unwrap();
-            let index_opt = basename.find("_");
-            if let Some(index) = index_opt {
-                if index > 0 {
-                    version = (&basename[0..index]).to_string().parse().unwrap_or_default();
-                }
-            }
-        }
+        return read_utf8_file(path)
+            .map(|content| ChangelogFile::from_path_and_content(path, content));
+    }
 
-        return std::fs::read_to_string(path)
-            .map(|content| {
-                let mut hasher = SipHasher13::new();
-                name.hash(&mut hasher);
-                version.hash(&mut hasher);
-                content.hash(&mut hasher);
-                let checksum = hasher.finish();
+    /// Load `ChangelogFile` from a file in the given encoding, e.g. `encoding_rs::WINDOWS_1252`
+    ///
+    /// The content is decoded to UTF-8 when it is read, so `content()` and the checksum are
+    /// always based on UTF-8. Content that is not valid in `encoding` results in an
+    /// `InvalidEncoding` error.
+    #[cfg(feature = "encoding")]
+    pub fn from_path_with_encoding(path: &Path, encoding: &'static encoding_rs::Encoding) -> Result<ChangelogFile> {
+        let bytes = std::fs::read(path)?;
+        let content = encoding.decode_without_bom_handling_and_without_replacement(bytes.as_slice())
+            .ok_or_else(|| ChangelogError::invalid_encoding(path))?;
+        return Ok(ChangelogFile::from_path_and_content(path, content.into_owned()));
+    }
 
-                ChangelogFile {
-                    version,
-                    name,
-                    checksum,
-                    content: Arc::new(content)
-                }
-            }
-            )
-            .or_else(|err| Err(err.into()));
+    /// Create `ChangelogFile` from the content of the file at `path`
+    ///
+    /// Version and name are taken from a filename like `V<version>_<name>.sql`, see
+    /// `parse_migration_stem`. Files not named like this get version `0` and an empty name.
+    fn from_path_and_content(path: &Path, content: String) -> ChangelogFile {
+        let stem = path.file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or_default();
+        let (version, name) = parse_migration_stem(stem.strip_prefix("V").unwrap_or(stem))
+            .map(|(version, name)| (version, name.to_string()))
+            .unwrap_or_default();
+
+        let checksum = compute_checksum(version, name.as_str(), content.as_str());
+        let content = Arc::new(content);
+        return ChangelogFile {
+            version,
+            name,
+            checksum,
+            content: content.clone(),
+            computed_checksum: OnceLock::from(checksum),
+            checksum_algorithm: None,
+            annotation: OnceLock::new(),
+            source: ChangelogContent::Loaded(content)
+        };
     }
 
     /// Create `ChangelogFile` from a version and a string containing the contents
     pub fn from_string(version: u64,name:&str, sql: &str) -> Result<ChangelogFile> {
+        let checksum = compute_checksum(version, name, sql);
 
-        let mut hasher = SipHasher13::new();
-        name.hash(&mut hasher);
-        version.hash(&mut hasher);
-        sql.hash(&mut hasher);
-        let checksum = hasher.finish();
+        return ChangelogFile::from_string_with_checksum(version, name, sql, checksum);
+    }
 
+    /// Create `ChangelogFile` from a string and a checksum computed before, e.g. at compile time
+    ///
+    /// The checksum is not verified, it must have been computed by `ChangelogFile::checksum()`
+    /// for the same version, name and content.
+    pub fn from_string_with_checksum(version: u64, name: &str, sql: &str, checksum: u64) -> Result<ChangelogFile> {
+        let content = Arc::new(sql.to_string());
         return Ok(ChangelogFile {
             version,
             name: name.to_string(),
             checksum,
-            content: Arc::new(sql.to_string())
+            content: content.clone(),
+            computed_checksum: OnceLock::from(checksum),
+            checksum_algorithm: None,
+            annotation: OnceLock::new(),
+            source: ChangelogContent::Loaded(content)
         });
     }
 
+    /// Create a `ChangelogFile` whose content is read from `path` on first access
+    ///
+    /// Nothing is read from the file system here. `load()` and the methods that need the content
+    /// read it on first access and return an error if the file cannot be read. `content()` is
+    /// empty until the content has been loaded.
+    pub fn lazy(version: u64, name: &str, path: &Path) -> ChangelogFile {
+        return ChangelogFile {
+            version,
+            name: name.to_string(),
+            checksum: 0,
+            content: Arc::new(String::new()),
+            computed_checksum: OnceLock::new(),
+            checksum_algorithm: None,
+            annotation: OnceLock::new(),
+            source: ChangelogContent::Lazy(Arc::new(LazyContent {
+                path: path.to_path_buf(),
+                content: OnceLock::new(),
+            })),
+        };
+    }
+
+    /// Create a `ChangelogFile` whose statements are read from `path` while they are iterated
+    ///
+    /// This bounds the memory needed for huge changelogs, e.g. data migrations, as `iter()`
+    /// reads the file in chunks instead of loading it. The file is read once here to compute the
+    /// checksum and the file annotation. Methods that need the whole text (`load()`,
+    /// `with_placeholders()` and serialization) still load it into memory.
+    pub fn streamed(version: u64, name: &str, path: &Path) -> Result<ChangelogFile> {
+        let checksum = compute_checksum_from_reader(version, name, std::fs::File::open(path)?)?;
+        let annotation = read_file_annotation(BufReader::new(std::fs::File::open(path)?))?;
+        return Ok(ChangelogFile {
+            version,
+            name: name.to_string(),
+            checksum,
+            content: Arc::new(String::new()),
+            computed_checksum: OnceLock::from(checksum),
+            checksum_algorithm: None,
+            annotation: OnceLock::from(annotation),
+            source: ChangelogContent::Streamed(Arc::new(StreamedContent {
+                file: LazyContent {
+                    path: path.to_path_buf(),
+                    content: OnceLock::new(),
+                },
+            })),
+        });
+    }
+
+    /// Create a copy of this `ChangelogFile` with its placeholders replaced
+    ///
+    /// See `Placeholders` for the substitution rules. The copy keeps the checksum of this
+    /// changelog, so the checksum does not depend on the environment the placeholders come from.
+    pub fn with_placeholders(&self, placeholders: &Placeholders) -> Result<ChangelogFile> {
+        let content = Arc::new(replace_placeholders(self.load()?, placeholders)?);
+        let checksum = self.checksum()?;
+        return Ok(ChangelogFile {
+            version: self.version,
+            name: self.name.clone(),
+            checksum,
+            content: content.clone(),
+            computed_checksum: OnceLock::from(checksum),
+            checksum_algorithm: self.checksum_algorithm.clone(),
+            annotation: OnceLock::new(),
+            source: ChangelogContent::Loaded(content),
+        });
+    }
+
+    /// Create a copy of this `ChangelogFile` with a checksum of `0`
+    ///
+    /// The recorded checksum of the copy is `0` as well, whatever the checksum algorithm.
+    pub fn without_checksum(&self) -> ChangelogFile {
+        return ChangelogFile {
+            version: self.version,
+            name: self.name.clone(),
+            checksum: 0,
+            content: self.content.clone(),
+            computed_checksum: OnceLock::from(0),
+            checksum_algorithm: None,
+            annotation: self.annotation.clone(),
+            source: self.source.clone(),
+        };
+    }
+
+    /// Create a copy of this `ChangelogFile` whose recorded checksum is computed by `algorithm`
+    pub fn with_checksum_algorithm(&self, algorithm: Arc<dyn ChecksumAlgorithm>) -> ChangelogFile {
+        let mut changelog = self.clone();
+        changelog.checksum_algorithm = Some(algorithm);
+        return changelog;
+    }
+
+    /// Load the content of this `ChangelogFile` if it has not been loaded yet
+    pub fn load(&self) -> Result<&str> {
+        return self.shared_content().map(|content| content.as_str());
+    }
+
+    /// Whether the content is held in memory
+    pub fn is_loaded(&self) -> bool {
+        match &self.source {
+            ChangelogContent::Loaded(_) => return true,
+            ChangelogContent::Lazy(lazy) => return lazy.content.get().is_some(),
+            ChangelogContent::Streamed(streamed) => return streamed.file.content.get().is_some(),
+        }
+    }
+
+    /// Whether the statements are read from a file while they are iterated, see `streamed()`
+    pub fn is_streamed(&self) -> bool {
+        return matches!(self.source, ChangelogContent::Streamed(_));
+    }
+
+    /// Get the source of the content of this `ChangelogFile`
+    pub fn content_source(&self) -> &ChangelogContent {
+        return &self.source;
+    }
+
     /// Create an iterator for the statements of this `ChangelogFile`
-    pub fn iter(&self) -> SqlStatementIterator {
-        return SqlStatementIterator::from_shared_string(self.content.clone());
+    ///
+    /// Comments are kept if the file is annotated with `preserve_comments: true`. Content loaded
+    /// lazily is read first, a streamed changelog is read from its file unless it has been loaded.
+    /// Errors reading the content end the iteration and are returned by `take_error()` of the
+    /// iterator.
+    pub fn iter(&self) -> SqlStatementIterator<'static> {
+        let preserve_comments = self.file_annotation()
+            .ok()
+            .flatten()
+            .map(|annotation| annotation.preserve_comments())
+            .unwrap_or(false);
+        let content = match &self.source {
+            ChangelogContent::Streamed(streamed) if streamed.file.content.get().is_none() => {
+                std::fs::File::open(streamed.path())
+                    .map(SqlStatementIterator::from_reader)
+                    .or_else(|err| Err(ChangelogError::io(err)))
+            },
+            _ => self.shared_content().map(|content| SqlStatementIterator::from_shared_string(content.clone())),
+        };
+        let iterator = match content {
+            Ok(iterator) => iterator,
+            Err(err) => {
+                let mut iterator = SqlStatementIterator::from_str("");
+                iterator.read_error = Some(err);
+                iterator
+            }
+        };
+        return iterator.with_preserve_comments(preserve_comments);
+    }
+
+    /// Create an iterator like `iter`, recognizing statement annotations by `prefix` instead
+    /// of `--! `, see `SqlStatementIterator::with_annotation_prefix`
+    pub fn iter_with_annotation_prefix(&self, prefix: &str) -> SqlStatementIterator<'static> {
+        return self.iter().with_annotation_prefix(prefix);
+    }
+
+    /// Check that the statements of this `ChangelogFile` can be parsed, see `SqlStatementIterator::validate`
+    ///
+    /// The content is loaded if necessary, unless it is streamed.
+    pub fn validate(&self) -> Result<()> {
+        if !self.is_streamed() {
+            self.load()?;
+        }
+        self.iter().validate()?;
+        return Ok(());
+    }
+
+    /// Get the annotated statements of this `ChangelogFile` with their index among all statements
+    ///
+    /// Fails if the content cannot be read.
+    pub fn annotated_statements(&self) -> Result<Vec<(usize, SqlStatement)>> {
+        let mut iterator = self.iter();
+        let annotated = (&mut iterator)
+            .enumerate()
+            .filter(|(_, statement)| statement.annotation.is_some())
+            .collect();
+        return match iterator.take_error() {
+            Some(err) => Err(err),
+            None => Ok(annotated),
+        };
+    }
+
+    /// Get the file annotation, i.e. the `--!! ` lines at the top of the file
+    ///
+    /// The annotation is parsed once and kept, clones of this `ChangelogFile` made afterwards
+    /// share it. Fails if the content is loaded lazily and cannot be read.
+    pub fn file_annotation(&self) -> Result<Option<&ChangelogAnnotation>> {
+        if let Some(annotation) = self.annotation.get() {
+            return Ok(annotation.as_ref());
+        }
+        let annotation = parse_file_annotation(self.shared_content()?.as_str());
+        return Ok(self.annotation.get_or_init(|| annotation).as_ref());
+    }
+
+    /// Whether this `ChangelogFile` is executed inside a transaction, see `ChangelogAnnotation`
+    ///
+    /// Fails if the content is loaded lazily and cannot be read.
+    pub fn is_transactional(&self) -> Result<bool> {
+        return self.file_annotation()
+            .map(|annotation| annotation.map(|annotation| annotation.transactional()).unwrap_or(true));
+    }
+
+    /// Group the statements of this `ChangelogFile` into transactions
+    ///
+    /// Consecutive statements form a `Transactional` group. A statement annotated with
+    /// `transactional: false` is `Standalone` and ends the group before it. If the whole file is
+    /// not transactional (see `is_transactional`), every statement is `Standalone`. The groups
+    /// and the statements inside them keep the order of the file, so executing the groups one
+    /// after the other executes all statements in file order.
+    ///
+    /// Fails if the content is loaded lazily and cannot be read.
+    pub fn split_into_transactions(&self) -> Result<Vec<StatementGroup>> {
+        let transactional = self.is_transactional()?;
+        let mut groups: Vec<StatementGroup> = Vec::new();
+        for statement in self.iter() {
+            let standalone = !transactional || statement.annotation.as_ref()
+                .map(|annotation| !annotation.transactional())
+                .unwrap_or(false);
+            if standalone {
+                groups.push(StatementGroup::Standalone(statement));
+                continue;
+            }
+            match groups.last_mut() {
+                Some(StatementGroup::Transactional(statements)) => statements.push(statement),
+                _ => groups.push(StatementGroup::Transactional(vec![statement])),
+            }
+        }
+        return Ok(groups);
+    }
+
+    /// Get the statements as they are executed, with their position in the file
+    ///
+    /// Placeholders are replaced first if `placeholders` is given, like `with_placeholders`
+    /// does. The annotation of each statement is merged with the file annotation, see
+    /// `ResolvedStatement::statement`. `line` and `offset` point to the first line of SQL after
+    /// the annotation and comment lines of the statement. They refer to the content after
+    /// placeholders have been replaced, which has the lines of the file unless a value spans
+    /// several lines. Nothing is executed, so this is meant for e.g. printing the SQL of a
+    /// migration before running it.
+    pub fn resolved_statements(&self, placeholders: Option<&Placeholders>) -> Result<Vec<ResolvedStatement>> {
+        let content = match placeholders {
+            Some(placeholders) => Arc::new(replace_placeholders(self.load()?, placeholders)?),
+            None => self.shared_content()?.clone(),
+        };
+        let file_annotation = parse_file_annotation(content.as_str());
+        let transactional = file_annotation.as_ref()
+            .map(|annotation| annotation.transactional())
+            .unwrap_or(true);
+        let preserve_comments = file_annotation.as_ref()
+            .map(|annotation| annotation.preserve_comments())
+            .unwrap_or(false);
+
+        let mut iterator = SqlStatementIterator::from_shared_string(content.clone())
+            .with_preserve_comments(preserve_comments);
+        let mut resolved: Vec<ResolvedStatement> = Vec::new();
+        while iterator.has_bytes() {
+            let start = iterator.position;
+            let mut statement = match iterator.next_statement() {
+                Some(statement) => statement,
+                None => continue,
+            };
+            let mut annotation = statement.annotation.take().unwrap_or_default();
+            annotation.transactional = Some(transactional && annotation.transactional());
+            statement.annotation = Some(annotation);
+
+            let offset = sql_offset(content.as_str(), start);
+            resolved.push(ResolvedStatement {
+                index: resolved.len(),
+                line: content[..offset].matches('\n').count() + 1,
+                offset,
+                statement,
+            });
+        }
+        return Ok(resolved);
     }
 
     /// Get the version of this `ChangelogFile`
@@ -244,61 +959,421 @@ impl ChangelogFile {
         return self.version;
     }
 
+    /// Get the checksum of this `ChangelogFile`
+    ///
+    /// Fails if the content is loaded lazily and cannot be read.
+    pub fn checksum(&self) -> Result<u64> {
+        if let Some(checksum) = self.computed_checksum.get() {
+            return Ok(*checksum);
+        }
+        let checksum = compute_checksum(self.version, self.name.as_str(), self.load()?);
+        return Ok(*self.computed_checksum.get_or_init(|| checksum));
+    }
+
+    /// Get the checksum of the content computed by `algorithm`, prefixed with its name
+    ///
+    /// For example `sha256:5f70bf18...`. Fails if the content is loaded lazily and cannot be
+    /// read. Streamed content is loaded as a whole.
+    pub fn checksum_with(&self, algorithm: &dyn ChecksumAlgorithm) -> Result<String> {
+        return Ok(format!("{}:{}", algorithm.name(), algorithm.compute(self.load()?)));
+    }
+
+    /// Get the checksum state managers record for this `ChangelogFile`
+    ///
+    /// This is `checksum_with()` the algorithm set by `with_checksum_algorithm()`, or
+    /// `checksum()` in decimal if no algorithm has been set.
+    pub fn recorded_checksum(&self) -> Result<String> {
+        return match &self.checksum_algorithm {
+            Some(algorithm) => self.checksum_with(algorithm.as_ref()),
+            None => self.checksum().map(|checksum| checksum.to_string()),
+        };
+    }
+
     /// Get the raw text of the `ChangelogFile`
+    ///
+    /// Nothing is read here: content loaded lazily or streamed is empty until `load()` has
+    /// succeeded.
     pub fn content(&self) -> &str {
-        return self.content.as_str();
+        match &self.source {
+            ChangelogContent::Loaded(content) => return content.as_str(),
+            ChangelogContent::Lazy(lazy) => return lazy.content.get().map(|content| content.as_str()).unwrap_or_default(),
+            ChangelogContent::Streamed(streamed) => return streamed.file.content.get().map(|content| content.as_str()).unwrap_or_default(),
+        }
+    }
+
+    /// Get the content, reading it if necessary
+    fn shared_content(&self) -> Result<&Arc<String>> {
+        match &self.source {
+            ChangelogContent::Loaded(content) => return Ok(content),
+            ChangelogContent::Lazy(lazy) => return lazy.load(),
+            ChangelogContent::Streamed(streamed) => return streamed.file.load(),
+        }
+    }
+}
+
+/// Find the first byte of SQL at or after `start`, skipping whitespace and `--` comment lines
+fn sql_offset(content: &str, start: usize) -> usize {
+    let mut offset = start;
+    for line in content[start..].split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        if !trimmed.is_empty() && !trimmed.starts_with("--") {
+            return offset + (line.len() - trimmed.len());
+        }
+        offset += line.len();
+    }
+    return offset;
+}
+
+/// Read a changelog file, failing with an `InvalidEncoding` error if it is not UTF-8
+fn read_utf8_file(path: &Path) -> Result<String> {
+    let bytes = std::fs::read(path)?;
+    return String::from_utf8(bytes)
+        .or_else(|_| Err(ChangelogError::invalid_encoding(path)));
+}
+
+/// An algorithm computing the checksums recorded for changelogs
+///
+/// Recorded checksums are prefixed with the name of their algorithm (see
+/// `ChangelogFile::checksum_with`), so a checksum can be verified even after the algorithm has
+/// been changed.
+pub trait ChecksumAlgorithm: std::fmt::Debug + Send + Sync {
+    /// The name of the algorithm, e.g. `sha256`
+    fn name(&self) -> &str;
+
+    /// Compute the checksum of the content of a changelog
+    fn compute(&self, content: &str) -> String;
+}
+
+/// CRC-32 (IEEE), fast but without collision resistance
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Crc32;
+
+impl ChecksumAlgorithm for Crc32 {
+    fn name(&self) -> &str {
+        return "crc32";
+    }
+
+    fn compute(&self, content: &str) -> String {
+        let mut crc: u32 = 0xffffffff;
+        for byte in content.as_bytes() {
+            crc ^= *byte as u32;
+            for _ in 0..8 {
+                let mask = (crc & 1).wrapping_neg();
+                crc = (crc >> 1) ^ (0xedb88320 & mask);
+            }
+        }
+        return format!("{:08x}", !crc);
+    }
+}
+
+/// SHA-256
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sha256;
+
+impl ChecksumAlgorithm for Sha256 {
+    fn name(&self) -> &str {
+        return "sha256";
+    }
+
+    fn compute(&self, content: &str) -> String {
+        return sha2::Sha256::digest(content.as_bytes())
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+    }
+}
+
+/// Compute the checksum of a changelog from its version, name and content
+///
+/// SipHash processes integers as little-endian on every platform, so the `migrations` macro
+/// computes the same checksums at compile time as the runtime does on the target.
+fn compute_checksum(version: u64, name: &str, content: &str) -> u64 {
+    let mut hasher = SipHasher13::new();
+    name.hash(&mut hasher);
+    version.hash(&mut hasher);
+    content.hash(&mut hasher);
+    return hasher.finish();
+}
+
+/// Compute the checksum like `compute_checksum`, reading the content from `reader` in chunks
+fn compute_checksum_from_reader<R: Read>(version: u64, name: &str, mut reader: R) -> Result<u64> {
+    let mut hasher = SipHasher13::new();
+    name.hash(&mut hasher);
+    version.hash(&mut hasher);
+    // Hashing a `str` writes its bytes followed by `0xff`
+    let mut chunk = vec![0; READ_CHUNK_SIZE];
+    loop {
+        match reader.read(chunk.as_mut_slice()) {
+            Ok(0) => break,
+            Ok(length) => hasher.write(&chunk[..length]),
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+    hasher.write_u8(0xff);
+    return Ok(hasher.finish());
+}
+
+impl Serialize for ChangelogFile {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let content = self.load().map_err(serde::ser::Error::custom)?;
+        return SerializedChangelogFile {
+            version: self.version,
+            name: self.name.clone(),
+            checksum: self.checksum().map_err(serde::ser::Error::custom)?,
+            content: content.to_string(),
+        }.serialize(serializer);
+    }
+}
+
+#[allow(deprecated)]
+impl<'de> Deserialize<'de> for ChangelogFile {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let serialized = SerializedChangelogFile::deserialize(deserializer)?;
+        let content = Arc::new(serialized.content);
+        return Ok(ChangelogFile {
+            version: serialized.version,
+            name: serialized.name,
+            checksum: serialized.checksum,
+            content: content.clone(),
+            computed_checksum: OnceLock::from(serialized.checksum),
+            checksum_algorithm: None,
+            annotation: OnceLock::new(),
+            source: ChangelogContent::Loaded(content),
+        });
+    }
+}
+
+impl PartialEq<Self> for ChangelogFile {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        return self.cmp(other) == Ordering::Equal;
+    }
+}
+
+impl PartialOrd<Self> for ChangelogFile {
+    #[inline]
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        return Some(self.cmp(other));
+    }
+}
+
+impl Eq for ChangelogFile { }
+
+/// `ChangelogFile`s are ordered by their numeric version, the order in which they are deployed
+///
+/// Changelogs of the same version are ordered by name and then by their source, so the order is
+/// total and agrees with `Eq`: content held in memory comes first and is compared by its text,
+/// then lazily loaded and then streamed files, which are compared by their path. Comparing never
+/// reads a file, so the order does not depend on the file system. Changelogs are equal if
+/// version, name and source are equal.
+impl Ord for ChangelogFile {
+    fn cmp(&self, other: &Self) -> Ordering {
+        return self.version.cmp(&other.version)
+            .then_with(|| self.name.cmp(&other.name))
+            .then_with(|| self.source.cmp_without_loading(&other.source));
+    }
+}
+
+impl ChangelogContent {
+    /// Compare sources by their kind, then by the content held in memory or by path
+    fn cmp_without_loading(&self, other: &ChangelogContent) -> Ordering {
+        return match (self, other) {
+            (ChangelogContent::Loaded(content), ChangelogContent::Loaded(other)) => content.cmp(other),
+            (ChangelogContent::Lazy(content), ChangelogContent::Lazy(other)) => content.path().cmp(other.path()),
+            (ChangelogContent::Streamed(content), ChangelogContent::Streamed(other)) => content.path().cmp(other.path()),
+            _ => self.rank().cmp(&other.rank()),
+        };
+    }
+
+    fn rank(&self) -> u8 {
+        return match self {
+            ChangelogContent::Loaded(_) => 0,
+            ChangelogContent::Lazy(_) => 1,
+            ChangelogContent::Streamed(_) => 2,
+        };
+    }
+}
+
+impl<'a> SqlStatementIterator<'a> {
+    /// Create object by reading content from a given path
+    pub fn from_path(path: &Path) -> Result<SqlStatementIterator<'a>> {
+        let text = read_utf8_file(path)?;
+
+        return Ok(Self::from_shared_string(Arc::new(text)));
+    }
+
+    /// Create object by reading content from a given path and validating it right away
+    ///
+    /// Unlike `from_path`, a malformed file (e.g. an unclosed quote or an invalid statement
+    /// annotation) is reported here as an `InvalidSql` error instead of silently losing statements
+    /// while iterating. See `validate`.
+    pub fn from_path_validated(path: &Path) -> Result<SqlStatementIterator<'a>> {
+        let text = Arc::new(read_utf8_file(path)?);
+        Self::from_shared_string(text.clone()).validate()?;
+
+        return Ok(Self::from_shared_string(text));
+    }
+
+    /// Create object borrowing a string, without copying it
+    pub fn from_str(content: &'a str) -> SqlStatementIterator<'a> {
+        return Self::with_content(StatementContent::Borrowed(content));
+    }
+
+    /// Create object from an `Arc<String>`
+    pub fn from_shared_string(content: Arc<String>) -> SqlStatementIterator<'a> {
+        return Self::with_content(StatementContent::Shared(content));
+    }
+
+    /// Create object reading the content from `reader` while iterating
+    ///
+    /// The content is read in chunks, so only the current chunk and statement are held in
+    /// memory. Iteration ends at the first read error, see `take_error()`.
+    pub fn from_reader<R: Read + Send + 'a>(reader: R) -> SqlStatementIterator<'a> {
+        return Self::with_content(StatementContent::Reader(Box::new(reader), Vec::new()));
+    }
+
+    fn with_content(content: StatementContent<'a>) -> SqlStatementIterator<'a> {
+        return SqlStatementIterator {
+            content,
+            position: 0,
+            state: SqlStatementIteratorState::Normal,
+            preserve_comments: false,
+            annotation_prefix: DEFAULT_ANNOTATION_PREFIX.to_string(),
+            annotation_error: None,
+            read_error: None,
+        };
     }
-}
 
-impl PartialEq<Self> for ChangelogFile {
-    #[inline]
-    fn eq(&self, other: &Self) -> bool {
-        return self.version.eq(&other.version) &&
-            self.content.eq(&other.content);
+    /// Keep `--` comments in the statements instead of stripping them (default: `false`)
+    ///
+    /// Comments are kept as whole lines, so that they end before the SQL following them. Annotation
+    /// lines (`--! ` and `--!! `) are never kept. A statement consisting of comments only is
+    /// still skipped.
+    pub fn with_preserve_comments(mut self, preserve_comments: bool) -> SqlStatementIterator<'a> {
+        self.preserve_comments = preserve_comments;
+        return self;
     }
-}
 
-impl PartialOrd<Self> for ChangelogFile {
-    #[inline]
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        return self.version.partial_cmp(&other.version);
+    /// Recognize statement annotation lines by `prefix` (default: `--! `)
+    ///
+    /// E.g. with `-- flyway:`, the line `-- flyway: may_fail: true` annotates the following
+    /// statement. The prefix must start with `--`, as annotations are comments. Lines starting
+    /// with the default prefix are plain comments then.
+    pub fn with_annotation_prefix(mut self, prefix: &str) -> SqlStatementIterator<'a> {
+        self.annotation_prefix = prefix.to_string();
+        return self;
     }
-}
-
-impl Eq for ChangelogFile { }
 
-impl Ord for ChangelogFile {
-    fn cmp(&self, other: &Self) -> Ordering {
-        return self.version.cmp(&other.version);
+    /// Split the remaining content and check that it is well-formed
+    ///
+    /// Fails if the content cannot be read, if a quoted region is still open at the end of the
+    /// content or if a statement annotation is not valid YAML. Returns the number of statements otherwise.
+    pub fn validate(mut self) -> Result<usize> {
+        let count = (&mut self).count();
+        if let Some(err) = self.take_error() {
+            return Err(err);
+        }
+        if let SqlStatementIteratorState::Quoted(_) | SqlStatementIteratorState::Escaped(_) = self.state {
+            return Err(ChangelogError::invalid_sql("quoted region is not closed at the end of the content"));
+        }
+        if let Some(err) = self.annotation_error {
+            return Err(ChangelogError::invalid_sql(format!("invalid statement annotation: {}", err).as_str()));
+        }
+        return Ok(count);
     }
-}
 
-impl SqlStatementIterator {
-    /// Create object by reading content from a given path
-    pub fn from_path(path: &Path) -> Result<SqlStatementIterator> {
-        let mut text = String::new();
-        std::fs::File::open(path)?.read_to_string(&mut text)?;
+    /// Take the error that ended reading the content, e.g. from a reader
+    ///
+    /// The statements returned before are complete, but the content after them is missing.
+    pub fn take_error(&mut self) -> Option<ChangelogError> {
+        return self.read_error.take();
+    }
 
-        return Ok(Self::from_str(text.as_str()));
+    /// Copy the bytes up to the next byte that may change the state in one go
+    ///
+    /// Outside of comments, these are all bytes except quotes, `-`, `;` and `\`. They are added
+    /// to the statement. Inside a comment (once `--` has been read), all bytes up to the next
+    /// line break are added to the comment.
+    fn copy_plain_bytes(&mut self, statement: &mut Vec<u8>) {
+        let bytes = self.content.as_bytes();
+        let start = self.position;
+        let end = match &mut self.state {
+            SqlStatementIteratorState::Comment(_, comment) => {
+                if comment.len() < 2 {
+                    return;
+                }
+                let end = bytes[start..].iter()
+                    .position(|byte| *byte == LINEFEED)
+                    .map_or(bytes.len(), |index| start + index);
+                comment.extend_from_slice(&bytes[start..end]);
+                end
+            },
+            _ => {
+                let end = bytes[start..].iter()
+                    .position(|byte| matches!(*byte, SINGLE_QUOTE1 | SINGLE_QUOTE2 | DOUBLE_QUOTE | SEMICOLON | BACKSLASH | MINUS))
+                    .map_or(bytes.len(), |index| start + index);
+                statement.extend_from_slice(&bytes[start..end]);
+                end
+            },
+        };
+        self.position = end;
     }
 
-    /// Create object from a string
-    pub fn from_str(content: &str) -> SqlStatementIterator {
-        return Self::from_shared_string(Arc::new(content.to_string()));
+    /// Add a byte other than a line break to the current comment
+    ///
+    /// The comment is extended in place. If only a single `-` has been read, there is no comment
+    /// after all, so the `-` is added to the statement and the state from before it is restored.
+    fn push_comment_byte(&mut self, statement: &mut Vec<u8>, byte: u8) {
+        self.state = match std::mem::replace(&mut self.state, SqlStatementIteratorState::Normal) {
+            SqlStatementIteratorState::Comment(prev_state, mut comment) => {
+                if comment.len() < 2 {
+                    statement.append(&mut comment);
+                    *prev_state
+                } else {
+                    comment.push(byte);
+                    SqlStatementIteratorState::Comment(prev_state, comment)
+                }
+            },
+            state => state,
+        };
     }
 
-    /// Create object from an `Arc<String>`
-    pub fn from_shared_string(content: Arc<String>) -> SqlStatementIterator {
-        return SqlStatementIterator {
-            content,
-            position: 0,
-            state: SqlStatementIteratorState::Normal,
+    /// Whether there are bytes left at the current position
+    ///
+    /// When reading from a reader, the next chunk is read once the current one is used up. The
+    /// reader is dropped at its end or at the first read error.
+    fn has_bytes(&mut self) -> bool {
+        if self.position < self.content.as_bytes().len() {
+            return true;
+        }
+        let result = match &mut self.content {
+            StatementContent::Reader(reader, chunk) => {
+                chunk.resize(READ_CHUNK_SIZE, 0);
+                let result = loop {
+                    match reader.read(chunk.as_mut_slice()) {
+                        Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                        result => break result,
+                    }
+                };
+                chunk.truncate(*result.as_ref().unwrap_or(&0));
+                result
+            },
+            _ => return false,
         };
+        self.position = 0;
+        match result {
+            Ok(length) if length > 0 => return true,
+            Ok(_) => {},
+            Err(err) => self.read_error = Some(ChangelogError::io(err)),
+        }
+        self.content = StatementContent::Borrowed("");
+        return false;
     }
 
     /// Get the next byte of the content
     fn next_byte(&mut self) -> Option<u8> {
-        if self.position < self.content.len() {
+        if self.has_bytes() {
             let ch = self.content.as_bytes()[self.position];
             self.position += 1;
             return Some(ch);
@@ -308,47 +1383,65 @@ impl SqlStatementIterator {
     }
 }
 
-impl Iterator for SqlStatementIterator {
+impl<'a> Iterator for SqlStatementIterator<'a> {
     type Item = SqlStatement;
 
     fn next(&mut self) -> Option<Self::Item> {
+        while self.has_bytes() {
+            let statement = self.next_statement();
+            if statement.is_some() {
+                return statement;
+            }
+        }
+
+        return None;
+    }
+}
+
+impl<'a> SqlStatementIterator<'a> {
+    /// Read the statement up to the next separator
+    ///
+    /// Returns `None` if the statement is empty.
+    fn next_statement(&mut self) -> Option<SqlStatement> {
         // println!("READING next statement: position={}, state={:?}", self.position, &self.state);
 
         //let mut len = 0;
         let mut statement: Vec<u8> = Vec::new();
         let mut annotation: Vec<u8> = Vec::new();
 
-        let mut ch = self.next_byte();
-
-        while ch.is_some() {
-            //len += 1;
-            let current_char = ch.unwrap();
-            ch = self.next_byte();
+        loop {
+            self.copy_plain_bytes(&mut statement);
+            let current_char = match self.next_byte() {
+                Some(current_char) => current_char,
+                None => break,
+            };
 
             //println!("ch={}", current_char);
 
             match current_char {
                 LINEFEED => {
-                    match &self.state {
+                    match std::mem::replace(&mut self.state, SqlStatementIteratorState::Normal) {
                         SqlStatementIteratorState::Comment(prev_state, comment) => {
-                            let comment_string: String = String::from_utf8(comment.to_vec())
+                            let comment_string: String = String::from_utf8(comment)
                                 .or_else::<FromUtf8Error, _>(|_: FromUtf8Error| Ok("(non-utf8)".to_string()))
                                 .unwrap();
 
                             let comment_string = comment_string.trim_start();
-                            if comment_string.starts_with("--! ") {
-                                let comment_string = &comment_string[4..comment_string.len()];
+                            if let Some(comment_string) = comment_string.strip_prefix(self.annotation_prefix.as_str()) {
                                 // println!("annotation line: {}", comment_string);
                                 for byte in comment_string.as_bytes() {
                                     annotation.push(*byte);
                                 }
-                            } else {
-                                // println!("SQL comment: {}", comment_string);
+                                annotation.push(LINEFEED);
+                            } else if self.preserve_comments && !comment_string.starts_with("--!! ") {
+                                statement.extend_from_slice(comment_string.as_bytes());
+                                statement.push(LINEFEED);
                             }
-                            self.state = *prev_state.clone();
+                            self.state = *prev_state;
                         },
-                        _ => {
+                        state => {
                             statement.push(current_char);
+                            self.state = state;
                         }
                     }
                 },
@@ -357,11 +1450,10 @@ impl Iterator for SqlStatementIterator {
                         SqlStatementIteratorState::Normal => {
                             self.state = SqlStatementIteratorState::Comment(Box::new(self.state.clone()), "-".to_string().into_bytes());
                         },
-                        SqlStatementIteratorState::Comment(prev_state, comment) => {
-                            self.state = SqlStatementIteratorState::Comment(
-                                prev_state.clone(),
-                                comment.to_vec().into_iter().chain(vec![current_char].into_iter()).collect()
-                            );
+                        SqlStatementIteratorState::Comment(_, _) => {
+                            if let SqlStatementIteratorState::Comment(_, comment) = &mut self.state {
+                                comment.push(current_char);
+                            }
                         },
                         _ => {
                             statement.push(current_char);
@@ -384,17 +1476,8 @@ impl Iterator for SqlStatementIterator {
                                 self.state = SqlStatementIteratorState::Normal;
                             }
                         },
-                        SqlStatementIteratorState::Comment(prev_state, comment) => {
-                            if comment.len() < 2 {
-                                let mut comment_clone = comment.clone();
-                                statement.append(&mut comment_clone);
-                                self.state = *prev_state.clone();
-                            } else {
-                                self.state = SqlStatementIteratorState::Comment(
-                                    prev_state.clone(),
-                                    comment.to_vec().into_iter().chain(vec![current_char].into_iter()).collect()
-                                );
-                            }
+                        SqlStatementIteratorState::Comment(_, _) => {
+                            self.push_comment_byte(&mut statement, current_char);
                         }
                     }
                 },
@@ -414,17 +1497,8 @@ impl Iterator for SqlStatementIterator {
                                 self.state = SqlStatementIteratorState::Normal;
                             }
                         },
-                        SqlStatementIteratorState::Comment(prev_state, comment) => {
-                            if comment.len() < 2 {
-                                let mut comment_clone = comment.clone();
-                                statement.append(&mut comment_clone);
-                                self.state = *prev_state.clone();
-                            } else {
-                                self.state = SqlStatementIteratorState::Comment(
-                                    prev_state.clone(),
-                                    comment.to_vec().into_iter().chain(vec![current_char].into_iter()).collect()
-                                );
-                            }
+                        SqlStatementIteratorState::Comment(_, _) => {
+                            self.push_comment_byte(&mut statement, current_char);
                         }
                     }
                 },
@@ -444,17 +1518,8 @@ impl Iterator for SqlStatementIterator {
                                 self.state = SqlStatementIteratorState::Normal;
                             }
                         },
-                        SqlStatementIteratorState::Comment(prev_state, comment) => {
-                            if comment.len() < 2 {
-                                let mut comment_clone = comment.clone();
-                                statement.append(&mut comment_clone);
-                                self.state = *prev_state.clone();
-                            } else {
-                                self.state = SqlStatementIteratorState::Comment(
-                                    prev_state.clone(),
-                                    comment.to_vec().into_iter().chain(vec![current_char].into_iter()).collect()
-                                );
-                            }
+                        SqlStatementIteratorState::Comment(_, _) => {
+                            self.push_comment_byte(&mut statement, current_char);
                         }
                     }
                 },
@@ -463,19 +1528,11 @@ impl Iterator for SqlStatementIterator {
                         SqlStatementIteratorState::Quoted(_) => {
                             statement.push(current_char);
                         },
-                        SqlStatementIteratorState::Comment(prev_state, comment) => {
-                            if comment.len() < 2 {
-                                let mut comment_clone = comment.clone();
-                                statement.append(&mut comment_clone);
-                                self.state = *prev_state.clone();
-                            } else {
-                                self.state = SqlStatementIteratorState::Comment(
-                                    prev_state.clone(),
-                                    comment.to_vec().into_iter().chain(vec![current_char].into_iter()).collect()
-                                );
-                            }
+                        SqlStatementIteratorState::Comment(_, _) => {
+                            self.push_comment_byte(&mut statement, current_char);
                         },
                         _ => {
+                            // The whitespace after the separator is trimmed from the next statement
                             break;
                         }
                     };
@@ -490,17 +1547,8 @@ impl Iterator for SqlStatementIterator {
                             statement.push(current_char);
                             self.state = SqlStatementIteratorState::Quoted(*q);
                         },
-                        SqlStatementIteratorState::Comment(prev_state, comment) => {
-                            if comment.len() < 2 {
-                                let mut comment_clone = comment.clone();
-                                statement.append(&mut comment_clone);
-                                self.state = *prev_state.clone();
-                            } else {
-                                self.state = SqlStatementIteratorState::Comment(
-                                    prev_state.clone(),
-                                    comment.to_vec().into_iter().chain(vec![current_char].into_iter()).collect()
-                                );
-                            }
+                        SqlStatementIteratorState::Comment(_, _) => {
+                            self.push_comment_byte(&mut statement, current_char);
                         },
                         _ => {
                             statement.push(current_char);
@@ -509,17 +1557,8 @@ impl Iterator for SqlStatementIterator {
                 },
                 _ => {
                     match &self.state {
-                        SqlStatementIteratorState::Comment(prev_state, comment) => {
-                            if comment.len() < 2 {
-                                let mut comment_clone = comment.clone();
-                                statement.append(&mut comment_clone);
-                                self.state = *prev_state.clone();
-                            } else {
-                                self.state = SqlStatementIteratorState::Comment(
-                                    prev_state.clone(),
-                                    comment.to_vec().into_iter().chain(vec![current_char].into_iter()).collect()
-                                );
-                            }
+                        SqlStatementIteratorState::Comment(_, _) => {
+                            self.push_comment_byte(&mut statement, current_char);
                         },
                         _ => {
                             statement.push(current_char);
@@ -543,15 +1582,21 @@ impl Iterator for SqlStatementIterator {
                 .map(|value| value.trim().to_string())
                 .ok()
                 .map_or_else(|| None, |value| {
-                    if value.len() > 0 {
+                    let has_sql = value.lines()
+                        .any(|line| !line.trim().is_empty() && !line.trim_start().starts_with("--"));
+                    if has_sql {
                         // println!("annotation length: {}", annotation.len());
                         let annotation = if annotation.len() > 0 {
-                            serde_yaml::from_slice::<SqlStatementAnnotation>(annotation.as_slice())
+                            parse_annotation::<SqlStatementAnnotation>(annotation.as_slice())
                                 .or_else(|err| {
-                                    // println!("Error parsing annotations: {:?}", err);
+                                    log::warn!("Ignoring invalid statement annotation: {}", err);
+                                    if self.annotation_error.is_none() {
+                                        self.annotation_error = Some(err.clone());
+                                    }
                                     return Err(err);
                                 })
                                 .ok()
+                                .flatten()
                         } else {
                             None
                         };
@@ -572,10 +1617,262 @@ impl Iterator for SqlStatementIterator {
     }
 }
 
+/// Split an SQL script into its statements
+///
+/// This is the general purpose entry point for splitting SQL, independent of `ChangelogFile`.
+/// Statements are separated by `;`. Separators inside quoted regions are ignored, `--` comments
+/// are removed and `--!` annotation comments are attached to the following statement. Empty
+/// statements are skipped. The separator cannot be changed.
+///
+/// Example:
+/// ```
+/// use flyway_sql_changelog::split_sql;
+///
+/// let statements: Vec<String> = split_sql("SELECT 1; SELECT ';';")
+///     .map(|statement| statement.statement)
+///     .collect();
+/// assert_eq!(statements, vec!["SELECT 1", "SELECT ';'"]);
+/// ```
+pub fn split_sql(content: &str) -> SqlStatementIterator<'_> {
+    return SqlStatementIterator::from_str(content);
+}
+
+/// Split a filename like `V<version>_<name>.sql` into version and name
+///
+/// The version may also be split into `_`-separated groups of digits if it is followed by `__`,
+/// e.g. `V20240115_1200__add_users.sql` for timestamp versions. The groups are joined, so this
+/// is version `202401151200` named `add_users`. Returns `None` for all other filenames.
+pub fn parse_migration_filename(filename: &str) -> Option<(u64, &str)> {
+    let stem = filename.strip_prefix("V")?.strip_suffix(".sql")?;
+    return parse_migration_stem(stem).ok();
+}
+
+/// Split the `<version>_<name>` part of a migration filename into version and name
+///
+/// This is `parse_migration_filename` without the `V` prefix and the extension, for callers
+/// accepting other prefixes or extensions. On error, the reason why `stem` is malformed is
+/// returned, e.g. `"missing name"`.
+pub fn parse_migration_stem(stem: &str) -> std::result::Result<(u64, &str), &'static str> {
+    let (version, name) = match split_grouped_version(stem)? {
+        Some((version, name)) => (version, name),
+        None => {
+            let index = stem.find("_").ok_or("missing `_` between version and name")?;
+            (stem[..index].to_string(), &stem[(index + 1)..])
+        }
+    };
+    if version.is_empty() {
+        return Err("missing version");
+    }
+    if !version.chars().all(|ch| ch.is_ascii_digit()) {
+        return Err("version is not a number");
+    }
+    if name.is_empty() {
+        return Err("missing name");
+    }
+    return version.parse::<u64>()
+        .map(|version| (version, name))
+        .or(Err("version is too large"));
+}
+
+/// Split `<digits>_<digits>__<name>` into the joined digits and the name
+///
+/// Groups are only joined if they form a timestamp, i.e. a date `YYYYMMDD` followed by groups of
+/// 2, 4 or 6 digits for the time of day, e.g. `20240115_1200`. Flyway reads `_` in versions as
+/// `.`, so other groups like `1_1` are rejected instead of silently becoming version `11`.
+/// Returns `None` if `stem` has no grouped version.
+fn split_grouped_version(stem: &str) -> std::result::Result<Option<(String, &str)>, &'static str> {
+    let (version, name) = match stem.split_once("__") {
+        Some(split) => split,
+        None => return Ok(None),
+    };
+    let groups: Vec<&str> = version.split("_").collect();
+    let grouped = groups.iter()
+        .all(|group| !group.is_empty() && group.chars().all(|ch| ch.is_ascii_digit()));
+    if !grouped {
+        return Ok(None);
+    }
+    let timestamp = groups[0].len() == 8 && groups[1..].iter().all(|group| [2, 4, 6].contains(&group.len()));
+    if groups.len() > 1 && !timestamp {
+        return Err("ambiguous version, Flyway reads `_` between digits as `.`");
+    }
+    return Ok(Some((version.replace("_", ""), name)));
+}
+
+/// Replace all string literals inside an SQL statement with `?`
+///
+/// Single- and double-quoted regions are replaced as a whole, including their quotes. Backslash
+/// escapes and doubled quotes inside them are respected. Backtick-quoted identifiers are kept.
+/// This is meant for logging statements without exposing the data they contain.
+pub fn redact_string_literals(statement: &str) -> String {
+    let mut result = String::with_capacity(statement.len());
+    let mut index = 0;
+    while let Some(offset) = statement[index..].find(['\'', '"']) {
+        result.push_str(&statement[index..(index + offset)]);
+        result.push('?');
+        index = string_literal_end(statement, index + offset);
+    }
+    result.push_str(&statement[index..]);
+    return result;
+}
+
+/// Find the end of the string literal whose opening quote is at `start`
+///
+/// Returns the index after the closing quote, or the length of `text` if the literal is not
+/// closed. Backslash escapes and doubled quotes are respected.
+fn string_literal_end(text: &str, start: usize) -> usize {
+    let bytes = text.as_bytes();
+    let quote = bytes[start];
+    let mut index = start + 1;
+    while index < bytes.len() {
+        if bytes[index] == BACKSLASH {
+            index += 2;
+        } else if bytes[index] == quote {
+            if bytes.get(index + 1) == Some(&quote) {
+                index += 2;
+            } else {
+                return index + 1;
+            }
+        } else {
+            index += 1;
+        }
+    }
+    return bytes.len();
+}
+
+/// Find the end of the `--` comment starting at `start`, i.e. the index after its newline
+fn comment_end(text: &str, start: usize) -> usize {
+    return text[start..].find('\n')
+        .map(|offset| start + offset + 1)
+        .unwrap_or(text.len());
+}
+
+/// Values for the placeholders of a changelog
+///
+/// A placeholder is a name between a prefix and a suffix, `${name}` by default. Names must not
+/// contain whitespace, otherwise the text is left as it is. Placeholders inside single- or
+/// double-quoted string literals and `--` comments are not replaced unless
+/// `with_replace_in_literals(true)` is set. Replacing a placeholder without a value fails with a `MissingPlaceholder` error.
+#[derive(Debug, Clone)]
+pub struct Placeholders {
+    /// Explicitly provided values
+    values: HashMap<String, String>,
+    /// Whether environment variables are used for names without an explicit value
+    from_env: bool,
+    prefix: String,
+    suffix: String,
+    /// Whether placeholders inside string literals are replaced
+    replace_in_literals: bool,
+}
+
+impl Placeholders {
+    /// Use the given values only
+    pub fn new(values: HashMap<String, String>) -> Placeholders {
+        return Placeholders {
+            values,
+            from_env: false,
+            prefix: "${".to_string(),
+            suffix: "}".to_string(),
+            replace_in_literals: false,
+        };
+    }
+
+    /// Use environment variables as values
+    pub fn from_env() -> Placeholders {
+        let mut placeholders = Placeholders::new(HashMap::new());
+        placeholders.from_env = true;
+        return placeholders;
+    }
+
+    /// Set the text that starts a placeholder (default: `${`)
+    pub fn with_prefix(mut self, prefix: &str) -> Placeholders {
+        self.prefix = prefix.to_string();
+        return self;
+    }
+
+    /// Set the text that ends a placeholder (default: `}`)
+    pub fn with_suffix(mut self, suffix: &str) -> Placeholders {
+        self.suffix = suffix.to_string();
+        return self;
+    }
+
+    /// Also replace placeholders inside string literals and comments (default: `false`)
+    pub fn with_replace_in_literals(mut self, replace_in_literals: bool) -> Placeholders {
+        self.replace_in_literals = replace_in_literals;
+        return self;
+    }
+
+    /// Get the value of a placeholder
+    fn value(&self, name: &str) -> Option<String> {
+        if let Some(value) = self.values.get(name) {
+            return Some(value.clone());
+        }
+        if self.from_env {
+            return std::env::var(name).ok();
+        }
+        return None;
+    }
+}
+
+impl Default for Placeholders {
+    fn default() -> Self {
+        return Placeholders::from_env();
+    }
+}
+
+/// Replace the placeholders in `content`, see `Placeholders`
+pub fn replace_placeholders(content: &str, placeholders: &Placeholders) -> Result<String> {
+    let prefix = placeholders.prefix.as_str();
+    let suffix = placeholders.suffix.as_str();
+    let mut result = String::with_capacity(content.len());
+    let mut index = 0;
+    while let Some(offset) = content[index..].find(prefix) {
+        let start = index + offset;
+        if !placeholders.replace_in_literals {
+            let quote_offset = content[index..start].find(['\'', '"']);
+            let comment_offset = content[index..start].find("--");
+            let end = match (quote_offset, comment_offset) {
+                (Some(quote_offset), Some(comment_offset)) if comment_offset < quote_offset => Some(comment_end(content, index + comment_offset)),
+                (None, Some(comment_offset)) => Some(comment_end(content, index + comment_offset)),
+                (Some(quote_offset), _) => Some(string_literal_end(content, index + quote_offset)),
+                (None, None) => None,
+            };
+            if let Some(end) = end {
+                result.push_str(&content[index..end]);
+                index = end;
+                continue;
+            }
+        }
+
+        let name_start = start + prefix.len();
+        let name = content[name_start..].find(suffix)
+            .map(|name_length| &content[name_start..(name_start + name_length)])
+            .filter(|name| !name.is_empty() && !name.contains(char::is_whitespace));
+        let name = match name {
+            Some(name) => name,
+            None => {
+                result.push_str(&content[index..name_start]);
+                index = name_start;
+                continue;
+            }
+        };
+        let value = placeholders.value(name)
+            .ok_or_else(|| ChangelogError::missing_placeholder(name))?;
+        result.push_str(&content[index..start]);
+        result.push_str(value.as_str());
+        index = name_start + name.len() + suffix.len();
+    }
+    result.push_str(&content[index..]);
+    return Ok(result);
+}
+
 #[cfg(test)]
 mod test {
     use std::path::Path;
-    use crate::ChangelogFile;
+    use std::collections::{BTreeSet, HashMap};
+    use std::sync::Arc;
+    use std::cmp::Ordering;
+    use crate::{ChangelogErrorKind, ChangelogFile, ChecksumAlgorithm, Crc32, Placeholders, Sha256, SqlStatement, SqlStatementIterator, parse_migration_filename, parse_migration_stem,
+                redact_string_literals, split_sql};
 
     #[test]
     pub fn test_load_changelog_file1() {
@@ -583,7 +1880,8 @@ mod test {
         let result = ChangelogFile::from_path(&path);
         match result {
             Ok(changelog) => {
-                assert_eq!(changelog.version, "V1");
+                assert_eq!(changelog.version, 1);
+                assert_eq!(changelog.name, "test1");
                 assert!(changelog.content().trim_start().starts_with("CREATE TABLE lorem"));
                 assert!(changelog.content().trim_end().ends_with("ipsum VARCHAR(16));"));
             }
@@ -599,7 +1897,8 @@ mod test {
         let result = ChangelogFile::from_path(&path);
         match result {
             Ok(changelog) => {
-                assert_eq!(changelog.version, "V2");
+                assert_eq!(changelog.version, 2);
+                assert_eq!(changelog.name, "test2");
                 assert!(changelog.content().trim_start().starts_with("CREATE INDEX idx_lorem_ipsum"));
                 assert!(changelog.content().trim_end().ends_with("sit INTEGER, ahmed BIGINT);"));
             }
@@ -655,4 +1954,478 @@ mod test {
             }
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    pub fn test_redact_string_literals() {
+        assert_eq!(redact_string_literals("INSERT INTO users(name, `password`) VALUES ('admin', \"s3cr'et\")"),
+                   "INSERT INTO users(name, `password`) VALUES (?, ?)");
+        assert_eq!(redact_string_literals("UPDATE users SET note='it''s \\'quoted\\'' WHERE id=1"),
+                   "UPDATE users SET note=? WHERE id=1");
+    }
+
+    #[test]
+    pub fn test_split_sql() {
+        let statements: Vec<String> = split_sql("-- comment\nCREATE TABLE a(id INT);;\n\nINSERT INTO a VALUES (1);\n")
+            .map(|statement| statement.statement)
+            .collect();
+        assert_eq!(statements, vec!["CREATE TABLE a(id INT)", "INSERT INTO a VALUES (1)"]);
+
+        let statements: Vec<String> = split_sql("a;b")
+            .map(|statement| statement.statement)
+            .collect();
+        assert_eq!(statements, vec!["a", "b"]);
+        let statements: Vec<String> = split_sql("SELECT 1;SELECT 2;;;SELECT 3;")
+            .map(|statement| statement.statement)
+            .collect();
+        assert_eq!(statements, vec!["SELECT 1", "SELECT 2", "SELECT 3"], "No byte after a separator may be lost.");
+    }
+
+    #[test]
+    pub fn test_changelog_file_serde() {
+        let changelog = ChangelogFile::from_string(3, "add_users", "CREATE TABLE users(id INT);").unwrap();
+        let yaml = serde_yaml::to_string(&changelog).unwrap();
+        let deserialized: ChangelogFile = serde_yaml::from_str(yaml.as_str()).unwrap();
+        assert_eq!(deserialized, changelog);
+        assert_eq!(deserialized.name, "add_users");
+        assert_eq!(deserialized.checksum().unwrap(), changelog.checksum().unwrap());
+    }
+
+    #[test]
+    pub fn test_checksum_algorithms() {
+        assert_eq!(Crc32.compute("123456789"), "cbf43926");
+        assert_eq!(Sha256.compute(""), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+
+        let changelog = ChangelogFile::from_string(1, "users", "CREATE TABLE users(id INT);").unwrap();
+        assert_eq!(changelog.recorded_checksum().unwrap(), changelog.checksum().unwrap().to_string());
+        let with_crc32 = changelog.with_checksum_algorithm(Arc::new(Crc32));
+        assert_eq!(with_crc32.recorded_checksum().unwrap(), format!("crc32:{}", Crc32.compute(changelog.content())));
+        assert_eq!(with_crc32.checksum().unwrap(), changelog.checksum().unwrap(), "The algorithm must not change checksum().");
+        assert!(changelog.checksum_with(&Sha256).unwrap().starts_with("sha256:"));
+        assert_eq!(with_crc32.without_checksum().recorded_checksum().unwrap(), "0");
+    }
+
+    #[test]
+    pub fn test_changelog_file_order() {
+        let v2 = ChangelogFile::from_string(2, "b", "SELECT 2;").unwrap();
+        let v10 = ChangelogFile::from_string(10, "a", "SELECT 10;").unwrap();
+        assert!(v2 < v10, "Versions must be compared numerically.");
+
+        let first = ChangelogFile::from_string(1, "init", "SELECT 1;").unwrap();
+        let second = ChangelogFile::from_string(1, "init", "SELECT 2;").unwrap();
+        let renamed = ChangelogFile::from_string(1, "setup", "SELECT 1;").unwrap();
+        assert_ne!(first, second);
+        assert_eq!(first.cmp(&second), Ordering::Less);
+        assert_eq!(second.cmp(&first), Ordering::Greater);
+        assert_eq!(first.cmp(&renamed), Ordering::Less);
+        assert_eq!(first.cmp(&first.clone()), Ordering::Equal);
+        assert_eq!(first, first.clone());
+
+        let mut sorted = vec![v10.clone(), renamed.clone(), second.clone(), v2.clone(), first.clone()];
+        sorted.sort();
+        assert_eq!(sorted, vec![first.clone(), second, renamed, v2, v10]);
+
+        let lazy_a = ChangelogFile::lazy(1, "init", Path::new("missing/a/V1_init.sql"));
+        let lazy_b = ChangelogFile::lazy(1, "init", Path::new("missing/b/V1_init.sql"));
+        assert_eq!(lazy_a.cmp(&lazy_b), Ordering::Less, "Files must be compared by path without reading them.");
+        assert_eq!(lazy_a, lazy_a.clone());
+        assert_eq!(first.cmp(&lazy_a), Ordering::Less, "Content in memory must come before files.");
+        assert!(lazy_a.load().is_err(), "Comparing must not have changed the result of loading.");
+    }
+
+    #[test]
+    // The cached checksum, annotation and content do not take part in the order
+    #[allow(clippy::mutable_key_type)]
+    pub fn test_changelog_file_btree_set() {
+        let mut set = BTreeSet::new();
+        assert!(set.insert(ChangelogFile::from_string(1, "init", "SELECT 1;").unwrap()));
+        assert!(set.insert(ChangelogFile::from_string(1, "init", "SELECT 2;").unwrap()),
+                "A changelog with different content must not be taken for a duplicate.");
+        assert!(!set.insert(ChangelogFile::from_string(1, "init", "SELECT 1;").unwrap()));
+        assert_eq!(set.iter().map(|changelog| changelog.content()).collect::<Vec<&str>>(), vec!["SELECT 1;", "SELECT 2;"]);
+
+        let mut changelogs = vec![
+            ChangelogFile::from_string(1, "init", "SELECT 2;").unwrap(),
+            ChangelogFile::from_string(1, "init", "SELECT 1;").unwrap(),
+            ChangelogFile::from_string(1, "init", "SELECT 2;").unwrap(),
+        ];
+        changelogs.sort();
+        changelogs.dedup();
+        assert_eq!(changelogs.len(), 2);
+    }
+
+    #[cfg(feature = "annotations")]
+    #[test]
+    pub fn test_preserve_comments() {
+        let sql = "-- users of the shop\n--! may_fail: true\nCREATE TABLE users(\n  email TEXT -- unique\n);\n-- trailing comment\n";
+        let stripped: Vec<SqlStatement> = SqlStatementIterator::from_str(sql).collect();
+        assert_eq!(stripped.len(), 1);
+        assert_eq!(stripped[0].statement, "CREATE TABLE users(\n  email TEXT )");
+
+        let preserved: Vec<SqlStatement> = SqlStatementIterator::from_str(sql).with_preserve_comments(true).collect();
+        assert_eq!(preserved.len(), 1, "Comment-only statements must be skipped.");
+        assert_eq!(preserved[0].statement, "-- users of the shop\nCREATE TABLE users(\n  email TEXT -- unique\n)");
+        assert!(preserved[0].annotation.as_ref().map(|annotation| annotation.may_fail()).unwrap_or(false));
+
+        let changelog = ChangelogFile::from_string(1, "users", format!("--!! preserve_comments: true\n{}", sql).as_str()).unwrap();
+        let statements: Vec<SqlStatement> = changelog.iter().collect();
+        assert_eq!(statements[0].statement, preserved[0].statement);
+    }
+
+    #[test]
+    pub fn test_borrowed_iterator() {
+        let sql = String::from("CREATE TABLE a(id INT);\nINSERT INTO a VALUES (1);");
+        let borrowed: Vec<SqlStatement> = SqlStatementIterator::from_str(sql.as_str()).collect();
+        let shared: Vec<SqlStatement> = SqlStatementIterator::from_shared_string(Arc::new(sql.clone())).collect();
+        assert_eq!(borrowed.iter().map(|statement| statement.statement.as_str()).collect::<Vec<&str>>(),
+                   vec!["CREATE TABLE a(id INT)", "INSERT INTO a VALUES (1)"]);
+        assert_eq!(borrowed.iter().map(|statement| statement.statement.as_str()).collect::<Vec<&str>>(),
+                   shared.iter().map(|statement| statement.statement.as_str()).collect::<Vec<&str>>());
+    }
+
+    #[cfg(feature = "annotations")]
+    #[test]
+    pub fn test_long_comment() {
+        let comment = "-".repeat(100_000);
+        let sql = format!("--{}\nSELECT 1;\n-- {}\n--! may_fail: true\nSELECT 2;", comment, comment);
+        let statements: Vec<SqlStatement> = split_sql(sql.as_str()).collect();
+        assert_eq!(statements.iter().map(|statement| statement.statement.as_str()).collect::<Vec<&str>>(), vec!["SELECT 1", "SELECT 2"]);
+        assert!(statements[1].annotation.as_ref().map(|annotation| annotation.may_fail()).unwrap_or(false));
+    }
+
+    #[test]
+    pub fn test_streamed_changelog() {
+        let sql = format!("--!! preserve_comments: true\n-- {}\nINSERT INTO t VALUES ('{};');\nSELECT 2;\n", "x".repeat(70_000), "y".repeat(70_000));
+        let path = std::env::temp_dir().join(format!("flyway_streamed_{}.sql", std::process::id()));
+        std::fs::write(&path, sql.as_str()).unwrap();
+        let streamed = ChangelogFile::streamed(4, "data", path.as_path()).unwrap();
+        let loaded = ChangelogFile::from_string(4, "data", sql.as_str()).unwrap();
+        assert!(streamed.is_streamed());
+        assert_eq!(streamed.checksum().unwrap(), loaded.checksum().unwrap());
+        let statements: Vec<String> = streamed.iter().map(|statement| statement.statement).collect();
+        assert!(!streamed.is_loaded(), "Iterating must not load the content.");
+        assert_eq!(statements.len(), 2);
+        assert_eq!(statements, loaded.iter().map(|statement| statement.statement).collect::<Vec<String>>());
+        assert!(streamed.validate().is_ok());
+
+        std::fs::remove_file(&path).unwrap();
+        let mut iterator = streamed.iter();
+        assert!(iterator.next().is_none());
+        assert!(matches!(iterator.take_error().as_ref().map(|err| err.kind()), Some(ChangelogErrorKind::IoError(_))),
+                "A missing file must be reported.");
+    }
+
+    #[test]
+    pub fn test_lazy_changelog() {
+        let path = std::env::temp_dir().join(format!("flyway_lazy_{}.sql", std::process::id()));
+        std::fs::write(&path, "SELECT 1;\nSELECT 2;").unwrap();
+        let lazy = ChangelogFile::lazy(5, "lazy", path.as_path());
+        let loaded = ChangelogFile::from_string(5, "lazy", "SELECT 1;\nSELECT 2;").unwrap();
+        assert_eq!(lazy.content(), "", "The content must not be read before it is loaded.");
+        assert_eq!(lazy.checksum().unwrap(), loaded.checksum().unwrap());
+        assert_eq!(lazy.content(), loaded.content());
+        assert_ne!(lazy, loaded, "Changelogs with different sources are not equal, whatever their content.");
+        std::fs::remove_file(&path).unwrap();
+
+        let missing = ChangelogFile::lazy(5, "lazy", path.as_path());
+        assert!(missing.checksum().is_err());
+        assert!(missing.checksum_with(&Sha256).is_err());
+        assert!(missing.recorded_checksum().is_err());
+        assert!(missing.annotated_statements().is_err());
+        assert_eq!(missing.content(), "");
+        assert_ne!(missing, loaded);
+        let mut iterator = missing.iter();
+        assert!(iterator.next().is_none());
+        assert!(matches!(iterator.take_error().as_ref().map(|err| err.kind()), Some(ChangelogErrorKind::IoError(_))),
+                "A missing file must be reported instead of panicking.");
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    pub fn test_deprecated_fields() {
+        let changelog = ChangelogFile::from_string(1, "users", "CREATE TABLE users(id INT);").unwrap();
+        assert_eq!(changelog.checksum, changelog.checksum().unwrap());
+        assert_eq!(changelog.content.as_str(), changelog.content());
+        assert_eq!(changelog.without_checksum().checksum, 0);
+    }
+
+    #[test]
+    pub fn test_invalid_encoding() {
+        let path = std::env::temp_dir().join(format!("V1_latin1_{}.sql", std::process::id()));
+        std::fs::write(&path, b"INSERT INTO t VALUES ('caf\xe9');").unwrap();
+        let result = ChangelogFile::from_path(path.as_path());
+        std::fs::remove_file(&path).unwrap();
+        match result {
+            Err(err) => {
+                assert!(matches!(err.kind(), ChangelogErrorKind::InvalidEncoding(invalid) if invalid == &path), "Unexpected error: {}", err);
+                assert!(err.to_string().contains(path.display().to_string().as_str()), "The message must name the file.");
+            },
+            Ok(_) => assert!(false, "Latin-1 content must be rejected."),
+        }
+    }
+
+    #[test]
+    pub fn test_from_path_validated() {
+        let path = std::env::temp_dir().join(format!("V1_validated_{}.sql", std::process::id()));
+        std::fs::write(&path, "CREATE TABLE t (id INT);\nINSERT INTO t VALUES (1);").unwrap();
+        match SqlStatementIterator::from_path_validated(path.as_path()) {
+            Ok(iterator) => assert_eq!(iterator.count(), 2),
+            Err(err) => assert!(false, "Valid file was rejected: {}", err),
+        }
+
+        std::fs::write(&path, "CREATE TABLE t (id INT);\nINSERT INTO t VALUES ('1);").unwrap();
+        assert!(SqlStatementIterator::from_path(path.as_path()).is_ok());
+        let result = SqlStatementIterator::from_path_validated(path.as_path());
+        std::fs::remove_file(&path).unwrap();
+        match result {
+            Err(err) => assert!(matches!(err.kind(), ChangelogErrorKind::InvalidSql(_)), "Unexpected error: {}", err),
+            Ok(_) => assert!(false, "Unclosed quote must be reported."),
+        }
+    }
+
+    #[cfg(feature = "encoding")]
+    #[test]
+    pub fn test_from_path_with_encoding() {
+        let path = std::env::temp_dir().join(format!("V1_windows1252_{}.sql", std::process::id()));
+        std::fs::write(&path, b"INSERT INTO t VALUES ('caf\xe9 \x80');").unwrap();
+        let result = ChangelogFile::from_path_with_encoding(path.as_path(), encoding_rs::WINDOWS_1252);
+        std::fs::remove_file(&path).unwrap();
+        match result {
+            Ok(changelog) => assert_eq!(changelog.content(), "INSERT INTO t VALUES ('café €');"),
+            Err(err) => assert!(false, "Changelog file loading failed: {}", err),
+        }
+    }
+
+    #[cfg(feature = "annotations")]
+    #[test]
+    pub fn test_validate() {
+        let changelog = ChangelogFile::from_string(1, "valid", "SELECT 'a;b';\n--! may_fail: true\nSELECT 2;").unwrap();
+        assert!(changelog.validate().is_ok());
+        assert_eq!(split_sql("SELECT 1; SELECT 2;").validate().unwrap(), 2);
+
+        let changelog = ChangelogFile::from_string(2, "quote", "SELECT 1;\nINSERT INTO t VALUES ('oops);\nSELECT 3;").unwrap();
+        match changelog.validate() {
+            Err(err) => assert!(matches!(err.kind(), ChangelogErrorKind::InvalidSql(_)), "Unexpected error: {}", err),
+            Ok(_) => assert!(false, "Unterminated quotes must be reported."),
+        }
+
+        let changelog = ChangelogFile::from_string(3, "annotation", "--! may_fail: [true\nSELECT 1;").unwrap();
+        match changelog.validate() {
+            Err(err) => assert!(matches!(err.kind(), ChangelogErrorKind::InvalidSql(_)), "Unexpected error: {}", err),
+            Ok(_) => assert!(false, "Invalid annotations must be reported."),
+        }
+    }
+
+    #[cfg(feature = "annotations")]
+    #[test]
+    pub fn test_annotated_statements() {
+        let sql = "CREATE TABLE users(email TEXT);\n--! may_fail: true\nDROP INDEX idx;\nSELECT 1;\n\
+                   --! params: [admin]\nINSERT INTO users(email) VALUES (?);";
+        let changelog = ChangelogFile::from_string(1, "mixed", sql).unwrap();
+        let annotated = changelog.annotated_statements().unwrap();
+        assert_eq!(annotated.iter().map(|(index, _)| *index).collect::<Vec<usize>>(), vec![1, 3]);
+        assert!(annotated[0].1.annotation.as_ref().map(|annotation| annotation.may_fail()).unwrap_or(false));
+        assert_eq!(annotated[1].1.statement.trim(), "INSERT INTO users(email) VALUES (?)");
+    }
+
+    #[cfg(feature = "annotations")]
+    #[test]
+    pub fn test_custom_annotation_prefix() {
+        let sql = "-- flyway: may_fail: true\nDROP INDEX idx;\n-- flywayish comment\n--! may_fail: true\nSELECT 1;";
+        let changelog = ChangelogFile::from_string(1, "custom", sql).unwrap();
+        let statements: Vec<SqlStatement> = changelog.iter_with_annotation_prefix("-- flyway:").collect();
+        assert_eq!(statements.len(), 2);
+        assert_eq!(statements[0].statement, "DROP INDEX idx");
+        assert!(statements[0].annotation.as_ref().map(|annotation| annotation.may_fail()).unwrap_or(false));
+        assert_eq!(statements[1].statement, "SELECT 1", "Plain comments are still stripped.");
+        assert!(statements[1].annotation.is_none(), "The default prefix is a plain comment with a custom prefix.");
+
+        let statements: Vec<SqlStatement> = changelog.iter().collect();
+        assert!(statements[0].annotation.is_none());
+        assert!(statements[1].annotation.is_some());
+    }
+
+    #[cfg(feature = "annotations")]
+    #[test]
+    pub fn test_split_into_transactions() {
+        use crate::StatementGroup;
+
+        let sql = "CREATE TABLE users(email TEXT);\nINSERT INTO users VALUES ('a');\n\
+                   --! transactional: false\nCREATE INDEX CONCURRENTLY idx ON users(email);\nSELECT 1;";
+        let changelog = ChangelogFile::from_string(1, "mixed", sql).unwrap();
+        let groups: Vec<(bool, Vec<String>)> = changelog.split_into_transactions().unwrap().into_iter()
+            .map(|group| match group {
+                StatementGroup::Transactional(statements) => (true, statements.into_iter().map(|statement| statement.statement).collect()),
+                StatementGroup::Standalone(statement) => (false, vec![statement.statement]),
+            })
+            .collect();
+        assert_eq!(groups, vec![
+            (true, vec!["CREATE TABLE users(email TEXT)".to_string(), "INSERT INTO users VALUES ('a')".to_string()]),
+            (false, vec!["CREATE INDEX CONCURRENTLY idx ON users(email)".to_string()]),
+            (true, vec!["SELECT 1".to_string()]),
+        ]);
+
+        let changelog = ChangelogFile::from_string(2, "standalone", "--!! transactional: false\nSELECT 1;\nSELECT 2;").unwrap();
+        let groups = changelog.split_into_transactions().unwrap();
+        assert_eq!(groups.len(), 2);
+        assert!(groups.iter().all(|group| matches!(group, StatementGroup::Standalone(_))));
+    }
+
+    #[test]
+    pub fn test_parse_migration_filename() {
+        assert_eq!(parse_migration_filename("V1_init.sql"), Some((1, "init")));
+        assert_eq!(parse_migration_filename("V007_add_users.sql"), Some((7, "add_users")));
+        assert_eq!(parse_migration_filename("V1_add__users.sql"), Some((1, "add__users")));
+        assert_eq!(parse_migration_filename("V20240115_1200__add_users.sql"), Some((202401151200, "add_users")));
+        assert_eq!(parse_migration_filename("V20240115120000__add_users.sql"), Some((20240115120000, "add_users")));
+        assert_eq!(parse_migration_filename("V20240115_12_00_00__add_users.sql"), Some((20240115120000, "add_users")));
+        assert_eq!(parse_migration_filename("V1__init.sql"), Some((1, "init")));
+        assert_eq!(parse_migration_filename("V1_1__fix.sql"), None, "Flyway reads this as version 1.1.");
+
+        assert_eq!(parse_migration_filename("v1_lower.sql"), None, "The prefix is case-sensitive.");
+        assert_eq!(parse_migration_filename("V1_notes.txt"), None);
+        assert_eq!(parse_migration_filename("README.md"), None);
+        assert_eq!(parse_migration_filename("V.sql"), None);
+        assert_eq!(parse_migration_filename("V1.sql"), None);
+        assert_eq!(parse_migration_filename("V_init.sql"), None);
+        assert_eq!(parse_migration_filename("V1_.sql"), None);
+        assert_eq!(parse_migration_filename("V1a_init.sql"), None);
+        assert_eq!(parse_migration_filename("V99999999999999999999_big.sql"), None);
+
+        assert_eq!(parse_migration_stem("1-typo"), Err("missing `_` between version and name"));
+        assert_eq!(parse_migration_stem("_init"), Err("missing version"));
+        assert_eq!(parse_migration_stem("1a_init"), Err("version is not a number"));
+        assert_eq!(parse_migration_stem("1_"), Err("missing name"));
+        assert_eq!(parse_migration_stem("20240115_01__"), Err("missing name"));
+        assert_eq!(parse_migration_stem("1_1__fix"), Err("ambiguous version, Flyway reads `_` between digits as `.`"));
+        assert_eq!(parse_migration_stem("2024_0115__fix"), Err("ambiguous version, Flyway reads `_` between digits as `.`"));
+        assert_eq!(parse_migration_stem("20240115_123__fix"), Err("ambiguous version, Flyway reads `_` between digits as `.`"));
+        assert_eq!(parse_migration_stem("99999999999999999999_big"), Err("version is too large"));
+    }
+
+    #[cfg(feature = "annotations")]
+    #[test]
+    pub fn test_file_annotation() {
+        let sql = "\n--!! transactional: false\n--! may_fail: true\nCREATE INDEX CONCURRENTLY idx ON users(email);";
+        let changelog = ChangelogFile::from_string(1, "index", sql).unwrap();
+        assert!(!changelog.is_transactional().unwrap());
+        let statements: Vec<SqlStatement> = changelog.iter().collect();
+        assert_eq!(statements.len(), 1);
+        assert!(statements[0].annotation.as_ref().map(|annotation| annotation.may_fail()).unwrap_or(false));
+
+        let changelog = ChangelogFile::from_string(2, "plain", "SELECT 1;\n--!! transactional: false\nSELECT 2;").unwrap();
+        assert!(changelog.file_annotation().unwrap().is_none(), "Annotations after the first statement are no file annotations.");
+        assert!(changelog.is_transactional().unwrap());
+
+        let changelog = ChangelogFile::from_string(3, "merge", "--!! requires_db: \">=15\"\nMERGE INTO users USING staging ON true WHEN MATCHED THEN DO NOTHING;").unwrap();
+        assert_eq!(changelog.file_annotation().unwrap().and_then(|annotation| annotation.requires_db()), Some(">=15"));
+
+        let changelog = ChangelogFile::lazy(4, "missing", Path::new("missing/V4_missing.sql"));
+        assert!(changelog.is_transactional().is_err(), "An unreadable file must be an error, not a panic.");
+    }
+
+    #[cfg(not(feature = "annotations"))]
+    #[test]
+    pub fn test_annotations_disabled() {
+        let sql = "--!! transactional: false\n--! may_fail: [true\nCREATE INDEX idx ON users(email);";
+        let changelog = ChangelogFile::from_string(1, "index", sql).unwrap();
+        let statements: Vec<SqlStatement> = changelog.iter().collect();
+        assert_eq!(statements.len(), 1);
+        assert_eq!(statements[0].statement, "CREATE INDEX idx ON users(email)");
+        assert!(statements[0].annotation.is_none(), "Annotations are not parsed.");
+        assert!(changelog.file_annotation().unwrap().is_none());
+        assert!(changelog.validate().is_ok(), "Annotations are not validated.");
+    }
+
+    #[cfg(feature = "annotations")]
+    #[test]
+    pub fn test_unknown_annotation_keys() {
+        let sql = "--! may_fail: true\n--! timeout: 30s\nCREATE INDEX idx ON users(email);";
+        let statements: Vec<SqlStatement> = split_sql(sql).collect();
+        assert_eq!(statements.len(), 1);
+        let annotation = match statements[0].annotation.as_ref() {
+            Some(annotation) => annotation,
+            None => {
+                assert!(false, "The annotation must not be dropped.");
+                return;
+            }
+        };
+        assert!(annotation.may_fail());
+        assert_eq!(annotation.extra().get("timeout").and_then(|value| value.as_str()), Some("30s"));
+    }
+
+    #[cfg(feature = "annotations")]
+    #[test]
+    pub fn test_statement_params() {
+        std::env::set_var("FLYWAY_TEST_ADMIN_EMAIL", "admin@example.com");
+        let sql = "--! may_fail: true\n--! params: [\"${FLYWAY_TEST_ADMIN_EMAIL}\", admin]\nINSERT INTO users(email, role) VALUES (?, ?);\n\
+                   --! params: [\"${FLYWAY_TEST_MISSING}\"]\nINSERT INTO users(email) VALUES (?);\nSELECT 1;";
+        let statements: Vec<SqlStatement> = split_sql(sql).collect();
+        assert_eq!(statements.len(), 3);
+
+        let annotation = statements[0].annotation.as_ref().unwrap();
+        assert!(annotation.may_fail());
+        assert_eq!(statements[0].params().unwrap(), vec!["admin@example.com", "admin"]);
+
+        match statements[1].params() {
+            Err(err) => assert!(matches!(err.kind(), ChangelogErrorKind::MissingParameter(name) if name == "FLYWAY_TEST_MISSING")),
+            Ok(params) => assert!(false, "Missing variable must be reported, got {:?}", params),
+        }
+        assert!(statements[2].params().unwrap().is_empty());
+    }
+
+    #[test]
+    pub fn test_with_placeholders() {
+        let values = HashMap::from([("schema".to_string(), "tenant_a".to_string())]);
+        let changelog = ChangelogFile::from_string(1, "init", "CREATE TABLE ${schema}.users(note TEXT DEFAULT '${schema}');").unwrap();
+
+        let replaced = changelog.with_placeholders(&Placeholders::new(values.clone())).unwrap();
+        assert_eq!(replaced.content(), "CREATE TABLE tenant_a.users(note TEXT DEFAULT '${schema}');");
+        assert_eq!(replaced.checksum().unwrap(), changelog.checksum().unwrap());
+
+        let replaced = changelog.with_placeholders(&Placeholders::new(values.clone()).with_replace_in_literals(true)).unwrap();
+        assert_eq!(replaced.content(), "CREATE TABLE tenant_a.users(note TEXT DEFAULT 'tenant_a');");
+
+        let changelog = ChangelogFile::from_string(2, "comment", "-- don't touch\nCREATE TABLE ${schema}.t(id INT);").unwrap();
+        let replaced = changelog.with_placeholders(&Placeholders::new(values.clone())).unwrap();
+        assert_eq!(replaced.content(), "-- don't touch\nCREATE TABLE tenant_a.t(id INT);", "Quotes inside comments must not start a literal.");
+        let changelog = ChangelogFile::from_string(3, "comment", "-- uses ${missing}\nSELECT '--', ${schema};").unwrap();
+        let replaced = changelog.with_placeholders(&Placeholders::new(values.clone())).unwrap();
+        assert_eq!(replaced.content(), "-- uses ${missing}\nSELECT '--', tenant_a;");
+
+        let changelog = ChangelogFile::from_string(2, "custom", "SELECT * FROM @@schema@@.users WHERE a = '@@@@'; SELECT @@missing@@;").unwrap();
+        let placeholders = Placeholders::new(values).with_prefix("@@").with_suffix("@@");
+        match changelog.with_placeholders(&placeholders) {
+            Err(err) => assert!(matches!(err.kind(), ChangelogErrorKind::MissingPlaceholder(name) if name == "missing")),
+            Ok(replaced) => assert!(false, "Missing placeholder must be reported, got {}", replaced.content()),
+        }
+    }
+
+    #[cfg(feature = "annotations")]
+    #[test]
+    pub fn test_resolved_statements() {
+        let sql = "--!! transactional: false\nCREATE TABLE ${schema}.users(id INT);\n\n-- The index\n--! may_fail: true\nCREATE INDEX idx ON ${schema}.users(id); SELECT 1;";
+        let changelog = ChangelogFile::from_string(1, "init", sql).unwrap();
+        let placeholders = Placeholders::new(HashMap::from([("schema".to_string(), "tenant_a".to_string())]));
+        let resolved = changelog.resolved_statements(Some(&placeholders)).unwrap();
+        assert_eq!(resolved.len(), 3);
+        assert_eq!(resolved[0].statement.statement, "CREATE TABLE tenant_a.users(id INT)");
+        assert_eq!(resolved[0].line, 2);
+        assert_eq!(resolved[1].statement.statement, "CREATE INDEX idx ON tenant_a.users(id)");
+        assert_eq!((resolved[1].index, resolved[1].line), (1, 6), "The position must skip comment and annotation lines.");
+        assert_eq!(&changelog.resolved_statements(None).unwrap()[1].statement.statement, "CREATE INDEX idx ON ${schema}.users(id)");
+        assert_eq!(resolved[2].line, 6);
+        assert_eq!(&sql.replace("${schema}", "tenant_a")[resolved[2].offset..], "SELECT 1;");
+
+        let annotation = resolved[1].statement.annotation.as_ref().unwrap();
+        assert!(annotation.may_fail());
+        assert!(!annotation.transactional(), "The file annotation must be merged into the statement annotation.");
+        assert!(!resolved[2].statement.annotation.as_ref().unwrap().may_fail());
+
+        let changelog = ChangelogFile::from_string(2, "plain", "SELECT 1;\n--! transactional: false\nVACUUM;").unwrap();
+        let transactional: Vec<bool> = changelog.resolved_statements(None).unwrap()
+            .iter()
+            .map(|resolved| resolved.statement.annotation.as_ref().unwrap().transactional())
+            .collect();
+        assert_eq!(transactional, vec![true, false]);
+    }
+}
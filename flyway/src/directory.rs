@@ -0,0 +1,63 @@
+use std::path::{Path, PathBuf};
+
+use flyway_sql_changelog::{parse_migration_filename, ChangelogFile, Result};
+
+use crate::MigrationStore;
+
+/// A `MigrationStore` reading migration files from a directory at runtime
+///
+/// The directory is scanned once when the store is created, using the same naming rules as the
+/// `migrations` macro (`V<version>_<name>.sql`). The content of a file is only read when it is
+/// needed, i.e. when its changelog is executed or its checksum is verified. Versions that are
+/// already deployed therefore never have their files read during a plain `migrate()`.
+#[derive(Debug, Clone)]
+pub struct DirectoryMigrationStore {
+    path: PathBuf,
+    changelogs: Vec<ChangelogFile>,
+}
+
+impl DirectoryMigrationStore {
+    /// Create a store for the migration files in `path`
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<DirectoryMigrationStore> {
+        let path = path.as_ref();
+        let mut changelogs: Vec<ChangelogFile> = Vec::new();
+        for entry in std::fs::read_dir(path)? {
+            let entry = entry?;
+            let filename = entry.file_name();
+            let parsed = filename.to_str().and_then(parse_migration_filename);
+            let (version, name) = match parsed {
+                Some(parsed) => parsed,
+                None => {
+                    log::debug!("Ignoring file: {:?}", entry.path());
+                    continue;
+                }
+            };
+            changelogs.push(ChangelogFile::lazy(version, name, entry.path().as_path()));
+        }
+        changelogs.sort();
+
+        return Ok(DirectoryMigrationStore {
+            path: path.to_path_buf(),
+            changelogs,
+        });
+    }
+
+    /// Get the directory this store reads from
+    pub fn path(&self) -> &Path {
+        return self.path.as_path();
+    }
+}
+
+impl MigrationStore for DirectoryMigrationStore {
+    fn changelogs(&self) -> Vec<ChangelogFile> {
+        return self.changelogs.clone();
+    }
+
+    fn changelogs_after(&self, version: Option<u64>) -> Vec<ChangelogFile> {
+        let start = match version {
+            Some(version) => self.changelogs.partition_point(|changelog| changelog.version() <= version),
+            None => 0,
+        };
+        return self.changelogs[start..].to_vec();
+    }
+}
@@ -0,0 +1,148 @@
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+
+use crate::{ChangelogFile, MigrationExecutor, MigrationState, MigrationStateManager, Result, SqlStatement};
+
+/// A `MigrationExecutor` capturing the statements of the changelogs instead of executing them
+///
+/// The executor wraps the real state manager of the database and is passed to the
+/// `MigrationRunner` as both state manager and executor. The deployed versions are read from the
+/// wrapped state manager, but versions are never recorded and transactions are never opened.
+/// The wrapped state manager is not prepared either, so the migrations table is neither created
+/// nor upgraded and reading it fails if it does not exist yet, unless `with_prepare(true)` is
+/// set. After `migrate()`, `statements()` holds the SQL that would have been executed:
+///
+/// ```ignore
+/// let dry_run = Arc::new(DryRunExecutor::new(driver.clone()));
+/// MigrationRunner::new(Migrations {}, dry_run.clone(), dry_run.clone(), false).migrate().await?;
+/// println!("{}", dry_run.sql());
+/// ```
+///
+/// As nothing is recorded, `with_verify_after(true)` makes the dry run fail.
+pub struct DryRunExecutor<M: ?Sized> {
+    state_manager: Arc<M>,
+    /// Whether the wrapped state manager is prepared
+    prepare: bool,
+    /// The captured statements, in execution order
+    statements: Mutex<Vec<String>>,
+}
+
+impl<M: MigrationStateManager + ?Sized> DryRunExecutor<M> {
+    /// Create a dry-run executor reading the deployed versions from `state_manager`
+    pub fn new(state_manager: Arc<M>) -> DryRunExecutor<M> {
+        return DryRunExecutor {
+            state_manager,
+            prepare: false,
+            statements: Mutex::new(Vec::new()),
+        };
+    }
+
+    /// Prepare the wrapped state manager like a real migration does (default: `false`)
+    ///
+    /// This may change the database, e.g. create the migrations table of a fresh database or add
+    /// missing columns and indexes to it.
+    pub fn with_prepare(mut self, prepare: bool) -> Self {
+        self.prepare = prepare;
+        return self;
+    }
+
+    /// Get the captured statements, in execution order
+    pub fn statements(&self) -> Vec<String> {
+        return self.statements.lock().unwrap().clone();
+    }
+
+    /// Get the captured statements as a single script, each statement terminated by `;`
+    pub fn sql(&self) -> String {
+        return self.statements()
+            .iter()
+            .map(|statement| format!("{};\n", statement))
+            .collect();
+    }
+}
+
+#[async_trait]
+impl<M: MigrationStateManager + ?Sized> MigrationStateManager for DryRunExecutor<M> {
+    async fn prepare(&self) -> Result<()> {
+        if !self.prepare {
+            log::info!("Dry run: not preparing the migration state");
+            return Ok(());
+        }
+        return self.state_manager.prepare().await;
+    }
+
+    async fn is_prepared(&self) -> Result<bool> {
+        return self.state_manager.is_prepared().await;
+    }
+
+    async fn lowest_version(&self) -> Result<Option<MigrationState>> {
+        return self.state_manager.lowest_version().await;
+    }
+
+    async fn highest_version(&self) -> Result<Option<MigrationState>> {
+        return self.state_manager.highest_version().await;
+    }
+
+    async fn list_versions(&self) -> Result<Vec<MigrationState>> {
+        return self.state_manager.list_versions().await;
+    }
+
+    async fn get_version(&self, version: u64) -> Result<Option<MigrationState>> {
+        return self.state_manager.get_version(version).await;
+    }
+
+    async fn is_deployed(&self, version: u64) -> Result<bool> {
+        return self.state_manager.is_deployed(version).await;
+    }
+
+    async fn server_version(&self) -> Result<Option<String>> {
+        return self.state_manager.server_version().await;
+    }
+
+    async fn list_problems(&self) -> Result<Vec<MigrationState>> {
+        return self.state_manager.list_problems().await;
+    }
+
+    async fn applied_checksums(&self) -> Result<Vec<(u64, Option<String>)>> {
+        return self.state_manager.applied_checksums().await;
+    }
+
+    async fn begin_version(&self, changelog_file: &ChangelogFile) -> Result<()> {
+        log::info!("Dry run: would deploy version {} ({})", changelog_file.version(), changelog_file.name);
+        return Ok(());
+    }
+
+    async fn finish_version(&self, _changelog_file: &ChangelogFile) -> Result<()> {
+        return Ok(());
+    }
+
+    async fn skip_version(&self, _changelog_file: &ChangelogFile) -> Result<()> {
+        return Ok(());
+    }
+
+    async fn mark_applied(&self, changelog_file: &ChangelogFile) -> Result<()> {
+        log::info!("Dry run: would mark version {} ({}) as applied", changelog_file.version(), changelog_file.name);
+        return Ok(());
+    }
+}
+
+#[async_trait]
+impl<M: MigrationStateManager + ?Sized> MigrationExecutor for DryRunExecutor<M> {
+    async fn begin_transaction(&self) -> Result<()> {
+        return Ok(());
+    }
+
+    async fn execute_statement(&self, statement: &SqlStatement) -> Result<u64> {
+        log::info!("Dry run: {}", statement.statement);
+        self.statements.lock().unwrap().push(statement.statement.clone());
+        return Ok(0);
+    }
+
+    async fn commit_transaction(&self) -> Result<()> {
+        return Ok(());
+    }
+
+    async fn rollback_transaction(&self) -> Result<()> {
+        return Ok(());
+    }
+}
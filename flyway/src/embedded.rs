@@ -0,0 +1,70 @@
+use flyway_sql_changelog::{parse_migration_filename, ChangelogError, ChangelogFile, Result};
+
+use crate::MigrationStore;
+
+/// A `MigrationStore` backed by migration files embedded into the binary
+///
+/// This is useful for projects that already embed their assets with e.g. `rust-embed` or
+/// `include_dir` and don't want to embed the migrations a second time via the `migrations`
+/// macro. The files are parsed with the same naming rules as the macro uses, i.e. each file
+/// must be named like `V<version>_<name>.sql`. All other files are ignored.
+#[derive(Debug, Clone)]
+pub struct EmbeddedMigrationStore {
+    changelogs: Vec<ChangelogFile>,
+}
+
+impl EmbeddedMigrationStore {
+    /// Create a store from `(filename, content)` pairs
+    ///
+    /// Filenames may contain a directory part (e.g. `migrations/V1_init.sql`), only the last
+    /// path component is used. The content must be valid UTF-8.
+    ///
+    /// Example:
+    /// ```ignore
+    /// #[derive(rust_embed::RustEmbed)]
+    /// #[folder = "migrations/"]
+    /// struct Assets;
+    ///
+    /// let store = EmbeddedMigrationStore::from_embedded(Assets::iter()
+    ///     .filter_map(|name| Assets::get(&name).map(|file| (name, file.data))))?;
+    /// ```
+    pub fn from_embedded<I, N, B>(entries: I) -> Result<EmbeddedMigrationStore>
+        where I: IntoIterator<Item = (N, B)>,
+              N: AsRef<str>,
+              B: AsRef<[u8]> {
+        let mut changelogs: Vec<ChangelogFile> = Vec::new();
+        for (filename, content) in entries.into_iter() {
+            let filename = filename.as_ref();
+            let basename = filename.rsplit(['/', '\\']).next().unwrap_or(filename);
+            let (version, name) = match parse_migration_filename(basename) {
+                Some(parsed) => parsed,
+                None => {
+                    log::debug!("Ignoring embedded file: {}", filename);
+                    continue;
+                }
+            };
+            let content = std::str::from_utf8(content.as_ref())
+                .or_else(|err| Err(ChangelogError::other(Box::new(err))))?;
+            changelogs.push(ChangelogFile::from_string(version, name, content)?);
+        }
+        changelogs.sort();
+
+        return Ok(EmbeddedMigrationStore {
+            changelogs,
+        });
+    }
+}
+
+impl MigrationStore for EmbeddedMigrationStore {
+    fn changelogs(&self) -> Vec<ChangelogFile> {
+        return self.changelogs.clone();
+    }
+
+    fn changelogs_after(&self, version: Option<u64>) -> Vec<ChangelogFile> {
+        let start = match version {
+            Some(version) => self.changelogs.partition_point(|changelog| changelog.version() <= version),
+            None => 0,
+        };
+        return self.changelogs[start..].to_vec();
+    }
+}
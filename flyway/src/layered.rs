@@ -0,0 +1,60 @@
+use std::collections::HashSet;
+
+use flyway_sql_changelog::ChangelogFile;
+
+use crate::MigrationStore;
+
+/// A `MigrationStore` overlaying the changelogs of one store on top of another
+///
+/// A changelog of the overlay replaces the changelog of the base with the same version, e.g. to
+/// swap a single embedded version for a stub in a test. Versions present in only one of the
+/// stores are kept. The base store is not modified.
+///
+/// ```ignore
+/// let store = LayeredMigrationStore::new(Migrations {}, ChangelogMigrationStore::from_changelog_files(vec![
+///     ChangelogFile::from_string(5, "stub", "SELECT 1;")?,
+/// ]));
+/// ```
+#[derive(Debug, Clone)]
+pub struct LayeredMigrationStore<B, O> {
+    base: B,
+    overlay: O,
+}
+
+impl<B: MigrationStore, O: MigrationStore> LayeredMigrationStore<B, O> {
+    /// Create a store whose `overlay` changelogs replace the `base` changelogs of the same version
+    pub fn new(base: B, overlay: O) -> LayeredMigrationStore<B, O> {
+        return LayeredMigrationStore { base, overlay };
+    }
+
+    /// Get the base store
+    pub fn base(&self) -> &B {
+        return &self.base;
+    }
+
+    /// Get the overlay store
+    pub fn overlay(&self) -> &O {
+        return &self.overlay;
+    }
+
+    /// Merge changelogs of both stores, the overlay winning for equal versions
+    fn merge(&self, base: Vec<ChangelogFile>, overlay: Vec<ChangelogFile>) -> Vec<ChangelogFile> {
+        let overridden: HashSet<u64> = overlay.iter().map(|changelog| changelog.version()).collect();
+        let mut changelogs: Vec<ChangelogFile> = base.into_iter()
+            .filter(|changelog| !overridden.contains(&changelog.version()))
+            .chain(overlay)
+            .collect();
+        changelogs.sort_by_key(|changelog| changelog.version());
+        return changelogs;
+    }
+}
+
+impl<B: MigrationStore, O: MigrationStore> MigrationStore for LayeredMigrationStore<B, O> {
+    fn changelogs(&self) -> Vec<ChangelogFile> {
+        return self.merge(self.base.changelogs(), self.overlay.changelogs());
+    }
+
+    fn changelogs_after(&self, version: Option<u64>) -> Vec<ChangelogFile> {
+        return self.merge(self.base.changelogs_after(version), self.overlay.changelogs_after(version));
+    }
+}
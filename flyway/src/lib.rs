@@ -1,11 +1,28 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fmt::{Display, Formatter};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use async_trait::async_trait;
+use futures::StreamExt;
 
 pub use flyway_codegen::{ migrations };
 pub use flyway_sql_changelog::{Result as ChangelogResult, *};
 
+mod directory;
+pub use directory::DirectoryMigrationStore;
+mod dry_run;
+pub use dry_run::DryRunExecutor;
+mod layered;
+pub use layered::LayeredMigrationStore;
+#[cfg(feature = "embedded")]
+mod embedded;
+#[cfg(feature = "embedded")]
+pub use embedded::EmbeddedMigrationStore;
+#[cfg(feature = "cancellation")]
+pub use tokio_util::sync::CancellationToken;
+
 /// Kinds of errors produced by the migration code
 #[derive(Debug)]
 pub enum MigrationsErrorKind {
@@ -28,6 +45,40 @@ pub enum MigrationsErrorKind {
 
     /// Some kind of error that has no specific representation
     CustomErrorMessage(String, Option<Box<dyn Error + Send + Sync>>),
+
+    /// The checksum recorded for a deployed version does not match its changelog file
+    ///
+    /// version, recorded checksum, changelog checksum
+    ChecksumMismatch(u64, String, String),
+
+    /// A changelog failed and rolling back its transaction failed as well
+    ///
+    /// original error, rollback error. The database may contain a partially applied changelog.
+    RollbackFailed(Box<MigrationsError>, Box<MigrationsError>),
+
+    /// The migration did not finish within the given time
+    ///
+    /// The transaction that was open at that time has been rolled back.
+    Timeout(Duration),
+
+    /// The migration has been cancelled
+    ///
+    /// The transaction that was open at that time has been rolled back.
+    Cancelled,
+
+    /// Deployed versions whose changelogs are not part of the store
+    OrphanedVersions(Vec<u64>),
+
+    /// Versions of the store that have not been deployed yet
+    PendingMigrations(Vec<u64>),
+
+    /// The highest deployed version is newer than every changelog of the store
+    ///
+    /// This usually means that old code is deployed against a database migrated by newer code.
+    DatabaseAheadOfStore { db_version: u64, store_max: u64 },
+
+    /// The database server does not satisfy the `requires_db` annotation of a changelog
+    UnsupportedDatabaseVersion { version: u64, requirement: String, server_version: String },
 }
 
 /// Represents errors produced by migration code
@@ -37,11 +88,11 @@ pub struct MigrationsError {
     kind: MigrationsErrorKind,
 
     /// The last successfully deployed version
-    last_successful_version: Option<u32>,
+    last_successful_version: Option<u64>,
 }
 
 impl MigrationsError {
-    pub fn migration_database_step_failed(last_successful_version: Option<u32>,
+    pub fn migration_database_step_failed(last_successful_version: Option<u64>,
                                           cause: Option<Box<dyn Error + Send + Sync>>) -> MigrationsError {
         return MigrationsError {
             kind: MigrationsErrorKind::MigrationDatabaseStepFailed(cause),
@@ -49,7 +100,7 @@ impl MigrationsError {
         };
     }
 
-    pub fn migration_database_failed(last_successful_version: Option<u32>,
+    pub fn migration_database_failed(last_successful_version: Option<u64>,
                                      cause: Option<Box<dyn Error + Send + Sync>>) -> MigrationsError {
         return MigrationsError {
             kind: MigrationsErrorKind::MigrationDatabaseFailed(cause),
@@ -71,7 +122,7 @@ impl MigrationsError {
         };
     }
 
-    pub fn custom_message(message: &str, last_successful_version: Option<u32>,
+    pub fn custom_message(message: &str, last_successful_version: Option<u64>,
                           cause: Option<Box<dyn Error + Send + Sync>>) -> MigrationsError {
         return MigrationsError {
             kind: MigrationsErrorKind::CustomErrorMessage(message.to_string(), cause),
@@ -79,13 +130,176 @@ impl MigrationsError {
         };
     }
 
+    pub fn checksum_mismatch(version: u64, recorded_checksum: String, changelog_checksum: String) -> MigrationsError {
+        return MigrationsError {
+            kind: MigrationsErrorKind::ChecksumMismatch(version, recorded_checksum, changelog_checksum),
+            last_successful_version: None,
+        };
+    }
+
+    pub fn rollback_failed(original: MigrationsError, rollback: MigrationsError) -> MigrationsError {
+        let last_successful_version = original.last_successful_version;
+        return MigrationsError {
+            kind: MigrationsErrorKind::RollbackFailed(Box::new(original), Box::new(rollback)),
+            last_successful_version,
+        };
+    }
+
+    pub fn timeout(timeout: Duration, last_successful_version: Option<u64>) -> MigrationsError {
+        return MigrationsError {
+            kind: MigrationsErrorKind::Timeout(timeout),
+            last_successful_version,
+        };
+    }
+
+    pub fn cancelled(last_successful_version: Option<u64>) -> MigrationsError {
+        return MigrationsError {
+            kind: MigrationsErrorKind::Cancelled,
+            last_successful_version,
+        };
+    }
+
+    pub fn orphaned_versions(versions: Vec<u64>) -> MigrationsError {
+        return MigrationsError {
+            kind: MigrationsErrorKind::OrphanedVersions(versions),
+            last_successful_version: None,
+        };
+    }
+
+    pub fn pending_migrations(versions: Vec<u64>) -> MigrationsError {
+        return MigrationsError {
+            kind: MigrationsErrorKind::PendingMigrations(versions),
+            last_successful_version: None,
+        };
+    }
+
+    pub fn database_ahead_of_store(db_version: u64, store_max: u64) -> MigrationsError {
+        return MigrationsError {
+            kind: MigrationsErrorKind::DatabaseAheadOfStore { db_version, store_max },
+            last_successful_version: None,
+        };
+    }
+
+    pub fn unsupported_database_version(version: u64, requirement: &str, server_version: &str) -> MigrationsError {
+        return MigrationsError {
+            kind: MigrationsErrorKind::UnsupportedDatabaseVersion {
+                version,
+                requirement: requirement.to_string(),
+                server_version: server_version.to_string(),
+            },
+            last_successful_version: None,
+        };
+    }
+
     pub fn kind(&self) -> &MigrationsErrorKind {
         &self.kind
     }
 
-    pub fn last_successful_version(&self) -> Option<u32> {
+    pub fn last_successful_version(&self) -> Option<u64> {
         self.last_successful_version
     }
+
+    /// Whether a single migration step failed
+    pub fn is_step_failure(&self) -> bool {
+        return matches!(self.kind, MigrationsErrorKind::MigrationDatabaseStepFailed(_));
+    }
+
+    /// Whether the migration state management could not be set up
+    pub fn is_setup_failure(&self) -> bool {
+        return matches!(self.kind, MigrationsErrorKind::MigrationSetupFailed(_));
+    }
+
+    /// Whether beginning or finishing a version failed
+    pub fn is_versioning_failure(&self) -> bool {
+        return matches!(self.kind, MigrationsErrorKind::MigrationVersioningFailed(_));
+    }
+
+    /// Whether the migration did not finish in time
+    pub fn is_timeout(&self) -> bool {
+        return matches!(self.kind, MigrationsErrorKind::Timeout(_));
+    }
+
+    /// Whether the migration has been cancelled
+    pub fn is_cancelled(&self) -> bool {
+        return matches!(self.kind, MigrationsErrorKind::Cancelled);
+    }
+
+    /// The error of the failed rollback, if rolling back failed
+    pub fn rollback_error(&self) -> Option<&MigrationsError> {
+        match &self.kind {
+            MigrationsErrorKind::RollbackFailed(_, rollback) => return Some(rollback),
+            _ => return None,
+        }
+    }
+
+    /// The error that caused this error, if any
+    ///
+    /// For `RollbackFailed`, this is the error of the changelog that failed.
+    pub fn cause(&self) -> Option<&(dyn Error + 'static)> {
+        let cause = match &self.kind {
+            MigrationsErrorKind::MigrationDatabaseStepFailed(cause) => cause,
+            MigrationsErrorKind::MigrationDatabaseFailed(cause) => cause,
+            MigrationsErrorKind::MigrationSetupFailed(cause) => cause,
+            MigrationsErrorKind::MigrationVersioningFailed(cause) => cause,
+            MigrationsErrorKind::CustomErrorMessage(_, cause) => cause,
+            MigrationsErrorKind::ChecksumMismatch(_, _, _) => return None,
+            MigrationsErrorKind::RollbackFailed(original, _) => return Some(&**original),
+            MigrationsErrorKind::Timeout(_) => return None,
+            MigrationsErrorKind::Cancelled => return None,
+            MigrationsErrorKind::OrphanedVersions(_) => return None,
+            MigrationsErrorKind::PendingMigrations(_) => return None,
+            MigrationsErrorKind::DatabaseAheadOfStore { .. } => return None,
+            MigrationsErrorKind::UnsupportedDatabaseVersion { .. } => return None,
+        };
+        return cause.as_ref().map(|cause| &**cause as &(dyn Error + 'static));
+    }
+
+    /// Create a cloneable snapshot of this error
+    ///
+    /// The snapshot is serializable with the `serde` feature.
+    pub fn to_info(&self) -> MigrationsErrorInfo {
+        return MigrationsErrorInfo {
+            kind: self.kind.name().to_string(),
+            message: self.to_string(),
+            last_successful_version: self.last_successful_version,
+        };
+    }
+}
+
+impl MigrationsErrorKind {
+    /// The name of the kind, e.g. `"MigrationSetupFailed"`
+    pub fn name(&self) -> &'static str {
+        match self {
+            MigrationsErrorKind::MigrationDatabaseStepFailed(_) => "MigrationDatabaseStepFailed",
+            MigrationsErrorKind::MigrationDatabaseFailed(_) => "MigrationDatabaseFailed",
+            MigrationsErrorKind::MigrationSetupFailed(_) => "MigrationSetupFailed",
+            MigrationsErrorKind::MigrationVersioningFailed(_) => "MigrationVersioningFailed",
+            MigrationsErrorKind::CustomErrorMessage(_, _) => "CustomErrorMessage",
+            MigrationsErrorKind::ChecksumMismatch(_, _, _) => "ChecksumMismatch",
+            MigrationsErrorKind::RollbackFailed(_, _) => "RollbackFailed",
+            MigrationsErrorKind::Timeout(_) => "Timeout",
+            MigrationsErrorKind::Cancelled => "Cancelled",
+            MigrationsErrorKind::OrphanedVersions(_) => "OrphanedVersions",
+            MigrationsErrorKind::PendingMigrations(_) => "PendingMigrations",
+            MigrationsErrorKind::DatabaseAheadOfStore { .. } => "DatabaseAheadOfStore",
+            MigrationsErrorKind::UnsupportedDatabaseVersion { .. } => "UnsupportedDatabaseVersion",
+        }
+    }
+}
+
+/// A snapshot of a `MigrationsError`
+///
+/// Unlike `MigrationsError`, this can be cloned, which is useful for storing errors or reporting
+/// them from several places. The message includes the causes of the error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MigrationsErrorInfo {
+    /// The name of the error kind, see `MigrationsErrorKind::name`
+    pub kind: String,
+    /// The error message
+    pub message: String,
+    /// The last successfully deployed version
+    pub last_successful_version: Option<u64>,
 }
 
 pub type Result<T> = std::result::Result<T, MigrationsError>;
@@ -127,51 +341,57 @@ impl Display for MigrationsError {
                     result = write!(fmt, "\nCaused by: {}", err_opt.as_ref().unwrap());
                 }
                 return result;
+            },
+            MigrationsErrorKind::ChecksumMismatch(version, recorded, actual) => {
+                return write!(fmt, "Checksum mismatch for version {}. Recorded checksum is {}, changelog checksum is {}.",
+                              version, recorded, actual);
+            }
+            MigrationsErrorKind::RollbackFailed(original, rollback) => {
+                return write!(fmt, "Migration failed and rollback failed.\nCaused by: {}\nRollback error: {}",
+                              original, rollback);
+            }
+            MigrationsErrorKind::Timeout(timeout) => {
+                return write!(fmt, "Migration did not finish within {:?}.", timeout);
+            }
+            MigrationsErrorKind::Cancelled => {
+                return write!(fmt, "Migration has been cancelled.");
+            }
+            MigrationsErrorKind::OrphanedVersions(versions) => {
+                return write!(fmt, "Deployed versions {:?} are missing from the migration store.", versions);
+            }
+            MigrationsErrorKind::PendingMigrations(versions) => {
+                return write!(fmt, "Database is not up to date, versions {:?} have not been deployed.", versions);
+            }
+            MigrationsErrorKind::DatabaseAheadOfStore { db_version, store_max } => {
+                return write!(fmt, "Database is at version {}, but the newest version of the migration store is {}.",
+                              db_version, store_max);
+            }
+            MigrationsErrorKind::UnsupportedDatabaseVersion { version, requirement, server_version } => {
+                return write!(fmt, "Version {} requires database version {}, but the server is at {}.",
+                              version, requirement, server_version);
             }
         };
     }
 }
 
 impl Error for MigrationsError {
-    // fn source(&self) -> Option<&(dyn Error + 'static)> {
-    //     match &self.kind {
-    //         MigrationsErrorKind::MigrationDatabaseStepFailed(err_opt) => {
-    //             if err_opt.is_some() {
-    //                 let err = err_opt.as_ref().unwrap();
-    //                 return Some(err);
-    //             }
-    //         },
-    //         MigrationsErrorKind::MigrationDatabaseFailed(err_opt) => {
-    //             if err_opt.is_some() {
-    //                 let err = err_opt.as_ref().unwrap();
-    //                 return Some(err);
-    //             }
-    //         },
-    //         MigrationsErrorKind::MigrationSetupFailed(err_opt) => {
-    //             if err_opt.is_some() {
-    //                 let err = err_opt.as_ref().unwrap();
-    //                 return Some(err);
-    //             }
-    //         },
-    //         MigrationsErrorKind::MigrationVersioningFailed(err_opt) => {
-    //             if err_opt.is_some() {
-    //                 let err = err_opt.as_ref().unwrap();
-    //                 return Some(err);
-    //             }
-    //         },
-    //         // MigrationsErrorKind::MigrationQueryArgumentReplacementFailed(err_opt) => {
-    //         //     if err_opt.is_some() {
-    //         //         let err = err_opt.as_ref().unwrap();
-    //         //         return Some(err);
-    //         //     }
-    //         // },
-    //     };
-    //     return None;
-    // }
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        return self.cause();
+    }
+}
+
+/// Wrap a `ChangelogError` in a `CustomErrorMessage`, keeping it as the cause
+impl From<ChangelogError> for MigrationsError {
+    fn from(err: ChangelogError) -> Self {
+        return MigrationsError::custom_message("Invalid changelog.", None, Some(Box::new(err)));
+    }
 }
 
 /// Status of a migration.
-#[derive(Debug, Clone)]
+///
+/// Statuses are ordered by how far a version got: `InProgress < Failed < Deployed`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum MigrationStatus {
     /// Migration is in progress.
     ///
@@ -182,23 +402,236 @@ pub enum MigrationStatus {
 
     /// Migration has been finished.
     Deployed,
+
+    /// Migration failed and has been skipped, because the runner continues on failures.
+    Failed,
 }
 
-/// The minimal information for a migration version
-#[derive(Debug, Clone)]
+impl MigrationStatus {
+    fn rank(&self) -> u8 {
+        return match self {
+            MigrationStatus::InProgress => 0,
+            MigrationStatus::Failed => 1,
+            MigrationStatus::Deployed => 2,
+        };
+    }
+}
+
+impl PartialOrd for MigrationStatus {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        return Some(self.cmp(other));
+    }
+}
+
+impl Ord for MigrationStatus {
+    fn cmp(&self, other: &Self) -> Ordering {
+        return self.rank().cmp(&other.rank());
+    }
+}
+
+/// Status of a version in `MigrationRunner::info`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum MigrationInfoStatus {
+    /// The changelog has not been deployed yet
+    Pending,
+    /// The version has been deployed
+    Deployed,
+    /// The version has been deployed, but its changelog is not part of the store
+    Missing,
+}
+
+/// A single version as reported by `MigrationRunner::info` and `MigrationRunner::plan`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MigrationInfoRow {
+    pub version: u64,
+    /// The name of the changelog, `None` if the changelog is missing
+    pub name: Option<String>,
+    pub status: MigrationInfoStatus,
+    /// The recorded checksum for deployed versions, the changelog checksum otherwise
+    pub checksum: Option<String>,
+    /// When the version has been deployed, if the state manager provides it
+    pub applied_at: Option<String>,
+    /// The number of statements of a pending changelog, `None` for deployed and missing versions
+    pub statement_count: Option<usize>,
+}
+
+impl MigrationInfoRow {
+    /// Create a row for a changelog of the store
+    ///
+    /// Only pending changelogs are read, to count their statements.
+    fn from_changelog(changelog: &ChangelogFile, status: MigrationInfoStatus, checksum: Option<String>,
+                      applied_at: Option<String>) -> Result<MigrationInfoRow> {
+        let statement_count = match status {
+            MigrationInfoStatus::Pending => {
+                load_changelog(changelog)?;
+                Some(changelog.iter().count())
+            }
+            _ => None,
+        };
+        return Ok(MigrationInfoRow {
+            version: changelog.version(),
+            name: Some(changelog.name.clone()),
+            status,
+            checksum,
+            applied_at,
+            statement_count,
+        });
+    }
+}
+
+/// Serialize the rows returned by `MigrationRunner::info` as pretty-printed JSON
+#[cfg(feature = "serde")]
+pub fn info_to_json(rows: &[MigrationInfoRow]) -> serde_json::Result<String> {
+    return serde_json::to_string_pretty(rows);
+}
+
+/// The changelogs `MigrationRunner::migrate` would deploy
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MigrationPlan {
+    /// The highest deployed version
+    pub current_version: Option<u64>,
+    /// The changelogs to deploy, in the order they would be deployed
+    pub pending: Vec<MigrationInfoRow>,
+}
+
+/// The outcome of `MigrationRunner::migrate_with_summary`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MigrationSummary {
+    /// The highest version deployed or skipped, as returned by `migrate`
+    pub highest_version: Option<u64>,
+    /// The versions deployed successfully, in the order they were deployed
+    pub deployed: Vec<u64>,
+    /// The number of rows affected by the changelog of each deployed version, as reported by
+    /// `MigrationExecutor::execute_changelog_file`
+    pub rows_affected: Vec<(u64, u64)>,
+    /// The versions that failed and were skipped because of `fail_continue`, with their errors
+    pub failed: Vec<(u64, MigrationsErrorInfo)>,
+}
+
+/// Counters of all migrations run by a `MigrationRunner`, see `MigrationRunner::metrics`
+///
+/// The counters only ever grow, so they can be exported as e.g. Prometheus counters.
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MigrationMetrics {
+    /// The number of `migrate` runs, successful or not
+    pub runs: u64,
+    /// The number of versions deployed
+    pub migrations_applied: u64,
+    /// The number of versions that failed and were skipped because of `fail_continue`
+    pub migrations_failed: u64,
+    /// The number of statements of the deployed changelogs executed successfully
+    pub statements_executed: u64,
+    /// The number of rows affected by the deployed changelogs
+    pub rows_affected: u64,
+    /// The time spent in all runs
+    pub total_duration: Duration,
+    /// The error of the last failed run or skipped version
+    pub last_error: Option<MigrationsErrorInfo>,
+}
+
+#[cfg(feature = "serde")]
+impl MigrationPlan {
+    /// Serialize the plan as pretty-printed JSON
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        return serde_json::to_string_pretty(self);
+    }
+}
+
+/// Controls how `MigrationRunner::migrate` deals with changelog checksums
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumPolicy {
+    /// Checksums are neither recorded nor verified
+    ///
+    /// Versions are recorded with a checksum of `0`, which is never verified.
+    Off,
+
+    /// Checksums are recorded when a version is deployed, but never verified
+    ///
+    /// This allows fixing e.g. typos in migrations that have already been deployed.
+    Record,
+
+    /// Checksums are recorded and verified before any migration runs
+    ///
+    /// `migrate` aborts with a `ChecksumMismatch` error if the checksum recorded for a deployed
+    /// version differs from the checksum of its changelog file.
+    Verify,
+}
+
+impl Default for ChecksumPolicy {
+    fn default() -> Self {
+        return ChecksumPolicy::Record;
+    }
+}
+
+/// The information recorded for a migration version
+///
+/// States are ordered by version, then by status (see `MigrationStatus`), then by the remaining
+/// fields.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct MigrationState {
     /// The version of the migration
     pub version: u64,
 
     /// The status of the migration
     pub status: MigrationStatus,
+
+    /// The name of the migration, if provided by the state manager
+    pub name: Option<String>,
+
+    /// The recorded checksum, if provided by the state manager
+    ///
+    /// Either prefixed with the name of its algorithm, e.g. `sha256:...`, or a legacy
+    /// `ChangelogFile::checksum()` in decimal.
+    pub checksum: Option<String>,
+
+    /// When the version has been recorded, in the format stored by the state manager
+    pub applied_at: Option<String>,
+}
+
+impl MigrationState {
+    /// Create a state with only a version and a status
+    pub fn new(version: u64, status: MigrationStatus) -> MigrationState {
+        return MigrationState {
+            version,
+            status,
+            name: None,
+            checksum: None,
+            applied_at: None,
+        };
+    }
+
+    /// Sort states by version and keep only the greatest state of each version
+    ///
+    /// Useful when merging states from several sources: for the same version, `Deployed` wins
+    /// over `Failed`, which wins over `InProgress`.
+    pub fn dedup(mut states: Vec<MigrationState>) -> Vec<MigrationState> {
+        states.sort();
+        let mut result: Vec<MigrationState> = Vec::with_capacity(states.len());
+        for state in states {
+            match result.last_mut() {
+                Some(last) if last.version == state.version => *last = state,
+                _ => result.push(state),
+            }
+        }
+        return result;
+    }
 }
 
 /// Trait for state management
 ///
 /// This should be implemented by DB drivers so that db-up can manage installed schema versions.
+///
+/// Implementations must be `Send + Sync` because the `MigrationRunner` holds them in an `Arc`
+/// and awaits their methods from async tasks that may move between threads.
 #[async_trait]
-pub trait MigrationStateManager {
+pub trait MigrationStateManager: Send + Sync {
     /// Prepare the DB for migration state management
     ///
     /// This will be called before any other methods to ensure that the dateabase is prepared
@@ -206,6 +639,15 @@ pub trait MigrationStateManager {
     /// management table exists.
     async fn prepare(&self) -> Result<()>;
 
+    /// Check whether the state management table exists, without creating it
+    ///
+    /// Read-only methods like `MigrationRunner::has_pending` don't call `prepare()`. If this
+    /// returns `false`, nothing has been deployed yet, so they report every version as pending
+    /// instead of reading the state. The default implementation returns `true`.
+    async fn is_prepared(&self) -> Result<bool> {
+        return Ok(true);
+    }
+
     /// Get the lowest deployed version
     async fn lowest_version(&self) -> Result<Option<MigrationState>>;
 
@@ -213,34 +655,234 @@ pub trait MigrationStateManager {
     async fn highest_version(&self) -> Result<Option<MigrationState>>;
 
     /// Get a list of all deployed versions
+    ///
+    /// The `applied_at` of each state is reported by `MigrationRunner::info`, if provided.
     async fn list_versions(&self) -> Result<Vec<MigrationState>>;
 
+    /// Get everything recorded for a single version, whatever its status
+    ///
+    /// Returns `None` if the version has not been recorded. The default implementation searches
+    /// `list_versions()`, so it only finds deployed versions. State managers should override it
+    /// with a query for the single version.
+    async fn get_version(&self, version: u64) -> Result<Option<MigrationState>> {
+        return Ok(self.list_versions()
+            .await?
+            .into_iter()
+            .find(|state| state.version == version));
+    }
+
+    /// Check whether a version is recorded as deployed
+    ///
+    /// The default implementation searches `list_versions()`. State managers should override it
+    /// with a query for the single version.
+    async fn is_deployed(&self, version: u64) -> Result<bool> {
+        return Ok(self.list_versions()
+            .await?
+            .iter()
+            .any(|state| state.version == version));
+    }
+
+    /// Get the version of the database server, e.g. `14.5`
+    ///
+    /// It is checked against the `requires_db` annotation of changelogs, see
+    /// `MigrationRunner::migrate`. Leading text like `PostgreSQL ` and trailing text after the
+    /// numbers are ignored. The default implementation returns `None`, i.e. unknown.
+    async fn server_version(&self) -> Result<Option<String>> {
+        return Ok(None);
+    }
+
+    /// Get all versions whose status is `InProgress` or `Failed`
+    ///
+    /// These are versions whose changelog failed or has been interrupted. The default
+    /// implementation returns an error.
+    async fn list_problems(&self) -> Result<Vec<MigrationState>> {
+        return Err(MigrationsError::custom_message("Listing problems is not supported by this state manager.", None, None));
+    }
+
+    /// Get the checksums recorded for all deployed versions
+    ///
+    /// Returns `(version, checksum)` pairs. The checksum is `None` if it could not be read,
+    /// e.g. because the version was recorded by an older version of the driver. The default
+    /// implementation takes the checksums of `list_versions()`.
+    async fn applied_checksums(&self) -> Result<Vec<(u64, Option<String>)>> {
+        return Ok(self.list_versions()
+            .await?
+            .into_iter()
+            .map(|state| (state.version, state.checksum))
+            .collect());
+    }
+
     /// Begin a new version
     async fn begin_version(&self, changelog_file: &ChangelogFile) -> Result<()>;
 
     /// Finish a new version
     ///
-    /// This will usually just set the status of the migration version to `Deployed`
+    /// This will usually just set the status of the migration version to `Deployed`. State
+    /// managers that record checksums should record `ChangelogFile::recorded_checksum()`.
     async fn finish_version(&self, changelog_file: &ChangelogFile) -> Result<()>;
    /// Skip version while  sql fail
     async fn skip_version(&self, changelog_file: &ChangelogFile) -> Result<()>;
 
+    /// Record a deployed version whose changelog has been executed again by `MigrationRunner::rerun`
+    ///
+    /// This must replace the recorded checksum, and the deployment time if it is recorded, with
+    /// those of `changelog_file`. The default implementation calls `finish_version`, which is
+    /// enough for state managers that write the checksum there.
+    async fn finish_rerun(&self, changelog_file: &ChangelogFile) -> Result<()> {
+        return self.finish_version(changelog_file).await;
+    }
+
+    /// Record a version as deployed without its changelog having been executed
+    ///
+    /// Called by `MigrationRunner::mark_applied`. The default implementation calls
+    /// `finish_version`, which records the name and checksum of `changelog_file`.
+    async fn mark_applied(&self, changelog_file: &ChangelogFile) -> Result<()> {
+        return self.finish_version(changelog_file).await;
+    }
+
+    /// Remove all migration state, e.g. by dropping the state management table
+    ///
+    /// This is destructive and therefore only available with the `clean` feature. It is meant
+    /// for resetting databases in tests. The default implementation returns an error.
+    #[cfg(feature = "clean")]
+    async fn clean(&self) -> Result<()> {
+        return Err(MigrationsError::custom_message("Cleaning is not supported by this state manager.", None, None));
+    }
+
 }
 
 /// Trait for executing migrations
 ///
 /// This should be implemented by DB drivers so that db-up can execute migrations on the
 /// database.
+///
+/// Implementations must be `Send + Sync` for the same reasons as `MigrationStateManager`.
+/// An executor holds at most one open transaction at a time, so a single executor instance
+/// must not be shared between `MigrationRunner`s that run concurrently.
 #[async_trait]
-pub trait MigrationExecutor {
+pub trait MigrationExecutor: Send + Sync {
+    /// Whether changelogs can be executed inside a transaction (default: `true`)
+    ///
+    /// If `false`, the runner never calls `begin_transaction`, `commit_transaction` and
+    /// `rollback_transaction`. Each changelog is executed directly and a failing changelog may
+    /// leave its earlier statements applied.
+    fn supports_transactions(&self) -> bool {
+        return true;
+    }
+
+    /// Whether `finish_version_in_transaction` is supported (default: `false`)
+    fn supports_atomic_versioning(&self) -> bool {
+        return false;
+    }
+
     async fn begin_transaction(&self) -> Result<()>;
-    async fn execute_changelog_file(&self, changelog_file: &ChangelogFile) -> Result<()>;
+
+    /// Execute a single statement and return the number of affected rows
+    async fn execute_statement(&self, statement: &SqlStatement) -> Result<u64>;
+
+    /// Execute all statements of a changelog and return how many have been executed and how many
+    /// rows they affected
+    ///
+    /// The default implementation calls `execute_statement` for each statement and applies the
+    /// statement annotations: a failing statement annotated with `may_fail: true` is logged and
+    /// skipped, it is not counted and affects no rows. Note that some databases, e.g. Postgres, abort the surrounding transaction on
+    /// any error, so the following statements fail anyway. Statements are read one at a time,
+    /// so a streamed changelog is never held in memory as a whole.
+    async fn execute_changelog_file(&self, changelog_file: &ChangelogFile) -> Result<ChangelogOutcome> {
+        return execute_statements(self, changelog_file, None).await;
+    }
+
+    /// Execute all statements of a changelog like `execute_changelog_file`, but commit the open
+    /// transaction and begin a new one every `commit_every` statements
+    ///
+    /// This is called inside a transaction instead of `execute_changelog_file` if the runner has
+    /// been configured with `with_commit_every`. If a statement fails after a commit, the error
+    /// tells how many statements have been committed.
+    async fn execute_changelog_file_in_batches(&self, changelog_file: &ChangelogFile, commit_every: usize) -> Result<ChangelogOutcome> {
+        return execute_statements(self, changelog_file, Some(commit_every)).await;
+    }
+
+    /// Record a changelog as deployed inside the current transaction
+    ///
+    /// This is used instead of `MigrationStateManager::finish_version` if the runner has been
+    /// configured with `with_atomic_versioning(true)`. It must write the same state as
+    /// `finish_version`, but through the transaction opened by `begin_transaction`, so that the
+    /// changelog and its state are committed or rolled back together.
+    async fn finish_version_in_transaction(&self, _changelog_file: &ChangelogFile) -> Result<()> {
+        return Err(MigrationsError::custom_message("Atomic versioning is not supported by this executor.", None, None));
+    }
+
     async fn commit_transaction(&self) -> Result<()>;
     async fn rollback_transaction(&self) -> Result<()>;
 }
 
+/// What executing a changelog did, see `MigrationExecutor::execute_changelog_file`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChangelogOutcome {
+    /// The number of statements executed successfully
+    pub statements_executed: u64,
+    /// The number of rows affected by the statements
+    pub rows_affected: u64,
+}
+
+/// Execute the statements of a changelog, see `MigrationExecutor::execute_changelog_file`
+///
+/// With `commit_every`, the transaction is committed and a new one is begun every
+/// `commit_every` statements. Executors overriding `execute_changelog_file`, e.g. to run all
+/// statements of a changelog on the same connection, can call this for the statement loop.
+pub async fn execute_statements<E: MigrationExecutor + ?Sized>(executor: &E, changelog_file: &ChangelogFile,
+                                                           commit_every: Option<usize>) -> Result<ChangelogOutcome> {
+    log::debug!("Executing changelog file ... {} ({})", changelog_file.version(), changelog_file.name);
+    // Counting the statements reads a streamed changelog twice, so its total is not logged
+    let count = match log::log_enabled!(log::Level::Debug) && !changelog_file.is_streamed() {
+        true => changelog_file.iter().count().to_string(),
+        false => "?".to_string(),
+    };
+    let mut statements = changelog_file.iter();
+    let mut outcome = ChangelogOutcome::default();
+    let mut committed: usize = 0;
+    for (index, statement) in statements.by_ref().enumerate() {
+        log::debug!("Executing statement {}/{} of version {}", index + 1, count, changelog_file.version());
+        let may_fail = statement.annotation.as_ref()
+            .map(|annotation| annotation.may_fail())
+            .unwrap_or(false);
+        match executor.execute_statement(&statement).await {
+            Ok(rows) => {
+                outcome.statements_executed += 1;
+                outcome.rows_affected += rows;
+            },
+            Err(err) if may_fail => {
+                log::warn!("Ignoring failed statement of version {}: {}", changelog_file.version(), err);
+            },
+            Err(err) if committed > 0 => {
+                return Err(MigrationsError::custom_message(
+                    format!("Version {} failed after {} of its statements had been committed. The changelog is applied partially.",
+                            changelog_file.version(), committed).as_str(),
+                    None,
+                    Some(Box::new(err))));
+            },
+            Err(err) => return Err(err),
+        }
+        if let Some(commit_every) = commit_every.filter(|commit_every| (index + 1) % commit_every == 0) {
+            log::debug!("Committing {} statements of version {}", commit_every, changelog_file.version());
+            executor.commit_transaction().await?;
+            executor.begin_transaction().await?;
+            committed = index + 1;
+        }
+    }
+    if let Some(err) = statements.take_error() {
+        return Err(MigrationsError::custom_message(
+            format!("Could not read changelog for version {}", changelog_file.version()).as_str(),
+            None,
+            Some(Box::new(err))));
+    }
+    return Ok(outcome);
+}
+
 /// Struct for running migrations on a database
-pub struct MigrationRunner<S, M, E> {
+///
+/// The state manager and the executor may be trait objects, see `DynMigrationRunner`.
+pub struct MigrationRunner<S, M: ?Sized, E: ?Sized> {
     /// The migration store containing the changelog files
     store: S,
 
@@ -258,27 +900,586 @@ pub struct MigrationRunner<S, M, E> {
 
     /// 当变更Sql 出现错误的时候，是否继续执行后边的变更文件
     fail_continue:bool,
+
+    /// How checksums are recorded and verified
+    checksum_policy: ChecksumPolicy,
+
+    /// The algorithm of the recorded checksums
+    checksum_algorithm: Arc<dyn ChecksumAlgorithm>,
+
+    /// Whether `migrate` fails if the store contains no changelogs at all
+    require_migrations: bool,
+
+    /// Statements executed once before the changelogs of a batch
+    pre_sql: Vec<String>,
+
+    /// Statements executed once after the changelogs of a batch
+    post_sql: Vec<String>,
+
+    /// Whether versions are recorded inside the transaction of their changelog
+    atomic_versioning: bool,
+
+    /// Whether the recorded checksums of deployed changelogs are read back after migrating
+    verify_after: bool,
+
+    /// Whether all pending changelogs are parsed before the first one is executed
+    pre_validate: bool,
+
+    /// Whether `migrate` fails if deployed versions are missing from the store
+    fail_on_orphans: bool,
+
+    /// Whether `migrate` fails if the database is at a newer version than the store
+    fail_on_database_ahead: bool,
+
+    /// Whether the state manager is assumed to be prepared already
+    skip_prepare: bool,
+
+    /// Whether `rerun` may execute deployed versions again
+    allow_rerun: bool,
+
+    /// Commit the transaction of a changelog every N statements
+    commit_every: Option<usize>,
+
+    /// The version whose changelog is being deployed right now
+    current_version: Mutex<Option<u64>>,
+
+    /// Whether a transaction has been begun and not been committed or rolled back yet
+    transaction_open: Mutex<bool>,
+
+    /// Counters of all runs
+    #[cfg(feature = "metrics")]
+    metrics: Mutex<MigrationMetrics>,
 }
 
+/// A `MigrationRunner` whose driver is chosen at runtime
+///
+/// The state manager and the executor are trait objects, so e.g. the driver can be picked from
+/// a connection URL at startup:
+///
+/// ```ignore
+/// let driver: Arc<RbatisMigrationDriver> = ...;
+/// let runner: DynMigrationRunner = MigrationRunner::new(Box::new(Migrations {}), driver.clone(), driver, false);
+/// ```
+pub type DynMigrationRunner<S = Box<dyn MigrationStore + Send + Sync>> = MigrationRunner<S, dyn MigrationStateManager, dyn MigrationExecutor>;
+
 /// Struct storing the changelogs needed for the migrations
 ///
 /// Implementations of this trait will usually be generated by the `migrations` macro, but can
 /// also be created manually.
 pub trait MigrationStore {
     fn changelogs(&self) -> Vec<ChangelogFile>;
+
+    /// Get the changelogs with a version greater than `version`
+    ///
+    /// `None` returns all changelogs. The default implementation filters `changelogs()`. Stores
+    /// that keep their changelogs sorted should override this, so that versions which have
+    /// already been deployed are not even cloned.
+    fn changelogs_after(&self, version: Option<u64>) -> Vec<ChangelogFile> {
+        return self.changelogs().into_iter()
+            .filter(|changelog| version.map(|version| changelog.version() > version).unwrap_or(true))
+            .collect();
+    }
 }
 
-impl<S, M, E> MigrationRunner<S, M, E>
-    where S: MigrationStore,
-          M: MigrationStateManager,
-          E: MigrationExecutor {
+/// Convenience methods for slicing the changelogs of a `MigrationStore`
+///
+/// This is implemented for every `MigrationStore`.
+pub trait MigrationStoreExt: MigrationStore {
+    /// Get the changelogs sorted by version
+    fn sorted_changelogs(&self) -> Vec<ChangelogFile> {
+        let mut changelogs = self.changelogs();
+        changelogs.sort();
+        return changelogs;
+    }
 
-    /// Create a new `MigrationRunner`
-    pub fn new(store: S, state_manager: Arc<M>, executor: Arc<E>,fail_continue:bool) -> Self {
-        return Self {
-            store, state_manager, executor,
+    /// Get the changelogs with versions in `[min, max]`, sorted by version
+    ///
+    /// Both `min` and `max` must be versions contained in the store, otherwise a
+    /// `MinVersionNotFound` or `MaxVersionNotFound` error is returned. An empty store results in
+    /// an `EmptyChangelog` error.
+    fn changelogs_in_range(&self, min: u64, max: u64) -> ChangelogResult<Vec<ChangelogFile>> {
+        let changelogs = self.sorted_changelogs();
+        let (first, last) = match (changelogs.first(), changelogs.last()) {
+            (Some(first), Some(last)) => (first.version(), last.version()),
+            _ => return Err(ChangelogError::empty_changelog()),
+        };
+        if !changelogs.iter().any(|changelog| changelog.version() == min) {
+            return Err(ChangelogError::min_version_not_found(first.to_string().as_str(), min.to_string().as_str()));
+        }
+        if !changelogs.iter().any(|changelog| changelog.version() == max) {
+            return Err(ChangelogError::max_version_not_found(last.to_string(), max.to_string()));
+        }
+
+        return Ok(changelogs.into_iter()
+            .filter(|changelog| changelog.version() >= min && changelog.version() <= max)
+            .collect());
+    }
+}
+
+impl<T: MigrationStore + ?Sized> MigrationStoreExt for T {}
+
+impl<T: MigrationStore + ?Sized> MigrationStore for &T {
+    fn changelogs(&self) -> Vec<ChangelogFile> {
+        return (**self).changelogs();
+    }
+
+    fn changelogs_after(&self, version: Option<u64>) -> Vec<ChangelogFile> {
+        return (**self).changelogs_after(version);
+    }
+}
+
+impl<T: MigrationStore + ?Sized> MigrationStore for Arc<T> {
+    fn changelogs(&self) -> Vec<ChangelogFile> {
+        return (**self).changelogs();
+    }
+
+    fn changelogs_after(&self, version: Option<u64>) -> Vec<ChangelogFile> {
+        return (**self).changelogs_after(version);
+    }
+}
+
+impl<T: MigrationStore + ?Sized> MigrationStore for Box<T> {
+    fn changelogs(&self) -> Vec<ChangelogFile> {
+        return (**self).changelogs();
+    }
+
+    fn changelogs_after(&self, version: Option<u64>) -> Vec<ChangelogFile> {
+        return (**self).changelogs_after(version);
+    }
+}
+
+/// A `MigrationStore` holding a list of already loaded changelogs
+#[derive(Debug, Clone)]
+pub struct ChangelogMigrationStore {
+    changelogs: Vec<ChangelogFile>,
+}
+
+impl ChangelogMigrationStore {
+    /// Create a store from a list of changelogs
+    pub fn from_changelog_files(changelogs: Vec<ChangelogFile>) -> ChangelogMigrationStore {
+        return ChangelogMigrationStore {
+            changelogs,
+        };
+    }
+}
+
+impl MigrationStore for ChangelogMigrationStore {
+    fn changelogs(&self) -> Vec<ChangelogFile> {
+        return self.changelogs.clone();
+    }
+}
+
+impl<S, M, E> MigrationRunner<S, M, E>
+    where S: MigrationStore,
+          M: MigrationStateManager + ?Sized,
+          E: MigrationExecutor + ?Sized {
+
+    /// Create a new `MigrationRunner`
+    pub fn new(store: S, state_manager: Arc<M>, executor: Arc<E>,fail_continue:bool) -> Self {
+        return Self {
+            store, state_manager, executor,
             fail_continue,
+            checksum_policy: ChecksumPolicy::default(),
+            checksum_algorithm: Arc::new(Sha256),
+            require_migrations: false,
+            pre_sql: Vec::new(),
+            post_sql: Vec::new(),
+            atomic_versioning: false,
+            verify_after: false,
+            pre_validate: false,
+            fail_on_orphans: false,
+            fail_on_database_ahead: false,
+            skip_prepare: false,
+            allow_rerun: false,
+            commit_every: None,
+            current_version: Mutex::new(None),
+            transaction_open: Mutex::new(false),
+            #[cfg(feature = "metrics")]
+            metrics: Mutex::new(MigrationMetrics::default()),
+        };
+    }
+
+    /// Create a runner for another database, sharing the store and configuration of this one
+    ///
+    /// This is meant for applying the same migrations to many databases, e.g. one per tenant,
+    /// without creating a new store for each of them:
+    ///
+    /// ```ignore
+    /// for driver in tenant_drivers {
+    ///     runner.for_target(driver.clone(), driver.clone()).migrate().await?;
+    /// }
+    /// ```
+    pub fn for_target<M2, E2>(&self, state_manager: Arc<M2>, executor: Arc<E2>) -> MigrationRunner<&S, M2, E2>
+        where M2: MigrationStateManager + ?Sized,
+              E2: MigrationExecutor + ?Sized {
+        return MigrationRunner {
+            store: &self.store,
+            state_manager,
+            executor,
+            fail_continue: self.fail_continue,
+            checksum_policy: self.checksum_policy,
+            checksum_algorithm: self.checksum_algorithm.clone(),
+            require_migrations: self.require_migrations,
+            pre_sql: self.pre_sql.clone(),
+            post_sql: self.post_sql.clone(),
+            atomic_versioning: self.atomic_versioning,
+            verify_after: self.verify_after,
+            pre_validate: self.pre_validate,
+            fail_on_orphans: self.fail_on_orphans,
+            fail_on_database_ahead: self.fail_on_database_ahead,
+            skip_prepare: self.skip_prepare,
+            allow_rerun: self.allow_rerun,
+            commit_every: self.commit_every,
+            current_version: Mutex::new(None),
+            transaction_open: Mutex::new(false),
+            #[cfg(feature = "metrics")]
+            metrics: Mutex::new(MigrationMetrics::default()),
+        };
+    }
+
+    /// Migrate another database with the store and configuration of this runner
+    ///
+    /// Shorthand for `for_target(state_manager, executor).migrate()`.
+    pub async fn migrate_with<M2, E2>(&self, state_manager: Arc<M2>, executor: Arc<E2>) -> Result<Option<u64>>
+        where M2: MigrationStateManager + ?Sized,
+              E2: MigrationExecutor + ?Sized {
+        return self.for_target(state_manager, executor).migrate().await;
+    }
+
+    /// Migrate many databases with the store and configuration of this runner
+    ///
+    /// At most `concurrency` targets are migrated at the same time (at least one). The targets
+    /// are isolated from each other: a failing target does not abort the others. The result of
+    /// each target is returned with its index in `targets`, in the order of `targets`.
+    pub async fn migrate_all<M2, E2>(&self, targets: Vec<(Arc<M2>, Arc<E2>)>, concurrency: usize) -> Vec<(usize, Result<Option<u64>>)>
+        where M2: MigrationStateManager + ?Sized,
+              E2: MigrationExecutor + ?Sized {
+        let mut results: Vec<(usize, Result<Option<u64>>)> = futures::stream::iter(targets.into_iter().enumerate())
+            .map(|(index, (state_manager, executor))| async move {
+                let result = self.migrate_with(state_manager, executor).await;
+                if let Err(err) = &result {
+                    log::error!("Migrating target {} failed: {}", index, err);
+                }
+                (index, result)
+            })
+            .buffer_unordered(concurrency.max(1))
+            .collect()
+            .await;
+        results.sort_by_key(|(index, _)| *index);
+        return results;
+    }
+
+    /// Set the `ChecksumPolicy` used by `migrate` (default: `ChecksumPolicy::Record`)
+    pub fn with_checksum_policy(mut self, checksum_policy: ChecksumPolicy) -> Self {
+        self.checksum_policy = checksum_policy;
+        return self;
+    }
+
+    /// Set the algorithm of the checksums recorded for deployed versions (default: `Sha256`)
+    ///
+    /// Recorded checksums are prefixed with the name of their algorithm and verified with that
+    /// algorithm, so changing it does not fail the verification of versions deployed before.
+    /// Checksums without a prefix have been recorded by older releases and are verified against
+    /// `ChangelogFile::checksum()`. A custom algorithm must have a name different from `crc32`
+    /// and `sha256`.
+    pub fn with_checksum_algorithm(mut self, checksum_algorithm: Arc<dyn ChecksumAlgorithm>) -> Self {
+        self.checksum_algorithm = checksum_algorithm;
+        return self;
+    }
+
+    /// Set statements to execute once before the changelogs of each `migrate` run
+    ///
+    /// The statements are executed through the `MigrationExecutor` inside their own transaction,
+    /// e.g. for creating extensions the migrations depend on. If one of them fails, the
+    /// migration aborts before any changelog is executed. Nothing is executed if there is no
+    /// pending changelog.
+    ///
+    /// The statements don't necessarily share a session with the changelogs. With
+    /// `flyway-rbatis`, their transaction runs on a connection of the pool and each changelog
+    /// acquires its own, so session settings like `SET search_path` don't carry over.
+    pub fn with_pre_sql(mut self, pre_sql: Vec<String>) -> Self {
+        self.pre_sql = pre_sql;
+        return self;
+    }
+
+    /// Set statements to execute once after the changelogs of each `migrate` run
+    ///
+    /// The statements are executed inside their own transaction, but only if all changelogs
+    /// have been deployed (or skipped because of `fail_continue`), and not if there was no
+    /// pending changelog.
+    pub fn with_post_sql(mut self, post_sql: Vec<String>) -> Self {
+        self.post_sql = post_sql;
+        return self;
+    }
+
+    /// Record each version inside the transaction of its changelog (default: `false`)
+    ///
+    /// By default, the state manager records a version after the transaction of its changelog
+    /// has been committed. If the process dies in between, the changelog is applied but not
+    /// recorded and will run again. With atomic versioning, the executor records the version via
+    /// `finish_version_in_transaction` before committing, so both happen or neither does.
+    /// `begin_version` and `finish_version` of the state manager are not called then.
+    ///
+    /// This needs an executor that supports transactions and atomic versioning, otherwise
+    /// deploying a changelog fails before anything is executed.
+    pub fn with_atomic_versioning(mut self, atomic_versioning: bool) -> Self {
+        self.atomic_versioning = atomic_versioning;
+        return self;
+    }
+
+    /// Read back the checksums of the versions deployed by `migrate` (default: `false`)
+    ///
+    /// After all changelogs have been deployed, the checksum recorded for each of them is
+    /// compared with the checksum of its changelog, and a `ChecksumMismatch` error is returned if
+    /// they differ. This catches state managers that truncate or mangle the checksum when writing
+    /// it. Nothing is verified with `ChecksumPolicy::Off`, as no checksums are recorded then.
+    pub fn with_verify_after(mut self, verify_after: bool) -> Self {
+        self.verify_after = verify_after;
+        return self;
+    }
+
+    /// Make `migrate` fail with an `EmptyChangelog` error if the store is empty (default: `false`)
+    ///
+    /// An empty store usually means that the migrations have been loaded from the wrong
+    /// directory. Without this flag, `migrate` succeeds without doing anything in that case.
+    pub fn with_require_migrations(mut self, require_migrations: bool) -> Self {
+        self.require_migrations = require_migrations;
+        return self;
+    }
+
+    /// Parse all pending changelogs before executing any of them (default: `false`)
+    ///
+    /// If a changelog cannot be split into statements, e.g. because of an unterminated quote,
+    /// nothing is executed and an error caused by an `InvalidSql` `ChangelogError` is returned.
+    /// Without this, the changelogs before the broken one would be deployed.
+    pub fn with_pre_validate(mut self, pre_validate: bool) -> Self {
+        self.pre_validate = pre_validate;
+        return self;
+    }
+
+    /// Do not call `MigrationStateManager::prepare` before accessing the state (default: `false`)
+    ///
+    /// Preparing usually issues DDL like `CREATE TABLE IF NOT EXISTS` on every run, which adds
+    /// latency where each query counts, e.g. in serverless functions. Only skip it if the
+    /// migrations table is known to exist: on a fresh database, reading the state fails and
+    /// nothing is deployed.
+    pub fn with_skip_prepare(mut self, skip_prepare: bool) -> Self {
+        self.skip_prepare = skip_prepare;
+        return self;
+    }
+
+    /// Allow `rerun` to execute deployed versions again (default: `false`)
+    ///
+    /// Only set this in development, see `rerun`.
+    pub fn with_allow_rerun(mut self, allow_rerun: bool) -> Self {
+        self.allow_rerun = allow_rerun;
+        return self;
+    }
+
+    /// Commit the transaction of a changelog every `commit_every` statements (default: `None`)
+    ///
+    /// This keeps huge changelogs, e.g. bulk data loads, from holding their locks and undo log
+    /// until the end, at the cost of atomicity: if a statement fails, only the statements since
+    /// the last commit are rolled back, so the changelog stays partially applied and its version
+    /// is not recorded as deployed. The error then tells how many statements have been committed.
+    /// Only changelogs executed inside a transaction are affected.
+    pub fn with_commit_every(mut self, commit_every: Option<usize>) -> Self {
+        self.commit_every = commit_every.filter(|commit_every| *commit_every > 0);
+        return self;
+    }
+
+    /// Prepare the state manager, unless `with_skip_prepare(true)` has been set
+    async fn prepare(&self) -> Result<()> {
+        if self.skip_prepare {
+            return Ok(());
+        }
+        return self.state_manager.prepare().await;
+    }
+
+    /// Get the version whose changelog is being deployed right now
+    ///
+    /// This is `None` while no changelog is deployed, i.e. before and after a run and between
+    /// two changelogs. It is meant for polling the progress of a long migration, e.g. from a
+    /// monitoring task sharing the runner. Runners created by `for_target` track their own
+    /// version.
+    pub fn current_version(&self) -> Option<u64> {
+        return *self.current_version.lock().unwrap();
+    }
+
+    /// Make `migrate` fail with an `OrphanedVersions` error if deployed versions are missing
+    /// from the store (default: `false`)
+    ///
+    /// The check happens before anything is executed, see `orphans()`.
+    pub fn with_fail_on_orphans(mut self, fail_on_orphans: bool) -> Self {
+        self.fail_on_orphans = fail_on_orphans;
+        return self;
+    }
+
+    /// Make `migrate` fail with a `DatabaseAheadOfStore` error if the highest deployed version
+    /// is newer than every changelog of the store (default: `false`)
+    ///
+    /// By default, `migrate` finds nothing to do in that case and succeeds. An empty store is
+    /// not checked, see `with_require_migrations` for that.
+    pub fn with_fail_on_database_ahead(mut self, fail_on_database_ahead: bool) -> Self {
+        self.fail_on_database_ahead = fail_on_database_ahead;
+        return self;
+    }
+
+    /// Get the deployed versions whose changelogs are not part of the store
+    ///
+    /// Such orphans usually mean that a migration file has been deleted by accident or that the
+    /// database has been migrated from a diverged branch. `info()` reports them as `Missing`.
+    pub async fn orphans(&self) -> Result<Vec<MigrationState>> {
+        self.prepare().await?;
+        return self.find_orphans().await;
+    }
+
+    /// Get all versions that are in progress or failed, sorted by version
+    ///
+    /// A version is in progress if its changelog has been interrupted, or if it is being
+    /// deployed by another runner right now. Failed versions have been skipped by a runner that
+    /// continues on failures.
+    pub async fn problems(&self) -> Result<Vec<MigrationState>> {
+        self.prepare().await?;
+        return Ok(MigrationState::dedup(self.state_manager.list_problems().await?));
+    }
+
+    /// Get the deployed versions that are not part of the store, without preparing
+    async fn find_orphans(&self) -> Result<Vec<MigrationState>> {
+        let versions: HashSet<u64> = self.store.changelogs().iter()
+            .map(|changelog| changelog.version())
+            .collect();
+        return Ok(MigrationState::dedup(self.state_manager.list_versions().await?)
+            .into_iter()
+            .filter(|state| !versions.contains(&state.version))
+            .collect());
+    }
+
+    /// Verify the recorded checksums of all deployed versions against the changelog files
+    ///
+    /// Versions without a recorded checksum (or with a checksum of `0`) and versions that are
+    /// not part of the store are ignored.
+    pub async fn validate(&self) -> Result<()> {
+        self.prepare().await?;
+        let changelogs = self.store.changelogs();
+        return self.verify_checksums(&changelogs).await;
+    }
+
+    /// Compare the recorded checksums with the checksums of the given changelogs
+    async fn verify_checksums(&self, changelogs: &[ChangelogFile]) -> Result<()> {
+        let applied_checksums = self.state_manager.applied_checksums().await?;
+        let mut recorded_names: Option<HashMap<u64, String>> = None;
+        for (version, recorded_checksum) in applied_checksums {
+            let recorded_checksum = match recorded_checksum {
+                Some(checksum) if checksum.trim() != "0" && !checksum.trim().is_empty() => checksum,
+                _ => continue,
+            };
+            let changelog = changelogs.iter().find(|changelog| changelog.version() == version);
+            if let Some(changelog) = changelog {
+                load_changelog(changelog)?;
+                let changelog_checksum = self.changelog_checksum(changelog, recorded_checksum.as_str())?;
+                if changelog_checksum == recorded_checksum.trim() {
+                    continue;
+                }
+                // Legacy checksums hash the name, which older releases parsed differently
+                if !recorded_checksum.contains(':') {
+                    if recorded_names.is_none() {
+                        recorded_names = Some(self.state_manager.list_versions()
+                            .await?
+                            .into_iter()
+                            .filter_map(|state| state.name.map(|name| (state.version, name)))
+                            .collect());
+                    }
+                    let legacy_name = recorded_names.as_ref()
+                        .and_then(|names| names.get(&version))
+                        .filter(|name| **name != changelog.name && legacy_identity(version, name.as_str()).is_some_and(|(_, parsed)| parsed == changelog.name));
+                    if let Some(legacy_name) = legacy_name {
+                        let legacy_checksum = ChangelogFile::from_string(version, legacy_name.as_str(), changelog.load()?)?.checksum()?;
+                        if legacy_checksum.to_string() == recorded_checksum.trim() {
+                            continue;
+                        }
+                    }
+                }
+                return Err(MigrationsError::checksum_mismatch(version, recorded_checksum, changelog_checksum));
+            }
+        }
+        return Ok(());
+    }
+
+    /// Fail if a pending changelog has been deployed under the version older releases parsed from its filename
+    ///
+    /// Older releases split filenames at the first `_`, so `V20240115_1200__add_users.sql` was
+    /// recorded as version `20240115` named `1200__add_users`. It is version `202401151200` now,
+    /// and would be deployed a second time.
+    async fn check_legacy_versions(&self, pending: &[ChangelogFile]) -> Result<()> {
+        if pending.is_empty() {
+            return Ok(());
+        }
+        for state in self.state_manager.list_versions().await? {
+            let recorded_name = match state.name.as_deref() {
+                Some(name) => name,
+                None => continue,
+            };
+            let (version, name) = match legacy_identity(state.version, recorded_name) {
+                Some((version, name)) if version != state.version => (version, name),
+                _ => continue,
+            };
+            if pending.iter().any(|changelog| changelog.version() == version && changelog.name == name) {
+                return Err(MigrationsError::custom_message(
+                    format!("Version {} has been deployed as version {} named `{}` by an older release, which split `V{}_{}.sql` at the first `_`. \
+                             Set the version of that row of the migrations table to {} and its name to `{}`, and clear its checksum, to keep it from being deployed again.",
+                            version, state.version, recorded_name, state.version, recorded_name, version, name).as_str(), None, None));
+            }
+        }
+        return Ok(());
+    }
+
+    /// Compute the checksum of a changelog with the algorithm a recorded checksum has been computed with
+    fn changelog_checksum(&self, changelog: &ChangelogFile, recorded_checksum: &str) -> Result<String> {
+        let name = match recorded_checksum.trim().split_once(':') {
+            Some((name, _)) => name,
+            None => return Ok(changelog.checksum()?.to_string()),
         };
+        if name == self.checksum_algorithm.name() {
+            return Ok(changelog.checksum_with(self.checksum_algorithm.as_ref())?);
+        }
+        let algorithm: &dyn ChecksumAlgorithm = match name {
+            "crc32" => &Crc32,
+            "sha256" => &Sha256,
+            _ => return Err(MigrationsError::custom_message(
+                format!("Version {} has been recorded with the unknown checksum algorithm {}.", changelog.version(), name).as_str(), None, None)),
+        };
+        return Ok(changelog.checksum_with(algorithm)?);
+    }
+
+    /// Prepare a changelog for being recorded by the state manager, according to the checksum policy
+    fn for_recording(&self, changelog: &ChangelogFile) -> ChangelogFile {
+        return match self.checksum_policy {
+            ChecksumPolicy::Off => changelog.without_checksum(),
+            _ => changelog.with_checksum_algorithm(self.checksum_algorithm.clone()),
+        };
+    }
+
+    /// Check that the given changelogs have been recorded with their checksums
+    async fn verify_recorded_checksums(&self, changelogs: &[ChangelogFile]) -> Result<()> {
+        let applied_checksums = self.state_manager.applied_checksums().await?;
+        for changelog in changelogs {
+            let recorded_checksum = applied_checksums.iter()
+                .find(|(version, _)| *version == changelog.version())
+                .map(|(_, checksum)| checksum.clone().unwrap_or_default());
+            let changelog_checksum = changelog.recorded_checksum()?;
+            match recorded_checksum {
+                Some(checksum) if checksum.trim() == changelog_checksum => {},
+                Some(checksum) => {
+                    return Err(MigrationsError::checksum_mismatch(changelog.version(), checksum, changelog_checksum));
+                },
+                None => {
+                    return Err(MigrationsError::custom_message(
+                        format!("Version {} has been deployed, but not recorded.", changelog.version()).as_str(), None, None));
+                }
+            }
+        }
+        return Ok(());
     }
 
     /// Migrate with a separate transaction for each changelog
@@ -287,59 +1488,648 @@ impl<S, M, E> MigrationRunner<S, M, E>
     /// occurs and the method returns prematurely, all versions that have been successfully
     /// deployed will stay in the database.
     pub async fn migrate(&self) -> Result<Option<u64>> {
-        self.state_manager.prepare().await?;
-        let mut current_highest_version = self.state_manager.highest_version()
+        return self.migrate_with_summary()
+            .await
+            .map(|summary| summary.highest_version);
+    }
+
+    /// Migrate like `migrate`, but return which versions have been deployed and which failed
+    ///
+    /// Only useful with `fail_continue`: a failing changelog is rolled back, its version is
+    /// recorded as failed via `skip_version` and the later versions are still deployed, e.g. for
+    /// best-effort maintenance migrations. Errors of the state manager, of committing or of rolling
+    /// back are returned as errors anyway. Without `fail_continue`, the first failure is returned
+    /// as an error, so `failed` is always empty.
+    pub async fn migrate_with_summary(&self) -> Result<MigrationSummary> {
+        return self.migrate_selected(&is_pending, true).await;
+    }
+
+    /// Migrate like `migrate`, but let `predicate` decide which changelogs are pending
+    ///
+    /// The predicate is called for every changelog of the store with the highest version
+    /// deployed before the run, e.g. to skip a known-broken version:
+    ///
+    /// ```ignore
+    /// runner.migrate_filtered(|changelog, highest| is_pending(changelog, highest) && changelog.version() != 7).await?;
+    /// ```
+    ///
+    /// The selected changelogs are always deployed in ascending version order. The caller is
+    /// responsible for the ordering semantics: changelogs selected below the highest deployed
+    /// version are deployed out of order, and already deployed versions must not be selected.
+    pub async fn migrate_filtered<F>(&self, predicate: F) -> Result<Option<u64>>
+        where F: Fn(&ChangelogFile, Option<u64>) -> bool + Send + Sync {
+        return self.migrate_selected(&predicate, false)
+            .await
+            .map(|summary| summary.highest_version);
+    }
+
+    /// Deploy the changelogs selected by `predicate` and add the run to the metrics
+    async fn migrate_selected<F>(&self, predicate: &F, only_newer: bool) -> Result<MigrationSummary>
+        where F: Fn(&ChangelogFile, Option<u64>) -> bool + Send + Sync {
+        #[cfg(feature = "metrics")]
+        let started = std::time::Instant::now();
+        let result = self.migrate_pending(predicate, only_newer).await;
+        #[cfg(feature = "metrics")]
+        self.record_run(started, &result);
+        return result;
+    }
+
+    /// Get the counters of all migrations run by this runner
+    ///
+    /// `migrate`, `migrate_with_summary` and `migrate_range` are counted, including runs that
+    /// failed. Runners created by `for_target` count their own migrations.
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> MigrationMetrics {
+        return self.metrics.lock().unwrap().clone();
+    }
+
+    /// Add a finished run to the metrics
+    #[cfg(feature = "metrics")]
+    fn record_run(&self, started: std::time::Instant, result: &Result<MigrationSummary>) {
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.runs += 1;
+        metrics.total_duration += started.elapsed();
+        if let Err(err) = result {
+            metrics.last_error = Some(err.to_info());
+        }
+    }
+
+    /// Deploy the pending changelogs, see `migrate_with_summary`
+    ///
+    /// With `only_newer`, the predicate never selects a changelog older than the highest
+    /// deployed version, so those are not taken from the store unless needed.
+    async fn migrate_pending<F>(&self, predicate: &F, only_newer: bool) -> Result<MigrationSummary>
+        where F: Fn(&ChangelogFile, Option<u64>) -> bool + Send + Sync {
+        if self.require_migrations && self.store.changelogs_after(None).is_empty() {
+            return Err(MigrationsError::custom_message("Migration store is empty.", None,
+                                                       Some(Box::new(ChangelogError::empty_changelog()))));
+        }
+        self.prepare().await?;
+        if self.fail_on_orphans {
+            let orphans = self.find_orphans().await?;
+            if !orphans.is_empty() {
+                return Err(MigrationsError::orphaned_versions(orphans.iter().map(|state| state.version).collect()));
+            }
+        }
+        let highest_version = self.state_manager.highest_version()
             .await?
             .map(|state| state.version);
-        let mut migrations: Vec<ChangelogFile> = self.store.changelogs().into_iter()
-            .filter(|migration| {
-                let version: u64 = migration.version();
-                return current_highest_version.map(|highest_version| version > highest_version)
-                    .or(Some(true))
-                    .unwrap();
-            })
+        if let (true, Some(db_version)) = (self.fail_on_database_ahead, highest_version) {
+            // The store is only scanned as a whole if nothing is pending
+            if self.store.changelogs_after(Some(db_version)).is_empty() {
+                let store_max = self.store.changelogs().iter().map(|changelog| changelog.version()).max();
+                if let Some(store_max) = store_max.filter(|store_max| *store_max < db_version) {
+                    return Err(MigrationsError::database_ahead_of_store(db_version, store_max));
+                }
+            }
+        }
+        // Without checksum verification only the pending changelogs are needed, so deployed
+        // versions are neither cloned nor loaded
+        let changelogs = match (&self.checksum_policy, only_newer) {
+            (ChecksumPolicy::Verify, _) | (_, false) => self.store.changelogs(),
+            _ => self.store.changelogs_after(highest_version),
+        };
+        return self.deploy_changelogs(changelogs, highest_version, predicate).await;
+    }
+
+    /// Migrate like `migrate`, but abort if migrating takes longer than `timeout`
+    ///
+    /// When the time is up, the migration stops at its current step and the open transaction,
+    /// if any, is rolled back. The returned `Timeout` error holds the highest version deployed
+    /// until then as `last_successful_version`, also if the rollback fails. Changelogs executed
+    /// without a transaction may be left partially applied.
+    #[cfg(feature = "timeout")]
+    pub async fn migrate_with_timeout(&self, timeout: Duration) -> Result<Option<u64>> {
+        if let Ok(result) = tokio::time::timeout(timeout, self.migrate()).await {
+            return result;
+        }
+        log::error!("Migration did not finish within {:?}, aborting ...", timeout);
+        return Err(self.abort(|last_successful_version| MigrationsError::timeout(timeout, last_successful_version)).await);
+    }
+
+    /// Migrate like `migrate`, but stop when `token` is cancelled, e.g. on Ctrl-C
+    ///
+    /// Cancelling stops the migration at its next step and rolls back the open transaction, if
+    /// any, like `migrate_with_timeout` does. The returned `Cancelled` error holds the highest
+    /// version deployed until then as `last_successful_version`. A statement that has already
+    /// been sent to the database may still complete on the server. Changelogs executed without
+    /// a transaction may be left partially applied. The version being deployed is left
+    /// `in_progress` whether its changelog runs inside a transaction or not, see `problems()`,
+    /// because `begin_version` is recorded outside of that transaction. With
+    /// `with_atomic_versioning(true)`, nothing is recorded for a cancelled transactional changelog.
+    #[cfg(feature = "cancellation")]
+    pub async fn migrate_with_cancellation(&self, token: CancellationToken) -> Result<Option<u64>> {
+        let migrate = std::pin::pin!(self.migrate());
+        let cancelled = std::pin::pin!(token.cancelled());
+        if let futures::future::Either::Left((result, _)) = futures::future::select(migrate, cancelled).await {
+            return result;
+        }
+        log::error!("Migration has been cancelled, aborting ...");
+        return Err(self.abort(MigrationsError::cancelled).await);
+    }
+
+    /// Roll back the open transaction of a migration that has been stopped
+    ///
+    /// `err` creates the error to return from the highest deployed version. Nothing is rolled
+    /// back if the migration has been stopped between two transactions. A failing rollback is
+    /// only logged, so that `err` is returned in any case.
+    #[cfg(any(feature = "timeout", feature = "cancellation"))]
+    async fn abort<F: FnOnce(Option<u64>) -> MigrationsError>(&self, err: F) -> MigrationsError {
+        let last_successful_version = self.state_manager.highest_version()
+            .await
+            .unwrap_or(None)
+            .map(|state| state.version);
+        let err = err(last_successful_version);
+        let transaction_open = std::mem::replace(&mut *self.transaction_open.lock().unwrap(), false);
+        if transaction_open {
+            if let Err(rollback_err) = self.executor.rollback_transaction().await {
+                log::error!("Rolling back the stopped migration failed: {}", rollback_err);
+            }
+        }
+        return err;
+    }
+
+    /// Get the changelogs `migrate` would deploy, without executing anything
+    ///
+    /// This is read-only: the state manager is not prepared, and if its table does not exist
+    /// yet, every version is pending.
+    pub async fn plan(&self) -> Result<MigrationPlan> {
+        let current_version = self.read_highest_version().await?;
+        let mut changelogs = self.store.changelogs_after(current_version);
+        changelogs.sort();
+        let pending = changelogs.iter()
+            .map(|changelog| MigrationInfoRow::from_changelog(changelog, MigrationInfoStatus::Pending, Some(changelog.checksum_with(self.checksum_algorithm.as_ref())?), None))
+            .collect::<Result<Vec<MigrationInfoRow>>>()?;
+        return Ok(MigrationPlan {
+            current_version,
+            pending,
+        });
+    }
+
+    /// Get the status of all versions, deployed or not, sorted by version
+    ///
+    /// Like `plan`, this does not prepare the state manager.
+    pub async fn info(&self) -> Result<Vec<MigrationInfoRow>> {
+        let (mut applied, versions) = match self.state_manager.is_prepared().await? {
+            true => (self.state_manager.applied_checksums().await?, self.state_manager.list_versions().await?),
+            false => (Vec::new(), Vec::new()),
+        };
+        let applied_at: HashMap<u64, String> = versions
+            .into_iter()
+            .filter_map(|state| state.applied_at.map(|applied_at| (state.version, applied_at)))
+            .collect();
+        let mut rows: Vec<MigrationInfoRow> = Vec::new();
+        for changelog in self.store.sorted_changelogs() {
+            let recorded = applied.iter()
+                .position(|(version, _)| *version == changelog.version())
+                .map(|index| applied.remove(index));
+            let row = match recorded {
+                Some((_, checksum)) => MigrationInfoRow::from_changelog(&changelog, MigrationInfoStatus::Deployed, checksum,
+                                                                        applied_at.get(&changelog.version()).cloned())?,
+                None => MigrationInfoRow::from_changelog(&changelog, MigrationInfoStatus::Pending, Some(changelog.checksum_with(self.checksum_algorithm.as_ref())?), None)?,
+            };
+            rows.push(row);
+        }
+        for (version, checksum) in applied {
+            rows.push(MigrationInfoRow {
+                version,
+                name: None,
+                status: MigrationInfoStatus::Missing,
+                checksum,
+                applied_at: applied_at.get(&version).cloned(),
+                statement_count: None,
+            });
+        }
+        rows.sort_by_key(|row| row.version);
+        return Ok(rows);
+    }
+
+    /// Check whether the store contains versions newer than the highest deployed version
+    ///
+    /// This is meant for readiness probes and CI checks. Only the state table is queried, the
+    /// content of the changelogs is never read. The state manager is not prepared; if its table
+    /// does not exist yet, every version is pending.
+    pub async fn has_pending(&self) -> Result<bool> {
+        let highest_version = self.read_highest_version().await?;
+        return Ok(!self.store.changelogs_after(highest_version).is_empty());
+    }
+
+    /// Get the deployed versions without preparing the state manager
+    ///
+    /// Returns no versions if the state table does not exist yet, see `MigrationStateManager::is_prepared`.
+    async fn read_versions(&self) -> Result<Vec<MigrationState>> {
+        if !self.state_manager.is_prepared().await? {
+            return Ok(Vec::new());
+        }
+        return self.state_manager.list_versions().await;
+    }
+
+    /// Get the highest deployed version without preparing the state manager
+    ///
+    /// Returns `None` if the state table does not exist yet, see `MigrationStateManager::is_prepared`.
+    async fn read_highest_version(&self) -> Result<Option<u64>> {
+        if !self.state_manager.is_prepared().await? {
+            return Ok(None);
+        }
+        return Ok(self.state_manager.highest_version()
+            .await?
+            .map(|state| state.version));
+    }
+
+    /// Fail with a `PendingMigrations` error if the store contains versions that have not been deployed
+    ///
+    /// Like `has_pending`, but the error lists the pending versions, e.g. for refusing to start
+    /// an application against an outdated schema. Unlike `has_pending`, versions below the
+    /// highest deployed one are reported as well if they have never been deployed, e.g. because
+    /// they were added out of order or failed with `fail_continue`. Nothing is executed, the
+    /// state manager is not prepared and the content of the changelogs is never read.
+    pub async fn assert_up_to_date(&self) -> Result<()> {
+        let deployed: HashSet<u64> = self.read_versions()
+            .await?
+            .iter()
+            .map(|state| state.version)
+            .collect();
+        let mut pending: Vec<u64> = self.store.changelogs_after(None).iter()
+            .map(|changelog| changelog.version())
+            .filter(|version| !deployed.contains(version))
+            .collect();
+        if pending.is_empty() {
+            return Ok(());
+        }
+        pending.sort();
+        return Err(MigrationsError::pending_migrations(pending));
+    }
+
+    /// Migrate only the versions in `[min, max]`
+    ///
+    /// Works like `migrate`, but ignores all changelogs outside of the given range. Both `min`
+    /// and `max` must be versions contained in the store, otherwise an error caused by a
+    /// `MinVersionNotFound` or `MaxVersionNotFound` `ChangelogError` is returned before
+    /// anything is executed. Checksums are only verified for the changelogs inside the range.
+    pub async fn migrate_range(&self, min: u64, max: u64) -> Result<Option<u64>> {
+        #[cfg(feature = "metrics")]
+        let started = std::time::Instant::now();
+        let result = self.migrate_range_pending(min, max).await;
+        #[cfg(feature = "metrics")]
+        self.record_run(started, &result);
+        return result.map(|summary| summary.highest_version);
+    }
+
+    /// Deploy the pending changelogs in `[min, max]`, see `migrate_range`
+    async fn migrate_range_pending(&self, min: u64, max: u64) -> Result<MigrationSummary> {
+        let changelogs = self.store.changelogs_in_range(min, max)
+            .or_else(|err| Err(MigrationsError::custom_message(
+                format!("Cannot migrate version range [{}, {}].", min, max).as_str(), None, Some(Box::new(err)))))?;
+        self.prepare().await?;
+        let highest_version = self.state_manager.highest_version()
+            .await?
+            .map(|state| state.version);
+        return self.deploy_changelogs(changelogs, highest_version, &is_pending).await;
+    }
+
+    /// Deploy all given changelogs selected by `predicate`, see `is_pending`
+    async fn deploy_changelogs<F>(&self, changelogs: Vec<ChangelogFile>, highest_version: Option<u64>, predicate: &F) -> Result<MigrationSummary>
+        where F: Fn(&ChangelogFile, Option<u64>) -> bool + Send + Sync {
+        let mut current_highest_version = highest_version;
+        if self.checksum_policy == ChecksumPolicy::Verify {
+            log::debug!("Verifying checksums ...");
+            self.verify_checksums(&changelogs).await?;
+        }
+        let mut migrations: Vec<ChangelogFile> = changelogs.into_iter()
+            .filter(|migration| predicate(migration, highest_version))
             .collect::<Vec<ChangelogFile>>();
         log::debug!("Sorting migrations ...");
         migrations.sort_by(|a, b| a.version().cmp(&b.version()));
         let migrations = migrations;
+        self.check_legacy_versions(&migrations).await?;
+        if migrations.is_empty() {
+            log::debug!("No pending migrations");
+            return Ok(MigrationSummary {
+                highest_version: current_highest_version,
+                deployed: Vec::new(),
+                rows_affected: Vec::new(),
+                failed: Vec::new(),
+            });
+        }
+
+        if self.pre_validate {
+            log::debug!("Validating pending changelogs ...");
+            for changelog in migrations.iter() {
+                changelog.validate()
+                    .or_else(|err| Err(MigrationsError::custom_message(
+                        format!("Changelog of version {} is invalid.", changelog.version()).as_str(), None, Some(Box::new(err)))))?;
+            }
+        }
+
+        self.execute_batch_sql("pre_sql", &self.pre_sql).await?;
 
         log::debug!("Running migrations ... {:?}", &migrations);
+        let mut deployed: Vec<ChangelogFile> = Vec::new();
+        let mut failed: Vec<(u64, MigrationsErrorInfo)> = Vec::new();
+        let mut rows_affected: Vec<(u64, u64)> = Vec::new();
         for changelog in migrations.into_iter() {
             let version: u64 = changelog.version();
-
-            self.state_manager.begin_version(&changelog).await?;
-            self.executor.begin_transaction().await?;
-            let result = self.executor
-                .execute_changelog_file(&changelog)
-                .await;
+            let result = self.apply_changelog(&changelog).await;
 
             match result {
-                Ok(_) => {
-                    self.executor.commit_transaction().await?;
-                    self.state_manager.finish_version(&changelog).await?;
-                    current_highest_version = Some(version);
+                Ok(outcome) => {
+                    log::info!("Deployed version {}, {} rows affected", version, outcome.rows_affected);
+                    current_highest_version = current_highest_version.max(Some(version));
+                    rows_affected.push((version, outcome.rows_affected));
+                    #[cfg(feature = "metrics")]
+                    {
+                        let mut metrics = self.metrics.lock().unwrap();
+                        metrics.migrations_applied += 1;
+                        metrics.statements_executed += outcome.statements_executed;
+                        metrics.rows_affected += outcome.rows_affected;
+                    }
+                    deployed.push(self.for_recording(&changelog));
+                },
+                // Only a failing changelog is skipped, errors of the state manager or of committing abort
+                Err(ApplyError::Changelog(err)) if self.fail_continue => {
+                    log::error!("Migration Fail but fail_continue is set true,will continue to execute");
+                    log::error!("Version {} failed: {}", version, err);
+                    self.state_manager.skip_version(&self.for_recording(&changelog)).await?;
+                    current_highest_version = current_highest_version.max(Some(version));
+                    #[cfg(feature = "metrics")]
+                    {
+                        let mut metrics = self.metrics.lock().unwrap();
+                        metrics.migrations_failed += 1;
+                        metrics.last_error = Some(err.to_info());
+                    }
+                    failed.push((version, err.to_info()));
                 },
                 Err(err) => {
-                    let _result = self.executor.rollback_transaction().await
-                        .or::<MigrationsError>(Ok(()))
-                        .unwrap();
-                    if self.fail_continue {
-                        log::error!("Migration Fail but fail_continue is set true,will continue to execute");
-                        self.state_manager.skip_version(&changelog).await?;
-                        current_highest_version = Some(version);
-                    }else {
-                        return Err(err);
+                    return Err(err.into());
+                }
+            }
+        }
+
+        self.execute_batch_sql("post_sql", &self.post_sql).await?;
+
+        if self.verify_after && self.checksum_policy != ChecksumPolicy::Off {
+            log::debug!("Verifying recorded checksums ...");
+            self.verify_recorded_checksums(&deployed).await?;
+        }
+        return Ok(MigrationSummary {
+            highest_version: current_highest_version,
+            deployed: deployed.iter().map(|changelog| changelog.version()).collect(),
+            rows_affected,
+            failed,
+        });
+    }
+
+    /// Execute statements that belong to no version inside their own transaction
+    ///
+    /// Each statement is executed as it is, without splitting it again, so comments and `$$`
+    /// bodies containing `;` are left intact. A trailing `;` is removed.
+    async fn execute_batch_sql(&self, name: &str, statements: &[String]) -> Result<()> {
+        if statements.is_empty() {
+            return Ok(());
+        }
+        log::debug!("Running {} ...", name);
+        let transactional = self.executor.supports_transactions();
+        if transactional {
+            self.executor.begin_transaction().await?;
+            *self.transaction_open.lock().unwrap() = true;
+        }
+        let mut result = Ok(());
+        for statement in statements.iter() {
+            let statement = SqlStatement {
+                annotation: None,
+                statement: statement.trim().trim_end_matches(';').to_string(),
+            };
+            if let Err(err) = self.executor.execute_statement(&statement).await {
+                log::error!("Failed to run {}: {}", name, err);
+                result = Err(err);
+                break;
+            }
+        }
+        if !transactional {
+            return result;
+        }
+
+        return match result {
+            Ok(_) => {
+                let committed = self.executor.commit_transaction().await;
+                *self.transaction_open.lock().unwrap() = false;
+                committed
+            },
+            Err(err) => {
+                let rolled_back = self.executor.rollback_transaction().await;
+                *self.transaction_open.lock().unwrap() = false;
+                match rolled_back {
+                    Ok(_) => Err(err),
+                    Err(rollback_err) => {
+                        log::error!("Rollback failed: {}", rollback_err);
+                        Err(MigrationsError::rollback_failed(err, rollback_err))
                     }
                 }
             }
+        };
+    }
+
+    /// Execute a changelog inside a transaction, if the executor supports transactions and the
+    /// changelog is not annotated with `transactional: false`
+    ///
+    /// If `record_version` is set, the version is recorded inside the transaction as well. If
+    /// executing the changelog fails, the transaction is rolled back and the error of the
+    /// changelog is returned. If the rollback fails too, a `RollbackFailed` error containing
+    /// both errors is returned. Returns what executing the changelog did otherwise.
+    ///
+    /// Only the error of the changelog itself is an `ApplyError::Changelog`, the errors of the
+    /// transaction, of recording the version and of a failed rollback are `ApplyError::Fatal`.
+    async fn execute_in_transaction(&self, changelog: &ChangelogFile, record_version: bool) -> std::result::Result<ChangelogOutcome, ApplyError> {
+        if !self.executor.supports_transactions() || !changelog.is_transactional()? {
+            return self.executor.execute_changelog_file(changelog)
+                .await
+                .or_else(|err| Err(ApplyError::Changelog(err)));
         }
 
-        return Ok(current_highest_version);
+        self.executor.begin_transaction().await?;
+        *self.transaction_open.lock().unwrap() = true;
+        let mut result = match self.commit_every {
+            Some(commit_every) => self.executor.execute_changelog_file_in_batches(changelog, commit_every).await,
+            None => self.executor.execute_changelog_file(changelog).await,
+        }.or_else(|err| Err(ApplyError::Changelog(err)));
+        if let (Ok(rows), true) = (&result, record_version) {
+            let rows = *rows;
+            result = self.executor.finish_version_in_transaction(changelog)
+                .await
+                .map(|_| rows)
+                .or_else(|err| Err(ApplyError::Fatal(err)));
+        }
+        match result {
+            Ok(rows) => {
+                let committed = self.executor.commit_transaction().await;
+                *self.transaction_open.lock().unwrap() = false;
+                committed?;
+                return Ok(rows);
+            },
+            Err(err) => {
+                let rolled_back = self.executor.rollback_transaction().await;
+                *self.transaction_open.lock().unwrap() = false;
+                return match rolled_back {
+                    Ok(_) => Err(err),
+                    Err(rollback_err) => {
+                        log::error!("Rollback failed: {}", rollback_err);
+                        Err(ApplyError::Fatal(MigrationsError::rollback_failed(err.into(), rollback_err)))
+                    }
+                };
+            }
+        }
     }
 
-    // /// Migrate with a single transaction for all changelogs
-    //
-    // /// This will execute all migrations inside one big DB transaction. Therefore, if an error
+    /// Remove all migration state managed by the state manager
+    ///
+    /// Only the migration state (e.g. the migrations table) is removed, the objects created by
+    /// the migrations themselves are left untouched. This is destructive and therefore only
+    /// available with the `clean` feature.
+    #[cfg(feature = "clean")]
+    pub async fn clean(&self) -> Result<()> {
+        log::warn!("Cleaning migration state ...");
+        return self.state_manager.clean().await;
+    }
+
+    /// Deploy a single changelog that is not necessarily part of the store
+    ///
+    /// The changelog is deployed exactly like `migrate` deploys each of its changelogs, i.e.
+    /// inside its own transaction which is rolled back if the changelog fails. This is meant
+    /// for tests and hotfixes. An error is returned without executing anything if the version
+    /// of the changelog has already been deployed.
+    pub async fn apply_one(&self, changelog: &ChangelogFile) -> Result<()> {
+        self.prepare().await?;
+        if self.state_manager.is_deployed(changelog.version()).await? {
+            return Err(MigrationsError::custom_message(
+                format!("Version {} has already been deployed.", changelog.version()).as_str(), None, None));
+        }
+
+        return self.apply_changelog(changelog)
+            .await
+            .map(|_| ())
+            .or_else(|err| Err(err.into()));
+    }
+
+    /// Record versions as deployed without executing their changelogs
+    ///
+    /// This is meant for databases that have been patched by hand, and generalizes a baseline
+    /// to any set of versions. Each version is recorded with the name and checksum of its
+    /// changelog. Versions that are deployed already are left as they are. An error is returned
+    /// without recording anything if a version is not part of the store.
+    pub async fn mark_applied(&self, versions: &[u64]) -> Result<()> {
+        let mut changelogs: Vec<ChangelogFile> = self.store.changelogs()
+            .into_iter()
+            .filter(|changelog| versions.contains(&changelog.version()))
+            .collect();
+        if let Some(missing) = versions.iter().find(|version| !changelogs.iter().any(|changelog| changelog.version() == **version)) {
+            return Err(MigrationsError::custom_message(
+                format!("Version {} is not part of the migration store.", missing).as_str(), None, None));
+        }
+        changelogs.sort();
+        self.prepare().await?;
+        for changelog in changelogs {
+            if self.state_manager.is_deployed(changelog.version()).await? {
+                log::info!("Version {} has already been deployed", changelog.version());
+                continue;
+            }
+            log::warn!("Marking version {} ({}) as applied without executing it", changelog.version(), changelog.name);
+            load_changelog(&changelog)?;
+            self.state_manager.mark_applied(&self.for_recording(&changelog)).await?;
+        }
+        return Ok(());
+    }
+
+    /// Execute the changelog of a deployed version again and record its new checksum
+    ///
+    /// This is meant for development, where a migration is tweaked and applied again without
+    /// bumping its version. **Never use it in production:** the changelog runs again on top of
+    /// the state it created the first time, so statements that are not idempotent fail or,
+    /// worse, change data twice, and other databases deployed with the old content silently
+    /// diverge. It therefore fails unless `with_allow_rerun(true)` has been set, and it only
+    /// re-runs versions that are part of the store and recorded as deployed.
+    ///
+    /// The changelog is executed inside a transaction if possible, then
+    /// `MigrationStateManager::finish_rerun` records it.
+    pub async fn rerun(&self, version: u64) -> Result<()> {
+        if !self.allow_rerun {
+            return Err(MigrationsError::custom_message(
+                format!("Re-running version {} is not allowed, see MigrationRunner::with_allow_rerun.", version).as_str(), None, None));
+        }
+        self.prepare().await?;
+        let changelog = self.store.changelogs()
+            .into_iter()
+            .find(|changelog| changelog.version() == version)
+            .ok_or_else(|| MigrationsError::custom_message(
+                format!("Version {} is not part of the migration store.", version).as_str(), None, None))?;
+        if !self.state_manager.is_deployed(version).await? {
+            return Err(MigrationsError::custom_message(
+                format!("Version {} has not been deployed, use migrate instead.", version).as_str(), None, None));
+        }
+
+        log::warn!("Re-running version {} ({}) ...", version, changelog.name);
+        load_changelog(&changelog)?;
+        let changelog = self.for_recording(&changelog);
+        self.execute_in_transaction(&changelog, false).await?;
+        return self.state_manager.finish_rerun(&changelog).await;
+    }
+
+    /// Fail if the database server does not satisfy the `requires_db` annotation of a changelog
+    ///
+    /// If the server version is unknown or cannot be parsed, a warning is logged and the
+    /// changelog is executed anyway, so that state managers without `server_version` keep working.
+    async fn check_database_version(&self, changelog: &ChangelogFile) -> Result<()> {
+        let requirement = match changelog.file_annotation()?.and_then(|annotation| annotation.requires_db()).map(|requirement| requirement.to_string()) {
+            Some(requirement) => requirement,
+            None => return Ok(()),
+        };
+        let version_req = semver::VersionReq::parse(requirement.as_str())
+            .or_else(|err| Err(MigrationsError::custom_message(
+                format!("Changelog of version {} has an invalid requires_db annotation.", changelog.version()).as_str(), None, Some(Box::new(err)))))?;
+        let server_version = self.state_manager.server_version().await?;
+        let parsed = server_version.as_deref().and_then(parse_server_version);
+        match (server_version, parsed) {
+            (Some(server_version), Some(parsed)) => {
+                if !version_req.matches(&parsed) {
+                    return Err(MigrationsError::unsupported_database_version(changelog.version(), requirement.as_str(), server_version.as_str()));
+                }
+            }
+            (server_version, _) => {
+                log::warn!("Cannot determine the database version ({:?}), deploying version {} requiring {} anyway",
+                           server_version, changelog.version(), requirement);
+            }
+        }
+        return Ok(());
+    }
+
+    /// Deploy a changelog inside its own transaction
+    ///
+    /// If executing the changelog fails, the transaction is rolled back and the error of the
+    /// changelog is returned as `ApplyError::Changelog`. All other errors are `ApplyError::Fatal`.
+    async fn apply_changelog(&self, changelog: &ChangelogFile) -> std::result::Result<ChangelogOutcome, ApplyError> {
+        *self.current_version.lock().unwrap() = Some(changelog.version());
+        let _current_version = CurrentVersionGuard(&self.current_version);
+        load_changelog(changelog)?;
+        self.check_database_version(changelog).await?;
+        let changelog = self.for_recording(changelog);
+
+        // Files annotated with `transactional: false` are recorded after they have been executed
+        if self.atomic_versioning && changelog.is_transactional()? {
+            if !self.executor.supports_transactions() || !self.executor.supports_atomic_versioning() {
+                return Err(ApplyError::Fatal(MigrationsError::custom_message(
+                    "Atomic versioning needs an executor with transactions and atomic versioning support.", None, None)));
+            }
+            return self.execute_in_transaction(&changelog, true).await;
+        }
+
+        self.state_manager.begin_version(&changelog).await?;
+        let outcome = self.execute_in_transaction(&changelog, false).await?;
+        self.state_manager.finish_version(&changelog).await?;
+        return Ok(outcome);
+    }
+
+    // /// Migrate with a single transaction for all changelogs
+    //
+    // /// This will execute all migrations inside one big DB transaction. Therefore, if an error
     // /// occurs and the method returns prematurely, none of the changes will stay inside
     // /// the database.
     // pub async fn migrate_single_transaction(&self) -> Result<Option<u32>> {
@@ -381,4 +2171,1461 @@ impl<S, M, E> MigrationRunner<S, M, E>
     //
     //     return Ok(current_highest_version);
     // }
-}
\ No newline at end of file
+}
+
+/// Error deploying a changelog, telling whether the run may continue with the next changelog
+enum ApplyError {
+    /// The changelog failed and has been rolled back, so it can be skipped
+    Changelog(MigrationsError),
+    /// The state manager or the transaction failed, so the run must abort
+    Fatal(MigrationsError),
+}
+
+impl From<MigrationsError> for ApplyError {
+    fn from(err: MigrationsError) -> Self {
+        return ApplyError::Fatal(err);
+    }
+}
+
+impl From<ChangelogError> for ApplyError {
+    fn from(err: ChangelogError) -> Self {
+        return ApplyError::Fatal(err.into());
+    }
+}
+
+impl From<ApplyError> for MigrationsError {
+    fn from(err: ApplyError) -> Self {
+        match err {
+            ApplyError::Changelog(err) => return err,
+            ApplyError::Fatal(err) => return err,
+        }
+    }
+}
+
+/// Resets the current version of a `MigrationRunner` when a changelog is done
+///
+/// Being dropped, this also covers errors and runs that are aborted, e.g. by a timeout.
+struct CurrentVersionGuard<'a>(&'a Mutex<Option<u64>>);
+
+impl<'a> Drop for CurrentVersionGuard<'a> {
+    fn drop(&mut self) {
+        *self.0.lock().unwrap() = None;
+    }
+}
+
+/// Read the content of a lazily loaded changelog, so that later accesses can't fail
+///
+/// Streamed changelogs are not loaded, errors reading them are reported when they are executed.
+fn load_changelog(changelog: &ChangelogFile) -> Result<()> {
+    if changelog.is_streamed() {
+        return Ok(());
+    }
+    return changelog.load()
+        .map(|_| ())
+        .or_else(|err| Err(MigrationsError::custom_message(
+            format!("Could not load changelog for version {}", changelog.version()).as_str(),
+            None,
+            Some(Box::new(err)))));
+}
+
+/// Get the version and name of a migration recorded by an older release, as they are parsed now
+///
+/// Older releases split filenames at the first `_`, e.g. `V1__init.sql` was recorded as
+/// version `1` named `_init`. Joining version and name again gives the stem of the file.
+fn legacy_identity(recorded_version: u64, recorded_name: &str) -> Option<(u64, String)> {
+    return parse_migration_stem(format!("{}_{}", recorded_version, recorded_name).as_str())
+        .ok()
+        .map(|(version, name)| (version, name.to_string()));
+}
+
+/// Parse the first number like `14`, `14.5` or `8.0.35` inside a server version
+///
+/// Missing minor and patch versions are `0`, further parts are ignored, e.g.
+/// `PostgreSQL 14.5 on x86_64` is `14.5.0` and `15.0.2000.5` (MSSQL) is `15.0.2000`.
+fn parse_server_version(server_version: &str) -> Option<semver::Version> {
+    let start = server_version.find(|ch: char| ch.is_ascii_digit())?;
+    let mut parts = server_version[start..]
+        .split(|ch: char| !ch.is_ascii_digit() && ch != '.')
+        .next()?
+        .split('.')
+        .map(|part| part.parse::<u64>().ok());
+    let major = parts.next()??;
+    let minor = parts.next().flatten().unwrap_or(0);
+    let patch = parts.next().flatten().unwrap_or(0);
+    return Some(semver::Version::new(major, minor, patch));
+}
+
+/// Check if a changelog is newer than the highest deployed version
+///
+/// This is the predicate `migrate` uses, see `MigrationRunner::migrate_filtered`.
+pub fn is_pending(changelog: &ChangelogFile, highest_version: Option<u64>) -> bool {
+    return match highest_version {
+        Some(highest_version) => changelog.version() > highest_version,
+        None => true,
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use async_trait::async_trait;
+    use crate::{ChangelogError, ChangelogErrorKind, ChangelogFile, ChangelogMigrationStore, ChangelogOutcome, DirectoryMigrationStore, DryRunExecutor, DynMigrationRunner, LayeredMigrationStore, MigrationExecutor,
+                MigrationInfoStatus, MigrationRunner, MigrationsError, MigrationsErrorKind, MigrationState, MigrationStateManager,
+                MigrationStatus, MigrationStore, MigrationStoreExt, Result, Sha256, SqlStatement, ChecksumPolicy, Crc32};
+
+    /// In-memory state manager and executor
+    #[derive(Default)]
+    struct TestDriver {
+        /// Deployed versions and their checksums
+        deployed: Mutex<Vec<(u64, String)>>,
+        /// Versions of all executed changelogs, in execution order
+        executed: Mutex<Vec<u64>>,
+        /// Versions whose execution fails
+        failing_versions: Vec<u64>,
+        /// Whether the driver rejects all transaction calls
+        non_transactional: bool,
+        /// Whether rolling back fails
+        failing_rollback: bool,
+        /// Versions whose recording by `finish_version` fails
+        failing_finish: Vec<u64>,
+        /// Whether only the first 8 characters of each checksum are recorded
+        truncating_checksums: bool,
+        /// Number of transactions begun
+        transactions: Mutex<usize>,
+        /// Number of transactions rolled back
+        rollbacks: Mutex<usize>,
+        /// How long executing a changelog takes
+        delay: Option<Duration>,
+        /// How long recording the begin of a version takes
+        begin_delay: Option<Duration>,
+        /// Number of `prepare` calls
+        prepares: Mutex<usize>,
+        /// Versions recorded as failed
+        skipped: Mutex<Vec<u64>>,
+        /// The version returned by `server_version`
+        server_version: Option<String>,
+        /// Statements executed outside of changelogs, in execution order
+        statements: Mutex<Vec<String>>,
+        /// Names recorded for deployed versions
+        names: HashMap<u64, String>,
+        /// Whether the state table does not exist, so that reading the state fails
+        unprepared: bool,
+    }
+
+    impl TestDriver {
+        fn deployed_versions(&self) -> Vec<u64> {
+            return self.deployed.lock().unwrap().iter().map(|(version, _)| *version).collect();
+        }
+
+        fn executed_versions(&self) -> Vec<u64> {
+            return self.executed.lock().unwrap().clone();
+        }
+    }
+
+    #[async_trait]
+    impl MigrationStateManager for TestDriver {
+        async fn prepare(&self) -> Result<()> {
+            *self.prepares.lock().unwrap() += 1;
+            return Ok(());
+        }
+
+        async fn is_prepared(&self) -> Result<bool> {
+            return Ok(!self.unprepared);
+        }
+
+        async fn server_version(&self) -> Result<Option<String>> {
+            return Ok(self.server_version.clone());
+        }
+
+        async fn lowest_version(&self) -> Result<Option<MigrationState>> {
+            return Ok(self.list_versions().await?.into_iter().next());
+        }
+
+        async fn highest_version(&self) -> Result<Option<MigrationState>> {
+            return Ok(self.list_versions().await?.into_iter().last());
+        }
+
+        // `applied_checksums` is not overridden, so the tests cover its default implementation
+        async fn list_versions(&self) -> Result<Vec<MigrationState>> {
+            if self.unprepared {
+                return Err(MigrationsError::migration_versioning_failed(None));
+            }
+            let mut versions = self.deployed.lock().unwrap().clone();
+            versions.sort();
+            // Each version is recorded as applied at the time of its number
+            return Ok(versions.into_iter()
+                .map(|(version, checksum)| MigrationState {
+                    applied_at: Some(version.to_string()),
+                    name: self.names.get(&version).cloned(),
+                    checksum: Some(checksum),
+                    ..MigrationState::new(version, MigrationStatus::Deployed)
+                })
+                .collect());
+        }
+
+        async fn begin_version(&self, _changelog_file: &ChangelogFile) -> Result<()> {
+            if let Some(delay) = self.begin_delay {
+                tokio::time::sleep(delay).await;
+            }
+            return Ok(());
+        }
+
+        async fn finish_version(&self, changelog_file: &ChangelogFile) -> Result<()> {
+            if self.failing_finish.contains(&changelog_file.version()) {
+                return Err(MigrationsError::migration_versioning_failed(None));
+            }
+            let checksum = match self.truncating_checksums {
+                true => changelog_file.recorded_checksum()?.chars().take(8).collect(),
+                false => changelog_file.recorded_checksum()?,
+            };
+            self.deployed.lock().unwrap().push((changelog_file.version(), checksum));
+            return Ok(());
+        }
+
+        async fn skip_version(&self, changelog_file: &ChangelogFile) -> Result<()> {
+            self.skipped.lock().unwrap().push(changelog_file.version());
+            return Ok(());
+        }
+
+        async fn list_problems(&self) -> Result<Vec<MigrationState>> {
+            return Ok(self.skipped.lock().unwrap().iter()
+                .map(|version| MigrationState::new(*version, MigrationStatus::Failed))
+                .collect());
+        }
+
+        async fn finish_rerun(&self, changelog_file: &ChangelogFile) -> Result<()> {
+            let recorded_checksum = changelog_file.recorded_checksum()?;
+            for (version, checksum) in self.deployed.lock().unwrap().iter_mut() {
+                if *version == changelog_file.version() {
+                    *checksum = recorded_checksum.clone();
+                }
+            }
+            return Ok(());
+        }
+    }
+
+    #[async_trait]
+    impl MigrationExecutor for TestDriver {
+        fn supports_transactions(&self) -> bool {
+            return !self.non_transactional;
+        }
+
+        fn supports_atomic_versioning(&self) -> bool {
+            return true;
+        }
+
+        async fn begin_transaction(&self) -> Result<()> {
+            if self.non_transactional {
+                return Err(MigrationsError::migration_database_failed(None, None));
+            }
+            *self.transactions.lock().unwrap() += 1;
+            return Ok(());
+        }
+
+        async fn execute_statement(&self, statement: &SqlStatement) -> Result<u64> {
+            self.statements.lock().unwrap().push(statement.statement.clone());
+            if statement.statement.contains("FAIL") {
+                return Err(MigrationsError::migration_database_step_failed(None, None));
+            }
+            return Ok(0);
+        }
+
+        async fn execute_changelog_file(&self, changelog_file: &ChangelogFile) -> Result<ChangelogOutcome> {
+            if let Some(delay) = self.delay {
+                tokio::time::sleep(delay).await;
+            }
+            if self.failing_versions.contains(&changelog_file.version()) {
+                return Err(MigrationsError::migration_database_step_failed(None, None));
+            }
+            self.executed.lock().unwrap().push(changelog_file.version());
+            // Each changelog affects as many rows as its version
+            return Ok(ChangelogOutcome {
+                statements_executed: changelog_file.iter().count() as u64,
+                rows_affected: changelog_file.version(),
+            });
+        }
+
+        async fn finish_version_in_transaction(&self, changelog_file: &ChangelogFile) -> Result<()> {
+            return self.finish_version(changelog_file).await;
+        }
+
+        async fn commit_transaction(&self) -> Result<()> {
+            return Ok(());
+        }
+
+        async fn rollback_transaction(&self) -> Result<()> {
+            if self.failing_rollback {
+                return Err(MigrationsError::migration_database_failed(None, None));
+            }
+            *self.rollbacks.lock().unwrap() += 1;
+            return Ok(());
+        }
+    }
+
+    fn runner(versions: &[u64], driver: Arc<TestDriver>) -> MigrationRunner<ChangelogMigrationStore, TestDriver, TestDriver> {
+        return MigrationRunner::new(store(versions), driver.clone(), driver, false);
+    }
+
+    /// Get the `ChangelogErrorKind` that caused a `MigrationsError`
+    fn changelog_error_kind(err: &MigrationsError) -> Option<&ChangelogErrorKind> {
+        return err.cause()
+            .and_then(|cause| cause.downcast_ref::<ChangelogError>())
+            .map(|err| err.kind());
+    }
+
+    fn store(versions: &[u64]) -> ChangelogMigrationStore {
+        return ChangelogMigrationStore::from_changelog_files(versions.iter()
+            .map(|version| ChangelogFile::from_string(*version, "test", "SELECT 1;").unwrap())
+            .collect());
+    }
+
+    #[test]
+    pub fn test_changelogs_after() {
+        let mut versions: Vec<u64> = store(&[3, 1, 10, 2]).changelogs_after(Some(2)).iter()
+            .map(|changelog| changelog.version())
+            .collect();
+        versions.sort();
+        assert_eq!(versions, vec![3, 10]);
+        assert_eq!(store(&[3, 1]).changelogs_after(None).len(), 2);
+    }
+
+    #[test]
+    pub fn test_layered_store() {
+        let overlay = ChangelogMigrationStore::from_changelog_files(vec![
+            ChangelogFile::from_string(2, "stub", "SELECT 'stub';").unwrap(),
+            ChangelogFile::from_string(7, "extra", "SELECT 7;").unwrap(),
+        ]);
+        let layered = LayeredMigrationStore::new(store(&[3, 1, 2]), overlay);
+        let names: Vec<(u64, String)> = layered.changelogs().into_iter()
+            .map(|changelog| (changelog.version(), changelog.name.clone()))
+            .collect();
+        assert_eq!(names, vec![(1, "test".to_string()), (2, "stub".to_string()), (3, "test".to_string()), (7, "extra".to_string())]);
+
+        let versions: Vec<u64> = layered.changelogs_after(Some(2)).iter().map(|changelog| changelog.version()).collect();
+        assert_eq!(versions, vec![3, 7]);
+        assert_eq!(layered.base().changelogs().len(), 3, "The base store is not modified.");
+    }
+
+    #[cfg(feature = "embedded")]
+    #[test]
+    pub fn test_embedded_store() {
+        let store = crate::EmbeddedMigrationStore::from_embedded(vec![
+            ("migrations/V10_roles.sql", b"CREATE TABLE roles(id INT);".to_vec()),
+            ("migrations\\V2_users.sql", b"CREATE TABLE users(id INT);".to_vec()),
+            ("README.md", b"# Migrations".to_vec()),
+            ("migrations/notes.sql", b"SELECT 1;".to_vec()),
+            ("V1_init.sql", b"SELECT 1;".to_vec()),
+        ]).unwrap();
+        let names: Vec<(u64, String)> = store.changelogs().into_iter()
+            .map(|changelog| (changelog.version(), changelog.name.clone()))
+            .collect();
+        assert_eq!(names, vec![(1, "init".to_string()), (2, "users".to_string()), (10, "roles".to_string())],
+                   "Directories must be stripped, other files ignored and versions sorted numerically.");
+        assert_eq!(store.changelogs()[1].content(), "CREATE TABLE users(id INT);");
+        let versions: Vec<u64> = store.changelogs_after(Some(1)).iter().map(|changelog| changelog.version()).collect();
+        assert_eq!(versions, vec![2, 10]);
+
+        let result = crate::EmbeddedMigrationStore::from_embedded(vec![("V1_init.sql", vec![0xff, 0xfe])]);
+        assert!(result.is_err(), "Invalid UTF-8 must be rejected.");
+    }
+
+    #[test]
+    pub fn test_sorted_changelogs() {
+        let versions: Vec<u64> = store(&[3, 1, 10, 2]).sorted_changelogs().iter()
+            .map(|changelog| changelog.version())
+            .collect();
+        assert_eq!(versions, vec![1, 2, 3, 10]);
+    }
+
+    #[test]
+    pub fn test_changelogs_in_range() {
+        let versions: Vec<u64> = store(&[3, 1, 10, 2]).changelogs_in_range(2, 3).unwrap().iter()
+            .map(|changelog| changelog.version())
+            .collect();
+        assert_eq!(versions, vec![2, 3]);
+    }
+
+    #[test]
+    pub fn test_changelogs_in_range_missing_bounds() {
+        let store = store(&[1, 2, 3]);
+        match store.changelogs_in_range(0, 3) {
+            Err(err) => assert!(matches!(err.kind(), ChangelogErrorKind::MinVersionNotFound(_, _))),
+            Ok(_) => assert!(false, "Missing min version must be reported."),
+        }
+        match store.changelogs_in_range(1, 4) {
+            Err(err) => assert!(matches!(err.kind(), ChangelogErrorKind::MaxVersionNotFound(_, _))),
+            Ok(_) => assert!(false, "Missing max version must be reported."),
+        }
+        match self::store(&[]).changelogs_in_range(1, 1) {
+            Err(err) => assert!(matches!(err.kind(), ChangelogErrorKind::EmptyChangelog)),
+            Ok(_) => assert!(false, "Empty store must be reported."),
+        }
+    }
+
+    #[test]
+    pub fn test_error_info() {
+        let err = MigrationsError::custom_message("Something failed.", Some(3), Some(Box::new(ChangelogError::empty_changelog())));
+        let info = err.to_info();
+        assert_eq!(info.kind, "CustomErrorMessage");
+        assert_eq!(info.message, "Something failed.\nCaused by: Database changelog is empty.");
+        assert_eq!(info.last_successful_version, Some(3));
+        assert_eq!(info.clone(), info);
+    }
+
+    #[test]
+    pub fn test_from_changelog_error() {
+        let convert = || -> Result<()> {
+            store(&[]).changelogs_in_range(1, 2)?;
+            return Ok(());
+        };
+        match convert() {
+            Err(err) => {
+                assert_eq!(err.kind().name(), "CustomErrorMessage");
+                assert!(matches!(changelog_error_kind(&err), Some(ChangelogErrorKind::EmptyChangelog)));
+                assert!(std::error::Error::source(&err).unwrap().is::<ChangelogError>());
+            }
+            Ok(_) => assert!(false, "Empty changelog must be reported."),
+        }
+    }
+
+    #[test]
+    pub fn test_error_predicates() {
+        let err = MigrationsError::migration_database_step_failed(None, Some(Box::new(ChangelogError::empty_changelog())));
+        assert!(err.is_step_failure());
+        assert!(!err.is_setup_failure());
+        assert!(!err.is_versioning_failure());
+        assert!(err.cause().unwrap().is::<ChangelogError>());
+        assert!(std::error::Error::source(&err).is_some());
+
+        let err = MigrationsError::migration_setup_failed(None);
+        assert!(err.is_setup_failure());
+        assert!(err.cause().is_none());
+        assert!(MigrationsError::migration_versioning_failed(None).is_versioning_failure());
+    }
+
+    #[tokio::test]
+    pub async fn test_migrate() {
+        let driver = Arc::new(TestDriver::default());
+        let result = runner(&[2, 1, 3], driver.clone()).migrate().await;
+        assert_eq!(result.unwrap(), Some(3));
+        assert_eq!(driver.executed_versions(), vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    pub async fn test_dyn_runner() {
+        let driver = Arc::new(TestDriver::default());
+        let state_manager: Arc<dyn MigrationStateManager> = driver.clone();
+        let executor: Arc<dyn MigrationExecutor> = driver.clone();
+        let runner: DynMigrationRunner = MigrationRunner::new(Box::new(store(&[1, 2])), state_manager, executor, false);
+        let result = tokio::spawn(async move { runner.migrate().await }).await.unwrap();
+        assert_eq!(result.unwrap(), Some(2));
+        assert_eq!(driver.executed_versions(), vec![1, 2]);
+    }
+
+    #[cfg(feature = "timeout")]
+    #[tokio::test(start_paused = true)]
+    pub async fn test_migrate_with_timeout() {
+        let driver = Arc::new(TestDriver {
+            delay: Some(Duration::from_millis(200)),
+            ..TestDriver::default()
+        });
+        let result = runner(&[1, 2, 3], driver.clone()).migrate_with_timeout(Duration::from_millis(500)).await;
+        match result {
+            Err(err) => {
+                assert!(err.is_timeout(), "Unexpected error: {}", err);
+                assert_eq!(err.last_successful_version(), Some(2));
+            }
+            Ok(_) => assert!(false, "Migration should time out."),
+        }
+        assert_eq!(driver.deployed_versions(), vec![1, 2]);
+        assert_eq!(*driver.rollbacks.lock().unwrap(), 1);
+
+        // Stopped between two versions, there is no transaction to roll back
+        let driver = Arc::new(TestDriver {
+            begin_delay: Some(Duration::from_millis(200)),
+            failing_rollback: true,
+            ..TestDriver::default()
+        });
+        let result = runner(&[1, 2, 3], driver.clone()).migrate_with_timeout(Duration::from_millis(300)).await;
+        assert!(result.as_ref().is_err_and(|err| err.is_timeout()), "Unexpected result: {:?}", result);
+        assert_eq!(driver.deployed_versions(), vec![1]);
+
+        let driver = Arc::new(TestDriver::default());
+        let result = runner(&[1, 2], driver.clone()).migrate_with_timeout(Duration::from_secs(10)).await;
+        assert_eq!(result.unwrap(), Some(2));
+    }
+
+    #[cfg(feature = "cancellation")]
+    #[tokio::test(start_paused = true)]
+    pub async fn test_migrate_with_cancellation() {
+        let driver = Arc::new(TestDriver {
+            delay: Some(Duration::from_millis(200)),
+            ..TestDriver::default()
+        });
+        let token = crate::CancellationToken::new();
+        let canceller = token.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            canceller.cancel();
+        });
+        match runner(&[1, 2, 3], driver.clone()).migrate_with_cancellation(token).await {
+            Err(err) => {
+                assert!(err.is_cancelled(), "Unexpected error: {}", err);
+                assert_eq!(err.last_successful_version(), Some(2));
+            }
+            Ok(_) => assert!(false, "Migration should be cancelled."),
+        }
+        assert_eq!(driver.deployed_versions(), vec![1, 2]);
+        assert_eq!(*driver.rollbacks.lock().unwrap(), 1);
+
+        let driver = Arc::new(TestDriver::default());
+        let result = runner(&[1, 2], driver.clone()).migrate_with_cancellation(crate::CancellationToken::new()).await;
+        assert_eq!(result.unwrap(), Some(2));
+    }
+
+    #[cfg(feature = "cancellation")]
+    #[tokio::test(start_paused = true)]
+    pub async fn test_cancel_between_versions() {
+        // Version 1 is deployed after 200ms, version 2 is cancelled before its transaction begins
+        let driver = Arc::new(TestDriver {
+            begin_delay: Some(Duration::from_millis(200)),
+            failing_rollback: true,
+            ..TestDriver::default()
+        });
+        let token = crate::CancellationToken::new();
+        let canceller = token.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            canceller.cancel();
+        });
+        match runner(&[1, 2, 3], driver.clone()).migrate_with_cancellation(token).await {
+            Err(err) => {
+                assert!(err.is_cancelled(), "Cancelling must not be reported as a failed rollback: {}", err);
+                assert_eq!(err.last_successful_version(), Some(1));
+            }
+            Ok(_) => assert!(false, "Migration should be cancelled."),
+        }
+        assert_eq!(driver.deployed_versions(), vec![1]);
+        assert_eq!(*driver.transactions.lock().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    pub async fn test_mark_applied() {
+        let driver = Arc::new(TestDriver {
+            deployed: Mutex::new(vec![(1, "11".to_string())]),
+            ..TestDriver::default()
+        });
+        let runner = runner(&[1, 2, 3, 4, 5], driver.clone());
+        match runner.mark_applied(&[3, 6]).await {
+            Err(err) => assert!(err.to_string().contains("Version 6"), "Unexpected error: {}", err),
+            Ok(_) => assert!(false, "Versions missing from the store must not be marked as applied."),
+        }
+        assert_eq!(driver.deployed_versions(), vec![1]);
+
+        runner.mark_applied(&[4, 1, 2]).await.unwrap();
+        assert_eq!(driver.deployed_versions(), vec![1, 2, 4]);
+        assert!(driver.executed_versions().is_empty(), "Marked versions must not be executed.");
+        let checksum = store(&[4]).changelogs()[0].checksum_with(&Sha256).unwrap();
+        assert!(driver.deployed.lock().unwrap().contains(&(4, checksum)));
+
+        assert_eq!(runner.migrate().await.unwrap(), Some(5));
+        assert_eq!(driver.executed_versions(), vec![5], "Only versions after the highest marked version are pending.");
+    }
+
+    #[tokio::test]
+    pub async fn test_dry_run() {
+        let driver = Arc::new(TestDriver {
+            deployed: Mutex::new(vec![(1, "11".to_string())]),
+            ..TestDriver::default()
+        });
+        let store = ChangelogMigrationStore::from_changelog_files(vec![
+            ChangelogFile::from_string(1, "init", "CREATE TABLE a(id INT);").unwrap(),
+            ChangelogFile::from_string(2, "more", "CREATE TABLE b(id INT);\nINSERT INTO b VALUES (1);").unwrap(),
+        ]);
+        let dry_run = Arc::new(DryRunExecutor::new(driver.clone()));
+        let result = MigrationRunner::new(store, dry_run.clone(), dry_run.clone(), false).migrate().await;
+        assert_eq!(result.unwrap(), Some(2));
+        assert_eq!(dry_run.statements(), vec!["CREATE TABLE b(id INT)".to_string(), "INSERT INTO b VALUES (1)".to_string()]);
+        assert_eq!(dry_run.sql(), "CREATE TABLE b(id INT);\nINSERT INTO b VALUES (1);\n");
+        assert_eq!(driver.deployed_versions(), vec![1], "A dry run records nothing.");
+        assert!(driver.executed_versions().is_empty(), "A dry run executes nothing.");
+        assert_eq!(*driver.prepares.lock().unwrap(), 0, "A dry run must not prepare the database.");
+
+        let dry_run = Arc::new(DryRunExecutor::new(driver.clone()).with_prepare(true));
+        MigrationRunner::new(self::store(&[1]), dry_run.clone(), dry_run.clone(), false).migrate().await.unwrap();
+        assert_eq!(*driver.prepares.lock().unwrap(), 1);
+    }
+
+    #[tokio::test]
+    pub async fn test_streamed_changelog() {
+        let path = std::env::temp_dir().join(format!("flyway_runner_streamed_{}.sql", std::process::id()));
+        std::fs::write(&path, "CREATE TABLE a(id INT);\nINSERT INTO a VALUES (1);\n").unwrap();
+        let changelog = ChangelogFile::streamed(1, "data", path.as_path()).unwrap();
+        let driver = Arc::new(TestDriver::default());
+        let dry_run = Arc::new(DryRunExecutor::new(driver.clone()));
+        let store = ChangelogMigrationStore::from_changelog_files(vec![changelog.clone()]);
+        let result = MigrationRunner::new(store, dry_run.clone(), dry_run.clone(), false).migrate().await;
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(result.unwrap(), Some(1));
+        assert_eq!(dry_run.statements(), vec!["CREATE TABLE a(id INT)".to_string(), "INSERT INTO a VALUES (1)".to_string()]);
+        assert!(!changelog.is_loaded(), "A streamed changelog must not be loaded.");
+    }
+
+    #[tokio::test]
+    pub async fn test_orphans() {
+        let driver = Arc::new(TestDriver {
+            deployed: Mutex::new(vec![(1, "11".to_string()), (2, "12".to_string())]),
+            ..TestDriver::default()
+        });
+        let store = ChangelogMigrationStore::from_changelog_files(vec![
+            ChangelogFile::from_string(2, "users", "CREATE TABLE users(id INT);").unwrap(),
+            ChangelogFile::from_string(3, "roles", "CREATE TABLE roles(id INT);").unwrap(),
+        ]);
+        let runner = MigrationRunner::new(store, driver.clone(), driver.clone(), false);
+        let orphans: Vec<u64> = runner.orphans().await.unwrap().iter().map(|state| state.version).collect();
+        assert_eq!(orphans, vec![1]);
+
+        match runner.for_target(driver.clone(), driver.clone()).with_fail_on_orphans(true).migrate().await {
+            Err(err) => assert!(matches!(err.kind(), MigrationsErrorKind::OrphanedVersions(versions) if versions == &vec![1]), "Unexpected error: {}", err),
+            Ok(_) => assert!(false, "Orphaned versions must fail a strict migration."),
+        }
+        assert!(driver.executed_versions().is_empty(), "Nothing must be executed if there are orphans.");
+
+        assert_eq!(runner.migrate().await.unwrap(), Some(3));
+    }
+
+    #[tokio::test]
+    pub async fn test_problems() {
+        let driver = Arc::new(TestDriver {
+            failing_versions: vec![2, 4],
+            ..TestDriver::default()
+        });
+        let runner = MigrationRunner::new(store(&[1, 2, 3, 4]), driver.clone(), driver.clone(), true);
+        assert!(runner.problems().await.unwrap().is_empty());
+
+        runner.migrate().await.unwrap();
+        let problems = runner.problems().await.unwrap();
+        assert_eq!(problems, vec![MigrationState::new(2, MigrationStatus::Failed), MigrationState::new(4, MigrationStatus::Failed)]);
+    }
+
+    #[tokio::test]
+    pub async fn test_assert_up_to_date() {
+        let driver = Arc::new(TestDriver {
+            deployed: Mutex::new(vec![(1, "11".to_string())]),
+            ..TestDriver::default()
+        });
+        let store = ChangelogMigrationStore::from_changelog_files(vec![
+            ChangelogFile::lazy(3, "roles", Path::new("missing/V3_roles.sql")),
+            ChangelogFile::lazy(1, "init", Path::new("missing/V1_init.sql")),
+            ChangelogFile::lazy(2, "users", Path::new("missing/V2_users.sql")),
+        ]);
+        let runner = MigrationRunner::new(store, driver.clone(), driver.clone(), false);
+        match runner.assert_up_to_date().await {
+            Err(err) => assert!(matches!(err.kind(), MigrationsErrorKind::PendingMigrations(versions) if versions == &vec![2, 3]), "Unexpected error: {}", err),
+            Ok(_) => assert!(false, "Pending versions must be reported."),
+        }
+        assert!(driver.executed_versions().is_empty());
+
+        driver.deployed.lock().unwrap().push((3, "13".to_string()));
+        match runner.assert_up_to_date().await {
+            Err(err) => assert!(matches!(err.kind(), MigrationsErrorKind::PendingMigrations(versions) if versions == &vec![2]), "Unexpected error: {}", err),
+            Ok(_) => assert!(false, "Versions below the highest deployed one must be reported."),
+        }
+
+        driver.deployed.lock().unwrap().push((2, "12".to_string()));
+        assert!(runner.assert_up_to_date().await.is_ok());
+        assert_eq!(*driver.prepares.lock().unwrap(), 0, "A read-only check must not prepare the database.");
+
+        let driver = Arc::new(TestDriver {
+            unprepared: true,
+            ..TestDriver::default()
+        });
+        match runner.for_target(driver.clone(), driver.clone()).assert_up_to_date().await {
+            Err(err) => assert!(matches!(err.kind(), MigrationsErrorKind::PendingMigrations(versions) if versions == &vec![1, 2, 3]), "Unexpected error: {}", err),
+            Ok(_) => assert!(false, "Without a state table, every version is pending."),
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_database_ahead_of_store() {
+        let driver = Arc::new(TestDriver {
+            deployed: Mutex::new(vec![(1, "11".to_string()), (7, "17".to_string())]),
+            ..TestDriver::default()
+        });
+        let store = ChangelogMigrationStore::from_changelog_files(vec![
+            ChangelogFile::from_string(1, "init", "CREATE TABLE a(id INT);").unwrap(),
+            ChangelogFile::from_string(5, "users", "CREATE TABLE users(id INT);").unwrap(),
+        ]);
+        let runner = MigrationRunner::new(store, driver.clone(), driver.clone(), false);
+        assert_eq!(runner.migrate().await.unwrap(), Some(7), "The lenient default must succeed.");
+
+        match runner.for_target(driver.clone(), driver.clone()).with_fail_on_database_ahead(true).migrate().await {
+            Err(err) => assert!(matches!(err.kind(), MigrationsErrorKind::DatabaseAheadOfStore { db_version: 7, store_max: 5 }), "Unexpected error: {}", err),
+            Ok(_) => assert!(false, "A database ahead of the store must be reported."),
+        }
+        assert!(driver.executed_versions().is_empty());
+    }
+
+    #[tokio::test]
+    pub async fn test_current_version() {
+        let driver = Arc::new(TestDriver {
+            deployed: Mutex::new(vec![(1, "11".to_string())]),
+            delay: Some(Duration::from_millis(200)),
+            ..TestDriver::default()
+        });
+        let runner = runner(&[1, 2], driver);
+        assert_eq!(runner.current_version(), None);
+        let (result, current_version) = tokio::join!(runner.migrate(), async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            runner.current_version()
+        });
+        assert_eq!(result.unwrap(), Some(2));
+        assert_eq!(current_version, Some(2), "The running version must be visible.");
+        assert_eq!(runner.current_version(), None, "The version must be cleared after the run.");
+    }
+
+    #[tokio::test]
+    pub async fn test_skip_prepare() {
+        let driver = Arc::new(TestDriver::default());
+        let runner = runner(&[1, 2], driver.clone());
+        assert_eq!(runner.migrate().await.unwrap(), Some(2));
+        assert_eq!(*driver.prepares.lock().unwrap(), 1);
+
+        let runner = runner.with_skip_prepare(true);
+        assert_eq!(runner.migrate().await.unwrap(), Some(2));
+        assert!(!runner.has_pending().await.unwrap());
+        assert_eq!(*driver.prepares.lock().unwrap(), 1, "prepare must not be called when it is skipped.");
+    }
+
+    #[tokio::test]
+    pub async fn test_rerun() {
+        let driver = Arc::new(TestDriver {
+            deployed: Mutex::new(vec![(1, "11".to_string()), (2, "12".to_string())]),
+            ..TestDriver::default()
+        });
+        let runner = runner(&[1, 2, 3], driver.clone());
+        assert!(runner.rerun(2).await.is_err(), "Re-running must be refused by default.");
+        assert!(driver.executed_versions().is_empty());
+
+        let runner = runner.with_allow_rerun(true);
+        assert!(runner.rerun(3).await.is_err(), "Versions that have not been deployed must not be re-run.");
+        assert!(runner.rerun(4).await.is_err(), "Versions missing from the store must not be re-run.");
+        runner.rerun(2).await.unwrap();
+        assert_eq!(driver.executed_versions(), vec![2]);
+        let checksum = store(&[2]).changelogs()[0].checksum_with(&Sha256).unwrap();
+        assert_eq!(*driver.deployed.lock().unwrap(), vec![(1, "11".to_string()), (2, checksum)]);
+    }
+
+    #[tokio::test]
+    pub async fn test_pre_validate() {
+        let changelogs = vec![
+            ChangelogFile::from_string(1, "valid", "SELECT 1;").unwrap(),
+            ChangelogFile::from_string(2, "broken", "INSERT INTO t VALUES ('oops);").unwrap(),
+        ];
+        let driver = Arc::new(TestDriver::default());
+        let result = MigrationRunner::new(ChangelogMigrationStore::from_changelog_files(changelogs.clone()), driver.clone(), driver.clone(), false)
+            .with_pre_validate(true)
+            .migrate()
+            .await;
+        match result {
+            Err(err) => assert!(matches!(changelog_error_kind(&err), Some(ChangelogErrorKind::InvalidSql(_))), "Unexpected error: {}", err),
+            Ok(_) => assert!(false, "Migration should fail."),
+        }
+        assert!(driver.executed_versions().is_empty(), "Nothing is executed if a changelog is invalid.");
+
+        let driver = Arc::new(TestDriver::default());
+        let result = MigrationRunner::new(ChangelogMigrationStore::from_changelog_files(changelogs), driver.clone(), driver.clone(), false)
+            .migrate()
+            .await;
+        assert!(result.is_ok());
+        assert_eq!(driver.executed_versions(), vec![1, 2]);
+    }
+
+    #[tokio::test]
+    pub async fn test_migrate_without_transactions() {
+        let driver = Arc::new(TestDriver {
+            non_transactional: true,
+            ..TestDriver::default()
+        });
+        let result = runner(&[1, 2], driver.clone()).migrate().await;
+        assert_eq!(result.unwrap(), Some(2));
+        assert_eq!(driver.deployed_versions(), vec![1, 2]);
+    }
+
+    #[tokio::test]
+    pub async fn test_fail_continue_aborts_on_state_errors() {
+        let driver = Arc::new(TestDriver {
+            failing_finish: vec![2],
+            ..TestDriver::default()
+        });
+        let result = MigrationRunner::new(store(&[1, 2, 3]), driver.clone(), driver.clone(), true).migrate().await;
+        match result {
+            Err(err) => assert!(matches!(err.kind(), MigrationsErrorKind::MigrationVersioningFailed(_)), "Unexpected error: {}", err),
+            Ok(_) => assert!(false, "A failing state manager must abort the run."),
+        }
+        assert!(driver.skipped.lock().unwrap().is_empty(), "Only failing changelogs may be skipped.");
+        assert_eq!(driver.deployed_versions(), vec![1]);
+
+        let driver = Arc::new(TestDriver {
+            failing_versions: vec![2],
+            failing_rollback: true,
+            ..TestDriver::default()
+        });
+        let result = MigrationRunner::new(store(&[1, 2, 3]), driver.clone(), driver.clone(), true).migrate().await;
+        assert!(matches!(result.as_ref().map_err(|err| err.kind()), Err(MigrationsErrorKind::RollbackFailed(_, _))));
+        assert!(driver.skipped.lock().unwrap().is_empty(), "A failed rollback must abort the run.");
+        assert_eq!(driver.deployed_versions(), vec![1]);
+    }
+
+    #[tokio::test]
+    pub async fn test_rollback_failure_is_reported() {
+        let driver = Arc::new(TestDriver {
+            failing_versions: vec![2],
+            failing_rollback: true,
+            ..TestDriver::default()
+        });
+        let result = runner(&[1, 2], driver.clone()).migrate().await;
+        match result {
+            Err(err) => {
+                assert!(matches!(err.kind(), MigrationsErrorKind::RollbackFailed(_, _)), "Unexpected error: {}", err);
+                assert!(err.rollback_error().is_some(), "Rollback error must be kept.");
+                let original = err.cause().and_then(|cause| cause.downcast_ref::<MigrationsError>());
+                assert!(original.map(|original| original.is_step_failure()).unwrap_or(false), "Original error must be kept.");
+            },
+            Ok(_) => assert!(false, "Failing version must be reported."),
+        }
+        assert_eq!(driver.deployed_versions(), vec![1]);
+    }
+
+    #[tokio::test]
+    pub async fn test_migrate_with_summary() {
+        let driver = Arc::new(TestDriver {
+            failing_versions: vec![2],
+            ..TestDriver::default()
+        });
+        let result = MigrationRunner::new(store(&[1, 2, 3]), driver.clone(), driver.clone(), true)
+            .migrate_with_summary()
+            .await;
+        match result {
+            Ok(summary) => {
+                assert_eq!(summary.highest_version, Some(3));
+                assert_eq!(summary.deployed, vec![1, 3]);
+                assert_eq!(summary.rows_affected, vec![(1, 1), (3, 3)]);
+                assert_eq!(summary.failed.len(), 1);
+                assert_eq!(summary.failed[0].0, 2);
+                assert_eq!(summary.failed[0].1.kind, "MigrationDatabaseStepFailed");
+            },
+            Err(err) => assert!(false, "Failing version must not abort the migration: {}", err),
+        }
+        assert_eq!(driver.deployed_versions(), vec![1, 3]);
+        assert_eq!(*driver.rollbacks.lock().unwrap(), 1);
+
+        let driver = Arc::new(TestDriver {
+            failing_versions: vec![2],
+            ..TestDriver::default()
+        });
+        assert!(runner(&[1, 2, 3], driver.clone()).migrate_with_summary().await.is_err(), "Failing version must be reported.");
+        assert_eq!(driver.deployed_versions(), vec![1]);
+    }
+
+    #[tokio::test]
+    pub async fn test_atomic_versioning() {
+        let driver = Arc::new(TestDriver {
+            failing_versions: vec![3],
+            ..TestDriver::default()
+        });
+        let result = runner(&[1, 2, 3], driver.clone()).with_atomic_versioning(true).migrate().await;
+        assert!(result.is_err(), "Failing version must be reported.");
+        assert_eq!(driver.deployed_versions(), vec![1, 2]);
+
+        let driver = Arc::new(TestDriver {
+            non_transactional: true,
+            ..TestDriver::default()
+        });
+        let result = runner(&[1], driver.clone()).with_atomic_versioning(true).migrate().await;
+        assert!(result.is_err(), "Atomic versioning without transactions must fail.");
+        assert!(driver.executed_versions().is_empty(), "Nothing has been executed.");
+    }
+
+    #[tokio::test]
+    pub async fn test_checksum_algorithm() {
+        let changelogs = store(&[1, 2, 3]).changelogs();
+        let driver = Arc::new(TestDriver {
+            // Recorded by an older release, by a runner using CRC-32 and by the default runner
+            deployed: Mutex::new(vec![(1, changelogs[0].checksum().unwrap().to_string()), (2, changelogs[1].checksum_with(&Crc32).unwrap())]),
+            ..TestDriver::default()
+        });
+        let runner = runner(&[1, 2, 3], driver.clone()).with_checksum_policy(ChecksumPolicy::Verify);
+        assert_eq!(runner.migrate().await.unwrap(), Some(3));
+        assert_eq!(driver.deployed.lock().unwrap()[2], (3, changelogs[2].checksum_with(&Sha256).unwrap()));
+        runner.for_target(driver.clone(), driver.clone()).with_checksum_algorithm(Arc::new(Crc32)).validate().await.unwrap();
+
+        driver.deployed.lock().unwrap()[1].1 = "crc32:00000000".to_string();
+        match runner.validate().await {
+            Err(err) => assert!(matches!(err.kind(), MigrationsErrorKind::ChecksumMismatch(2, recorded, _) if recorded == "crc32:00000000"), "Unexpected error: {}", err),
+            Ok(_) => assert!(false, "A changed checksum must be detected."),
+        }
+        driver.deployed.lock().unwrap()[1].1 = "md5:00000000".to_string();
+        assert!(runner.validate().await.is_err(), "Unknown algorithms cannot be verified.");
+    }
+
+    #[tokio::test]
+    pub async fn test_legacy_names() {
+        // `V1__init.sql` as recorded by releases splitting filenames at the first `_`
+        let content = "CREATE TABLE users(id INT);";
+        let recorded = ChangelogFile::from_string(1, "_init", content).unwrap();
+        let driver = Arc::new(TestDriver {
+            deployed: Mutex::new(vec![(1, recorded.checksum().unwrap().to_string())]),
+            names: HashMap::from([(1, "_init".to_string())]),
+            ..TestDriver::default()
+        });
+        let (version, name) = crate::parse_migration_filename("V1__init.sql").unwrap();
+        assert_eq!((version, name), (1, "init"));
+        let changelogs = vec![ChangelogFile::from_string(version, name, content).unwrap()];
+        let runner = MigrationRunner::new(ChangelogMigrationStore::from_changelog_files(changelogs), driver.clone(), driver.clone(), false)
+            .with_checksum_policy(ChecksumPolicy::Verify);
+        runner.validate().await.unwrap();
+        runner.migrate().await.unwrap();
+        assert!(driver.executed_versions().is_empty());
+
+        let changed = vec![ChangelogFile::from_string(version, name, "CREATE TABLE roles(id INT);").unwrap()];
+        let runner = MigrationRunner::new(ChangelogMigrationStore::from_changelog_files(changed), driver.clone(), driver.clone(), false);
+        match runner.validate().await {
+            Err(err) => assert!(matches!(err.kind(), MigrationsErrorKind::ChecksumMismatch(1, _, _)), "Unexpected error: {}", err),
+            Ok(_) => assert!(false, "A changed changelog must still be detected."),
+        }
+
+        // `V20240115_1200__add_users.sql` was recorded as version 20240115
+        let recorded = ChangelogFile::from_string(20240115, "1200__add_users", content).unwrap();
+        let driver = Arc::new(TestDriver {
+            deployed: Mutex::new(vec![(20240115, recorded.checksum().unwrap().to_string())]),
+            names: HashMap::from([(20240115, "1200__add_users".to_string())]),
+            ..TestDriver::default()
+        });
+        let (version, name) = crate::parse_migration_filename("V20240115_1200__add_users.sql").unwrap();
+        let changelogs = vec![ChangelogFile::from_string(version, name, content).unwrap()];
+        let runner = MigrationRunner::new(ChangelogMigrationStore::from_changelog_files(changelogs), driver.clone(), driver.clone(), false);
+        match runner.migrate().await {
+            Err(err) => assert!(err.to_string().contains("202401151200"), "Unexpected error: {}", err),
+            Ok(_) => assert!(false, "A version recorded by an older release must not be deployed again."),
+        }
+        assert!(driver.executed_versions().is_empty());
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    pub async fn test_metrics() {
+        let driver = Arc::new(TestDriver {
+            failing_versions: vec![3],
+            ..TestDriver::default()
+        });
+        let changelogs = vec![
+            ChangelogFile::from_string(1, "users", "CREATE TABLE users(id INT); CREATE TABLE roles(id INT);").unwrap(),
+            ChangelogFile::from_string(2, "groups", "CREATE TABLE groups(id INT);").unwrap(),
+            ChangelogFile::from_string(3, "broken", "SELECT;").unwrap(),
+        ];
+        let runner = MigrationRunner::new(ChangelogMigrationStore::from_changelog_files(changelogs), driver.clone(), driver.clone(), false);
+        assert_eq!(runner.metrics(), crate::MigrationMetrics::default());
+
+        assert!(runner.migrate().await.is_err());
+        let metrics = runner.metrics();
+        assert_eq!(metrics.runs, 1);
+        assert_eq!(metrics.migrations_applied, 2);
+        assert_eq!(metrics.statements_executed, 3);
+        assert_eq!(metrics.rows_affected, 3, "The test driver affects as many rows as the version.");
+        assert!(metrics.last_error.is_some());
+
+        assert!(runner.migrate().await.is_err());
+        assert_eq!(runner.metrics().runs, 2);
+        assert_eq!(runner.metrics().migrations_applied, 2);
+    }
+
+    #[tokio::test]
+    pub async fn test_migrate_tenants() {
+        let runner = runner(&[1, 2, 3], Arc::new(TestDriver::default()));
+        let tenants: Vec<Arc<TestDriver>> = vec![
+            Arc::new(TestDriver::default()),
+            Arc::new(TestDriver {
+                deployed: Mutex::new(vec![(1, "0".to_string()), (2, "0".to_string())]),
+                ..TestDriver::default()
+            }),
+        ];
+        for tenant in tenants.iter() {
+            let result = runner.migrate_with(tenant.clone(), tenant.clone()).await;
+            assert_eq!(result.unwrap(), Some(3));
+        }
+        assert_eq!(tenants[0].executed_versions(), vec![1, 2, 3]);
+        assert_eq!(tenants[1].executed_versions(), vec![3]);
+    }
+
+    #[tokio::test]
+    pub async fn test_migrate_all() {
+        let runner = runner(&[1, 2, 3], Arc::new(TestDriver::default()));
+        let targets: Vec<Arc<TestDriver>> = vec![
+            Arc::new(TestDriver::default()),
+            Arc::new(TestDriver {
+                failing_versions: vec![2],
+                ..TestDriver::default()
+            }),
+            Arc::new(TestDriver {
+                deployed: Mutex::new(vec![(1, "0".to_string())]),
+                ..TestDriver::default()
+            }),
+        ];
+        let results = runner.migrate_all(targets.iter().map(|target| (target.clone(), target.clone())).collect(), 2).await;
+        assert_eq!(results.iter().map(|(index, _)| *index).collect::<Vec<usize>>(), vec![0, 1, 2]);
+        assert_eq!(results[0].1.as_ref().unwrap(), &Some(3));
+        assert!(results[1].1.is_err(), "The failing target must be reported.");
+        assert_eq!(results[2].1.as_ref().unwrap(), &Some(3), "A failing target must not abort the others.");
+        assert_eq!(targets[2].executed_versions(), vec![2, 3]);
+    }
+
+    #[tokio::test]
+    pub async fn test_plan_and_info() {
+        let driver = Arc::new(TestDriver {
+            deployed: Mutex::new(vec![(1, "11".to_string()), (5, "55".to_string())]),
+            ..TestDriver::default()
+        });
+        let runner = runner(&[1, 2, 3], driver.clone());
+
+        let plan = runner.plan().await.unwrap();
+        assert_eq!(plan.current_version, Some(5));
+        assert!(plan.pending.is_empty(), "Nothing is newer than version 5.");
+
+        let info = runner.info().await.unwrap();
+        let statuses: Vec<(u64, MigrationInfoStatus)> = info.iter().map(|row| (row.version, row.status)).collect();
+        assert_eq!(statuses, vec![(1, MigrationInfoStatus::Deployed), (2, MigrationInfoStatus::Pending),
+                                  (3, MigrationInfoStatus::Pending), (5, MigrationInfoStatus::Missing)]);
+        assert_eq!(info[0].checksum.as_deref(), Some("11"));
+        assert_eq!(info[0].statement_count, None, "Deployed changelogs are not read.");
+        assert_eq!(info[1].statement_count, Some(1));
+        assert_eq!(info[0].applied_at.as_deref(), Some("1"));
+        assert_eq!(info[1].applied_at, None, "Pending versions have not been applied.");
+        assert_eq!(info[3].name, None);
+        assert_eq!(info[3].applied_at.as_deref(), Some("5"));
+        assert!(driver.executed_versions().is_empty(), "Nothing has been executed.");
+
+        let plan = self::runner(&[1, 2], Arc::new(TestDriver::default())).plan().await.unwrap();
+        assert_eq!(plan.pending.iter().map(|row| row.version).collect::<Vec<u64>>(), vec![1, 2]);
+
+        // The file of a deployed version may be gone, e.g. in a slim production image
+        let changelogs = vec![ChangelogFile::lazy(1, "init", Path::new("missing/V1_init.sql"))];
+        let runner = MigrationRunner::new(ChangelogMigrationStore::from_changelog_files(changelogs), driver.clone(), driver.clone(), false);
+        let info = runner.info().await.unwrap();
+        assert_eq!(info[0].status, MigrationInfoStatus::Deployed);
+        assert_eq!(*driver.prepares.lock().unwrap(), 0, "plan and info must not prepare the database.");
+
+        // Without a state table, every version is pending
+        let driver = Arc::new(TestDriver {
+            unprepared: true,
+            ..TestDriver::default()
+        });
+        let runner = self::runner(&[1, 2], driver.clone());
+        let plan = runner.plan().await.unwrap();
+        assert_eq!(plan.current_version, None);
+        assert_eq!(plan.pending.len(), 2);
+        let info = runner.info().await.unwrap();
+        assert!(info.iter().all(|row| row.status == MigrationInfoStatus::Pending), "Unexpected info: {:?}", info);
+        assert_eq!(*driver.prepares.lock().unwrap(), 0);
+    }
+
+    #[cfg(feature = "serde")]
+    #[tokio::test]
+    pub async fn test_plan_to_json() {
+        let plan = runner(&[1], Arc::new(TestDriver::default())).plan().await.unwrap();
+        let json = plan.to_json().unwrap();
+        assert!(json.contains("\"current_version\": null"), "Unexpected JSON: {}", json);
+        assert!(json.contains("\"status\": \"Pending\""), "Unexpected JSON: {}", json);
+        let rows = crate::info_to_json(&plan.pending).unwrap();
+        assert!(rows.starts_with("["), "Unexpected JSON: {}", rows);
+    }
+
+    #[tokio::test]
+    pub async fn test_migrate_empty_store() {
+        let driver = Arc::new(TestDriver::default());
+        let result = runner(&[], driver.clone()).migrate().await;
+        assert_eq!(result.unwrap(), None);
+
+        let result = runner(&[], driver.clone()).with_require_migrations(true).migrate().await;
+        match result {
+            Err(err) => assert!(matches!(changelog_error_kind(&err), Some(ChangelogErrorKind::EmptyChangelog))),
+            Ok(_) => assert!(false, "Empty store must be reported."),
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_pre_and_post_sql() {
+        let driver = Arc::new(TestDriver::default());
+        let result = runner(&[1, 2], driver.clone())
+            .with_pre_sql(vec!["SET statement_timeout = 0".to_string()])
+            .with_post_sql(vec!["ANALYZE;".to_string()])
+            .migrate().await;
+        assert_eq!(result.unwrap(), Some(2));
+        assert_eq!(driver.executed_versions(), vec![1, 2]);
+        assert_eq!(*driver.statements.lock().unwrap(), vec!["SET statement_timeout = 0", "ANALYZE"]);
+        assert_eq!(*driver.transactions.lock().unwrap(), 4, "Pre and post SQL run inside their own transactions.");
+
+        let driver = Arc::new(TestDriver::default());
+        let result = runner(&[1], driver.clone())
+            .with_pre_sql(vec![
+                "SET a = 1 -- note".to_string(),
+                "SET b = 2".to_string(),
+                "DO $$ BEGIN PERFORM 1; PERFORM 2; END $$".to_string(),
+            ])
+            .migrate().await;
+        assert_eq!(result.unwrap(), Some(1));
+        assert_eq!(*driver.statements.lock().unwrap(), vec![
+            "SET a = 1 -- note",
+            "SET b = 2",
+            "DO $$ BEGIN PERFORM 1; PERFORM 2; END $$",
+        ], "Statements must not be split again.");
+
+        // Nothing is pending, so neither pre nor post SQL is executed
+        let result = runner(&[1], driver.clone())
+            .with_pre_sql(vec!["SET a = 1".to_string()])
+            .with_post_sql(vec!["ANALYZE".to_string()])
+            .migrate().await;
+        assert_eq!(result.unwrap(), Some(1));
+        assert_eq!(driver.statements.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    pub async fn test_failing_pre_sql_aborts() {
+        let driver = Arc::new(TestDriver::default());
+        let result = runner(&[1, 2], driver.clone())
+            .with_pre_sql(vec!["CREATE EXTENSION FAIL".to_string(), "SELECT 1".to_string()])
+            .migrate().await;
+        assert!(result.is_err(), "Failing pre SQL must abort the migration.");
+        assert_eq!(driver.statements.lock().unwrap().len(), 1, "The statements after the failing one are skipped.");
+        assert_eq!(*driver.rollbacks.lock().unwrap(), 1);
+        assert!(driver.executed_versions().is_empty(), "No changelog must be executed.");
+        assert!(driver.deployed_versions().is_empty(), "No version must be deployed.");
+    }
+
+    #[tokio::test]
+    pub async fn test_apply_one() {
+        let driver = Arc::new(TestDriver::default());
+        let runner = runner(&[1], driver.clone());
+        runner.migrate().await.unwrap();
+
+        let hotfix = ChangelogFile::from_string(5, "hotfix", "SELECT 1;").unwrap();
+        runner.apply_one(&hotfix).await.unwrap();
+        assert_eq!(driver.deployed_versions(), vec![1, 5]);
+
+        assert!(runner.apply_one(&hotfix).await.is_err(), "Deployed version must not be applied again.");
+        assert_eq!(driver.executed_versions(), vec![1, 5]);
+    }
+
+    #[tokio::test]
+    pub async fn test_requires_db() {
+        let changelog = |version: u64| ChangelogFile::from_string(version, "merge", "--!! requires_db: \">=15\"\nSELECT 1;").unwrap();
+        let driver = Arc::new(TestDriver {
+            server_version: Some("PostgreSQL 14.5 on x86_64-pc-linux-gnu".to_string()),
+            ..TestDriver::default()
+        });
+        let old_server = runner(&[1], driver.clone());
+        old_server.migrate().await.unwrap();
+        match old_server.apply_one(&changelog(2)).await {
+            Err(err) => assert!(matches!(err.kind(), MigrationsErrorKind::UnsupportedDatabaseVersion { version: 2, .. }), "Unexpected error: {}", err),
+            Ok(_) => assert!(false, "An older server must be rejected."),
+        }
+        assert_eq!(driver.executed_versions(), vec![1]);
+
+        let driver = Arc::new(TestDriver {
+            server_version: Some("15.0.2000.5".to_string()),
+            ..TestDriver::default()
+        });
+        runner(&[], driver.clone()).apply_one(&changelog(2)).await.unwrap();
+        assert_eq!(driver.executed_versions(), vec![2]);
+
+        let driver = Arc::new(TestDriver::default());
+        runner(&[], driver.clone()).apply_one(&changelog(2)).await.unwrap();
+        assert_eq!(driver.executed_versions(), vec![2], "An unknown server version must not prevent the deployment.");
+
+        let invalid = ChangelogFile::from_string(3, "invalid", "--!! requires_db: \"fourteen\"\nSELECT 1;").unwrap();
+        assert!(runner(&[], driver.clone()).apply_one(&invalid).await.is_err(), "Invalid requirements must be reported.");
+    }
+
+    #[tokio::test]
+    pub async fn test_migrate_range() {
+        let driver = Arc::new(TestDriver::default());
+        let result = runner(&[1, 2, 3, 4], driver.clone()).migrate_range(2, 3).await;
+        assert_eq!(result.unwrap(), Some(3));
+        assert_eq!(driver.executed_versions(), vec![2, 3]);
+    }
+
+    #[tokio::test]
+    pub async fn test_migrate_filtered() {
+        let driver = Arc::new(TestDriver::default());
+        let runner = runner(&[1, 2, 3, 4], driver.clone());
+        let result = runner.migrate_filtered(|changelog, highest| crate::is_pending(changelog, highest) && changelog.version() != 3).await;
+        assert_eq!(result.unwrap(), Some(4));
+        assert_eq!(driver.executed_versions(), vec![1, 2, 4]);
+
+        let result = runner.migrate_filtered(|changelog, _| changelog.version() == 3).await;
+        assert_eq!(result.unwrap(), Some(4), "A version deployed out of order must not lower the highest version.");
+        assert_eq!(driver.executed_versions(), vec![1, 2, 4, 3]);
+        assert_eq!(runner.migrate().await.unwrap(), Some(4));
+        assert_eq!(driver.executed_versions(), vec![1, 2, 4, 3], "Nothing is pending anymore.");
+    }
+
+    #[tokio::test]
+    pub async fn test_migrate_range_min_below_available() {
+        let driver = Arc::new(TestDriver::default());
+        let result = runner(&[2, 3, 4], driver.clone()).migrate_range(1, 3).await;
+        match result {
+            Err(err) => assert!(matches!(changelog_error_kind(&err), Some(ChangelogErrorKind::MinVersionNotFound(_, _)))),
+            Ok(_) => assert!(false, "Missing min version must be reported."),
+        }
+        assert!(driver.executed_versions().is_empty(), "Nothing has been executed.");
+    }
+
+    #[tokio::test]
+    pub async fn test_migrate_range_max_above_available() {
+        let driver = Arc::new(TestDriver::default());
+        let result = runner(&[2, 3, 4], driver.clone()).migrate_range(2, 5).await;
+        match result {
+            Err(err) => assert!(matches!(changelog_error_kind(&err), Some(ChangelogErrorKind::MaxVersionNotFound(_, _)))),
+            Ok(_) => assert!(false, "Missing max version must be reported."),
+        }
+        assert!(driver.executed_versions().is_empty(), "Nothing has been executed.");
+    }
+
+    #[tokio::test]
+    pub async fn test_directory_store_loads_lazily() {
+        let dir = std::env::temp_dir().join(format!("flyway-directory-store-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("V1_init.sql"), "SELECT 1;").unwrap();
+        std::fs::write(dir.join("V2_next.sql"), "SELECT 2;").unwrap();
+        std::fs::write(dir.join("README.md"), "not a migration").unwrap();
+
+        let store = DirectoryMigrationStore::new(&dir).unwrap();
+        let changelogs = store.sorted_changelogs();
+        assert_eq!(changelogs.iter().map(|changelog| changelog.version()).collect::<Vec<u64>>(), vec![1, 2]);
+        assert!(changelogs.iter().all(|changelog| !changelog.is_loaded()), "Content must not be read eagerly.");
+
+        // Version 1 is already deployed, so its file must never be read
+        std::fs::remove_file(dir.join("V1_init.sql")).unwrap();
+        let driver = Arc::new(TestDriver {
+            deployed: Mutex::new(vec![(1, "0".to_string())]),
+            ..TestDriver::default()
+        });
+        let result = MigrationRunner::new(store, driver.clone(), driver.clone(), false).migrate().await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(result.unwrap(), Some(2));
+        assert_eq!(driver.executed_versions(), vec![2]);
+    }
+
+    #[tokio::test]
+    pub async fn test_timestamp_versions() {
+        let dir = std::env::temp_dir().join(format!("flyway-timestamp-versions-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for filename in ["V20240115_1200__add_users.sql", "V20231231_2359__init.sql", "V20240115_0930__add_roles.sql", "V9_legacy.sql"] {
+            std::fs::write(dir.join(filename), "SELECT 1;").unwrap();
+        }
+
+        let store = DirectoryMigrationStore::new(&dir).unwrap();
+        let names: Vec<(u64, String)> = store.sorted_changelogs().into_iter()
+            .map(|changelog| (changelog.version(), changelog.name.clone()))
+            .collect();
+        assert_eq!(names, vec![(9, "legacy".to_string()), (202312312359, "init".to_string()),
+                               (202401150930, "add_roles".to_string()), (202401151200, "add_users".to_string())]);
+
+        let driver = Arc::new(TestDriver {
+            deployed: Mutex::new(vec![(9, "0".to_string()), (202312312359, "0".to_string())]),
+            ..TestDriver::default()
+        });
+        let result = MigrationRunner::new(store, driver.clone(), driver.clone(), false).migrate().await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(result.unwrap(), Some(202401151200));
+        assert_eq!(driver.executed_versions(), vec![202401150930, 202401151200]);
+    }
+
+    #[tokio::test]
+    pub async fn test_verify_after() {
+        let driver = Arc::new(TestDriver::default());
+        let result = runner(&[1, 2], driver.clone()).with_verify_after(true).migrate().await;
+        assert_eq!(result.unwrap(), Some(2));
+
+        let driver = Arc::new(TestDriver {
+            truncating_checksums: true,
+            ..TestDriver::default()
+        });
+        let err = runner(&[1, 2], driver.clone()).with_verify_after(true).migrate().await.unwrap_err();
+        match err.kind() {
+            MigrationsErrorKind::ChecksumMismatch(version, _, _) => assert_eq!(*version, 1),
+            _ => assert!(false, "Unexpected error: {:?}", err),
+        }
+
+        let driver = Arc::new(TestDriver {
+            truncating_checksums: true,
+            ..TestDriver::default()
+        });
+        let result = runner(&[1, 2], driver.clone()).migrate().await;
+        assert_eq!(result.unwrap(), Some(2), "Nothing is verified by default.");
+    }
+
+    #[tokio::test]
+    pub async fn test_get_version() {
+        let driver = TestDriver {
+            deployed: Mutex::new(vec![(1, "11".to_string()), (2, "22".to_string())]),
+            ..TestDriver::default()
+        };
+        let state = driver.get_version(2).await.unwrap();
+        match state {
+            Some(state) => assert_eq!(state.version, 2),
+            None => assert!(false, "Version 2 has been deployed."),
+        }
+        assert!(driver.get_version(3).await.unwrap().is_none(), "Version 3 has not been deployed.");
+    }
+
+    #[test]
+    pub fn test_migration_state_dedup() {
+        assert!(MigrationStatus::InProgress < MigrationStatus::Failed);
+        assert!(MigrationStatus::Failed < MigrationStatus::Deployed);
+
+        let states = vec![
+            MigrationState::new(2, MigrationStatus::Deployed),
+            MigrationState::new(1, MigrationStatus::Deployed),
+            MigrationState::new(2, MigrationStatus::InProgress),
+            MigrationState::new(3, MigrationStatus::InProgress),
+            MigrationState::new(1, MigrationStatus::Failed),
+        ];
+        let mut sorted = states.clone();
+        sorted.sort();
+        let versions: Vec<u64> = sorted.iter().map(|state| state.version).collect();
+        assert_eq!(versions, vec![1, 1, 2, 2, 3]);
+        assert_eq!(sorted[2].status, MigrationStatus::InProgress);
+
+        let deduped = MigrationState::dedup(states);
+        assert_eq!(deduped, vec![
+            MigrationState::new(1, MigrationStatus::Deployed),
+            MigrationState::new(2, MigrationStatus::Deployed),
+            MigrationState::new(3, MigrationStatus::InProgress),
+        ]);
+    }
+
+    /// Executor relying on the default `execute_changelog_file`
+    #[derive(Default)]
+    struct StatementExecutor {
+        /// All executed statements, in execution order
+        statements: Mutex<Vec<String>>,
+        /// Number of transactions committed
+        commits: Mutex<usize>,
+    }
+
+    #[async_trait]
+    impl MigrationExecutor for StatementExecutor {
+        async fn begin_transaction(&self) -> Result<()> {
+            return Ok(());
+        }
+
+        async fn execute_statement(&self, statement: &SqlStatement) -> Result<u64> {
+            self.statements.lock().unwrap().push(statement.statement.clone());
+            if statement.statement.contains("FAIL") {
+                return Err(MigrationsError::migration_database_step_failed(None, None));
+            }
+            return Ok(1);
+        }
+
+        async fn commit_transaction(&self) -> Result<()> {
+            *self.commits.lock().unwrap() += 1;
+            return Ok(());
+        }
+
+        async fn rollback_transaction(&self) -> Result<()> {
+            return Ok(());
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_execute_statements() {
+        let executor = StatementExecutor::default();
+        let changelog = ChangelogFile::from_string(1, "test",
+            "SELECT 1;\n--! may_fail: true\nSELECT FAIL;\nSELECT 2;\n").unwrap();
+        let outcome = executor.execute_changelog_file(&changelog).await.unwrap();
+        assert_eq!(outcome.rows_affected, 2, "The failed statement affects no rows.");
+        assert_eq!(outcome.statements_executed, 2, "The failed statement is not counted.");
+        assert_eq!(executor.statements.lock().unwrap().len(), 3);
+
+        let executor = StatementExecutor::default();
+        let changelog = ChangelogFile::from_string(1, "test", "SELECT 1;\nSELECT FAIL;\nSELECT 2;\n").unwrap();
+        let result = executor.execute_changelog_file(&changelog).await;
+        assert!(result.is_err(), "Statements without may_fail must not be ignored.");
+        assert_eq!(executor.statements.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    pub async fn test_commit_every() {
+        let executor = StatementExecutor::default();
+        let changelog = ChangelogFile::from_string(1, "test", "SELECT 1;\nSELECT 2;\nSELECT 3;\nSELECT 4;\nSELECT 5;\n").unwrap();
+        assert_eq!(executor.execute_changelog_file_in_batches(&changelog, 2).await.unwrap(), ChangelogOutcome {
+            statements_executed: 5,
+            rows_affected: 5,
+        });
+        assert_eq!(*executor.commits.lock().unwrap(), 2);
+
+        let executor = StatementExecutor::default();
+        let changelog = ChangelogFile::from_string(1, "test", "SELECT 1;\nSELECT 2;\nSELECT FAIL;\nSELECT 4;\n").unwrap();
+        match executor.execute_changelog_file_in_batches(&changelog, 2).await {
+            Err(err) => assert!(err.to_string().contains("after 2 of its statements had been committed"), "Unexpected error: {}", err),
+            Ok(_) => assert!(false, "Failing statement must be reported."),
+        }
+
+        let driver = Arc::new(TestDriver::default());
+        let runner = runner(&[1, 2], driver.clone()).with_commit_every(Some(1));
+        assert_eq!(runner.migrate().await.unwrap(), Some(2));
+        assert_eq!(*driver.transactions.lock().unwrap(), 4, "A new transaction is begun after each statement.");
+        assert_eq!(driver.deployed_versions(), vec![1, 2]);
+    }
+
+    #[tokio::test]
+    pub async fn test_non_transactional_file() {
+        let driver = Arc::new(TestDriver::default());
+        let store = ChangelogMigrationStore::from_changelog_files(vec![
+            ChangelogFile::from_string(1, "table", "CREATE TABLE users(email TEXT);").unwrap(),
+            ChangelogFile::from_string(2, "index", "--!! transactional: false\nCREATE INDEX CONCURRENTLY idx ON users(email);").unwrap(),
+        ]);
+        let runner = MigrationRunner::new(store, driver.clone(), driver.clone(), false)
+            .with_atomic_versioning(true);
+        assert_eq!(runner.migrate().await.unwrap(), Some(2));
+        assert_eq!(driver.executed_versions(), vec![1, 2]);
+        assert_eq!(driver.deployed_versions(), vec![1, 2]);
+        assert_eq!(*driver.transactions.lock().unwrap(), 1, "Version 2 must not run inside a transaction.");
+    }
+
+    #[tokio::test]
+    pub async fn test_has_pending() {
+        let driver = Arc::new(TestDriver {
+            deployed: Mutex::new(vec![(1, "0".to_string())]),
+            ..TestDriver::default()
+        });
+        assert!(runner(&[1, 2], driver.clone()).has_pending().await.unwrap(), "Version 2 is pending.");
+        assert!(!runner(&[1], driver.clone()).has_pending().await.unwrap(), "Version 1 is deployed.");
+        assert!(!runner(&[], driver.clone()).has_pending().await.unwrap(), "The store is empty.");
+
+        // The files are gone, so reading any content would fail
+        let dir = std::env::temp_dir().join(format!("flyway-has-pending-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("V1_init.sql"), "SELECT 1;").unwrap();
+        std::fs::write(dir.join("V2_next.sql"), "SELECT 2;").unwrap();
+        let store = DirectoryMigrationStore::new(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let result = MigrationRunner::new(store, driver.clone(), driver.clone(), false).has_pending().await;
+        assert!(result.unwrap(), "Version 2 is pending.");
+        assert!(driver.executed_versions().is_empty(), "Nothing has been executed.");
+        assert_eq!(*driver.prepares.lock().unwrap(), 0, "A read-only check must not prepare the database.");
+
+        // Without a state table, nothing has been deployed
+        let driver = Arc::new(TestDriver {
+            unprepared: true,
+            ..TestDriver::default()
+        });
+        assert!(runner(&[1], driver.clone()).has_pending().await.unwrap());
+        assert!(!runner(&[], driver.clone()).has_pending().await.unwrap());
+        assert_eq!(*driver.prepares.lock().unwrap(), 0);
+    }
+}